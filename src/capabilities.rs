@@ -0,0 +1,236 @@
+//! A summary of what an app is allowed to do, for reviewing apps before they
+//! ship.
+//!
+//! The summary collects the permissions, storage IDs, and kernel version from
+//! the command line, and the credentials of each TBF. It can be written as
+//! Markdown for people or as JSON for scripts.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Everything the capabilities report covers.
+#[derive(Debug, Default)]
+pub struct Capabilities {
+    pub package_name: String,
+    /// Allowed (driver, command) pairs.
+    pub permissions: Vec<(u32, u32)>,
+    /// Explicitly denied (driver, command) pairs.
+    pub permissions_deny: Vec<(u32, u32)>,
+    pub write_id: Option<u32>,
+    pub read_ids: Vec<u32>,
+    pub access_ids: Vec<u32>,
+    /// The minimum kernel version as (major, minor).
+    pub kernel_version: Option<(u16, u16)>,
+    /// Whether newer major kernel versions are accepted too.
+    pub kernel_version_no_upper: bool,
+    /// Architecture and credential names of each TBF.
+    pub tbfs: Vec<(String, Vec<&'static str>)>,
+}
+
+/// Parse a map from driver numbers to names given as a JSON object, e.g.
+/// `{"0x1": "console", "2": "led"}`. JSON keys are strings, so the driver
+/// numbers can be decimal or hexadecimal.
+pub fn parse_driver_names(contents: &str) -> Result<BTreeMap<u32, String>, String> {
+    let map: BTreeMap<String, String> =
+        serde_json::from_str(contents).map_err(|e| format!("expected a JSON object: {}", e))?;
+    map.into_iter()
+        .map(|(number, name)| {
+            let driver = match number.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => number.parse(),
+            }
+            .map_err(|_| format!("`{}` is not a driver number", number))?;
+            Ok((driver, name))
+        })
+        .collect()
+}
+
+/// Whether a TBF with these credentials is signed, not just hashed.
+fn is_signed(credentials: &[&str]) -> bool {
+    credentials.contains(&"rsa4096") || credentials.contains(&"rsa3072")
+}
+
+impl Capabilities {
+    fn kernel_version_requirement(&self) -> Option<String> {
+        self.kernel_version.map(|(major, minor)| {
+            if self.kernel_version_no_upper {
+                format!(">= {}.{}", major, minor)
+            } else {
+                format!("^{}.{}", major, minor)
+            }
+        })
+    }
+
+    /// A Markdown summary. Drivers in `driver_names` are shown by name.
+    pub fn to_markdown(&self, driver_names: &BTreeMap<u32, String>) -> String {
+        let driver = |number: u32| match driver_names.get(&number) {
+            Some(name) => format!("{} ({:#x})", name, number),
+            None => format!("{:#x}", number),
+        };
+        let ids = |ids: &[u32]| {
+            if ids.is_empty() {
+                return "none".to_string();
+            }
+            ids.iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        let mut report = String::new();
+        if self.package_name.is_empty() {
+            writeln!(report, "# Capabilities\n").unwrap();
+        } else {
+            writeln!(report, "# Capabilities of {}\n", self.package_name).unwrap();
+        }
+
+        for (title, permissions) in [
+            ("Permissions", &self.permissions),
+            ("Denied permissions", &self.permissions_deny),
+        ] {
+            writeln!(report, "## {}\n", title).unwrap();
+            if permissions.is_empty() {
+                writeln!(report, "None.\n").unwrap();
+                continue;
+            }
+            writeln!(report, "| Driver | Command |\n|--------|---------|").unwrap();
+            for (number, command) in permissions {
+                writeln!(report, "| {} | {} |", driver(*number), command).unwrap();
+            }
+            writeln!(report).unwrap();
+        }
+
+        writeln!(report, "## Storage\n").unwrap();
+        match self.write_id {
+            Some(write_id) => writeln!(report, "- Write ID: {}", write_id).unwrap(),
+            None => writeln!(report, "- Write ID: none").unwrap(),
+        }
+        writeln!(report, "- Read IDs: {}", ids(&self.read_ids)).unwrap();
+        writeln!(report, "- Access IDs: {}\n", ids(&self.access_ids)).unwrap();
+
+        writeln!(report, "## Kernel version\n").unwrap();
+        match self.kernel_version_requirement() {
+            Some(requirement) => writeln!(report, "{}\n", requirement).unwrap(),
+            None => writeln!(report, "Any.\n").unwrap(),
+        }
+
+        writeln!(report, "## TBFs\n").unwrap();
+        writeln!(
+            report,
+            "| Architecture | Credentials | Signed |\n|--------------|-------------|--------|"
+        )
+        .unwrap();
+        for (architecture, credentials) in &self.tbfs {
+            writeln!(
+                report,
+                "| {} | {} | {} |",
+                architecture,
+                if credentials.is_empty() {
+                    "none".to_string()
+                } else {
+                    credentials.join(", ")
+                },
+                if is_signed(credentials) { "yes" } else { "no" }
+            )
+            .unwrap();
+        }
+        report
+    }
+
+    /// A JSON summary. Drivers in `driver_names` get a `name` field.
+    pub fn to_json(&self, driver_names: &BTreeMap<u32, String>) -> String {
+        let permissions = |permissions: &[(u32, u32)]| {
+            permissions
+                .iter()
+                .map(|(driver, command)| {
+                    let mut permission = serde_json::json!({
+                        "driver": driver,
+                        "command": command,
+                    });
+                    if let Some(name) = driver_names.get(driver) {
+                        permission["name"] = name.as_str().into();
+                    }
+                    permission
+                })
+                .collect::<Vec<_>>()
+        };
+        let tbfs: Vec<_> = self
+            .tbfs
+            .iter()
+            .map(|(architecture, credentials)| {
+                serde_json::json!({
+                    "architecture": architecture,
+                    "credentials": credentials,
+                    "signed": is_signed(credentials),
+                })
+            })
+            .collect();
+        let report = serde_json::json!({
+            "package_name": self.package_name,
+            "permissions": permissions(&self.permissions),
+            "permissions_deny": permissions(&self.permissions_deny),
+            "write_id": self.write_id,
+            "read_ids": self.read_ids,
+            "access_ids": self.access_ids,
+            "kernel_version": self.kernel_version_requirement(),
+            "tbfs": tbfs,
+        });
+        serde_json::to_string_pretty(&report).unwrap() + "\n"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_driver_names, Capabilities};
+
+    fn capabilities() -> Capabilities {
+        Capabilities {
+            package_name: "blink".to_string(),
+            permissions: vec![(1, 0), (2, 1)],
+            write_id: Some(7),
+            read_ids: vec![7, 8],
+            kernel_version: Some((2, 1)),
+            tbfs: vec![
+                ("cortex-m4".to_string(), vec!["sha256", "rsa4096"]),
+                ("rv32imc".to_string(), vec![]),
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    pub fn parses_driver_names() {
+        let names = parse_driver_names(r#"{"0x1": "console", "2": "led"}"#).unwrap();
+        assert_eq!(names[&1], "console");
+        assert_eq!(names[&2], "led");
+        assert!(parse_driver_names(r#"{"console": "1"}"#).is_err());
+        assert!(parse_driver_names("[1, 2]").is_err());
+    }
+
+    #[test]
+    pub fn writes_markdown_report() {
+        let names = parse_driver_names(r#"{"1": "console"}"#).unwrap();
+        let report = capabilities().to_markdown(&names);
+        assert!(report.starts_with("# Capabilities of blink\n"));
+        assert!(report.contains("| console (0x1) | 0 |\n| 0x2 | 1 |\n"));
+        assert!(report.contains("## Denied permissions\n\nNone.\n"));
+        assert!(report.contains("- Write ID: 7\n- Read IDs: 7, 8\n- Access IDs: none\n"));
+        assert!(report.contains("## Kernel version\n\n^2.1\n"));
+        assert!(report.contains("| cortex-m4 | sha256, rsa4096 | yes |\n"));
+        assert!(report.contains("| rv32imc | none | no |\n"));
+    }
+
+    #[test]
+    pub fn writes_json_report() {
+        let names = parse_driver_names(r#"{"1": "console"}"#).unwrap();
+        let report: serde_json::Value =
+            serde_json::from_str(&capabilities().to_json(&names)).unwrap();
+        assert_eq!(report["permissions"][0]["name"], "console");
+        assert_eq!(report["permissions"][1]["driver"], 2);
+        assert!(report["permissions"][1].get("name").is_none());
+        assert_eq!(report["write_id"], 7);
+        assert_eq!(report["kernel_version"], "^2.1");
+        assert_eq!(report["tbfs"][0]["signed"], true);
+        assert_eq!(report["tbfs"][1]["signed"], false);
+    }
+}
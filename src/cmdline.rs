@@ -1,14 +1,186 @@
 //! Command line parser setup for elf2tab.
 
+use crate::convert::TrailingPadding;
+use crate::header::{CommandCredentialType, HeaderChecksum, TbfEndian};
+use clap::builder::{PossibleValue, TypedValueParser};
 use std::error::Error;
-use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
 
-fn parse_perms(s: &str) -> Result<(u32, u32), Box<dyn Error + Send + Sync>> {
+/// The most commands a single permission range may cover.
+const MAX_PERMISSION_RANGE_LEN: u32 = 0x10000;
+
+/// A driver number and a range of its command numbers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PermissionRange {
+    pub driver: u32,
+    pub commands: RangeInclusive<u32>,
+}
+
+/// Expand permission ranges to individual (driver, command) pairs.
+pub fn expand_permissions(permissions: &[PermissionRange]) -> Vec<(u32, u32)> {
+    permissions
+        .iter()
+        .flat_map(|permission| {
+            permission
+                .commands
+                .clone()
+                .map(|command| (permission.driver, command))
+        })
+        .collect()
+}
+
+/// The first permission that `--max-command max_command` rejects, i.e. with a
+/// command number of `max_command` or larger.
+pub fn permission_above_max_command(
+    permissions: &[(u32, u32)],
+    max_command: u32,
+) -> Option<(u32, u32)> {
+    permissions
+        .iter()
+        .copied()
+        .find(|(_, command)| *command >= max_command)
+}
+
+/// Parse a non-negative percentage.
+fn parse_percentage(s: &str) -> Result<f64, Box<dyn Error + Send + Sync>> {
+    let percentage: f64 = s.parse()?;
+    if !(percentage >= 0.0 && percentage.is_finite()) {
+        return Err(format!("`{}` is not a non-negative percentage", s).into());
+    }
+    Ok(percentage)
+}
+
+/// Parse a permission in the form `driver,command` or `driver,first-last`.
+fn parse_perms(s: &str) -> Result<PermissionRange, Box<dyn Error + Send + Sync>> {
     let pos = s
         .find(',')
         .ok_or_else(|| format!("invalid number,option: no `,` found in `{}`", s))?;
-    Ok((s[..pos].parse()?, s[pos + 1..].parse()?))
+    let commands = &s[pos + 1..];
+    let commands = match commands.split_once('-') {
+        Some((first, last)) => {
+            let (first, last): (u32, u32) = (first.parse()?, last.parse()?);
+            if first > last {
+                return Err(format!("command range `{}` is empty", commands).into());
+            }
+            if last - first >= MAX_PERMISSION_RANGE_LEN {
+                return Err(format!(
+                    "command range `{}` covers more than {} commands",
+                    commands, MAX_PERMISSION_RANGE_LEN
+                )
+                .into());
+            }
+            first..=last
+        }
+        None => {
+            let command = commands.parse()?;
+            command..=command
+        }
+    };
+    Ok(PermissionRange {
+        driver: s[..pos].parse()?,
+        commands,
+    })
+}
+
+fn parse_arch_map(s: &str) -> Result<(String, String), Box<dyn Error + Send + Sync>> {
+    let pos = s
+        .find('=')
+        .ok_or_else(|| format!("invalid target=architecture: no `=` found in `{}`", s))?;
+    Ok((s[..pos].to_string(), s[pos + 1..].to_string()))
+}
+
+fn parse_include_file(s: &str) -> Result<(String, PathBuf), Box<dyn Error + Send + Sync>> {
+    let pos = s
+        .find('=')
+        .ok_or_else(|| format!("invalid NAME=PATH: no `=` found in `{}`", s))?;
+    if pos == 0 {
+        return Err(format!("invalid NAME=PATH: empty name in `{}`", s).into());
+    }
+    Ok((s[..pos].to_string(), PathBuf::from(&s[pos + 1..])))
+}
+
+fn parse_vendor_string(s: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    if s.len() > u16::MAX as usize {
+        return Err(format!(
+            "vendor string is {} bytes, but the TLV length is limited to {} bytes",
+            s.len(),
+            u16::MAX
+        )
+        .into());
+    }
+    Ok(s.to_string())
+}
+
+/// TAB format versions elf2tab can create.
+pub const SUPPORTED_TAB_VERSIONS: &[u32] = &[1];
+
+fn parse_tab_version(s: &str) -> Result<u32, Box<dyn Error + Send + Sync>> {
+    let version: u32 = s.parse()?;
+    if !SUPPORTED_TAB_VERSIONS.contains(&version) {
+        let supported: Vec<String> = SUPPORTED_TAB_VERSIONS
+            .iter()
+            .map(|version| version.to_string())
+            .collect();
+        return Err(format!(
+            "unsupported TAB version `{}`, supported versions: {}",
+            s,
+            supported.join(", ")
+        )
+        .into());
+    }
+    Ok(version)
+}
+
+fn parse_power_of_two(s: &str) -> Result<u32, Box<dyn Error + Send + Sync>> {
+    let value: u32 = clap_num::maybe_hex(s)?;
+    if !value.is_power_of_two() {
+        return Err(format!("`{}` is not a power of two", s).into());
+    }
+    Ok(value)
+}
+
+/// Parse a padding mode: `pow2`, `multiple:N`, or `none`.
+fn parse_padding_mode(s: &str) -> Result<TrailingPadding, Box<dyn Error + Send + Sync>> {
+    match s {
+        "pow2" => Ok(TrailingPadding::TotalSizePowerOfTwo),
+        "none" => Ok(TrailingPadding::Disabled),
+        _ => {
+            let multiple = s
+                .strip_prefix("multiple:")
+                .ok_or_else(|| format!("`{}` is not pow2, multiple:N, or none", s))?;
+            let multiple: u32 = clap_num::maybe_hex(multiple)?;
+            if multiple == 0 {
+                return Err("the padding multiple must not be 0".into());
+            }
+            Ok(TrailingPadding::TotalSizeMultiple(multiple as usize))
+        }
+    }
+}
+
+/// Parse a pattern for TBF names in the TAB. Only the `{arch}`, `{name}`, and
+/// `{version}` placeholders are allowed, and TBF names must end in `.tbf`.
+fn parse_tbf_name_pattern(s: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let mut rest = s;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unterminated placeholder in `{}`", s))?;
+        let placeholder = &rest[start..start + end + 1];
+        if !["{arch}", "{name}", "{version}"].contains(&placeholder) {
+            return Err(format!(
+                "unknown placeholder `{}`; use {{arch}}, {{name}}, or {{version}}",
+                placeholder
+            )
+            .into());
+        }
+        rest = &rest[start + end + 1..];
+    }
+    if !s.ends_with(".tbf") {
+        return Err(format!("`{}` does not end in .tbf", s).into());
+    }
+    Ok(s.to_string())
 }
 
 /// Helper struct for keeping track of the ELF files to convert and an optional
@@ -21,33 +193,271 @@ pub struct ElfFile {
     /// Otherwise the architecture will be inferred from the name of the ELF
     /// file.
     pub architecture: Option<String>,
+    /// Callers may optionally set the stack size for this ELF, overriding
+    /// `--stack`.
+    pub stack_size: Option<u32>,
+    /// Callers may optionally choose the credentials for this ELF, overriding
+    /// `--sha256`, `--sha384`, `--sha512`, `--sha3-256`, `--sha256-binary`,
+    /// and whether the key from `--rsa4096-private` or `--pkcs11-uri` is used.
+    pub credentials: Option<Vec<Credential>>,
+}
+
+/// Credentials that can be chosen per input.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Credential {
+    Sha256,
+    Sha384,
+    Sha512,
+    #[value(name = "sha3-256")]
+    Sha3_256,
+    Rsa4096,
+    Sha256Binary,
 }
 
-impl From<&OsStr> for ElfFile {
-    fn from(value: &OsStr) -> Self {
-        let mut elf_file = ElfFile {
-            path: value.into(),
-            architecture: None,
-        };
-        if let Some(s) = value.to_str() {
-            if let Some(index) = s.rfind(',') {
-                elf_file.path = PathBuf::from(&s[0..index]);
-                elf_file.architecture = Some(String::from(&s[index + 1..]));
+/// Parse an input in the form
+/// `elf[,architecture][,stack=N][,sign=CREDENTIAL[+CREDENTIAL...]]`.
+fn parse_elf_file(value: OsString) -> Result<ElfFile, Box<dyn Error + Send + Sync>> {
+    use clap::ValueEnum;
+
+    let mut elf_file = ElfFile {
+        path: value.clone().into(),
+        architecture: None,
+        stack_size: None,
+        credentials: None,
+    };
+    if let Some(mut s) = value.to_str() {
+        // Per-input options come last, in any order.
+        while let Some(index) = s.rfind(',') {
+            let option = &s[index + 1..];
+            if let Some(stack_size) = option.strip_prefix("stack=") {
+                elf_file.stack_size = Some(
+                    clap_num::maybe_hex(stack_size)
+                        .map_err(|e| format!("invalid stack size `{}`: {}", stack_size, e))?,
+                );
+            } else if let Some(credentials) = option.strip_prefix("sign=") {
+                elf_file.credentials = Some(if credentials == "none" {
+                    Vec::new()
+                } else {
+                    credentials
+                        .split('+')
+                        .map(|credential| {
+                            Credential::from_str(credential, false)
+                                .map_err(|_| format!("unknown credential `{}`", credential))
+                        })
+                        .collect::<Result<_, _>>()?
+                });
+            } else {
+                break;
             }
+            s = &s[0..index];
+            elf_file.path = PathBuf::from(s);
+        }
+        if let Some(index) = s.rfind(',') {
+            elf_file.path = PathBuf::from(&s[0..index]);
+            elf_file.architecture = Some(String::from(&s[index + 1..]));
+        }
+    }
+    Ok(elf_file)
+}
+
+/// Digest algorithms available for the TAB sidecar checksum file.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+/// Architectures a reconstructed ELF can be created for.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ElfMachine {
+    Arm,
+    Riscv,
+    X86,
+}
+
+impl ElfMachine {
+    /// The ELF `e_machine` value for this architecture.
+    pub fn e_machine(self) -> u16 {
+        match self {
+            ElfMachine::Arm => elf::abi::EM_ARM,
+            ElfMachine::Riscv => elf::abi::EM_RISCV,
+            ElfMachine::X86 => elf::abi::EM_386,
         }
-        elf_file
     }
 }
 
+// The header types are also used without the command line, so their names on
+// the command line are given here rather than derived in `header`.
+impl clap::ValueEnum for HeaderChecksum {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[HeaderChecksum::Xor, HeaderChecksum::Crc32]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            HeaderChecksum::Xor => PossibleValue::new("xor")
+                .help("XOR of all header words, as the TBF format currently defines"),
+            HeaderChecksum::Crc32 => {
+                PossibleValue::new("crc32").help("CRC-32 (IEEE 802.3) over the header bytes")
+            }
+        })
+    }
+}
+
+impl clap::ValueEnum for TbfEndian {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[TbfEndian::Little, TbfEndian::Big]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            TbfEndian::Little => PossibleValue::new("little")
+                .help("The byte order of the TBF format, which Tock kernels expect"),
+            TbfEndian::Big => PossibleValue::new("big")
+                .help("Only for testing how kernels handle headers in the wrong byte order"),
+        })
+    }
+}
+
+impl clap::ValueEnum for CommandCredentialType {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            CommandCredentialType::Rsa3072,
+            CommandCredentialType::Rsa4096,
+            CommandCredentialType::Sha256,
+            CommandCredentialType::Sha384,
+            CommandCredentialType::Sha512,
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(PossibleValue::new(match self {
+            CommandCredentialType::Rsa3072 => "rsa3072",
+            CommandCredentialType::Rsa4096 => "rsa4096",
+            CommandCredentialType::Sha256 => "sha256",
+            CommandCredentialType::Sha384 => "sha384",
+            CommandCredentialType::Sha512 => "sha512",
+        }))
+    }
+}
+
+/// Tools for working with existing TBFs and TABs. Without a command, elf2tab
+/// converts ELFs to a TAB.
+#[derive(clap::Subcommand, Debug)]
+pub enum Command {
+    #[command(about = "Reconstruct a minimal ELF from a TBF for debugging")]
+    ToElf {
+        #[arg(id = "tbf", help = "TBF to reconstruct the ELF from")]
+        tbf: PathBuf,
+
+        #[arg(
+            long = "output-file",
+            short = 'o',
+            id = "filename",
+            help = "output file name [default: <tbf>.elf]"
+        )]
+        output: Option<PathBuf>,
+
+        #[arg(
+            long = "machine",
+            id = "machine",
+            help = "Architecture of the app",
+            value_enum,
+            default_value = "arm"
+        )]
+        machine: ElfMachine,
+
+        #[arg(
+            long = "tbf-address",
+            id = "tbf-address",
+            help = "Flash address of the start of the TBF [default: from the fixed flash address]",
+            value_parser = clap_num::maybe_hex::<u32>,
+        )]
+        tbf_address: Option<u32>,
+    },
+
+    #[command(about = "Extract the TBF for one architecture from a TAB")]
+    Extract {
+        #[arg(id = "tab", help = "TAB to extract the TBF from")]
+        tab: PathBuf,
+
+        #[arg(
+            id = "architecture",
+            help = "Architecture of the TBF to extract",
+            required_unless_present = "list"
+        )]
+        architecture: Option<String>,
+
+        #[arg(
+            long = "output-file",
+            short = 'o',
+            id = "filename",
+            help = "output file name [default: <architecture>.tbf]",
+            conflicts_with = "list"
+        )]
+        output: Option<PathBuf>,
+
+        #[arg(
+            long = "list",
+            id = "list",
+            help = "List the files in the TAB instead of extracting one",
+            conflicts_with = "architecture"
+        )]
+        list: bool,
+    },
+
+    #[command(about = "Replace the footer credentials of a TBF with Reserved space")]
+    StripCredentials {
+        #[arg(id = "tbf", help = "TBF to remove the credentials from")]
+        tbf: PathBuf,
+
+        #[arg(
+            long = "output-file",
+            short = 'o',
+            id = "filename",
+            help = "output file name [default: overwrite <tbf>]"
+        )]
+        output: Option<PathBuf>,
+    },
+
+    #[command(about = "List the TLVs in the header of a TBF")]
+    Tlvs {
+        #[arg(id = "tbf", help = "TBF to list the header TLVs of")]
+        tbf: PathBuf,
+    },
+}
+
 #[derive(clap::Parser, Debug)]
 #[command(
     about = "Convert Tock userland apps from .elf files to Tock Application Bundles (TABs or .tab files).",
-    version
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
 )]
 pub struct Opt {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     #[arg(short = 'v', long = "verbose", help = "Be verbose")]
     pub verbose: bool,
 
+    #[arg(
+        short = 'q',
+        long = "quiet",
+        conflicts_with = "verbose",
+        help = "Do not print warnings"
+    )]
+    pub quiet: bool,
+
+    #[arg(
+        long = "strict",
+        help = "Treat warnings about the app configuration as errors"
+    )]
+    pub strict: bool,
+
+    #[arg(long = "werror", help = "Treat all warnings as errors")]
+    pub werror: bool,
+
     #[arg(long = "deterministic", help = "Produce a deterministic TAB file")]
     pub deterministic: bool,
 
@@ -56,10 +466,9 @@ pub struct Opt {
 
     #[arg(
         long = "app-version",
-        help = "Set the version number",
-        default_value = "0"
+        help = "Set the version number [default: .app_version section or 0]"
     )]
-    pub app_version: u32,
+    pub app_version: Option<u32>,
 
     #[arg(
         long = "minimum-ram-size",
@@ -76,7 +485,7 @@ pub struct Opt {
         short = 'o',
         id = "filename",
         default_value = "TockApp.tab",
-        help = "output file name"
+        help = "output file name, or - for stdout"
     )]
     pub output: PathBuf,
 
@@ -109,9 +518,10 @@ pub struct Opt {
 
     #[arg(
         id = "elf[,architecture]",
-        help = "application file(s) to package",
+        help = "application file(s) to package, optionally with a per-file stack size and credentials (elf[,architecture][,stack=N][,sign=CREDENTIALS])",
         num_args = 1..,
         required = true,
+        value_parser = clap::builder::OsStringValueParser::new().try_map(parse_elf_file),
     )]
     pub input: Vec<ElfFile>,
 
@@ -125,11 +535,28 @@ pub struct Opt {
     #[arg(
         long = "permissions",
         id = "permissions",
-        help = "A list of driver numbers and allowed commands",
+        help = "A list of driver numbers and allowed commands or command ranges (driver,command or driver,first-last)",
+        num_args = 1..,
+        value_parser = parse_perms,
+    )]
+    pub permissions: Vec<PermissionRange>,
+
+    #[arg(
+        long = "permissions-deny",
+        id = "permissions-deny",
+        requires = "experimental-tlvs",
+        help = "A list of driver numbers and commands the app is explicitly denied",
         num_args = 1..,
         value_parser = parse_perms,
     )]
-    pub permissions: Vec<(u32, u32)>,
+    pub permissions_deny: Vec<PermissionRange>,
+
+    #[arg(
+        long = "max-command",
+        id = "max-command",
+        help = "Reject permissions for commands numbered this value or higher"
+    )]
+    pub max_command: Option<u32>,
 
     #[arg(
         long = "write_id",
@@ -180,6 +607,14 @@ pub struct Opt {
     )]
     pub kernel_minor: Option<u16>,
 
+    #[arg(
+        long = "kernel-version-no-upper",
+        id = "kernel-version-no-upper",
+        requires_all = ["kernel-major-version", "experimental-tlvs"],
+        help = "Only require a minimum kernel version, without capping the major version"
+    )]
+    pub kernel_version_no_upper: bool,
+
     #[arg(
         long = "supported-boards",
         id = "supported-boards",
@@ -216,267 +651,2213 @@ pub struct Opt {
     )]
     pub sha512_enable: bool,
 
+    #[arg(
+        long = "sha3-256",
+        id = "sha3-256-add",
+        requires = "experimental-tlvs",
+        help = "Add a SHA3-256 hash credential to each TBF"
+    )]
+    pub sha3_256_enable: bool,
+
     #[arg(
         long = "rsa4096-private",
         id = "rsa4096-private-key",
         help = "Add an 4096-bit RSA signature credential using this private key"
     )]
     pub rsa4096_private_key: Option<PathBuf>,
-}
 
-mod test {
+    #[arg(
+        long = "padding-warn-threshold",
+        id = "padding-warn-threshold",
+        help = "Warn when inserting at least this many bytes of padding between segments",
+        default_value = "4096"
+    )]
+    pub padding_warn_threshold: usize,
 
-    #[cfg(test)]
-    use super::Opt;
-    #[cfg(test)]
-    use clap::Parser;
+    #[arg(
+        long = "output-checksum",
+        id = "output-checksum",
+        help = "Write a <output>.<algorithm> checksum file of the finished TAB"
+    )]
+    pub output_checksum: Option<ChecksumAlgorithm>,
 
-    #[test]
-    // elf2tab [FLAGS] [--package-name=<pkg-name>] [--output-file=[<filename>]] <elf[,architecture]>...
-    fn simple_invocations_succeed() {
-        {
-            let args = vec!["elf2tab", "app.elf"];
-            let result = Opt::try_parse_from(args.iter());
-            assert!(result.is_ok());
-        }
-        {
-            let args = vec!["elf2tab", "--package-name", "my-pkg", "app.elf"];
-            let result = Opt::try_parse_from(args.iter());
-            assert!(result.is_ok());
-        }
-        {
-            let args = vec!["elf2tab", "--output-file", "out.tab", "app.elf"];
-            let result = Opt::try_parse_from(args.iter());
-            assert!(result.is_ok());
-        }
-        {
-            let args = vec!["elf2tab", "--package-name", "my-pkg", "app.elf"];
-            let result = Opt::try_parse_from(args.iter());
-            assert!(result.is_ok());
-        }
-        {
-            let args = vec![
-                "elf2tab",
-                "--output-file",
-                "out.tab",
-                "--package-name",
-                "pkg-name",
-                "app.elf",
-            ];
-            let result = Opt::try_parse_from(args.iter());
-            println!("{:?}", result);
-            assert!(result.is_ok());
-        }
-    }
+    #[arg(
+        long = "arch-map",
+        id = "arch-map",
+        help = "Name TBFs built for a target triple with a specific architecture (e.g. thumbv7em-none-eabi=cortex-m7)",
+        num_args = 1..,
+        value_parser = parse_arch_map,
+    )]
+    pub arch_map: Vec<(String, String)>,
 
-    #[test]
-    // elf2tab [FLAGS] [--package-name=<pkg-name>] [--output-file=[<filename>]] <elf[,architecture]>...
-    fn simple_invocations_fail() {
-        {
-            let args = vec!["elf2tab", "app.elf", "--package-name"];
-            let result = Opt::try_parse_from(args.iter());
-            assert!(result.is_err());
-        }
-    }
+    #[arg(
+        long = "footer-align",
+        id = "footer-align",
+        help = "Pad the app binary so the footer starts at a multiple of this power of two",
+        value_parser = parse_power_of_two,
+    )]
+    pub footer_align: Option<u32>,
 
-    #[test]
-    // elf2tab [FLAGS] [--package-name=<pkg-name>] [--output-file=[<filename>]] [--minimum-stack-size=<min-stack-size>] <elf>...
-    fn advanced_invocations_succeed() {
-        {
-            let args = vec![
-                "elf2tab",
-                "--package-name",
-                "my-pkg",
-                "--minimum-ram-size",
-                "10",
-                "app.elf",
-            ];
-            let result = Opt::try_parse_from(args.iter());
-            assert!(result.is_ok());
-        }
-    }
+    #[arg(
+        long = "pkcs11-uri",
+        id = "pkcs11-uri",
+        conflicts_with = "rsa4096-private-key",
+        help = "Add an 4096-bit RSA signature credential using the key in a PKCS#11 token selected by this URI"
+    )]
+    pub pkcs11_uri: Option<String>,
 
-    #[test]
-    // elf2tab [FLAGS] [--package-name=<pkg-name>] [--output-file=[<filename>]] [--minimum-stack-size=<min-stack-size>] <elf[,architecture]>...
-    fn advanced_invocations_fail() {
-        {
-            let args = vec![
-                "elf2tab",
-                "--package-name",
-                "my-pkg",
-                "--minimum-ram-size",
-                "app.elf",
-            ];
-            let result = Opt::try_parse_from(args.iter());
-            assert!(result.is_err());
-        }
-        {
-            let args = vec![
-                "elf2tab",
-                "--package-name",
-                "my-pkg",
-                "--minimum-ram-size",
-                "10",
-                "--app-heap",
-                "10",
-                "app.elf",
-            ];
-            let result = Opt::try_parse_from(args.iter());
-            assert!(result.is_err());
-        }
-        {
-            let args = vec![
-                "elf2tab",
-                "--package-name",
-                "my-pkg",
-                "--minimum-ram-size",
-                "10",
-                "--stack",
-                "10",
-                "app.elf",
-            ];
-            let result = Opt::try_parse_from(args.iter());
-            assert!(result.is_err());
-        }
-        {
-            let args = vec![
-                "elf2tab",
-                "--package-name",
-                "my-pkg",
-                "--minimum-ram-size",
-                "10",
-                "--kernel-heap",
-                "10",
-                "app.elf",
-            ];
-            let result = Opt::try_parse_from(args.iter());
-            assert!(result.is_err());
-        }
-    }
+    #[arg(
+        long = "raw-header",
+        help = "Use the ELF's .tbfheader section verbatim as the TBF header if it exists"
+    )]
+    pub raw_header: bool,
 
-    #[test]
-    // elf2tab [FLAGS] [--package-name=<pkg-name>] [--output-file=[<filename>]] [--app-heap[=<heap-size>]]
-    //                [--kernel-heap[=<kernel-heap-size>]] [--stack[=<stack-size>]] <elf[,architecture]>..."
-    fn expert_invocations_succeed() {
-        {
-            let args = vec![
-                "elf2tab",
-                "--package-name",
-                "my-pkg",
-                "--kernel-heap",
-                "10",
-                "app.elf",
-            ];
-            let result = Opt::try_parse_from(args.iter());
+    #[arg(
+        long = "append-binary",
+        id = "append-binary",
+        help = "Append the contents of this file to the app binary, after the relocation data"
+    )]
+    pub append_binary: Option<PathBuf>,
+
+    #[arg(
+        long = "main-ram-override",
+        id = "main-ram-override",
+        help = "Set the Main header's minimum_ram_size independently of the Program header (for kernel testing)",
+        value_parser = clap_num::maybe_hex::<u32>,
+    )]
+    pub main_ram_override: Option<u32>,
+
+    #[arg(
+        long = "footer-output",
+        id = "footer-output",
+        help = "Also write the TBF footer (credentials) to this file"
+    )]
+    pub footer_output: Option<PathBuf>,
+
+    #[arg(
+        long = "list-architectures",
+        id = "list-architectures",
+        help = "Print the <architecture>.tbf name for each input and exit without converting"
+    )]
+    pub list_architectures: bool,
+
+    #[arg(
+        long = "metadata-hash",
+        id = "metadata-hash",
+        requires = "experimental-tlvs",
+        help = "Include a SHA256 hash of the TAB's metadata.toml in each TBF header"
+    )]
+    pub metadata_hash: bool,
+
+    #[arg(
+        long = "include-section",
+        id = "include-section",
+        help = "Append this ELF section to the app binary even if it is not in a loadable segment",
+        action = clap::ArgAction::Append,
+    )]
+    pub include_sections: Vec<String>,
+
+    #[arg(
+        long = "exclude-section",
+        id = "exclude-section",
+        help = "Leave this ELF section out of the app binary",
+        action = clap::ArgAction::Append,
+    )]
+    pub exclude_sections: Vec<String>,
+
+    #[arg(
+        long = "config",
+        id = "config",
+        help = "Read options from this TOML file; options on the command line take precedence"
+    )]
+    pub config: Option<PathBuf>,
+
+    #[arg(
+        long = "from-cargo",
+        id = "from-cargo",
+        help = "Use the package name, version, and [package.metadata.tock] options from this Cargo.toml unless set otherwise"
+    )]
+    pub from_cargo: Option<PathBuf>,
+
+    #[arg(
+        long = "allow-duplicate-entry",
+        id = "allow-duplicate-entry",
+        help = "Use the first segment containing the entry point if there are several, instead of failing"
+    )]
+    pub allow_duplicate_entry: bool,
+
+    #[arg(
+        long = "entry-align",
+        id = "entry-align",
+        help = "Require the entry point to be aligned to this power of two [default: 2 for ARM, 4 for RISC-V]",
+        value_parser = parse_power_of_two,
+    )]
+    pub entry_align: Option<u32>,
+
+    #[arg(
+        long = "also-emit-unpadded",
+        id = "also-emit-unpadded",
+        help = "Also write a TBF without trailing padding to this file (it is not added to the TAB)"
+    )]
+    pub also_emit_unpadded: Option<PathBuf>,
+
+    #[arg(
+        long = "tab-version",
+        id = "tab-version",
+        help = "The TAB format version to create",
+        default_value = "1",
+        value_parser = parse_tab_version,
+    )]
+    pub tab_version: u32,
+
+    #[arg(
+        long = "flash-size-report",
+        id = "flash-size-report",
+        help = "Print the flash each TBF needs before and after trailing padding"
+    )]
+    pub flash_size_report: bool,
+
+    #[arg(
+        long = "include-file",
+        id = "include-file",
+        help = "Add the file at PATH to the TAB as NAME, e.g. a license or SBOM (NAME=PATH)",
+        action = clap::ArgAction::Append,
+        value_parser = parse_include_file,
+    )]
+    pub include_files: Vec<(String, PathBuf)>,
+
+    #[arg(
+        long = "no-program-header",
+        id = "no-program-header",
+        help = "Leave out the Program header for very old kernels (no credentials or app version)",
+        conflicts_with_all = [
+            "sha256-add",
+            "sha384-add",
+            "sha512-add",
+            "sha3-256-add",
+            "sha256-binary-add",
+            "rsa4096-private-key",
+            "pkcs11-uri",
+            "app_version",
+            "main-ram-override",
+        ],
+    )]
+    pub no_program_header: bool,
+
+    #[arg(
+        long = "vendor-string",
+        id = "vendor-string",
+        requires = "experimental-tlvs",
+        help = "Add a human-readable vendor or build string to the TBF header",
+        value_parser = parse_vendor_string,
+    )]
+    pub vendor_string: Option<String>,
+
+    #[arg(
+        long = "check-elf",
+        id = "check-elf",
+        help = "Check that each ELF is suitable for conversion first, reporting all problems found"
+    )]
+    pub check_elf: bool,
+
+    #[arg(
+        long = "reproducible-check",
+        id = "reproducible-check",
+        help = "Convert each ELF twice and fail if the TBFs are not byte-identical"
+    )]
+    pub reproducible_check: bool,
+
+    #[arg(
+        long = "protected-contents",
+        id = "protected-contents",
+        help = "Fill the protected region after the TBF header with this file instead of zeros"
+    )]
+    pub protected_contents: Option<PathBuf>,
+
+    #[arg(
+        long = "padding-mode",
+        id = "padding-mode",
+        value_name = "pow2|multiple:N|none",
+        value_parser = parse_padding_mode,
+        help = "Trailing padding for the TBF, instead of the default for the architecture"
+    )]
+    pub padding_mode: Option<TrailingPadding>,
+
+    #[arg(
+        long = "ram-breakdown",
+        id = "ram-breakdown",
+        requires = "experimental-tlvs",
+        help = "Record the stack, heap, and segment RAM sizes in a header TLV"
+    )]
+    pub ram_breakdown: bool,
+
+    #[arg(
+        long = "flatten",
+        id = "flatten",
+        help = "Merge all ELFs into a single TBF, placing their segments by address"
+    )]
+    pub flatten: bool,
+
+    #[arg(
+        long = "security-version",
+        id = "security-version",
+        requires = "experimental-tlvs",
+        help = "Security version for rollback protection, stored in a header TLV",
+        value_parser = clap_num::maybe_hex::<u32>,
+    )]
+    pub security_version: Option<u32>,
+
+    #[arg(
+        long = "report-debug-size",
+        id = "report-debug-size",
+        help = "Print the sizes of the .debug_* sections, which are not included in the TBF"
+    )]
+    pub report_debug_size: bool,
+
+    #[arg(
+        long = "tbf-name-pattern",
+        id = "tbf-name-pattern",
+        value_parser = parse_tbf_name_pattern,
+        help = "Name of each TBF in the TAB, using {arch}, {name}, and {version} [default: {arch}.tbf]"
+    )]
+    pub tbf_name_pattern: Option<String>,
+
+    #[arg(
+        long = "omit-empty-relocation",
+        id = "omit-empty-relocation",
+        help = "Leave out the relocation data length if there is no relocation data (needs a kernel that allows this)"
+    )]
+    pub omit_empty_relocation: bool,
+
+    #[arg(
+        long = "valid-drivers",
+        id = "valid-drivers",
+        help = "Reject permissions for drivers that are not in this JSON array of driver numbers"
+    )]
+    pub valid_drivers: Option<PathBuf>,
+
+    #[arg(
+        long = "header-only",
+        id = "header-only",
+        help = "Create TBFs with an empty app binary, only the header and footer (for kernel testing)",
+        conflicts_with_all = ["append-binary", "footer-align", "flatten", "include-section"],
+    )]
+    pub header_only: bool,
+
+    #[arg(
+        long = "progress",
+        id = "progress",
+        help = "Print progress to stderr as each input is converted (always on with --verbose)"
+    )]
+    pub progress: bool,
+
+    #[arg(
+        long = "require-signature",
+        id = "require-signature",
+        help = "Fail if a TBF ends up without a signature credential (a hash alone is not enough)"
+    )]
+    pub require_signature: bool,
+
+    #[arg(
+        long = "symbol-offset-report",
+        id = "symbol-offset-report",
+        help = "Write the offset in the TBF of each function and object symbol to this file"
+    )]
+    pub symbol_offset_report: Option<PathBuf>,
+
+    #[arg(
+        long = "size-baseline",
+        id = "size-baseline",
+        help = "Fail if a TBF is larger than in this JSON file of sizes by architecture, by more than --size-threshold"
+    )]
+    pub size_baseline: Option<PathBuf>,
+
+    #[arg(
+        long = "size-threshold",
+        id = "size-threshold",
+        help = "How many percent a TBF may grow compared to --size-baseline",
+        default_value = "0",
+        value_parser = parse_percentage,
+        requires = "size-baseline"
+    )]
+    pub size_threshold: f64,
+
+    #[arg(
+        long = "write-size-baseline",
+        id = "write-size-baseline",
+        help = "Write the size of each TBF by architecture to this JSON file, for use with --size-baseline"
+    )]
+    pub write_size_baseline: Option<PathBuf>,
+
+    #[arg(
+        long = "total-ram",
+        id = "total-ram",
+        help = "Total RAM for the app in bytes; what segments and stack leave over is heap",
+        value_parser = clap_num::maybe_hex::<u32>,
+        conflicts_with = "min-ram-size",
+        conflicts_with = "heap-size",
+        conflicts_with = "kernel-heap-size"
+    )]
+    pub total_ram: Option<u32>,
+
+    #[arg(
+        long = "header-checksum",
+        id = "header-checksum",
+        help = "Algorithm for the TBF header checksum",
+        value_enum,
+        default_value = "xor"
+    )]
+    pub header_checksum: HeaderChecksum,
+
+    #[arg(
+        long = "integrity-end",
+        id = "integrity-end",
+        requires = "experimental-tlvs",
+        help = "Only cover the TBF up to this offset with credentials, and record the offset in each credential",
+        value_parser = clap_num::maybe_hex::<u32>
+    )]
+    pub integrity_end: Option<u32>,
+
+    #[arg(
+        long = "board-ram",
+        id = "board-ram",
+        help = "Warn if an app needs more than this many bytes of RAM (an error with --strict)",
+        value_parser = clap_num::maybe_hex::<u32>
+    )]
+    pub board_ram: Option<u32>,
+
+    #[arg(
+        long = "sha256-binary",
+        id = "sha256-binary-add",
+        requires = "experimental-tlvs",
+        help = "Add a SHA256 hash credential of only the app binary, without the TBF header, to each TBF"
+    )]
+    pub sha256_binary_enable: bool,
+
+    #[arg(
+        long = "header-size",
+        id = "header-size",
+        help = "Pad the TBF header to exactly this many bytes with a Reserved TLV",
+        value_parser = clap_num::maybe_hex::<u32>,
+        conflicts_with = "raw_header"
+    )]
+    pub header_size: Option<u32>,
+
+    #[arg(
+        long = "pic-from-elf-type",
+        id = "pic-from-elf-type",
+        help = "Treat ET_DYN ELFs as position independent even if they are linked for a fixed flash address"
+    )]
+    pub pic_from_elf_type: bool,
+
+    #[arg(
+        long = "ram-app",
+        id = "ram-app",
+        help = "The app is copied to RAM and runs from the address of its lowest executable segment",
+        conflicts_with = "pic-from-elf-type"
+    )]
+    pub ram_app: bool,
+
+    #[arg(
+        long = "keep-going",
+        id = "keep-going",
+        help = "If an input fails to convert, continue with the others and create a TAB of the rest"
+    )]
+    pub keep_going: bool,
+
+    #[arg(
+        long = "capabilities-report",
+        id = "capabilities-report",
+        help = "Write a summary of the app's permissions, storage IDs, kernel version, and credentials to this file (JSON if it ends in .json, otherwise Markdown)"
+    )]
+    pub capabilities_report: Option<PathBuf>,
+
+    #[arg(
+        long = "driver-names",
+        id = "driver-names",
+        requires = "capabilities-report",
+        help = "Name drivers in the capabilities report using this JSON object of driver numbers and names"
+    )]
+    pub driver_names: Option<PathBuf>,
+
+    #[arg(
+        long = "fixed-flash",
+        id = "fixed-flash",
+        help = "Use this flash address for the app binary instead of detecting it from the ELF",
+        value_parser = clap_num::maybe_hex::<u32>,
+        conflicts_with = "ram-app",
+        conflicts_with = "pic-from-elf-type"
+    )]
+    pub fixed_flash: Option<u32>,
+
+    #[arg(
+        long = "fixed-ram",
+        id = "fixed-ram",
+        help = "Use this RAM address for the app instead of the ELF's _sram_origin symbol",
+        value_parser = clap_num::maybe_hex::<u32>,
+        conflicts_with = "ram-app"
+    )]
+    pub fixed_ram: Option<u32>,
+
+    #[arg(
+        long = "base-version",
+        id = "base-version",
+        help = "Set the TBF version in the base header, for forked kernels [default: 2]",
+        value_parser = clap_num::maybe_hex::<u16>,
+        conflicts_with = "raw_header"
+    )]
+    pub base_version: Option<u16>,
+
+    #[arg(
+        long = "base-flags",
+        id = "base-flags",
+        help = "Set all flags in the base header to this value, for forked kernels",
+        value_parser = clap_num::maybe_hex::<u32>,
+        conflicts_with = "disabled",
+        conflicts_with = "raw_header"
+    )]
+    pub base_flags: Option<u32>,
+
+    #[arg(
+        long = "embed-elf-hash",
+        id = "embed-elf-hash",
+        requires = "experimental-tlvs",
+        help = "Include a SHA256 hash of the input ELF (not the TBF) in each TBF header"
+    )]
+    pub embed_elf_hash: bool,
+
+    #[arg(
+        long = "experimental-tlvs",
+        id = "experimental-tlvs",
+        help = "Allow options that write TLV types or credential formats not yet allocated in the TBF specification"
+    )]
+    pub experimental_tlvs: bool,
+
+    #[arg(
+        long = "credential-command",
+        id = "credential-command",
+        requires = "credential-command-type",
+        help = "Command that reads the bytes covered by credentials on stdin and writes a credential to stdout"
+    )]
+    pub credential_command: Option<String>,
+
+    #[arg(
+        long = "credential-command-type",
+        id = "credential-command-type",
+        requires = "credential-command",
+        help = "Type of the credential written by --credential-command"
+    )]
+    pub credential_command_type: Option<CommandCredentialType>,
+
+    #[arg(
+        long = "section-hashes",
+        id = "section-hashes",
+        help = "Write the offset in the TBF, size, and SHA256 of each section in the app binary to this file"
+    )]
+    pub section_hashes: Option<PathBuf>,
+
+    #[arg(
+        long = "ram-pow2",
+        id = "ram-pow2",
+        conflicts_with = "total-ram",
+        help = "Round the minimum RAM size up to a power of two, for MPUs that need power-of-two regions"
+    )]
+    pub ram_pow2: bool,
+
+    #[arg(
+        long = "tbf-endian",
+        id = "tbf-endian",
+        help = "Byte order of the TBF header, for testing kernels (Tock kernels expect little)",
+        default_value = "little",
+        conflicts_with_all = ["raw_header", "footer-output"]
+    )]
+    pub tbf_endian: TbfEndian,
+
+    #[arg(
+        long = "no-main-header",
+        id = "no-main-header",
+        help = "Leave out the legacy Main header, which kernels that understand the Program header do not need",
+        conflicts_with_all = ["no-program-header", "raw_header", "main-ram-override"]
+    )]
+    pub no_main_header: bool,
+
+    #[arg(
+        long = "release",
+        id = "release",
+        help = "Make the smallest TBF for production: implies --no-main-header and --padding-mode none, with no reserved footer space",
+        conflicts_with_all = ["no-program-header", "raw_header", "main-ram-override", "padding-mode", "min-footer-size"]
+    )]
+    pub release: bool,
+
+    #[arg(
+        long = "sign-all-architectures-with",
+        id = "sign-all-architectures-with",
+        help = "Sign every TBF with this RSA4096 private key, and fail if any TBF ends up without that signature",
+        conflicts_with_all = ["rsa4096-private-key", "pkcs11-uri", "no-program-header", "tbf-endian", "base-version"]
+    )]
+    pub sign_all_architectures_with: Option<PathBuf>,
+
+    #[arg(
+        long = "wfr-erase-sizes",
+        id = "wfr-erase-sizes",
+        help = "Record the erase size of `.wfr.<size>` sections in extended 12-byte WriteableFlashRegions TLVs"
+    )]
+    pub wfr_erase_sizes: bool,
+}
+
+mod test {
+
+    #[cfg(test)]
+    use super::{
+        expand_permissions, permission_above_max_command, Command, Credential, ElfMachine, Opt,
+    };
+    #[cfg(test)]
+    use crate::convert::TrailingPadding;
+    #[cfg(test)]
+    use crate::header::{CommandCredentialType, HeaderChecksum, TbfEndian};
+    #[cfg(test)]
+    use clap::Parser;
+    #[cfg(test)]
+    use std::path::PathBuf;
+
+    #[test]
+    // elf2tab [FLAGS] [--package-name=<pkg-name>] [--output-file=[<filename>]] <elf[,architecture]>...
+    fn simple_invocations_succeed() {
+        {
+            let args = vec!["elf2tab", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_ok());
+        }
+        {
+            let args = vec!["elf2tab", "--package-name", "my-pkg", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_ok());
+        }
+        {
+            let args = vec!["elf2tab", "--output-file", "out.tab", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_ok());
+        }
+        {
+            let args = vec!["elf2tab", "--package-name", "my-pkg", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_ok());
+        }
+        {
+            let args = vec![
+                "elf2tab",
+                "--output-file",
+                "out.tab",
+                "--package-name",
+                "pkg-name",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            println!("{:?}", result);
+            assert!(result.is_ok());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--package-name=<pkg-name>] [--output-file=[<filename>]] <elf[,architecture]>...
+    fn simple_invocations_fail() {
+        {
+            let args = vec!["elf2tab", "app.elf", "--package-name"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--package-name=<pkg-name>] [--output-file=[<filename>]] [--minimum-stack-size=<min-stack-size>] <elf>...
+    fn advanced_invocations_succeed() {
+        {
+            let args = vec![
+                "elf2tab",
+                "--package-name",
+                "my-pkg",
+                "--minimum-ram-size",
+                "10",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_ok());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--package-name=<pkg-name>] [--output-file=[<filename>]] [--minimum-stack-size=<min-stack-size>] <elf[,architecture]>...
+    fn advanced_invocations_fail() {
+        {
+            let args = vec![
+                "elf2tab",
+                "--package-name",
+                "my-pkg",
+                "--minimum-ram-size",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+        {
+            let args = vec![
+                "elf2tab",
+                "--package-name",
+                "my-pkg",
+                "--minimum-ram-size",
+                "10",
+                "--app-heap",
+                "10",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+        {
+            let args = vec![
+                "elf2tab",
+                "--package-name",
+                "my-pkg",
+                "--minimum-ram-size",
+                "10",
+                "--stack",
+                "10",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+        {
+            let args = vec![
+                "elf2tab",
+                "--package-name",
+                "my-pkg",
+                "--minimum-ram-size",
+                "10",
+                "--kernel-heap",
+                "10",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--package-name=<pkg-name>] [--output-file=[<filename>]] [--app-heap[=<heap-size>]]
+    //                [--kernel-heap[=<kernel-heap-size>]] [--stack[=<stack-size>]] <elf[,architecture]>..."
+    fn expert_invocations_succeed() {
+        {
+            let args = vec![
+                "elf2tab",
+                "--package-name",
+                "my-pkg",
+                "--kernel-heap",
+                "10",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_ok());
+        }
+        {
+            let args = vec![
+                "elf2tab",
+                "--package-name",
+                "my-pkg",
+                "--app-heap",
+                "10",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_ok());
+        }
+        {
+            let args = vec![
+                "elf2tab",
+                "--package-name",
+                "my-pkg",
+                "--stack",
+                "10",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_ok());
+        }
+        {
+            let args = vec![
+                "elf2tab",
+                "--package-name",
+                "my-pkg",
+                "--stack",
+                "10",
+                "--app-heap",
+                "10",
+                "--kernel-heap",
+                "10",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_ok());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--package-name=<pkg-name>] [--output-file=[<filename>]] [--app-heap[=<heap-size>]]
+    //                [--kernel-heap[=<kernel-heap-size>]] [--stack[=<stack-size>]] <elf[,architecture]>..."
+    fn expert_invocations_fail() {
+        {
+            let args = vec![
+                "elf2tab",
+                "--package-name",
+                "my-pkg",
+                "--kernel-heap",
+                "10",
+                "--minimum-ram-size",
+                "10",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+        {
+            let args = vec![
+                "elf2tab",
+                "--package-name",
+                "my-pkg",
+                "--app-heap",
+                "10",
+                "--minimum-ram-size",
+                "10",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+        {
+            let args = vec![
+                "elf2tab",
+                "--package-name",
+                "my-pkg",
+                "--stack",
+                "10",
+                "--minimum-ram-size",
+                "10",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--write_id=<write_id>] [--read_ids=<read_ids>] [--access_ids=<access_ids>]
+    //                <elf[,architecture]>..."
+    fn storage_ids() {
+        {
+            let args = vec![
+                "elf2tab",
+                "--write_id",
+                "1234567",
+                "--read_ids",
+                "1",
+                "2",
+                "--access_ids",
+                "2",
+                "3",
+                "--",
+                "app.elf",
+            ];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert_eq!(opt.write_id, Some(1234567));
+            assert_eq!(opt.read_ids, Some(vec![1, 2]));
+            assert_eq!(opt.access_ids, Some(vec![2, 3]));
+            assert_eq!(opt.input.len(), 1);
+        }
+        {
+            // Without `--` the ELF is taken as another storage ID.
+            let args = vec!["elf2tab", "--access_ids", "2", "3", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--permissions <permissions>...] [--max-command=<max-command>]
+    //                <elf[,architecture]>..."
+    fn max_command() {
+        {
+            let args = vec![
+                "elf2tab",
+                "--max-command",
+                "16",
+                "--permissions",
+                "1,0",
+                "1,15",
+                "--",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_ok());
+            assert_eq!(result.unwrap().max_command, Some(16));
+        }
+        {
+            let permissions = [(1, 0), (1, 15)];
+            assert_eq!(permission_above_max_command(&permissions, 16), None);
+            assert_eq!(
+                permission_above_max_command(&permissions, 15),
+                Some((1, 15))
+            );
+            assert_eq!(permission_above_max_command(&permissions, 0), Some((1, 0)));
+        }
+        {
+            let args = vec!["elf2tab", "--max-command", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--output-checksum=<output-checksum>] <elf[,architecture]>..."
+    fn output_checksum() {
+        {
+            let args = vec!["elf2tab", "--output-checksum", "sha512", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_ok());
+            assert_eq!(
+                result.unwrap().output_checksum,
+                Some(super::ChecksumAlgorithm::Sha512)
+            );
+        }
+        {
+            let args = vec!["elf2tab", "--output-checksum", "md5", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--arch-map <arch-map>...] <elf[,architecture]>..."
+    fn arch_map() {
+        {
+            let args = vec![
+                "elf2tab",
+                "--arch-map",
+                "thumbv7em-none-eabi=cortex-m7",
+                "--",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_ok());
+            assert_eq!(
+                result.unwrap().arch_map,
+                vec![("thumbv7em-none-eabi".to_string(), "cortex-m7".to_string())]
+            );
+        }
+        {
+            let args = vec!["elf2tab", "--arch-map", "cortex-m7", "--", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--footer-align=<footer-align>] <elf[,architecture]>..."
+    fn footer_align() {
+        {
+            let args = vec!["elf2tab", "--footer-align", "0x100", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_ok());
+            assert_eq!(result.unwrap().footer_align, Some(256));
+        }
+        {
+            let args = vec!["elf2tab", "--footer-align", "24", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+        {
+            let args = vec!["elf2tab", "--footer-align", "0", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--rsa4096-private=<rsa4096-private-key>] [--pkcs11-uri=<pkcs11-uri>]
+    //                <elf[,architecture]>..."
+    fn pkcs11_uri() {
+        {
+            let args = vec![
+                "elf2tab",
+                "--pkcs11-uri",
+                "pkcs11:object=key?module-path=/usr/lib/p11.so",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_ok());
+        }
+        {
+            let args = vec![
+                "elf2tab",
+                "--pkcs11-uri",
+                "pkcs11:object=key?module-path=/usr/lib/p11.so",
+                "--rsa4096-private",
+                "key.pk8",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--access_ids=<access_ids>] <elf[,architecture]>..."
+    fn access_ids_only() {
+        {
+            let args = vec!["elf2tab", "app.elf", "--access_ids", "2", "3"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_ok());
+            let opt = result.unwrap();
+            assert_eq!(opt.write_id, None);
+            assert_eq!(opt.read_ids, None);
+            assert_eq!(opt.access_ids, Some(vec![2, 3]));
+        }
+        {
+            let args = vec!["elf2tab", "--access_ids", "2", "0x3", "--", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_ok());
+            let opt = result.unwrap();
+            assert_eq!(opt.write_id, None);
+            assert_eq!(opt.access_ids, Some(vec![2, 3]));
+            assert_eq!(opt.input.len(), 1);
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] <elf[,architecture]>...
+    fn quiet_and_verbose_conflict() {
+        {
+            let args = vec!["elf2tab", "--quiet", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_ok());
+        }
+        {
+            let args = vec!["elf2tab", "-q", "-v", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--permissions <permissions>...] [--permissions-deny <permissions-deny>...]
+    //                <elf[,architecture]>..."
+    fn permissions_deny() {
+        {
+            let args = vec![
+                "elf2tab",
+                "--experimental-tlvs",
+                "app.elf",
+                "--permissions",
+                "1,0",
+                "--permissions-deny",
+                "1,1",
+                "2,0",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_ok());
+            let opt = result.unwrap();
+            assert_eq!(expand_permissions(&opt.permissions), vec![(1, 0)]);
+            assert_eq!(
+                expand_permissions(&opt.permissions_deny),
+                vec![(1, 1), (2, 0)]
+            );
+        }
+        {
+            let args = vec![
+                "elf2tab",
+                "--experimental-tlvs",
+                "app.elf",
+                "--permissions-deny",
+                "1",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--app-version <APP_VERSION>] <elf[,architecture]>..."
+    fn app_version() {
+        {
+            let args = vec!["elf2tab", "app.elf"];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert_eq!(opt.app_version, None);
+        }
+        {
+            let args = vec!["elf2tab", "--app-version", "7", "app.elf"];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert_eq!(opt.app_version, Some(7));
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--main-ram-override <main-ram-override>] <elf[,architecture]>..."
+    fn main_ram_override() {
+        {
+            let args = vec!["elf2tab", "app.elf"];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert_eq!(opt.main_ram_override, None);
+        }
+        {
+            let args = vec!["elf2tab", "--main-ram-override", "0x1000", "app.elf"];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert_eq!(opt.main_ram_override, Some(0x1000));
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] <elf[,architecture]>..."
+    fn strict() {
+        {
+            let args = vec!["elf2tab", "app.elf"];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert!(!opt.strict);
+        }
+        {
+            let args = vec!["elf2tab", "--strict", "--quiet", "app.elf"];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert!(opt.strict);
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--footer-output <footer-output>] <elf[,architecture]>..."
+    fn footer_output() {
+        let args = vec!["elf2tab", "--footer-output", "app.footer", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.footer_output, Some("app.footer".into()));
+    }
+
+    #[test]
+    // elf2tab [FLAGS] --list-architectures <elf[,architecture]>..."
+    fn list_architectures() {
+        let args = vec![
+            "elf2tab",
+            "--list-architectures",
+            "cortex-m4.elf",
+            "app.elf,rv32imac",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.list_architectures);
+        assert_eq!(opt.input.len(), 2);
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--metadata-hash] <elf[,architecture]>..."
+    fn metadata_hash() {
+        let args = vec![
+            "elf2tab",
+            "--experimental-tlvs",
+            "--metadata-hash",
+            "app.elf",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.metadata_hash);
+
+        // The MetadataHash TLV type is not allocated in the TBF specification.
+        let args = vec!["elf2tab", "--metadata-hash", "app.elf"];
+        assert!(Opt::try_parse_from(args.iter()).is_err());
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--kernel-major <kernel-major-version>] [--kernel-version-no-upper]
+    //                <elf[,architecture]>..."
+    fn kernel_version_no_upper() {
+        {
+            let args = vec![
+                "elf2tab",
+                "--experimental-tlvs",
+                "--kernel-major",
+                "2",
+                "--kernel-version-no-upper",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_ok());
+        }
+        {
+            let args = vec![
+                "elf2tab",
+                "--experimental-tlvs",
+                "--kernel-version-no-upper",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--include-section <include-section>]... <elf[,architecture]>..."
+    fn include_section() {
+        let args = vec![
+            "elf2tab",
+            "--include-section",
+            ".vendor_data",
+            "--include-section",
+            ".config",
+            "app.elf",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.include_sections, vec![".vendor_data", ".config"]);
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--exclude-section <exclude-section>]... <elf[,architecture]>..."
+    fn exclude_section() {
+        let args = vec!["elf2tab", "--exclude-section", ".fixture", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.exclude_sections, vec![".fixture"]);
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--allow-duplicate-entry] <elf[,architecture]>..."
+    fn allow_duplicate_entry() {
+        let args = vec!["elf2tab", "--allow-duplicate-entry", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.allow_duplicate_entry);
+        assert!(!opt.disabled);
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--entry-align <entry-align>] <elf[,architecture]>..."
+    fn entry_align() {
+        {
+            let args = vec!["elf2tab", "--entry-align", "4", "app.elf"];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert_eq!(opt.entry_align, Some(4));
+        }
+        {
+            let args = vec!["elf2tab", "--entry-align", "3", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--also-emit-unpadded <also-emit-unpadded>] <elf[,architecture]>..."
+    fn also_emit_unpadded() {
+        let args = vec![
+            "elf2tab",
+            "--also-emit-unpadded",
+            "app.unpadded.tbf",
+            "app.elf",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.also_emit_unpadded, Some("app.unpadded.tbf".into()));
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--tab-version <tab-version>] <elf[,architecture]>..."
+    fn tab_version() {
+        {
+            let args = vec!["elf2tab", "app.elf"];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert_eq!(opt.tab_version, 1);
+        }
+        {
+            let args = vec!["elf2tab", "--tab-version", "1", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
             assert!(result.is_ok());
         }
+        {
+            let args = vec!["elf2tab", "--tab-version", "2", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--flash-size-report] <elf[,architecture]>..."
+    fn flash_size_report() {
+        let args = vec!["elf2tab", "--flash-size-report", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.flash_size_report);
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--include-file <include-file>] <elf[,architecture]>..."
+    fn include_file() {
+        {
+            let args = vec![
+                "elf2tab",
+                "--include-file",
+                "LICENSE=../LICENSE-MIT",
+                "--include-file",
+                "sbom.spdx.json=build/sbom.json",
+                "app.elf",
+            ];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert_eq!(
+                opt.include_files,
+                vec![
+                    ("LICENSE".to_string(), "../LICENSE-MIT".into()),
+                    ("sbom.spdx.json".to_string(), "build/sbom.json".into()),
+                ]
+            );
+        }
+        {
+            let args = vec!["elf2tab", "--include-file", "LICENSE", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+        {
+            let args = vec!["elf2tab", "--include-file", "=LICENSE", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--no-program-header] <elf[,architecture]>..."
+    fn no_program_header() {
+        {
+            let args = vec!["elf2tab", "--no-program-header", "app.elf"];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert!(opt.no_program_header);
+        }
+        {
+            let args = vec!["elf2tab", "--no-program-header", "--sha256", "app.elf"];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+        {
+            let args = vec![
+                "elf2tab",
+                "--no-program-header",
+                "--app-version",
+                "2",
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--vendor-string <vendor-string>] <elf[,architecture]>..."
+    fn vendor_string() {
+        {
+            let args = vec![
+                "elf2tab",
+                "--experimental-tlvs",
+                "--vendor-string",
+                "acme build 42",
+                "app.elf",
+            ];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert_eq!(opt.vendor_string, Some("acme build 42".to_string()));
+        }
+        {
+            let long = "x".repeat(u16::MAX as usize + 1);
+            let args = vec![
+                "elf2tab",
+                "--experimental-tlvs",
+                "--vendor-string",
+                &long,
+                "app.elf",
+            ];
+            let result = Opt::try_parse_from(args.iter());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--check-elf] <elf[,architecture]>..."
+    fn check_elf() {
+        let args = vec!["elf2tab", "--check-elf", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.check_elf);
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--reproducible-check] <elf[,architecture]>..."
+    fn reproducible_check() {
+        let args = vec!["elf2tab", "--reproducible-check", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.reproducible_check);
+    }
+
+    #[test]
+    // elf2tab [FLAGS] <elf[,architecture][,stack=N]>..."
+    fn per_input_stack_size() {
         {
             let args = vec![
                 "elf2tab",
-                "--package-name",
-                "my-pkg",
-                "--app-heap",
-                "10",
-                "app.elf",
+                "--stack",
+                "2048",
+                "cortex-m0.elf",
+                "app.elf,rv32imac,stack=0x1000",
+                "other.elf,stack=4096",
             ];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert_eq!(opt.stack_size, Some(2048));
+            assert_eq!(opt.input[0].path, PathBuf::from("cortex-m0.elf"));
+            assert_eq!(opt.input[0].architecture, None);
+            assert_eq!(opt.input[0].stack_size, None);
+            assert_eq!(opt.input[1].path, PathBuf::from("app.elf"));
+            assert_eq!(opt.input[1].architecture, Some("rv32imac".to_string()));
+            assert_eq!(opt.input[1].stack_size, Some(0x1000));
+            assert_eq!(opt.input[2].path, PathBuf::from("other.elf"));
+            assert_eq!(opt.input[2].architecture, None);
+            assert_eq!(opt.input[2].stack_size, Some(4096));
+        }
+        {
+            let args = vec!["elf2tab", "app.elf,cortex-m4,stack=big"];
             let result = Opt::try_parse_from(args.iter());
-            assert!(result.is_ok());
+            assert!(result.is_err());
         }
+    }
+
+    #[test]
+    // elf2tab to-elf [--output-file <filename>] [--machine <machine>] [--tbf-address <tbf-address>] <tbf>
+    fn to_elf() {
         {
             let args = vec![
                 "elf2tab",
-                "--package-name",
-                "my-pkg",
-                "--stack",
-                "10",
+                "to-elf",
+                "--machine",
+                "riscv",
+                "--tbf-address",
+                "0x20040000",
+                "-o",
                 "app.elf",
+                "app.tbf",
             ];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            match opt.command {
+                Some(Command::ToElf {
+                    tbf,
+                    output,
+                    machine,
+                    tbf_address,
+                }) => {
+                    assert_eq!(tbf, PathBuf::from("app.tbf"));
+                    assert_eq!(output, Some(PathBuf::from("app.elf")));
+                    assert_eq!(machine, ElfMachine::Riscv);
+                    assert_eq!(tbf_address, Some(0x20040000));
+                }
+                _ => panic!("expected the to-elf command"),
+            }
+            assert!(opt.input.is_empty());
+        }
+        {
+            // Without a command, an input is still required.
+            let args = vec!["elf2tab", "--sha256"];
             let result = Opt::try_parse_from(args.iter());
-            assert!(result.is_ok());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--werror] <elf[,architecture]>..."
+    fn werror() {
+        let args = vec!["elf2tab", "--werror", "--quiet", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.werror);
+        assert!(opt.quiet);
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--protected-contents <protected-contents>] <elf[,architecture]>..."
+    fn protected_contents() {
+        let args = vec![
+            "elf2tab",
+            "--protected-region-size",
+            "512",
+            "--protected-contents",
+            "handoff.bin",
+            "app.elf",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.protected_contents, Some(PathBuf::from("handoff.bin")));
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--padding-mode <pow2|multiple:N|none>] <elf[,architecture]>..."
+    fn padding_mode() {
+        let args = vec!["elf2tab", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.padding_mode, None);
+
+        for (mode, expected) in [
+            ("pow2", TrailingPadding::TotalSizePowerOfTwo),
+            ("multiple:4096", TrailingPadding::TotalSizeMultiple(4096)),
+            ("multiple:0x100", TrailingPadding::TotalSizeMultiple(256)),
+            ("none", TrailingPadding::Disabled),
+        ] {
+            let args = vec!["elf2tab", "--padding-mode", mode, "app.elf"];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert_eq!(opt.padding_mode, Some(expected));
         }
+
+        for mode in ["multiple:0", "multiple:", "pow3"] {
+            let args = vec!["elf2tab", "--padding-mode", mode, "app.elf"];
+            assert!(Opt::try_parse_from(args.iter()).is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab extract [--output-file <filename>] [--list] <tab> [architecture]
+    fn extract() {
         {
             let args = vec![
                 "elf2tab",
-                "--package-name",
-                "my-pkg",
-                "--stack",
-                "10",
-                "--app-heap",
-                "10",
-                "--kernel-heap",
-                "10",
-                "app.elf",
+                "extract",
+                "app.tab",
+                "cortex-m4",
+                "-o",
+                "out.tbf",
+            ];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            match opt.command {
+                Some(Command::Extract {
+                    tab,
+                    architecture,
+                    output,
+                    list,
+                }) => {
+                    assert_eq!(tab, PathBuf::from("app.tab"));
+                    assert_eq!(architecture, Some("cortex-m4".to_string()));
+                    assert_eq!(output, Some(PathBuf::from("out.tbf")));
+                    assert!(!list);
+                }
+                _ => panic!("expected the extract command"),
+            }
+        }
+        {
+            let args = vec!["elf2tab", "extract", "--list", "app.tab"];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert!(matches!(
+                opt.command,
+                Some(Command::Extract {
+                    architecture: None,
+                    list: true,
+                    ..
+                })
+            ));
+        }
+        {
+            // Either an architecture or --list is needed, but not both.
+            let args = vec!["elf2tab", "extract", "app.tab"];
+            assert!(Opt::try_parse_from(args.iter()).is_err());
+            let args = vec!["elf2tab", "extract", "--list", "app.tab", "cortex-m4"];
+            assert!(Opt::try_parse_from(args.iter()).is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--ram-breakdown] <elf[,architecture]>..."
+    fn ram_breakdown() {
+        let args = vec!["elf2tab", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(!opt.ram_breakdown);
+
+        let args = vec![
+            "elf2tab",
+            "--experimental-tlvs",
+            "--ram-breakdown",
+            "app.elf",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.ram_breakdown);
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--flatten] <elf[,architecture]>..."
+    fn flatten() {
+        let args = vec!["elf2tab", "--flatten", "app.elf", "lib.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.flatten);
+        assert_eq!(opt.input.len(), 2);
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--security-version <security-version>] <elf[,architecture]>..."
+    fn security_version() {
+        let args = vec!["elf2tab", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.security_version, None);
+
+        let args = vec![
+            "elf2tab",
+            "--experimental-tlvs",
+            "--security-version",
+            "0x10",
+            "app.elf",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.security_version, Some(16));
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--report-debug-size] <elf[,architecture]>..."
+    fn report_debug_size() {
+        let args = vec!["elf2tab", "--report-debug-size", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.report_debug_size);
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--tbf-name-pattern <tbf-name-pattern>] <elf[,architecture]>..."
+    fn tbf_name_pattern() {
+        let args = vec!["elf2tab", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.tbf_name_pattern, None);
+
+        let args = vec![
+            "elf2tab",
+            "--tbf-name-pattern",
+            "{name}.{arch}-v{version}.tbf",
+            "app.elf",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(
+            opt.tbf_name_pattern,
+            Some("{name}.{arch}-v{version}.tbf".to_string())
+        );
+
+        for pattern in ["{board}.tbf", "{arch.tbf", "{arch}.bin"] {
+            let args = vec!["elf2tab", "--tbf-name-pattern", pattern, "app.elf"];
+            assert!(Opt::try_parse_from(args.iter()).is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--omit-empty-relocation] <elf[,architecture]>..."
+    fn omit_empty_relocation() {
+        let args = vec!["elf2tab", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(!opt.omit_empty_relocation);
+
+        let args = vec!["elf2tab", "--omit-empty-relocation", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.omit_empty_relocation);
+    }
+
+    #[test]
+    // elf2tab [FLAGS] <elf[,architecture][,sign=CREDENTIALS]>..."
+    fn per_input_credentials() {
+        {
+            let args = vec![
+                "elf2tab",
+                "--sha256",
+                "cortex-m0.elf",
+                "app.elf,cortex-m4,sign=sha256+rsa4096",
+                "dev.elf,rv32imac,sign=sha512,stack=2048",
+                "other.elf,sign=none",
             ];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert_eq!(opt.input[0].credentials, None);
+            assert_eq!(opt.input[1].path, PathBuf::from("app.elf"));
+            assert_eq!(opt.input[1].architecture, Some("cortex-m4".to_string()));
+            assert_eq!(
+                opt.input[1].credentials,
+                Some(vec![Credential::Sha256, Credential::Rsa4096])
+            );
+            assert_eq!(opt.input[2].path, PathBuf::from("dev.elf"));
+            assert_eq!(opt.input[2].architecture, Some("rv32imac".to_string()));
+            assert_eq!(opt.input[2].stack_size, Some(2048));
+            assert_eq!(opt.input[2].credentials, Some(vec![Credential::Sha512]));
+            assert_eq!(opt.input[3].path, PathBuf::from("other.elf"));
+            assert_eq!(opt.input[3].architecture, None);
+            assert_eq!(opt.input[3].credentials, Some(vec![]));
+        }
+        {
+            let args = vec!["elf2tab", "app.elf,cortex-m4,sign=md5"];
             let result = Opt::try_parse_from(args.iter());
-            assert!(result.is_ok());
+            assert!(result.is_err());
         }
     }
 
     #[test]
-    // elf2tab [FLAGS] [--package-name=<pkg-name>] [--output-file=[<filename>]] [--app-heap[=<heap-size>]]
-    //                [--kernel-heap[=<kernel-heap-size>]] [--stack[=<stack-size>]] <elf[,architecture]>..."
-    fn expert_invocations_fail() {
+    // elf2tab [FLAGS] [--valid-drivers <valid-drivers>] <elf[,architecture]>..."
+    fn valid_drivers() {
+        let args = vec![
+            "elf2tab",
+            "--permissions",
+            "1,0",
+            "--valid-drivers",
+            "drivers.json",
+            "app.elf",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.valid_drivers, Some(PathBuf::from("drivers.json")));
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--header-only] <elf[,architecture]>..."
+    fn header_only() {
+        {
+            let args = vec!["elf2tab", "--header-only", "app.elf"];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert!(opt.header_only);
+        }
         {
             let args = vec![
                 "elf2tab",
-                "--package-name",
-                "my-pkg",
-                "--kernel-heap",
-                "10",
-                "--minimum-ram-size",
-                "10",
+                "--header-only",
+                "--append-binary",
+                "config.bin",
                 "app.elf",
             ];
             let result = Opt::try_parse_from(args.iter());
             assert!(result.is_err());
         }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--progress] <elf[,architecture]>..."
+    fn progress() {
+        let args = vec!["elf2tab", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(!opt.progress);
+
+        let args = vec!["elf2tab", "--progress", "--quiet", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.progress);
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--require-signature] <elf[,architecture]>..."
+    fn require_signature() {
+        let args = vec!["elf2tab", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(!opt.require_signature);
+
+        let args = vec![
+            "elf2tab",
+            "--require-signature",
+            "--rsa4096-private",
+            "key.pk8",
+            "app.elf",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.require_signature);
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--permissions <driver,first-last>...] <elf[,architecture]>..."
+    fn permission_ranges() {
         {
             let args = vec![
                 "elf2tab",
-                "--package-name",
-                "my-pkg",
-                "--app-heap",
-                "10",
-                "--minimum-ram-size",
-                "10",
+                "--experimental-tlvs",
+                "--permissions",
+                "1,0-3",
+                "2,5",
+                "--permissions-deny",
+                "1,2-2",
+                "--",
                 "app.elf",
             ];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert_eq!(
+                expand_permissions(&opt.permissions),
+                vec![(1, 0), (1, 1), (1, 2), (1, 3), (2, 5)]
+            );
+            assert_eq!(expand_permissions(&opt.permissions_deny), vec![(1, 2)]);
+        }
+        for range in ["1,3-1", "1,0-", "1,-3", "1,0-65536"] {
+            let args = vec!["elf2tab", "--permissions", range, "--", "app.elf"];
             let result = Opt::try_parse_from(args.iter());
-            assert!(result.is_err());
+            assert!(result.is_err(), "{}", range);
         }
+    }
+
+    #[test]
+    // elf2tab strip-credentials [-o <filename>] <tbf>
+    fn strip_credentials_command() {
+        let args = vec!["elf2tab", "strip-credentials", "app.tbf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        match opt.command {
+            Some(Command::StripCredentials { tbf, output }) => {
+                assert_eq!(tbf, PathBuf::from("app.tbf"));
+                assert_eq!(output, None);
+            }
+            _ => panic!("expected the strip-credentials command"),
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--symbol-offset-report <symbol-offset-report>] <elf[,architecture]>..."
+    fn symbol_offset_report() {
+        let args = vec![
+            "elf2tab",
+            "--symbol-offset-report",
+            "symbols.csv",
+            "app.elf",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.symbol_offset_report, Some(PathBuf::from("symbols.csv")));
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--size-baseline <size-baseline>] [--size-threshold <size-threshold>]
+    //                [--write-size-baseline <write-size-baseline>] <elf[,architecture]>..."
+    fn size_baseline() {
         {
             let args = vec![
                 "elf2tab",
-                "--package-name",
-                "my-pkg",
-                "--stack",
-                "10",
-                "--minimum-ram-size",
-                "10",
+                "--size-baseline",
+                "sizes.json",
+                "--size-threshold",
+                "2.5",
+                "--write-size-baseline",
+                "sizes.json",
                 "app.elf",
             ];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert_eq!(opt.size_baseline, Some(PathBuf::from("sizes.json")));
+            assert_eq!(opt.size_threshold, 2.5);
+            assert_eq!(opt.write_size_baseline, Some(PathBuf::from("sizes.json")));
+        }
+        {
+            let args = vec!["elf2tab", "--size-baseline", "sizes.json", "app.elf"];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert_eq!(opt.size_threshold, 0.0);
+        }
+        for args in [
+            vec!["elf2tab", "--size-threshold", "5", "app.elf"],
+            vec![
+                "elf2tab",
+                "--size-baseline",
+                "sizes.json",
+                "--size-threshold",
+                "-1",
+                "app.elf",
+            ],
+        ] {
             let result = Opt::try_parse_from(args.iter());
             assert!(result.is_err());
         }
     }
 
     #[test]
-    // elf2tab [FLAGS] [--write_id=<write_id>] [--read_ids=<read_ids>] [--access_ids=<access_ids>]
-    //                <elf[,architecture]>..."
-    fn storage_ids() {
+    // elf2tab [FLAGS] [--total-ram <total-ram>] <elf[,architecture]>..."
+    fn total_ram() {
         {
             let args = vec![
                 "elf2tab",
-                "--write_id",
-                "1234567",
-                "--read_ids",
-                "1 2",
-                "--access_ids",
-                "2 3",
+                "--total-ram",
+                "0x4000",
+                "--stack",
+                "2048",
+                "app.elf",
+            ];
+            let opt = Opt::try_parse_from(args.iter()).unwrap();
+            assert_eq!(opt.total_ram, Some(0x4000));
+            assert_eq!(opt.stack_size, Some(2048));
+        }
+        {
+            let args = vec![
+                "elf2tab",
+                "--total-ram",
+                "16384",
+                "--app-heap",
+                "4096",
                 "app.elf",
             ];
             let result = Opt::try_parse_from(args.iter());
             assert!(result.is_err());
         }
     }
+
+    #[test]
+    // elf2tab [FLAGS] [--header-checksum <header-checksum>] <elf[,architecture]>..."
+    fn header_checksum() {
+        let args = vec!["elf2tab", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.header_checksum, HeaderChecksum::Xor);
+
+        let args = vec!["elf2tab", "--header-checksum", "crc32", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.header_checksum, HeaderChecksum::Crc32);
+
+        let args = vec!["elf2tab", "--header-checksum", "sum", "app.elf"];
+        assert!(Opt::try_parse_from(args.iter()).is_err());
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--integrity-end <integrity-end>] <elf[,architecture]>..."
+    fn integrity_end() {
+        let args = vec![
+            "elf2tab",
+            "--experimental-tlvs",
+            "--integrity-end",
+            "0x400",
+            "--sha256",
+            "app.elf",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.integrity_end, Some(0x400));
+        assert!(opt.sha256_enable);
+
+        // The covered length in each credential is not in the TBF specification.
+        let args = vec!["elf2tab", "--integrity-end", "0x400", "--sha256", "app.elf"];
+        assert!(Opt::try_parse_from(args.iter()).is_err());
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--board-ram <board-ram>] <elf[,architecture]>..."
+    fn board_ram() {
+        let args = vec!["elf2tab", "--board-ram", "0x10000", "--strict", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.board_ram, Some(0x10000));
+        assert!(opt.strict);
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--sha256] [--sha3-256] <elf[,architecture]>..."
+    fn sha3_256() {
+        let args = vec![
+            "elf2tab",
+            "--experimental-tlvs",
+            "--sha256",
+            "--sha3-256",
+            "app.elf,sign=sha3-256",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.sha256_enable);
+        assert!(opt.sha3_256_enable);
+        assert_eq!(opt.input[0].credentials, Some(vec![Credential::Sha3_256]));
+
+        // SHA3-256 has no credential format in the TBF specification yet.
+        let args = vec!["elf2tab", "--sha3-256", "app.elf"];
+        assert!(Opt::try_parse_from(args.iter()).is_err());
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--sha256-binary] <elf[,architecture]>..."
+    fn sha256_binary() {
+        let args = vec![
+            "elf2tab",
+            "--experimental-tlvs",
+            "--sha256-binary",
+            "app.elf,sign=sha256-binary",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.sha256_binary_enable);
+        assert_eq!(
+            opt.input[0].credentials,
+            Some(vec![Credential::Sha256Binary])
+        );
+
+        let args = vec![
+            "elf2tab",
+            "--experimental-tlvs",
+            "--sha256-binary",
+            "--no-program-header",
+            "app.elf",
+        ];
+        assert!(Opt::try_parse_from(args.iter()).is_err());
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--header-size <header-size>] <elf[,architecture]>..."
+    fn header_size() {
+        let args = vec!["elf2tab", "--header-size", "0x80", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.header_size, Some(0x80));
+
+        let args = vec![
+            "elf2tab",
+            "--header-size",
+            "0x80",
+            "--raw-header",
+            "app.elf",
+        ];
+        assert!(Opt::try_parse_from(args.iter()).is_err());
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--pic-from-elf-type] <elf[,architecture]>..."
+    fn pic_from_elf_type() {
+        let args = vec!["elf2tab", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(!opt.pic_from_elf_type);
+
+        let args = vec!["elf2tab", "--pic-from-elf-type", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.pic_from_elf_type);
+    }
+
+    #[test]
+    // elf2tab tlvs <tbf>
+    fn tlvs() {
+        let args = vec!["elf2tab", "tlvs", "app.tbf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        match opt.command {
+            Some(Command::Tlvs { tbf }) => assert_eq!(tbf, PathBuf::from("app.tbf")),
+            _ => panic!("expected the tlvs command"),
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--ram-app] <elf[,architecture]>..."
+    fn ram_app() {
+        let args = vec!["elf2tab", "--ram-app", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.ram_app);
+
+        let args = vec!["elf2tab", "--ram-app", "--pic-from-elf-type", "app.elf"];
+        assert!(Opt::try_parse_from(args.iter()).is_err());
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--keep-going] <elf[,architecture]>..."
+    fn keep_going() {
+        let args = vec!["elf2tab", "--keep-going", "a.elf", "b.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.keep_going);
+        assert_eq!(opt.input.len(), 2);
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--capabilities-report <capabilities-report>] [--driver-names <driver-names>] <elf[,architecture]>..."
+    fn capabilities_report() {
+        let args = vec![
+            "elf2tab",
+            "--capabilities-report",
+            "capabilities.md",
+            "--driver-names",
+            "drivers.json",
+            "app.elf",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(
+            opt.capabilities_report,
+            Some(PathBuf::from("capabilities.md"))
+        );
+        assert_eq!(opt.driver_names, Some(PathBuf::from("drivers.json")));
+
+        // Driver names are only used by the report.
+        let args = vec!["elf2tab", "--driver-names", "drivers.json", "app.elf"];
+        assert!(Opt::try_parse_from(args.iter()).is_err());
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--fixed-flash <fixed-flash>] [--fixed-ram <fixed-ram>] <elf[,architecture]>..."
+    fn fixed_addresses() {
+        let args = vec![
+            "elf2tab",
+            "--fixed-flash",
+            "0x40000",
+            "--fixed-ram",
+            "0x20004000",
+            "app.elf",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.fixed_flash, Some(0x40000));
+        assert_eq!(opt.fixed_ram, Some(0x20004000));
+
+        // A RAM app has no flash address.
+        let args = vec![
+            "elf2tab",
+            "--ram-app",
+            "--fixed-flash",
+            "0x40000",
+            "app.elf",
+        ];
+        assert!(Opt::try_parse_from(args.iter()).is_err());
+
+        // A RAM app runs from its own load address.
+        let args = vec![
+            "elf2tab",
+            "--ram-app",
+            "--fixed-ram",
+            "0x20004000",
+            "app.elf",
+        ];
+        assert!(Opt::try_parse_from(args.iter()).is_err());
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--base-version <base-version>] [--base-flags <base-flags>] <elf[,architecture]>..."
+    fn base_header() {
+        let args = vec![
+            "elf2tab",
+            "--base-version",
+            "3",
+            "--base-flags",
+            "0x80000001",
+            "app.elf",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.base_version, Some(3));
+        assert_eq!(opt.base_flags, Some(0x80000001));
+
+        // The flags replace the enable flag `--disable` clears.
+        let args = vec!["elf2tab", "--disable", "--base-flags", "0", "app.elf"];
+        assert!(Opt::try_parse_from(args.iter()).is_err());
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [-o -] <elf[,architecture]>..."
+    fn output_to_stdout() {
+        let args = vec!["elf2tab", "-o", "-", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.output, PathBuf::from("-"));
+        assert_eq!(opt.input.len(), 1);
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--embed-elf-hash] <elf[,architecture]>..."
+    fn embed_elf_hash() {
+        let args = vec![
+            "elf2tab",
+            "--experimental-tlvs",
+            "--embed-elf-hash",
+            "app.elf",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.embed_elf_hash);
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--credential-command <credential-command>] [--credential-command-type <credential-command-type>] <elf[,architecture]>..."
+    fn credential_command() {
+        let args = vec![
+            "elf2tab",
+            "--credential-command",
+            "sign-tbf --key hsm:1",
+            "--credential-command-type",
+            "rsa3072",
+            "app.elf",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(
+            opt.credential_command,
+            Some("sign-tbf --key hsm:1".to_string())
+        );
+        assert_eq!(
+            opt.credential_command_type,
+            Some(CommandCredentialType::Rsa3072)
+        );
+
+        let args = vec!["elf2tab", "--credential-command", "sign-tbf", "app.elf"];
+        assert!(Opt::try_parse_from(args.iter()).is_err());
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--section-hashes <section-hashes>] <elf[,architecture]>..."
+    fn section_hashes() {
+        let args = vec!["elf2tab", "--section-hashes", "sections.csv", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.section_hashes, Some(PathBuf::from("sections.csv")));
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--ram-pow2] <elf[,architecture]>..."
+    fn ram_pow2() {
+        let args = vec!["elf2tab", "--ram-pow2", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.ram_pow2);
+
+        let args = vec!["elf2tab", "--ram-pow2", "--total-ram", "0x4000", "app.elf"];
+        assert!(Opt::try_parse_from(args.iter()).is_err());
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--tbf-endian <tbf-endian>] <elf[,architecture]>..."
+    fn tbf_endian() {
+        let args = vec!["elf2tab", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.tbf_endian, TbfEndian::Little);
+
+        let args = vec!["elf2tab", "--tbf-endian", "big", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(opt.tbf_endian, TbfEndian::Big);
+
+        let args = vec!["elf2tab", "--tbf-endian", "big", "--raw-header", "app.elf"];
+        assert!(Opt::try_parse_from(args.iter()).is_err());
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--no-main-header] <elf[,architecture]>..."
+    fn no_main_header() {
+        let args = vec!["elf2tab", "--no-main-header", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.no_main_header);
+
+        let args = vec![
+            "elf2tab",
+            "--no-main-header",
+            "--no-program-header",
+            "app.elf",
+        ];
+        assert!(Opt::try_parse_from(args.iter()).is_err());
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--release] <elf[,architecture]>..."
+    fn release() {
+        let args = vec![
+            "elf2tab",
+            "--release",
+            "--rsa4096-private",
+            "key.pk8",
+            "app.elf",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.release);
+
+        for conflicting in [
+            vec!["--padding-mode", "pow2"],
+            vec!["--minimum-footer-size", "1024"],
+        ] {
+            let mut args = vec!["elf2tab", "--release"];
+            args.extend(conflicting);
+            args.push("app.elf");
+            assert!(Opt::try_parse_from(args.iter()).is_err());
+        }
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--sign-all-architectures-with <sign-all-architectures-with>] <elf[,architecture]>..."
+    fn sign_all_architectures_with() {
+        let args = vec![
+            "elf2tab",
+            "--sign-all-architectures-with",
+            "fleet.pk8",
+            "app-m4.elf,cortex-m4",
+            "app-rv.elf,rv32imac",
+        ];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert_eq!(
+            opt.sign_all_architectures_with,
+            Some(PathBuf::from("fleet.pk8"))
+        );
+
+        let args = vec![
+            "elf2tab",
+            "--sign-all-architectures-with",
+            "fleet.pk8",
+            "--rsa4096-private",
+            "other.pk8",
+            "app.elf",
+        ];
+        assert!(Opt::try_parse_from(args.iter()).is_err());
+    }
+
+    #[test]
+    // elf2tab [FLAGS] [--wfr-erase-sizes] <elf[,architecture]>..."
+    fn wfr_erase_sizes() {
+        let args = vec!["elf2tab", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(!opt.wfr_erase_sizes);
+
+        let args = vec!["elf2tab", "--wfr-erase-sizes", "app.elf"];
+        let opt = Opt::try_parse_from(args.iter()).unwrap();
+        assert!(opt.wfr_erase_sizes);
+    }
 }
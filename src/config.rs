@@ -0,0 +1,297 @@
+//! Read elf2tab options from a TOML config file.
+//!
+//! The keys in the config file are the long names of the command line options,
+//! for example:
+//!
+//! ```toml
+//! package-name = "blink"
+//! stack = 2048
+//! permissions = ["1,0", "1,1"]
+//! sha256 = true
+//! ```
+//!
+//! Options given on the command line take precedence over the config file.
+//!
+//! With `--from-cargo`, the `[package]` name and version from a Cargo.toml are
+//! used as the package name and app version, and the `[package.metadata.tock]`
+//! table can set any other option using the same keys as a config file. These
+//! are only used for options set neither on the command line nor in the config
+//! file.
+
+use crate::cmdline::Opt;
+use clap::parser::ValueSource;
+use clap::CommandFactory;
+use std::ffi::OsString;
+use std::fs;
+
+/// Find the value of the path option `option` (e.g. `--config`) in the raw
+/// command line arguments.
+fn option_path(args: &[OsString], option: &str) -> Option<OsString> {
+    let prefix = format!("{}=", option);
+    let mut args = args.iter().skip(1).take_while(|arg| *arg != "--");
+    while let Some(arg) = args.next() {
+        if arg == option {
+            return args.next().cloned();
+        }
+        if let Some(path) = arg
+            .to_str()
+            .and_then(|arg| arg.strip_prefix(prefix.as_str()))
+        {
+            return Some(path.into());
+        }
+    }
+    None
+}
+
+/// Convert a single TOML value to the string clap would see on the command
+/// line.
+fn config_value(key: &str, value: &toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(value) => Ok(value.clone()),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        _ => Err(format!(
+            "config option `{}` must be a string, integer, boolean, or list of those",
+            key
+        )),
+    }
+}
+
+/// Convert the contents of a config file to command line arguments, leaving
+/// out options that are already set in `args`.
+fn config_args(contents: &str, args: &[OsString]) -> Result<Vec<OsString>, String> {
+    let table: toml::Table = contents
+        .parse()
+        .map_err(|e| format!("invalid config file: {}", e))?;
+    table_args(&table, args, "config file")
+}
+
+/// Pack a Cargo package version `major.minor.patch` into an app version as
+/// `major << 16 | minor << 8 | patch`. Pre-release and build suffixes are
+/// ignored.
+fn cargo_app_version(version: &str) -> Result<u32, String> {
+    let error = || {
+        format!(
+            "Cargo package version `{}` does not fit in an app version",
+            version
+        )
+    };
+    let core = version.split(['-', '+']).next().unwrap_or(version);
+    let parts = core
+        .split('.')
+        .map(|part| part.parse::<u32>().map_err(|_| error()))
+        .collect::<Result<Vec<u32>, String>>()?;
+    match parts[..] {
+        [major, minor, patch] if major <= 0xFFFF && minor <= 0xFF && patch <= 0xFF => {
+            Ok(major << 16 | minor << 8 | patch)
+        }
+        _ => Err(error()),
+    }
+}
+
+/// Convert the package metadata in a Cargo.toml to command line arguments,
+/// leaving out options that are already set in `args`.
+fn cargo_args(contents: &str, args: &[OsString]) -> Result<Vec<OsString>, String> {
+    let manifest: toml::Table = contents
+        .parse()
+        .map_err(|e| format!("invalid Cargo.toml: {}", e))?;
+    let package = manifest
+        .get("package")
+        .and_then(toml::Value::as_table)
+        .ok_or("Cargo.toml has no [package] table")?;
+
+    let mut table = package
+        .get("metadata")
+        .and_then(|metadata| metadata.get("tock"))
+        .map(|tock| {
+            tock.as_table()
+                .cloned()
+                .ok_or("[package.metadata.tock] in Cargo.toml must be a table")
+        })
+        .transpose()?
+        .unwrap_or_default();
+    if let Some(name) = package.get("name") {
+        table.entry("package-name").or_insert_with(|| name.clone());
+    }
+    // The Cargo version is only needed if nothing else sets the app version,
+    // so one that cannot be represented does not fail a build that overrides
+    // it.
+    if let Some(version) = package.get("version").and_then(toml::Value::as_str) {
+        if !table.contains_key("app-version") && option_path(args, "--app-version").is_none() {
+            let app_version = cargo_app_version(version)?;
+            table.insert("app-version".to_string(), i64::from(app_version).into());
+        }
+    }
+    table_args(&table, args, "[package.metadata.tock]")
+}
+
+/// Convert a table of options to command line arguments, leaving out options
+/// that are already set in `args`. `source` names the table in errors.
+fn table_args(
+    table: &toml::Table,
+    args: &[OsString],
+    source: &str,
+) -> Result<Vec<OsString>, String> {
+    // Parse the command line on its own to find out which options were given
+    // there. Errors are ignored since required arguments may come from the
+    // config file.
+    let command = Opt::command();
+    let matches = command.clone().ignore_errors(true).get_matches_from(args);
+
+    let mut config_args = Vec::new();
+    for (key, value) in table {
+        let arg = command
+            .get_arguments()
+            .find(|arg| {
+                arg.get_long() == Some(key.as_str()) && key != "config" && key != "from-cargo"
+            })
+            .ok_or_else(|| format!("unknown option `{}` in {}", key, source))?;
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+
+        match (value, arg.get_action()) {
+            (toml::Value::Boolean(set), clap::ArgAction::SetTrue) => {
+                if *set {
+                    config_args.push(format!("--{}", key).into());
+                }
+            }
+            (toml::Value::Array(values), _) => {
+                for value in values {
+                    config_args.push(format!("--{}={}", key, config_value(key, value)?).into());
+                }
+            }
+            (value, _) => {
+                config_args.push(format!("--{}={}", key, config_value(key, value)?).into());
+            }
+        }
+    }
+    Ok(config_args)
+}
+
+/// Add the options from the config file given with `--config` and the
+/// Cargo.toml given with `--from-cargo`, if any, to the command line arguments
+/// `args`. Options already on the command line are not added again, so the
+/// command line takes precedence, followed by the config file.
+pub fn args_with_config(args: Vec<OsString>) -> Result<Vec<OsString>, String> {
+    let mut merged = args;
+    // Put the new options right after the program name. They all use the
+    // `--option=value` form, so they cannot take any of the following
+    // arguments as values.
+    if let Some(path) = option_path(&merged, "--config") {
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!("could not read config file {:?}: {}", path, e))?;
+        let config_args = config_args(&contents, &merged)?;
+        merged.splice(1..1, config_args);
+    }
+    if let Some(path) = option_path(&merged, "--from-cargo") {
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!("could not read Cargo.toml {:?}: {}", path, e))?;
+        let cargo_args = cargo_args(&contents, &merged)?;
+        merged.splice(1..1, cargo_args);
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cargo_app_version, cargo_args, config_args, option_path};
+    use crate::cmdline::{expand_permissions, Opt};
+    use clap::Parser;
+    use std::ffi::OsString;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    pub fn finds_config_path() {
+        assert_eq!(
+            option_path(
+                &args(&["elf2tab", "--config", "a.toml", "app.elf"]),
+                "--config"
+            ),
+            Some("a.toml".into())
+        );
+        assert_eq!(
+            option_path(
+                &args(&["elf2tab", "--config=a.toml", "app.elf"]),
+                "--config"
+            ),
+            Some("a.toml".into())
+        );
+        assert_eq!(
+            option_path(&args(&["elf2tab", "--", "--config=a.toml"]), "--config"),
+            None
+        );
+    }
+
+    #[test]
+    pub fn command_line_takes_precedence() {
+        let config = r#"
+            package-name = "blink"
+            stack = 2048
+            permissions = ["1,0", "1,1"]
+            sha256 = true
+            sha512 = false
+        "#;
+        let cli = args(&["elf2tab", "--stack", "1024", "app.elf"]);
+        let mut merged = cli.clone();
+        merged.splice(1..1, config_args(config, &cli).unwrap());
+
+        let opt = Opt::try_parse_from(merged).unwrap();
+        assert_eq!(opt.package_name, Some("blink".to_string()));
+        assert_eq!(opt.stack_size, Some(1024));
+        assert_eq!(expand_permissions(&opt.permissions), vec![(1, 0), (1, 1)]);
+        assert!(opt.sha256_enable);
+        assert!(!opt.sha512_enable);
+        assert_eq!(opt.input.len(), 1);
+    }
+
+    #[test]
+    pub fn rejects_unknown_options() {
+        let cli = args(&["elf2tab", "app.elf"]);
+        assert!(config_args("stak = 2048", &cli).is_err());
+        assert!(config_args("config = \"other.toml\"", &cli).is_err());
+        assert!(config_args("stack = 1.5", &cli).is_err());
+    }
+
+    #[test]
+    pub fn reads_cargo_metadata() {
+        let cargo_toml = r#"
+            [package]
+            name = "blink"
+            version = "1.2.3-rc.1"
+
+            [package.metadata.tock]
+            stack = 2048
+            app-heap = 4096
+            permissions = ["1,0"]
+        "#;
+        let cli = args(&["elf2tab", "--stack", "1024", "app.elf"]);
+        let mut merged = cli.clone();
+        merged.splice(1..1, cargo_args(cargo_toml, &cli).unwrap());
+
+        let opt = Opt::try_parse_from(merged).unwrap();
+        assert_eq!(opt.package_name, Some("blink".to_string()));
+        assert_eq!(opt.app_version, Some(0x010203));
+        assert_eq!(opt.stack_size, Some(1024));
+        assert_eq!(opt.app_heap_size, 4096);
+        assert_eq!(expand_permissions(&opt.permissions), vec![(1, 0)]);
+
+        assert!(cargo_args("[workspace]", &cli).is_err());
+        assert!(cargo_app_version("1.2").is_err());
+        assert!(cargo_app_version("1.256.0").is_err());
+
+        let cargo_toml = r#"
+            [package]
+            name = "blink"
+            version = "1.256.0"
+        "#;
+        let cli = args(&["elf2tab", "--app-version", "7", "app.elf"]);
+        let mut merged = cli.clone();
+        merged.splice(1..1, cargo_args(cargo_toml, &cli).unwrap());
+        let opt = Opt::try_parse_from(merged).unwrap();
+        assert_eq!(opt.app_version, Some(7));
+    }
+}
@@ -1,16 +1,102 @@
 //! Convert ELF to TBF.
 
+use crate::error::{Elf2TabError, ErrorCategory};
 use crate::header;
+use crate::pkcs11;
 use crate::util::{self, align_to, amount_alignment_needed};
+use elf::endian::EndianParse;
 use ring::signature::KeyPair;
 use ring::{rand, signature};
 use sha2::{Digest, Sha256, Sha384, Sha512};
+use sha3::Sha3_256;
 use std::cmp;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::collections::VecDeque;
+use std::io::{Read, Seek, SeekFrom};
 use std::mem;
 use std::path::{Path, PathBuf};
+use std::process;
 use std::{fs, io};
 
+/// Alignment elf2tab tries to give the start of non-PIC TBFs in flash, by
+/// sizing the protected region.
+const NON_PIC_TBF_ALIGNMENT: u32 = 256;
+
+/// Specify how elf2tab should add trailing padding to the end of the TBF
+/// file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrailingPadding {
+    /// Make sure the entire TBF is a power of 2 in size, so add any
+    /// necessary padding to make that happen.
+    TotalSizePowerOfTwo,
+    /// Make sure the entire TBF is a multiple of a specific value.
+    TotalSizeMultiple(usize),
+    /// Do not add any trailing padding.
+    Disabled,
+}
+
+/// How the flash used by a TBF breaks down.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TbfSizes {
+    /// Size of the header and app binary, i.e. everything before the footer.
+    pub binary_size: usize,
+    /// Bytes the protected region adds after the TBF header, for example to
+    /// align the app binary of a non-PIC app. Included in `binary_size`.
+    pub protected_overhead: usize,
+    /// Size of the footer credentials and any reserved space requested with
+    /// `minimum_footer_size`.
+    pub footer_size: usize,
+    /// Size of the TBF after any trailing padding.
+    pub total_size: usize,
+}
+
+/// What `elf_to_tbf()` created.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TbfInfo {
+    pub sizes: TbfSizes,
+    /// Names of the credentials in the footer, in order, not counting reserved
+    /// space.
+    pub credentials: Vec<&'static str>,
+    /// Names and sizes of the `.debug_*` sections in the ELF, which are not
+    /// included in the TBF.
+    pub debug_sections: Vec<(String, u64)>,
+    /// The app version in the Program header.
+    pub app_version: u32,
+    /// Name, ELF address, and offset in the TBF of each function and object
+    /// symbol in the app binary, if requested.
+    pub symbol_offsets: Vec<(String, u64, usize)>,
+    /// The minimum RAM size in the header, including stack and heaps.
+    pub minimum_ram_size: u32,
+    /// Offset of the footer in the TBF. Without a Program header there is no
+    /// footer, and this is the total size.
+    pub footer_offset: usize,
+    /// Index of each loadable segment, the name of each section with contents
+    /// in it, and whether the section ended up in the app binary. This records
+    /// the decisions of the section selection heuristics for tests.
+    pub section_decisions: Vec<(usize, String, bool)>,
+    /// Name, offset in the TBF, size, and SHA256 of each section in the app
+    /// binary, if requested.
+    pub section_hashes: Vec<(String, usize, usize, [u8; 32])>,
+}
+
+impl TbfInfo {
+    /// Whether the footer has a cryptographic signature, not just a hash.
+    pub fn has_signature(&self) -> bool {
+        self.credentials.contains(&"rsa4096") || self.credentials.contains(&"rsa3072")
+    }
+}
+
+impl TbfSizes {
+    /// The flash the app actually needs, without trailing padding.
+    pub fn required_size(&self) -> usize {
+        self.binary_size + self.footer_size
+    }
+
+    /// Bytes added only to pad the TBF to the requested size.
+    pub fn padding_size(&self) -> usize {
+        self.total_size - self.required_size()
+    }
+}
+
 /// Helper function for reading RSA DER key files.
 fn read_rsa_file(path: &std::path::Path) -> Result<Vec<u8>, std::io::Error> {
     let mut file = std::fs::File::open(path)?;
@@ -19,6 +105,36 @@ fn read_rsa_file(path: &std::path::Path) -> Result<Vec<u8>, std::io::Error> {
     Ok(contents)
 }
 
+/// Read a 4096-bit RSA private key in PKCS#8 DER format.
+pub fn read_rsa4096_key(private_key_path: &Path) -> io::Result<ring::signature::RsaKeyPair> {
+    let signing_error = |msg: String| {
+        Elf2TabError::io_error(ErrorCategory::Signing, io::ErrorKind::InvalidData, msg)
+    };
+    let private_key_contents = read_rsa_file(private_key_path).map_err(|e| {
+        Elf2TabError::io_error(
+            ErrorCategory::Signing,
+            e.kind(),
+            format!(
+                "Failed to read private key from {:?}: {}",
+                private_key_path, e
+            ),
+        )
+    })?;
+
+    let key_pair = ring::signature::RsaKeyPair::from_pkcs8(&private_key_contents)
+        .map_err(|e| signing_error(format!("RSA4096 could not be parsed: {:?}", e)))?;
+
+    if key_pair.public_modulus_len() != 512 {
+        // A 4096-bit key should have a 512-byte modulus
+        return Err(signing_error(format!(
+            "RSA4096 signature requested but key {:?} is not 4096 bits, it is {} bits",
+            private_key_path,
+            key_pair.public_modulus_len() * 8
+        )));
+    }
+    Ok(key_pair)
+}
+
 /// Helper function to determine if any nonzero length section is inside a
 /// given segment.
 ///
@@ -110,85 +226,804 @@ fn section_in_segment(
         && secoffset - poffset <= segment.p_filesz - 1
 }
 
+/// Where a section excluded with `--exclude-section` was found in a segment.
+#[derive(Debug, PartialEq)]
+enum ExcludedSection {
+    /// The section is not part of the segment's file contents.
+    NotInSegment,
+    /// The section was at the start or end of the segment, and the segment was
+    /// shrunk to remove it.
+    Trimmed,
+    /// The section is in the middle of the segment, starting at this offset
+    /// into the segment.
+    Middle(usize),
+}
+
+/// Helper function to remove a section from a segment.
+///
+/// Sections at the start or end of the segment are removed by shrinking the
+/// segment. Sections in the middle cannot be removed that way, so the caller
+/// must cut them out of the segment contents.
+fn exclude_section_from_segment(
+    section: &elf::section::SectionHeader,
+    segment: &mut elf::segment::ProgramHeader,
+) -> ExcludedSection {
+    if section.sh_size == 0
+        || section.sh_type == elf::abi::SHT_NOBITS
+        || !section_in_segment(section, segment)
+    {
+        return ExcludedSection::NotInSegment;
+    }
+
+    let start = section.sh_offset - segment.p_offset;
+    let end = start + section.sh_size;
+    if start == 0 {
+        segment.p_offset += section.sh_size;
+        segment.p_paddr += section.sh_size;
+        segment.p_vaddr += section.sh_size;
+        segment.p_filesz -= section.sh_size;
+        segment.p_memsz -= section.sh_size;
+        ExcludedSection::Trimmed
+    } else if end == segment.p_filesz {
+        // Memory past the file contents (such as .bss) stays where it is.
+        if segment.p_memsz == segment.p_filesz {
+            segment.p_memsz -= section.sh_size;
+        }
+        segment.p_filesz -= section.sh_size;
+        ExcludedSection::Trimmed
+    } else {
+        ExcludedSection::Middle(start as usize)
+    }
+}
+
+/// Helper function to find where `offset` into a segment ends up once the
+/// `excluded` (offset, length) ranges are cut out of the segment contents.
+/// Returns `None` if `offset` is in one of the excluded ranges.
+fn offset_after_exclusions(offset: usize, excluded: &[(usize, usize)]) -> Option<usize> {
+    let mut moved = 0;
+    for (start, length) in excluded {
+        if offset >= *start && offset < start + length {
+            return None;
+        }
+        if *start < offset {
+            moved += length;
+        }
+    }
+    Some(offset - moved)
+}
+
+/// Run `command` (a program and its arguments, separated by whitespace) with
+/// `covered` on stdin, and return what it writes to stdout as the data of a
+/// credential of type `credential_type`.
+fn run_credential_command(
+    command: &str,
+    covered: &[u8],
+    credential_type: header::CommandCredentialType,
+) -> io::Result<Vec<u8>> {
+    let signing_error = |msg: String| {
+        Elf2TabError::io_error(ErrorCategory::Signing, io::ErrorKind::InvalidData, msg)
+    };
+    let mut words = command.split_whitespace();
+    let program = words
+        .next()
+        .ok_or_else(|| signing_error("credential command is empty".to_string()))?;
+    let mut child = process::Command::new(program)
+        .args(words)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            signing_error(format!(
+                "Could not run credential command `{}`: {}",
+                command, e
+            ))
+        })?;
+    // Write the input from another thread so a command that starts writing
+    // before it has read everything cannot deadlock.
+    let mut stdin = child.stdin.take().unwrap();
+    let covered = covered.to_vec();
+    let writer = std::thread::spawn(move || io::Write::write_all(&mut stdin, &covered));
+    let result = child.wait_with_output()?;
+    // A command that exits without reading all of its input is not an error
+    // in itself; its exit status and output are checked below.
+    let _ = writer.join();
+    if !result.status.success() {
+        return Err(signing_error(format!(
+            "Credential command `{}` failed: {}",
+            command, result.status
+        )));
+    }
+    if result.stdout.len() != credential_type.data_length() {
+        return Err(signing_error(format!(
+            "Credential command `{}` returned {} bytes, but a {} credential is {} bytes",
+            command,
+            result.stdout.len(),
+            credential_type.name(),
+            credential_type.data_length()
+        )));
+    }
+    Ok(result.stdout)
+}
+
+/// Find the symbol called `name` in the ELF's symbol table, if it has one.
+fn find_symbol(
+    elf_file: &elf::ElfBytes<elf::endian::AnyEndian>,
+    name: &str,
+) -> io::Result<Option<elf::symbol::Symbol>> {
+    let Ok(Some((symtab, sym_strtab))) = elf_file.symbol_table() else {
+        return Ok(None);
+    };
+    for sym in symtab.iter() {
+        let sym_name = sym_strtab.get(sym.st_name as usize).map_err(|e| {
+            Elf2TabError::io_error(
+                ErrorCategory::ElfParse,
+                io::ErrorKind::InvalidData,
+                format!("Failed to parse symbol name: {}", e),
+            )
+        })?;
+        if sym_name == name {
+            return Ok(Some(sym));
+        }
+    }
+    Ok(None)
+}
+
+/// Helper function to check that no two sections with contents in a segment
+/// occupy the same bytes of the ELF file.
+///
+/// Overlapping sections indicate a malformed ELF, and would cause relocation
+/// or writeable flash region data to be double counted or misplaced.
+fn check_overlapping_sections(
+    shdrs: &[(String, elf::section::SectionHeader)],
+    segment: &elf::segment::ProgramHeader,
+) -> io::Result<()> {
+    let mut sections: Vec<&(String, elf::section::SectionHeader)> = shdrs
+        .iter()
+        .filter(|(_, shdr)| {
+            shdr.sh_size > 0
+                && shdr.sh_type != elf::abi::SHT_NOBITS
+                && section_in_segment(shdr, segment)
+        })
+        .collect();
+    sections.sort_by_key(|(_, shdr)| shdr.sh_offset);
+
+    for pair in sections.windows(2) {
+        let (first_name, first) = pair[0];
+        let (second_name, second) = pair[1];
+        if second.sh_offset < first.sh_offset + first.sh_size {
+            return Err(Elf2TabError::io_error(
+                ErrorCategory::ElfParse,
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Sections {} (offset {:#x}, length {:#x}) and {} (offset {:#x}, length {:#x}) overlap",
+                    first_name,
+                    first.sh_offset,
+                    first.sh_size,
+                    second_name,
+                    second.sh_offset,
+                    second.sh_size
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Get the erase block size from a writeable flash region section named
+/// `.wfr.<size>`, e.g. `.wfr.4096` or `.app_state.wfr.0x1000`.
+fn wfr_erase_size(section_name: &str) -> io::Result<Option<u32>> {
+    match section_name.rsplit_once(".wfr.") {
+        Some((_, size)) if size.starts_with(|c: char| c.is_ascii_digit()) => {
+            match clap_num::maybe_hex::<u32>(size) {
+                Ok(size) if size > 0 => Ok(Some(size)),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Writeable flash region {} has an invalid erase size `{}`",
+                        section_name, size
+                    ),
+                )),
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Helper function to check that each writeable flash region, given as its
+/// section name, offset in the TBF, and size, lies within the app binary
+/// between `start` and `end`.
+///
+/// The kernel allows the app to write to these regions, so one that starts in
+/// the header or protected region, or reaches into the footer, would let the
+/// app overwrite data the kernel trusts.
+fn check_writeable_flash_regions(
+    regions: &[(String, usize, u64)],
+    start: usize,
+    end: usize,
+) -> io::Result<()> {
+    for (name, offset, size) in regions {
+        if *offset < start {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Writeable flash region {} (offset {:#x}) is inside the TBF header and protected region, which end at {:#x}; check where the linker script places it",
+                    name, offset, start
+                ),
+            ));
+        }
+        if *offset as u64 + size > end as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Writeable flash region {} (offset {:#x}, length {:#x}) is not within the app binary ({:#x} to {:#x})",
+                    name, offset, size, start, end
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Helper function to check that no two flash segments, given as start
+/// address, end address, and the ELF they come from, overlap.
+fn check_overlapping_segments(segments: &mut [(u64, u64, String)]) -> io::Result<()> {
+    segments.sort();
+    for pair in segments.windows(2) {
+        let (first_start, first_end, first_elf) = &pair[0];
+        let (second_start, second_end, second_elf) = &pair[1];
+        if second_start < first_end {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Segments {:#x}-{:#x} in {} and {:#x}-{:#x} in {} overlap",
+                    first_start, first_end, first_elf, second_start, second_end, second_elf
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Helper function to check that the flash address, estimated from the lowest
+/// executable segment or given with `fixed_flash`, is where the app binary
+/// actually starts. Segments are copied into the binary in program header
+/// order. Ones entirely below `flash_address` are left out and ones that start
+/// below it are truncated, so the first remaining loaded segment must start at
+/// `flash_address` or offsets in the app binary would be wrong.
+fn check_flash_segment_order(
+    segments: &[elf::segment::ProgramHeader],
+    flash_address: u32,
+) -> io::Result<()> {
+    let first = segments.iter().find(|segment| {
+        segment.p_type == elf::abi::PT_LOAD
+            && segment.p_filesz > 0
+            && segment.p_paddr + segment.p_filesz > flash_address as u64
+    });
+    match first {
+        Some(first) if first.p_paddr > flash_address as u64 => Err(Elf2TabError::io_error(
+            ErrorCategory::ElfParse,
+            io::ErrorKind::InvalidData,
+            format!(
+                "The first segment (at {:#x}) does not start at the app binary address {:#x}, \
+                 so offsets in the app binary would be wrong",
+                first.p_paddr, flash_address
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Helper function to read the flash segments of an ELF that is merged into
+/// the TBF with `flatten`, as (address, contents) pairs in address order.
+///
+/// The ELF must be for `e_machine` and must only have segments in flash at or
+/// after `flash_start`, with no RAM beyond their contents. Its sections are
+/// not looked at, so it cannot have relocations or writeable flash regions.
+fn flatten_segments(
+    path: &Path,
+    e_machine: u16,
+    flash_start: u64,
+) -> io::Result<Vec<(u64, Vec<u8>)>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let elf_file_buf = fs::read(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to read {:?}: {}", path, e)))?;
+    let elf_file =
+        elf::ElfBytes::<elf::endian::AnyEndian>::minimal_parse(&elf_file_buf).map_err(|e| {
+            Elf2TabError::io_error(
+                ErrorCategory::ElfParse,
+                io::ErrorKind::InvalidData,
+                format!("{:?} is not a valid ELF: {}", path, e),
+            )
+        })?;
+    if elf_file.ehdr.e_machine != e_machine {
+        return Err(invalid(format!(
+            "{:?} is for machine {} but the first ELF is for machine {}",
+            path, elf_file.ehdr.e_machine, e_machine
+        )));
+    }
+    let segments = elf_file
+        .segments()
+        .ok_or_else(|| invalid(format!("{:?} has no program headers", path)))?;
+
+    let mut flattened = Vec::new();
+    for segment in segments.iter() {
+        if segment.p_type != elf::abi::PT_LOAD {
+            continue;
+        }
+        // Only the first ELF's RAM is counted in the minimum RAM size, so
+        // memory without contents (such as .bss) cannot be provided.
+        if segment.p_memsz > segment.p_filesz {
+            return Err(invalid(format!(
+                "{:?} has a segment at {:#x} with {} bytes of RAM; only the first ELF can use RAM",
+                path,
+                segment.p_vaddr,
+                segment.p_memsz - segment.p_filesz
+            )));
+        }
+        if segment.p_filesz == 0 {
+            continue;
+        }
+        if segment.p_vaddr != segment.p_paddr && segment.p_flags & elf::abi::PF_W > 0 {
+            return Err(invalid(format!(
+                "{:?} has a RAM segment at {:#x}; only the first ELF can use RAM",
+                path, segment.p_vaddr
+            )));
+        }
+        if segment.p_paddr < flash_start {
+            return Err(invalid(format!(
+                "{:?} has a segment at {:#x}, before the app starts at {:#x}",
+                path, segment.p_paddr, flash_start
+            )));
+        }
+        let content = elf_file_buf
+            .get(segment.p_offset as usize..(segment.p_offset + segment.p_filesz) as usize)
+            .ok_or_else(|| invalid(format!("{:?} is truncated", path)))?;
+        flattened.push((segment.p_paddr, content.to_vec()));
+    }
+    flattened.sort_by_key(|(address, _)| *address);
+    Ok(flattened)
+}
+
+/// Helper function to append a segment merged in with `flatten` to the binary,
+/// after padding from the end of the previous segment (or the start of the app
+/// binary at `flash_start`).
+fn append_flattened_segment(
+    binary: &mut Vec<u8>,
+    binary_index: &mut usize,
+    last_segment_address_end: &mut Option<usize>,
+    flash_start: usize,
+    address: u64,
+    content: Vec<u8>,
+    verbose: bool,
+) {
+    let padding = address as usize - last_segment_address_end.unwrap_or(flash_start);
+    if verbose && padding > 0 {
+        eprintln!("  Including padding between segments size={}", padding);
+    }
+    binary.extend(vec![0; padding]);
+    *binary_index += padding;
+    if verbose {
+        eprintln!(
+            "  Adding flattened segment at {0:#x}. Offset: {1} ({1:#x}). Length: {2} ({2:#x}) bytes.",
+            address,
+            binary_index,
+            content.len()
+        );
+    }
+    *binary_index += content.len();
+    *last_segment_address_end = Some(address as usize + content.len());
+    binary.extend(content);
+}
+
+/// Helper function to find the relocation section for the section at
+/// `section_index` in the section header table.
+///
+/// Targets use either REL or RELA (e.g. RISC-V) relocations. The relocation
+/// section refers to the section it applies to with `sh_info`. Its name is
+/// usually `.rel<section name>` or `.rela<section name>`, but is not used, so
+/// other sections with such names are not mistaken for relocations.
+fn find_relocation_section(
+    shdrs: &[(String, elf::section::SectionHeader)],
+    section_index: usize,
+) -> Option<&(String, elf::section::SectionHeader)> {
+    shdrs.iter().find(|(_, shdr)| {
+        (shdr.sh_type == elf::abi::SHT_REL || shdr.sh_type == elf::abi::SHT_RELA)
+            && shdr.sh_info as usize == section_index
+    })
+}
+
+/// Helper function to determine how much reserved space must be added to the
+/// footer so it is at least `minimum_footer_size` bytes, given the length of
+/// the credentials already in the footer.
+///
+/// Reserved space is stored in a Reserved credentials TLV, so it is always at
+/// least the size of that TLV's header and a multiple of 4 bytes. The footer
+/// can therefore end up larger than requested.
+fn footer_reserved_space(credentials_len: usize, minimum_footer_size: usize) -> usize {
+    if minimum_footer_size <= credentials_len {
+        return 0;
+    }
+    let reserved_space = cmp::max(
+        minimum_footer_size - credentials_len,
+        mem::size_of::<header::TbfHeaderTlv>() + mem::size_of::<header::TbfFooterCredentialsType>(),
+    );
+    // We also must ensure that if there were to be a TLV after the
+    // reserved TLV that it would start at a 4 byte alignment.
+    align_to(reserved_space as u32, 4) as usize
+}
+
+/// Convert a size or offset to the 32 bits the TBF format stores, returning an
+/// error instead of silently truncating values that do not fit.
+fn checked_u32<T: TryInto<u32> + Copy + std::fmt::Display>(
+    value: T,
+    what: &str,
+) -> io::Result<u32> {
+    value.try_into().map_err(|_| {
+        Elf2TabError::io_error(
+            ErrorCategory::Size,
+            io::ErrorKind::InvalidData,
+            format!("{} ({}) does not fit in 32 bits", what, value),
+        )
+    })
+}
+
+/// Check that an ELF file has what `elf_to_tbf()` needs to convert it.
+///
+/// Unlike the conversion, which stops at the first problem, this reports every
+/// problem it finds. An empty list means the ELF looks suitable.
+pub fn check_elf(elf_file_buf: &[u8]) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let elf_file = match elf::ElfBytes::<elf::endian::AnyEndian>::minimal_parse(elf_file_buf) {
+        Ok(elf_file) => elf_file,
+        Err(e) => {
+            problems.push(format!("not a valid ELF file: {}", e));
+            return problems;
+        }
+    };
+
+    // We use the section headers to find sections like .symtab, .stack, and
+    // *.wfr, so they and their names must be readable.
+    match elf_file.section_headers_with_strtab() {
+        Ok((Some(shdr_tab), Some(strtab))) => {
+            for shdr in shdr_tab.iter() {
+                if let Err(e) = strtab.get(shdr.sh_name as usize) {
+                    problems.push(format!(
+                        "section name at string table offset {} is invalid: {}",
+                        shdr.sh_name, e
+                    ));
+                }
+            }
+        }
+        Ok((None, _)) => problems.push("ELF has no section headers".to_string()),
+        Ok((Some(_), None)) => problems.push("ELF has no section name string table".to_string()),
+        Err(e) => problems.push(format!("ELF section headers are invalid: {}", e)),
+    }
+
+    let loaded_segments: Vec<elf::segment::ProgramHeader> = match elf_file.segments() {
+        Some(segments) => segments
+            .iter()
+            .filter(|segment| segment.p_type == elf::abi::PT_LOAD && segment.p_filesz > 0)
+            .collect(),
+        None => {
+            problems.push("ELF has no program headers".to_string());
+            Vec::new()
+        }
+    };
+    if !loaded_segments
+        .iter()
+        .any(|segment| segment.p_flags & elf::abi::PF_X != 0)
+    {
+        problems.push("ELF has no loadable executable segment".to_string());
+    }
+
+    // The entry point is found the same way as during the conversion.
+    let entry = elf_file.ehdr.e_entry;
+    match loaded_segments
+        .iter()
+        .find(|segment| entry >= segment.p_paddr && entry < segment.p_paddr + segment.p_filesz)
+    {
+        None => problems.push(format!(
+            "entry point {:#x} is not in a loaded segment",
+            entry
+        )),
+        Some(segment) if segment.p_flags & elf::abi::PF_X == 0 => problems.push(format!(
+            "entry point {:#x} is in a segment that is not executable",
+            entry
+        )),
+        Some(_) => {}
+    }
+
+    problems
+}
+
+/// Settings for `elf_to_tbf()`. The defaults leave every option off, so the
+/// command line defaults (e.g. for the heap sizes) are not applied.
+#[derive(Debug, Clone, Default)]
+pub struct ConvertOptions {
+    /// Name of the app, stored in the PackageName TLV.
+    pub package_name: Option<String>,
+    /// Print details of the conversion to stderr.
+    pub verbose: bool,
+    /// Stack size. Without it the size of the `.stack` section is used.
+    pub stack_len: Option<u32>,
+    /// Size of the app heap.
+    pub app_heap_len: u32,
+    /// Size of the grant region the kernel allocates for the app.
+    pub kernel_heap_len: u32,
+    /// Size of the protected region, including the header.
+    pub protected_region_size_arg: Option<u32>,
+    /// Driver and command pairs the app may use.
+    pub permissions: Vec<(u32, u32)>,
+    /// Driver and command pairs the app is explicitly denied.
+    pub denied_permissions: Vec<(u32, u32)>,
+    /// Write ID, read IDs, and access IDs for the Persistent TLV.
+    pub storage_ids: (Option<u32>, Option<Vec<u32>>, Option<Vec<u32>>),
+    /// Minimum kernel major and minor version.
+    pub kernel_version: Option<(u16, u16)>,
+    /// Fixed ShortId for the app.
+    pub short_id: Option<u32>,
+    /// Mark the app as disabled in the base header.
+    pub disabled: bool,
+    /// Room to reserve in the footer for credentials.
+    pub minimum_footer_size: u32,
+    /// App version for the Program header. Without it the `.app_version` section is used.
+    pub app_version: Option<u32>,
+    /// Add a SHA256 credential.
+    pub sha256: bool,
+    /// Add a SHA384 credential.
+    pub sha384: bool,
+    /// Add a SHA512 credential.
+    pub sha512: bool,
+    /// Add a SHA3-256 credential.
+    pub sha3_256: bool,
+    /// Sign with the RSA4096 key in this file.
+    pub rsa4096_private_key: Option<PathBuf>,
+    /// Warn if the trailing padding is more than this many bytes.
+    pub padding_warn_threshold: usize,
+    /// Align the start of the footer to this many bytes.
+    pub footer_align: Option<u32>,
+    /// Sign with the RSA4096 key on this PKCS#11 token.
+    pub pkcs11_uri: Option<String>,
+    /// Use the `.tbfheader` section of the ELF as the header.
+    pub raw_header: bool,
+    /// Do not print warnings.
+    pub quiet: bool,
+    /// Append the contents of this file to the app binary.
+    pub append_binary: Option<PathBuf>,
+    /// Minimum RAM size for the Main header only.
+    pub main_ram_override: Option<u32>,
+    /// SHA256 of the TAB's metadata.toml for a MetadataHash TLV.
+    pub metadata_hash: Option<[u8; 32]>,
+    /// Only require a minimum kernel version.
+    pub kernel_version_no_upper: bool,
+    /// Sections to append to the app binary even if they are not loaded.
+    pub include_sections: Vec<String>,
+    /// Sections to leave out of the app binary.
+    pub exclude_sections: Vec<String>,
+    /// Allow more than one segment to contain the entry point.
+    pub allow_duplicate_entry: bool,
+    /// Required alignment of the entry point in the TBF.
+    pub entry_align: Option<u32>,
+    /// Leave out trailing padding.
+    pub no_trailing_padding: bool,
+    /// Leave out the Program header.
+    pub no_program_header: bool,
+    /// String for a VendorString TLV.
+    pub vendor_string: Option<String>,
+    /// Return warnings as errors.
+    pub werror: bool,
+    /// Fill the protected region after the header with this file.
+    pub protected_contents: Option<PathBuf>,
+    /// Trailing padding to use instead of the default for the architecture.
+    pub padding_mode: Option<TrailingPadding>,
+    /// Add a RamBreakdown TLV.
+    pub ram_breakdown: bool,
+    /// Other ELFs to merge into this TBF.
+    pub flatten: Vec<PathBuf>,
+    /// Security version for a SecurityVersion TLV.
+    pub security_version: Option<u32>,
+    /// Leave out the relocation length if there are no relocations.
+    pub omit_empty_relocation: bool,
+    /// Create a TBF without an app binary.
+    pub header_only: bool,
+    /// Record where each symbol ends up in the TBF.
+    pub report_symbol_offsets: bool,
+    /// Total RAM for the app; the heap gets whatever is left over.
+    pub total_ram: Option<u32>,
+    /// How the header checksum is computed.
+    pub header_checksum: header::HeaderChecksum,
+    /// Offset where the part of the TBF covered by credentials ends.
+    pub integrity_end: Option<u32>,
+    /// Warn if the app needs more RAM than this.
+    pub board_ram: Option<u32>,
+    /// Add a SHA256 credential of only the app binary.
+    pub sha256_binary: bool,
+    /// Pad the header to this many bytes.
+    pub header_size: Option<u32>,
+    /// Decide whether the app is PIC from the ELF type.
+    pub pic_from_elf_type: bool,
+    /// The app is copied to RAM and runs from there.
+    pub ram_app: bool,
+    /// Flash address to use instead of detecting it.
+    pub fixed_flash: Option<u32>,
+    /// RAM address to use instead of detecting it.
+    pub fixed_ram: Option<u32>,
+    /// TBF version in the base header.
+    pub base_version: Option<u16>,
+    /// Flags in the base header.
+    pub base_flags: Option<u32>,
+    /// Add an ElfHash TLV.
+    pub embed_elf_hash: bool,
+    /// Command that creates a credential, and the type of that credential.
+    pub credential_command: Option<(String, header::CommandCredentialType)>,
+    /// Treat suspicious ELFs as errors.
+    pub strict: bool,
+    /// Record the offset, size, and hash of each section.
+    pub report_section_hashes: bool,
+    /// Round the minimum RAM size up to a power of two.
+    pub ram_pow2: bool,
+    /// Byte order of the header.
+    pub tbf_endian: header::TbfEndian,
+    /// Leave out the Main header.
+    pub no_main_header: bool,
+    /// Use extended WriteableFlashRegions TLVs for `.wfr.<size>` sections.
+    pub record_wfr_erase_sizes: bool,
+}
+
 /// Convert an ELF file to a TBF (Tock Binary Format) binary file.
 ///
 /// This will place all segments from the ELF file into a binary and prepend a
 /// TBF header to it. For all writeable sections in the included segments, if
-/// there is a .rel.X section it will be included at the end with a 32 bit
+/// there is a .rel.X (or .rela.X) section it will be included at the end with a 32 bit
 /// length parameter first.
 ///
+/// Verbose output and warnings are printed to stderr. Warnings are not printed
+/// if `quiet` is set, and are returned as errors if `werror` is set.
+///
+/// The TBF is streamed to `output` unless a credential needs to hash it, in
+/// which case the part covered by integrity is buffered in memory first.
+///
 /// Assumptions:
 /// - Any segments that are writable and set to be loaded into flash but with a
 ///   different virtual address will be in RAM and should count towards minimum
 ///   required RAM.
 /// - Sections that are writeable flash regions include .wfr in their name.
-pub fn elf_to_tbf(
-    input_file: &mut fs::File,
-    output: &mut Vec<u8>,
-    package_name: Option<String>,
-    verbose: bool,
-    stack_len: Option<u32>,
-    app_heap_len: u32,
-    kernel_heap_len: u32,
-    protected_region_size_arg: Option<u32>,
-    permissions: Vec<(u32, u32)>,
-    storage_ids: (Option<u32>, Option<Vec<u32>>, Option<Vec<u32>>),
-    kernel_version: Option<(u16, u16)>,
-    short_id: Option<u32>,
-    disabled: bool,
-    minimum_footer_size: u32,
-    app_version: u32,
-    sha256: bool,
-    sha384: bool,
-    sha512: bool,
-    rsa4096_private_key: Option<PathBuf>,
-) -> io::Result<()> {
+pub fn elf_to_tbf<R: Read + Seek, W: io::Write>(
+    input_file: &mut R,
+    output: &mut W,
+    options: ConvertOptions,
+) -> io::Result<TbfInfo> {
+    let ConvertOptions {
+        package_name,
+        verbose,
+        stack_len,
+        app_heap_len,
+        kernel_heap_len,
+        protected_region_size_arg,
+        permissions,
+        denied_permissions,
+        storage_ids,
+        kernel_version,
+        short_id,
+        disabled,
+        minimum_footer_size,
+        app_version,
+        sha256,
+        sha384,
+        sha512,
+        sha3_256,
+        rsa4096_private_key,
+        padding_warn_threshold,
+        footer_align,
+        pkcs11_uri,
+        raw_header,
+        quiet,
+        append_binary,
+        main_ram_override,
+        metadata_hash,
+        kernel_version_no_upper,
+        include_sections,
+        exclude_sections,
+        allow_duplicate_entry,
+        entry_align,
+        no_trailing_padding,
+        no_program_header,
+        vendor_string,
+        werror,
+        protected_contents,
+        padding_mode,
+        ram_breakdown,
+        flatten,
+        security_version,
+        omit_empty_relocation,
+        header_only,
+        report_symbol_offsets,
+        total_ram,
+        header_checksum,
+        integrity_end,
+        board_ram,
+        sha256_binary,
+        header_size,
+        pic_from_elf_type,
+        ram_app,
+        fixed_flash,
+        fixed_ram,
+        base_version,
+        base_flags,
+        embed_elf_hash,
+        credential_command,
+        strict,
+        report_section_hashes,
+        ram_pow2,
+        tbf_endian,
+        no_main_header,
+        record_wfr_erase_sizes,
+    } = options;
+
+    let warnings = util::Warnings::new(quiet, werror, strict);
+
+    // Without a Program header the kernel cannot find the footer, so there is
+    // nowhere to put credentials.
+    if no_program_header
+        && (sha256
+            || sha384
+            || sha512
+            || sha3_256
+            || sha256_binary
+            || rsa4096_private_key.is_some()
+            || pkcs11_uri.is_some())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "credentials cannot be added to a TBF without a Program header",
+        ));
+    }
+
+    // Check the PKCS#11 URI before doing any work.
+    let pkcs11_uri = pkcs11_uri
+        .map(|uri| pkcs11::Pkcs11Uri::parse(&uri))
+        .transpose()?;
+
     let package_name = package_name.unwrap_or_default();
 
     // Load and parse ELF.
     let mut elf_file_buf = Vec::<u8>::default();
     input_file.read_to_end(&mut elf_file_buf)?;
+    let elf_parse_error = |msg: String| {
+        Elf2TabError::io_error(ErrorCategory::ElfParse, io::ErrorKind::InvalidData, msg)
+    };
     let elf_file = elf::ElfBytes::<elf::endian::AnyEndian>::minimal_parse(elf_file_buf.as_slice())
-        .expect("Could not parse the .elf file.");
+        .map_err(|e| elf_parse_error(format!("Could not parse the .elf file: {}", e)))?;
 
     let (shdr_tab, strtab) = match elf_file.section_headers_with_strtab() {
         Ok((Some(shdr_tab), Some(strtab))) => (shdr_tab, strtab),
         _ => {
             // We use the section headers to find sections like .symtab, .stack, and *.wfr
-            panic!("Cannot convert ELF file with no section headers");
+            return Err(elf_parse_error(
+                "Cannot convert ELF file with no section headers".to_string(),
+            ));
         }
     };
 
     let elf_sections: Vec<(String, elf::section::SectionHeader)> = shdr_tab
         .iter()
         .map(|shdr| {
-            (
-                strtab
-                    .get(shdr.sh_name as usize)
-                    .expect("Failed to parse section name")
-                    .to_string(),
-                shdr,
-            )
+            let name = strtab
+                .get(shdr.sh_name as usize)
+                .map_err(|e| elf_parse_error(format!("Failed to parse section name: {}", e)))?;
+            Ok((name.to_string(), shdr))
         })
-        .collect();
+        .collect::<io::Result<_>>()?;
 
     let mut elf_phdrs: Vec<elf::segment::ProgramHeader> = elf_file
         .segments()
-        .expect("Failed to locate ELF program headers")
+        .ok_or_else(|| elf_parse_error("Failed to locate ELF program headers".to_string()))?
         .iter()
         .collect();
 
-    /// Specify how elf2tab should add trailing padding to the end of the TBF
-    /// file.
-    enum TrailingPadding {
-        /// Make sure the entire TBF is a power of 2 in size, so add any
-        /// necessary padding to make that happen.
-        TotalSizePowerOfTwo,
-        /// Make sure the entire TBF is a multiple of a specific value.
-        TotalSizeMultiple(usize),
-    }
-
-    // Add trailing padding for certain architectures.
+    // Add trailing padding for certain architectures, unless a padding mode
+    // was given explicitly.
     //
     // - ARM: make sure the entire TBF is a power of 2 to make configuring the
     //   MPU easy.
@@ -196,6 +1031,8 @@ pub fn elf_to_tbf(
     //   alignment requirements.
     // - x86: use 4k padding to match page size.
     let trailing_padding = match elf_file.ehdr.e_machine {
+        _ if no_trailing_padding => None,
+        _ if padding_mode.is_some() => padding_mode,
         elf::abi::EM_ARM => Some(TrailingPadding::TotalSizePowerOfTwo),
         elf::abi::EM_RISCV => Some(TrailingPadding::TotalSizeMultiple(4)),
         elf::abi::EM_386 => Some(TrailingPadding::TotalSizeMultiple(4096)),
@@ -209,22 +1046,64 @@ pub fn elf_to_tbf(
     // Set the size of the stack, either as specified by command line arguments,
     // based on a section set by the linker, or if all else fails to a default
     // value.
+    let stack_section_len = elf_sections
+        .iter()
+        .find(|(sh_name, _)| sh_name == ".stack")
+        .map(|(_, shdr)| checked_u32(shdr.sh_size, ".stack section size"))
+        .transpose()?;
+    // A stale `--stack` silently overrides the linker script, so point out
+    // when the two disagree.
+    if let (Some(stack_len), Some(stack_section_len)) = (stack_len, stack_section_len) {
+        if stack_len != stack_section_len {
+            warnings.warn(&format!(
+                "Using stack size {} from the command line, but the .stack section is {} bytes.",
+                stack_len, stack_section_len
+            ))?;
+        }
+    }
     let stack_len = stack_len
         // not provided, read from binary
-        .or_else(|| {
-            elf_sections.iter().find_map(|(sh_name, shdr)| {
-                if sh_name == ".stack" {
-                    Some(shdr.sh_size as u32)
-                } else {
-                    None
-                }
-            })
-        })
+        .or(stack_section_len)
         // nothing in binary, use default
         .unwrap_or(2048);
 
+    // Like the stack size, the app version can come from the command line or
+    // from a `.app_version` section holding a u32 in the ELF's byte order.
+    let app_version = match app_version {
+        Some(app_version) => app_version,
+        None => match elf_sections
+            .iter()
+            .find(|(sh_name, _)| sh_name == ".app_version")
+        {
+            Some((_, shdr)) => {
+                let data = elf_file
+                    .section_data(shdr)
+                    .map(|(data, _)| data)
+                    .unwrap_or(&[]);
+                elf_file
+                    .ehdr
+                    .endianness
+                    .parse_u32_at(&mut 0, data)
+                    .map_err(|_| {
+                        elf_parse_error(format!(
+                            ".app_version section must be at least 4 bytes, found {}",
+                            data.len()
+                        ))
+                    })?
+            }
+            None => 0,
+        },
+    };
+
     // Keep track of how much RAM this app will need.
     let mut minimum_ram_size: u32 = 0;
+    let ram_size_overflow = || {
+        Elf2TabError::io_error(
+            ErrorCategory::Size,
+            io::ErrorKind::InvalidData,
+            "minimum RAM size does not fit in 32 bits",
+        )
+    };
 
     // Find all segments destined for the RAM section that are stored in flash.
     // These are set in the linker file to consume memory, and we need to
@@ -237,16 +1116,20 @@ pub fn elf_to_tbf(
         //   loaded into flash but actually reside in memory.
         // - Are not zero size in memory.
         // - Are writable (RAM should be writable).
+        //
+        // An app that runs from RAM needs RAM for all of its segments.
         if segment.p_type == elf::abi::PT_LOAD
-            && segment.p_vaddr != segment.p_paddr
             && segment.p_memsz > 0
-            && ((segment.p_flags & elf::abi::PF_W) > 0)
+            && (ram_app
+                || (segment.p_vaddr != segment.p_paddr && ((segment.p_flags & elf::abi::PF_W) > 0)))
         {
-            minimum_ram_size += segment.p_memsz as u32;
+            minimum_ram_size = minimum_ram_size
+                .checked_add(checked_u32(segment.p_memsz, "RAM segment size")?)
+                .ok_or_else(ram_size_overflow)?;
         }
     }
     if verbose {
-        println!(
+        eprintln!(
             "Min RAM size from segments in ELF: {} bytes",
             minimum_ram_size
         );
@@ -254,8 +1137,75 @@ pub fn elf_to_tbf(
 
     // Add in room the app is asking us to reserve for the stack and heaps to
     // the minimum required RAM size.
-    minimum_ram_size +=
-        align_to(stack_len, 8) + align_to(app_heap_len, 4) + align_to(kernel_heap_len, 4);
+    let segment_ram_size = minimum_ram_size;
+    let stack_ram_size = align_to(stack_len, 8);
+    let segment_and_stack_ram_size = segment_ram_size
+        .checked_add(stack_ram_size)
+        .ok_or_else(ram_size_overflow)?;
+    let heap_ram_size = match total_ram {
+        // With a fixed total, whatever the segments and stack leave over is
+        // heap.
+        Some(total_ram) => {
+            let heap_ram_size = total_ram
+                .checked_sub(segment_and_stack_ram_size)
+                .ok_or_else(|| {
+                    Elf2TabError::io_error(
+                        ErrorCategory::Size,
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "total RAM of {} bytes is less than the {} bytes needed for segments and stack",
+                            total_ram, segment_and_stack_ram_size
+                        ),
+                    )
+                })?;
+            if verbose {
+                eprintln!("Heap implied by total RAM: {} bytes", heap_ram_size);
+            }
+            heap_ram_size
+        }
+        None => align_to(app_heap_len, 4)
+            .checked_add(align_to(kernel_heap_len, 4))
+            .ok_or_else(ram_size_overflow)?,
+    };
+    minimum_ram_size = segment_and_stack_ram_size
+        .checked_add(heap_ram_size)
+        .ok_or_else(ram_size_overflow)?;
+
+    // Some MPUs can only protect power-of-two sized regions.
+    if ram_pow2 {
+        let rounded = minimum_ram_size
+            .checked_next_power_of_two()
+            .ok_or_else(|| {
+                Elf2TabError::io_error(
+                    ErrorCategory::Size,
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "minimum RAM size of {} bytes cannot be rounded up to a power of two",
+                        minimum_ram_size
+                    ),
+                )
+            })?;
+        if verbose {
+            eprintln!(
+                "Min RAM size: {} bytes, rounded up to {} bytes",
+                minimum_ram_size, rounded
+            );
+        }
+        minimum_ram_size = rounded;
+    }
+
+    // Catch apps that cannot fit in the board's RAM before they are flashed.
+    if let Some(board_ram) = board_ram {
+        if minimum_ram_size > board_ram {
+            warnings.warn_config(
+                ErrorCategory::Size,
+                &format!(
+                    "App needs {} bytes of RAM, but the board only has {} bytes (--board-ram).",
+                    minimum_ram_size, board_ram
+                ),
+            )?;
+        }
+    }
 
     ////////////////////////////////////////////////////////////////////////////
     // Determine fixed addresses this app must be loaded at
@@ -314,27 +1264,43 @@ pub fn elf_to_tbf(
     // Do flash address.
 
     // Try to get the flash address via the `_flash_origin` symbol.
-    let flash_origin_address = if let Ok(Some((symtab, sym_strtab))) = elf_file.symbol_table() {
-        // We are looking for the `_flash_origin` symbol and its value. If it
-        // exists, this tells us the first address of flash when the app was
-        // compiled.
-        if let Some(flash_origin) = symtab.iter().find(|sym| {
-            let name = sym_strtab
-                .get(sym.st_name as usize)
-                .expect("Failed to parse symbol name");
-            name == "_flash_origin"
-        }) {
-            Some(flash_origin.st_value as u32)
-        } else {
-            None
-        }
-    } else {
-        None
-    };
+    // We are looking for the `_flash_origin` symbol and its value. If it
+    // exists, this tells us the first address of flash when the app was
+    // compiled.
+    let flash_origin_address =
+        find_symbol(&elf_file, "_flash_origin")?.map(|flash_origin| flash_origin.st_value as u32);
 
     // Figure out if this is a PIC app or not, and if we couldn't find the
-    // symbol then we estimate the address from segments.
-    if let Some(flash_origin) = flash_origin_address {
+    // symbol then we estimate the address from segments. An app that runs
+    // entirely from RAM has no flash address: the whole binary is copied to
+    // RAM and runs at the address of its lowest executable segment.
+    if ram_app {
+        let ram_base = elf_phdrs
+            .iter()
+            .filter(|segment| {
+                segment.p_type == elf::abi::PT_LOAD
+                    && segment.p_filesz > 0
+                    && (segment.p_flags & elf::abi::PF_X) > 0
+            })
+            .map(|segment| segment.p_vaddr)
+            .min()
+            .ok_or_else(|| {
+                elf_parse_error("RAM app has no loadable executable segments".to_string())
+            })?;
+        let ram_base = checked_u32(ram_base, "RAM load address")?;
+        if verbose {
+            eprintln!("RAM app runs from {:#x}.", ram_base);
+        }
+        fixed_address_ram = Some(ram_base);
+    } else if let Some(flash_address) = fixed_flash {
+        // The address from the command line replaces any detection, but the
+        // segments still have to start there.
+        if verbose {
+            eprintln!("Using fixed flash address {:#x}.", flash_address);
+        }
+        check_flash_segment_order(&elf_phdrs, flash_address)?;
+        fixed_address_flash = Some(flash_address);
+    } else if let Some(flash_origin) = flash_origin_address {
         if flash_origin == 0x80000000 {
             // Matches the PIC address.
             fixed_address_flash_pic = true;
@@ -366,7 +1332,7 @@ pub fn elf_to_tbf(
                     // address in flash that we are going to specify this app
                     // needs to be loaded at. To do this we compare this segment
                     // to any previous and keep track of the lowest address.
-                    let segment_start = segment.p_paddr as u32;
+                    let segment_start = checked_u32(segment.p_paddr, "flash segment address")?;
 
                     fixed_address_flash = match fixed_address_flash {
                         Some(prev_addr) => Some(cmp::min(segment_start, prev_addr)),
@@ -379,6 +1345,41 @@ pub fn elf_to_tbf(
                 }
             }
         }
+        // Offsets in the binary assume the segment we picked comes first.
+        if let (Some(flash_address), false) = (fixed_address_flash, fixed_address_flash_pic) {
+            check_flash_segment_order(&elf_phdrs, flash_address)?;
+        }
+    }
+
+    // The ELF type is a more direct signal than the flash address: a shared
+    // object (ET_DYN) is meant to be position independent. Tock PIC apps are
+    // commonly linked as ET_EXEC at the PIC address though, so only that case
+    // is merely noted in verbose output. A flash address from the command line
+    // is used as is.
+    let elf_type_pic = elf_file.ehdr.e_type == elf::abi::ET_DYN;
+    if let (true, Some(flash_address), false, None) = (
+        elf_type_pic,
+        fixed_address_flash,
+        fixed_address_flash_pic,
+        fixed_flash,
+    ) {
+        if pic_from_elf_type {
+            if verbose {
+                eprintln!("Treating ELF as position independent because it is ET_DYN.");
+            }
+            fixed_address_flash_pic = true;
+        } else {
+            warnings.warn(&format!(
+                "ELF is ET_DYN (position independent), but is linked for the fixed flash address {:#x}.\n\
+                 Use --pic-from-elf-type to treat it as position independent.",
+                flash_address
+            ))?;
+        }
+    } else if !elf_type_pic && fixed_address_flash_pic && verbose {
+        eprintln!(
+            "ELF is linked at the PIC flash address, but its type is {} instead of ET_DYN.",
+            elf::to_str::e_type_to_string(elf_file.ehdr.e_type)
+        );
     }
 
     // Use the flags to see if we got PIC sections, and clear any other fixed
@@ -387,18 +1388,19 @@ pub fn elf_to_tbf(
         fixed_address_flash = None;
     }
 
-    // Do RAM address.
+    // Do RAM address. An address from the command line replaces any detection,
+    // and a RAM app already has one.
     // Get the symbol table section if it exists.
-    if let Ok(Some((symtab, sym_strtab))) = elf_file.symbol_table() {
+    if let Some(ram_address) = fixed_ram {
+        if verbose {
+            eprintln!("Using fixed RAM address {:#x}.", ram_address);
+        }
+        fixed_address_ram = Some(ram_address);
+    } else if !ram_app {
         // We are looking for the `_sram_origin` symbol and its value.
         // If it exists, we try to use it. Otherwise, we just do not try
         // to find a fixed RAM address.
-        if let Some(sram_origin) = symtab.iter().find(|sym| {
-            let name = sym_strtab
-                .get(sym.st_name as usize)
-                .expect("Failed to parse symbol name");
-            name == "_sram_origin"
-        }) {
+        if let Some(sram_origin) = find_symbol(&elf_file, "_sram_origin")? {
             let sram_origin_address = sram_origin.st_value as u32;
             if sram_origin_address != 0x00000000 {
                 fixed_address_ram = Some(sram_origin_address);
@@ -413,8 +1415,12 @@ pub fn elf_to_tbf(
     // We need to reserve space for the writeable flash region information in
     // the header, so we need to know how many writeable flash regions are in
     // this app. Iterate the segments of the ELF file and then iterate sections
-    // within that segment to find sections with ".wfr" in the name.
+    // within that segment to find sections with ".wfr" in the name. If asked
+    // to, a region named `.wfr.<N>` also records the erase block size `N` of
+    // its flash. Kernels that do not know the extended TLV misparse it, so
+    // this is opt-in.
     let mut writeable_flash_regions_count: usize = 0;
+    let mut wfr_erase_sizes: Vec<Option<u32>> = Vec::new();
     for segment in &elf_phdrs {
         // Only consider segments which are set to be loaded.
         if segment.p_type != elf::abi::PT_LOAD || segment.p_filesz == 0 {
@@ -423,13 +1429,35 @@ pub fn elf_to_tbf(
 
         // We only want nonzero sections within a segment.
         for (sh_name, shdr) in elf_sections.iter() {
-            if shdr.sh_size > 0 && section_in_segment(shdr, segment) && sh_name.contains(".wfr") {
+            if shdr.sh_size > 0
+                && section_in_segment(shdr, segment)
+                && sh_name.contains(".wfr")
+                && !exclude_sections.contains(sh_name)
+            {
                 writeable_flash_regions_count += 1;
+                let erase_size = if record_wfr_erase_sizes {
+                    wfr_erase_size(sh_name)?
+                } else {
+                    None
+                };
+                if let Some(erase_size) = erase_size {
+                    if shdr.sh_size % erase_size as u64 != 0 {
+                        warnings.warn(&format!(
+                            "Writeable flash region {} is {} bytes, which is not a multiple of its erase size {}.",
+                            sh_name, shdr.sh_size, erase_size
+                        ))?;
+                    }
+                }
+                wfr_erase_sizes.push(erase_size);
             }
         }
     }
+    // A header-only TBF has no app binary for the regions to point into.
+    if header_only {
+        writeable_flash_regions_count = 0;
+    }
     if verbose {
-        println!(
+        eprintln!(
             "Number of writeable flash regions: {}",
             writeable_flash_regions_count
         );
@@ -438,7 +1466,11 @@ pub fn elf_to_tbf(
     // Additional debug information.
     if verbose {
         if let Some((major, minor)) = kernel_version {
-            println!("Kernel version: {}.{}", major, minor);
+            if kernel_version_no_upper {
+                eprintln!("Kernel version: >={}.{}", major, minor);
+            } else {
+                eprintln!("Kernel version: {}.{}", major, minor);
+            }
         }
     }
 
@@ -448,9 +1480,24 @@ pub fn elf_to_tbf(
 
     // Set the binary end offset here because it will cause a program header to
     // be inserted. This ensures the length calculations for the binary will be
-    // correct.
-    tbfheader.set_binary_end_offset(0);
+    // correct. Very old kernels do not understand the Program header, so it
+    // can be left out and only the Main header is used.
+    if !no_program_header {
+        tbfheader.set_binary_end_offset(0);
+    }
+    // Conversely, kernels that understand the Program header do not need the
+    // Main header.
+    if no_main_header {
+        tbfheader.remove_main_header();
+    }
     tbfheader.set_app_version(app_version);
+    tbfheader.set_checksum_algorithm(header_checksum);
+    tbfheader.set_endian(tbf_endian);
+    // Record which ELF this TBF came from. This hashes the input ELF, so it
+    // stays the same no matter how the TBF is signed or padded.
+    if embed_elf_hash {
+        tbfheader.set_elf_hash(Sha256::digest(&elf_file_buf).into());
+    }
 
     let header_length = tbfheader.create(
         minimum_ram_size,
@@ -459,11 +1506,81 @@ pub fn elf_to_tbf(
         fixed_address_ram,
         fixed_address_flash,
         permissions,
+        denied_permissions,
         storage_ids,
         kernel_version,
         short_id,
         disabled,
-    );
+        metadata_hash,
+        kernel_version_no_upper,
+        vendor_string,
+        ram_breakdown.then_some((stack_ram_size, heap_ram_size, segment_ram_size)),
+        security_version,
+        wfr_erase_sizes,
+    )?;
+    // Some flash programmers write the header separately and want it to have
+    // the same size no matter which TLVs it contains.
+    let header_length = match header_size {
+        Some(header_size) => tbfheader.pad_to(header_size as usize)?,
+        None => header_length,
+    };
+    // Forked kernels may expect a different version or flags in the base
+    // header. The checksum covers whatever ends up there.
+    if let Some(base_version) = base_version {
+        tbfheader.set_base_version(base_version);
+    }
+    if let Some(base_flags) = base_flags {
+        tbfheader.set_base_flags(base_flags);
+    }
+
+    // Advanced users can provide the entire TBF header through a `.tbfheader`
+    // section in the ELF. If requested, we use that section verbatim instead of
+    // the header we just created, and only fill in the checksum.
+    let raw_header: Option<Vec<u8>> = if raw_header {
+        elf_sections
+            .iter()
+            .find(|(sh_name, _)| sh_name == ".tbfheader")
+            .map(|(_, shdr)| {
+                elf_file
+                    .section_data(shdr)
+                    .map(|(data, _)| data.to_vec())
+                    .map_err(|e| {
+                        elf_parse_error(format!("Failed to read .tbfheader section: {}", e))
+                    })
+            })
+            .transpose()?
+    } else {
+        None
+    };
+    let header_length = if let Some(raw_header) = &raw_header {
+        // The header must at least contain the base header and its declared
+        // size must match the section.
+        let declared_header_size = if raw_header.len() >= mem::size_of::<u32>() * 4 {
+            u16::from_le_bytes([raw_header[2], raw_header[3]]) as usize
+        } else {
+            0
+        };
+        if declared_header_size != raw_header.len() || raw_header.len() % 4 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    ".tbfheader section is {} bytes but declares header_size = {}. \
+                     It must be a multiple of 4 bytes long and match header_size.",
+                    raw_header.len(),
+                    declared_header_size
+                ),
+            ));
+        }
+        if verbose {
+            eprintln!(
+                "Using .tbfheader section as the TBF header ({} bytes).",
+                raw_header.len()
+            );
+        }
+        raw_header.len()
+    } else {
+        header_length
+    };
 
     ////////////////////////////////////////////////////////////////////////////
     // Adjust the protected region size to make fixed address work
@@ -471,23 +1588,8 @@ pub fn elf_to_tbf(
 
     // Applications can hint a desired protected region size to elf2tab by
     // defining a special `tbf_protected_region_size` symbol:
-    let protected_region_size_symbol =
-        if let Ok(Some((symtab, sym_strtab))) = elf_file.symbol_table() {
-            // We are looking for the `tbf_protected_region_size` symbol and its
-            // value. If it exists, we can use it as a hint for the protected
-            // region size.
-            symtab
-                .iter()
-                .find(|sym| {
-                    let name = sym_strtab
-                        .get(sym.st_name as usize)
-                        .expect("Failed to parse symbol name");
-                    name == "tbf_protected_region_size"
-                })
-                .map(|tbf_header_sym| tbf_header_sym.st_value as u32)
-        } else {
-            None
-        };
+    let protected_region_size_symbol = find_symbol(&elf_file, "tbf_protected_region_size")?
+        .map(|tbf_header_sym| tbf_header_sym.st_value as u32);
 
     // Determine the protected region size by checking the following sources in
     // this order:
@@ -508,11 +1610,11 @@ pub fn elf_to_tbf(
             // If we have also been passed a fixed protected region size on the
             // command line, warn that the ELF symbol will take precedence!
             if protected_region_size_arg.is_some() {
-                println!(
+                warnings.warn(&format!(
                     "Overriding command-line specified protected_region_size \
-		 with tbf_protected_region_size symbol = {} bytes!",
+                     with tbf_protected_region_size symbol = {} bytes!",
                     fixed_protected_region_size
-                );
+                ))?;
             }
 
             fixed_protected_region_size
@@ -550,14 +1652,15 @@ pub fn elf_to_tbf(
             //
             // So, we put the start address of the TBF header at an alignment of
             // 256 if the application binary is at the expected address.
-            if !fixed_address_flash_pic {
+            if !fixed_address_flash_pic && !ram_app {
                 // Non-PIC case. As a reasonable guess we try to get our TBF
                 // start address to be at a 256 byte alignment.
                 let app_binary_address = fixed_address_flash.unwrap_or(0); // Already checked for `None`.
-                let tbf_start_address = util::align_down(app_binary_address, 256);
+                let tbf_start_address = util::align_down(app_binary_address, NON_PIC_TBF_ALIGNMENT);
                 app_binary_address - tbf_start_address
             } else {
-                // Normal PIC case, no need to insert extra protected region.
+                // Normal PIC case, or a RAM app that is not placed at a fixed
+                // flash address. No need to insert extra protected region.
                 header_length as u32
             }
         };
@@ -565,7 +1668,8 @@ pub fn elf_to_tbf(
     // Validate that the protected region size at the very least fits our TBF
     // headers:
     if protected_region_size < header_length as u32 {
-        return Err(io::Error::new(
+        return Err(Elf2TabError::io_error(
+            ErrorCategory::Size,
             io::ErrorKind::InvalidInput,
             format!(
                 "protected_region_size = {} is too small for the TBF headers. Header size: {}",
@@ -578,7 +1682,7 @@ pub fn elf_to_tbf(
     // such that Tock can set its memory protection accordingly:
     if protected_region_size > header_length as u32 {
         if verbose {
-            println!(
+            eprintln!(
                 "Inserting nonzero protected region trailer of length: {} \
 		 bytes, protected region size: {} bytes.",
                 protected_region_size - header_length as u32,
@@ -600,38 +1704,133 @@ pub fn elf_to_tbf(
     // are in creating the TBF binary.
     let mut binary_index = 0;
 
-    // Add in padding for the protected region size beyond the actual TBF header
-    // size and increment our index counter past the protected region.
-    binary.extend(vec![0; protected_region_size as usize - header_length]);
-    binary_index += protected_region_size as usize;
-
-    // The init function is where the app will start executing, defined as an
-    // offset from the end of protected region at the beginning of the app in
+    // Fill the protected region beyond the actual TBF header size, either with
+    // the requested contents or with zeros, and increment our index counter
+    // past the protected region.
+    let protected_trailer_len = protected_region_size as usize - header_length;
+    let protected_contents = match protected_contents {
+        Some(path) => fs::read(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read protected region contents {:?}: {}", path, e),
+            )
+        })?,
+        None => Vec::new(),
+    };
+    if protected_contents.len() > protected_trailer_len {
+        return Err(Elf2TabError::io_error(
+            ErrorCategory::Size,
+            io::ErrorKind::InvalidInput,
+            format!(
+                "protected region contents are {} bytes, but only {} bytes of the protected region follow the TBF header",
+                protected_contents.len(),
+                protected_trailer_len
+            ),
+        ));
+    }
+    if verbose && !protected_contents.is_empty() {
+        eprintln!(
+            "Filling {} of {} bytes of the protected region trailer with the given contents.",
+            protected_contents.len(),
+            protected_trailer_len
+        );
+    }
+    binary.extend(&protected_contents);
+    binary.extend(vec![0; protected_trailer_len - protected_contents.len()]);
+    binary_index += protected_region_size as usize;
+
+    // The init function is where the app will start executing, defined as an
+    // offset from the end of protected region at the beginning of the app in
     // flash. Typically the protected region only includes the TBF header. To
     // calculate the offset we need to find which section includes the entry
     // function and then determine its offset relative to the end of the
     // protected region.
     let mut init_fn_offset: Option<u32> = None;
 
-    // Need a place to put relocation data.
+    // Need a place to put relocation data. The entries are copied as is, so
+    // all relocation sections must be of the same type (REL or RELA).
     let mut relocation_binary: Vec<u8> = Vec::new();
+    let mut relocation_type: Option<(u32, &str)> = None;
 
     // Keep track of the end address of the last segment (once we have a first
     // segment). This allows us to insert padding between segments as necessary.
     let mut last_segment_address_end: Option<usize> = None;
+    // Keep track of the writeable flash regions so they can be checked once
+    // the end of the app binary is known.
+    let mut writeable_flash_regions: Vec<(String, usize, u64)> = Vec::new();
+    // Where function and object symbols end up in the TBF.
+    let mut symbol_offsets: Vec<(String, u64, usize)> = Vec::new();
+    let mut section_hashes: Vec<(String, usize, usize, [u8; 32])> = Vec::new();
+
+    // Segments of other ELFs merged into this TBF, in address order. They are
+    // placed by their flash address, so all ELFs must be compiled for fixed
+    // addresses and none of their segments can overlap.
+    let mut flattened: VecDeque<(u64, Vec<u8>)> = VecDeque::new();
+    let flash_start = fixed_address_flash.unwrap_or(0) as u64;
+    if !flatten.is_empty() {
+        if fixed_address_flash.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Flattening ELFs requires them to be compiled for a fixed flash address",
+            ));
+        }
+        let mut ranges: Vec<(u64, u64, String)> = elf_phdrs
+            .iter()
+            .filter(|segment| {
+                segment.p_type == elf::abi::PT_LOAD
+                    && segment.p_filesz > 0
+                    && segment.p_paddr + segment.p_filesz >= flash_start
+            })
+            .map(|segment| {
+                (
+                    cmp::max(segment.p_paddr, flash_start),
+                    segment.p_paddr + segment.p_filesz,
+                    "the first ELF".to_string(),
+                )
+            })
+            .collect();
+        for path in &flatten {
+            let segments = flatten_segments(path, elf_file.ehdr.e_machine, flash_start)?;
+            ranges.extend(segments.iter().map(|(address, content)| {
+                (
+                    *address,
+                    *address + content.len() as u64,
+                    format!("{:?}", path),
+                )
+            }));
+            flattened.extend(segments);
+        }
+        check_overlapping_segments(&mut ranges)?;
+        flattened
+            .make_contiguous()
+            .sort_by_key(|(address, _)| *address);
+    }
 
     // Iterate over ELF's Program Headers to assemble the binary image as a
     // contiguous memory block. Only take into consideration segments where
-    // filesz is greater than 0.
-    for segment in &mut elf_phdrs {
+    // filesz is greater than 0. Record whether each section with contents in
+    // a loadable segment ends up in the app binary as this is decided.
+    let mut section_decisions: Vec<(usize, String, bool)> = Vec::new();
+    let leave_out_segment = |section_decisions: &mut Vec<(usize, String, bool)>,
+                             segment_index: usize,
+                             segment: &elf::segment::ProgramHeader| {
+        for (sh_name, shdr) in &elf_sections {
+            if shdr.sh_size > 0 && section_in_segment(shdr, segment) {
+                section_decisions.push((segment_index, sh_name.clone(), false));
+            }
+        }
+    };
+    for (segment_index, segment) in elf_phdrs.iter_mut().enumerate() {
         // Only consider segments which are set to be loaded.
         if segment.p_type != elf::abi::PT_LOAD {
             continue;
         }
+        let original_segment = *segment;
 
         // Do not include segments with zero size, as these likely go in memory,
         // not flash.
         if segment.p_filesz == 0 {
+            leave_out_segment(&mut section_decisions, segment_index, &original_segment);
             continue;
         }
 
@@ -640,6 +1839,7 @@ pub fn elf_to_tbf(
         if let Some(flash_address) = fixed_address_flash {
             let flash_address: u64 = flash_address as u64;
             if segment.p_paddr + segment.p_filesz < flash_address {
+                leave_out_segment(&mut section_decisions, segment_index, &original_segment);
                 continue;
             }
         }
@@ -661,6 +1861,65 @@ pub fn elf_to_tbf(
             }
         }
 
+        // Remove any sections the user asked to exclude. Sections in the
+        // middle of the segment are cut out of the contents once they are
+        // read.
+        let mut excluded_ranges: Vec<(usize, usize)> = Vec::new();
+        let mut excluded_names: Vec<&str> = Vec::new();
+        for exclude_section in &exclude_sections {
+            let (_, shdr) = elf_sections
+                .iter()
+                .find(|(sh_name, _)| sh_name == exclude_section)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Section {} requested with --exclude-section does not exist",
+                            exclude_section
+                        ),
+                    )
+                })?;
+            match exclude_section_from_segment(shdr, segment) {
+                ExcludedSection::NotInSegment => {}
+                ExcludedSection::Trimmed => {
+                    if verbose {
+                        eprintln!("  Excluding section {}.", exclude_section);
+                    }
+                    excluded_names.push(exclude_section);
+                }
+                ExcludedSection::Middle(offset) => {
+                    warnings.warn(&format!(
+                        "Excluding section {} from the middle of a segment. Everything after it \
+                         in the segment moves, which may break code that uses absolute addresses.",
+                        exclude_section
+                    ))?;
+                    excluded_ranges.push((offset, shdr.sh_size as usize));
+                    excluded_names.push(exclude_section);
+                }
+            }
+        }
+        if segment.p_filesz == 0 {
+            leave_out_segment(&mut section_decisions, segment_index, &original_segment);
+            continue;
+        }
+
+        // Place any flattened segments that come before this one.
+        while flattened
+            .front()
+            .is_some_and(|(address, _)| *address < segment.p_paddr)
+        {
+            let (address, content) = flattened.pop_front().unwrap();
+            append_flattened_segment(
+                &mut binary,
+                &mut binary_index,
+                &mut last_segment_address_end,
+                flash_start as usize,
+                address,
+                content,
+                verbose,
+            );
+        }
+
         // Insert padding between segments if needed.
         if let Some(last_segment_address_end) = last_segment_address_end {
             // We have a previous segment. Now, check if there is any padding
@@ -670,16 +1929,22 @@ pub fn elf_to_tbf(
             if let Some(padding) = chk_padding {
                 if padding > 0 {
                     if verbose {
-                        println!("  Including padding between segments size={}", padding);
+                        eprintln!("  Including padding between segments size={}", padding);
                     }
 
-                    if padding >= 4096 {
+                    if padding >= padding_warn_threshold {
                         // Warn the user that we're inserting a large amount of
-                        // padding (>= 4096, which is the ELF file segment padding)
-                        // into the binary. This can be a sign of an incorrect /
-                        // broken ELF file (where not all LOADed non-zero sized
-                        // sections are marked to be loaded from flash).
-                        println!("  Warning! Inserting a large amount of padding.");
+                        // padding (by default >= 4096, which is the ELF file
+                        // segment padding) into the binary. This can be a sign
+                        // of an incorrect / broken ELF file (where not all
+                        // LOADed non-zero sized sections are marked to be
+                        // loaded from flash), or of a gap left by a
+                        // GNU_RELRO or debug section boundary.
+                        warnings.warn(&format!(
+                            "Inserting a large amount of padding ({0} bytes) \
+                             between segments ending at {1:#x} and starting at {2:#x}.",
+                            padding, last_segment_address_end, segment.p_paddr
+                        ))?;
                     }
 
                     // Insert the padding into the generated binary.
@@ -687,15 +1952,15 @@ pub fn elf_to_tbf(
                     binary_index += padding;
                 }
             } else {
-                println!(
-                    "  Warning! Expecting ELF sections to be in physical (load) address order."
-                );
-                println!("           Not inserting padding, the resulting TBF may be broken.");
+                warnings.warn(
+                    "Expecting ELF sections to be in physical (load) address order.\n\
+                     Not inserting padding, the resulting TBF may be broken.",
+                )?;
             }
         }
 
         if verbose {
-            println!(
+            eprintln!(
                 "  Adding segment. Offset: {0} ({0:#x}). Length: {1} ({1:#x}) bytes.",
                 binary_index, segment.p_filesz
             );
@@ -705,10 +1970,91 @@ pub fn elf_to_tbf(
         let mut content: Vec<u8> = vec![0; (segment.p_filesz) as usize];
         input_file
             .seek(SeekFrom::Start(segment.p_offset))
-            .expect("unable to seek input ELF file");
-        input_file
-            .read_exact(&mut content)
-            .expect("failed to read segment data");
+            .and_then(|_| input_file.read_exact(&mut content))
+            .map_err(|e| {
+                elf_parse_error(format!(
+                    "Failed to read segment {} from the ELF file: {}",
+                    segment_index, e
+                ))
+            })?;
+        // Cut out excluded sections, starting with the last one so the offsets
+        // of the others stay valid.
+        excluded_ranges.sort_unstable();
+        for (offset, length) in excluded_ranges.iter().rev() {
+            content.drain(*offset..*offset + *length);
+        }
+        // Sections cut off before the start of flash or excluded above are
+        // left out, as are sections without contents in the ELF.
+        for (sh_name, shdr) in &elf_sections {
+            if shdr.sh_size > 0 && section_in_segment(shdr, &original_segment) {
+                let included = shdr.sh_type != elf::abi::SHT_NOBITS
+                    && section_in_segment(shdr, segment)
+                    && !excluded_names.contains(&sh_name.as_str());
+                section_decisions.push((segment_index, sh_name.clone(), included));
+            }
+        }
+
+        // Find the symbols in this segment. Like the entry point, each is at
+        // its offset in the segment from where the segment is placed.
+        if report_symbol_offsets {
+            if let Ok(Some((symtab, sym_strtab))) = elf_file.symbol_table() {
+                for sym in symtab.iter() {
+                    if !matches!(sym.st_symtype(), elf::abi::STT_FUNC | elf::abi::STT_OBJECT)
+                        || sym.st_value < segment.p_vaddr
+                        || sym.st_value >= segment.p_vaddr + segment.p_filesz
+                    {
+                        continue;
+                    }
+                    // Symbols in excluded sections are not in the TBF, and the
+                    // ones after them move.
+                    let Some(offset) = offset_after_exclusions(
+                        (sym.st_value - segment.p_vaddr) as usize,
+                        &excluded_ranges,
+                    ) else {
+                        continue;
+                    };
+                    let name = sym_strtab.get(sym.st_name as usize).unwrap_or_default();
+                    symbol_offsets.push((name.to_string(), sym.st_value, binary_index + offset));
+                }
+            }
+        }
+
+        // Hash each section in this segment where it ends up in the TBF, so
+        // updates can be done per section.
+        if report_section_hashes {
+            for (sh_name, shdr) in &elf_sections {
+                if shdr.sh_size == 0
+                    || shdr.sh_type == elf::abi::SHT_NOBITS
+                    || !section_in_segment(shdr, segment)
+                    || shdr.sh_offset < segment.p_offset
+                    || shdr.sh_offset + shdr.sh_size > segment.p_offset + segment.p_filesz
+                {
+                    continue;
+                }
+                let start = (shdr.sh_offset - segment.p_offset) as usize;
+                let end = start + shdr.sh_size as usize;
+                // Excluded sections are not in the TBF, and the ones after
+                // them move.
+                if excluded_ranges
+                    .iter()
+                    .any(|(offset, length)| start < offset + length && *offset < end)
+                {
+                    continue;
+                }
+                let excluded: usize = excluded_ranges
+                    .iter()
+                    .filter(|(offset, _)| *offset < start)
+                    .map(|(_, length)| length)
+                    .sum();
+                let data = &content[start - excluded..end - excluded];
+                section_hashes.push((
+                    sh_name.clone(),
+                    binary_index + start - excluded,
+                    data.len(),
+                    Sha256::digest(data).into(),
+                ));
+            }
+        }
 
         let start_segment = segment.p_paddr;
         let end_segment = segment.p_paddr + segment.p_filesz;
@@ -722,17 +2068,46 @@ pub fn elf_to_tbf(
                 // this allows us to load them.
                 if disabled {
                     if verbose {
-                        println!("Duplicate entry point in Program Segments");
+                        eprintln!("Duplicate entry point in Program Segments");
                     }
+                } else if allow_duplicate_entry {
+                    warnings.warn(&format!(
+                        "Entry point {:#x} is in more than one segment, using the first one.",
+                        elf_file.ehdr.e_entry
+                    ))?;
                 } else {
-                    panic!("Duplicate entry point in Program Segments");
+                    return Err(elf_parse_error(
+                        "Duplicate entry point in Program Segments".to_string(),
+                    ));
                 }
             } else {
-                // Get the position of the entry point in the segment.
-                let entry_offset = (elf_file.ehdr.e_entry - start_segment) as usize;
+                // A linker bug can put the entry point in a segment that is
+                // not executable, and the app would fault immediately.
+                if segment.p_flags & elf::abi::PF_X == 0 {
+                    let msg = format!(
+                        "Entry point {:#x} is in segment {}, which is not executable.",
+                        elf_file.ehdr.e_entry, segment_index
+                    );
+                    warnings.warn_config(ErrorCategory::ElfParse, &msg)?;
+                }
+                // Get the position of the entry point in the segment, once
+                // any excluded sections before it are cut out.
+                let entry_offset = offset_after_exclusions(
+                    (elf_file.ehdr.e_entry - start_segment) as usize,
+                    &excluded_ranges,
+                )
+                .ok_or_else(|| {
+                    elf_parse_error(format!(
+                        "Entry point {:#x} is in a section excluded with --exclude-section",
+                        elf_file.ehdr.e_entry
+                    ))
+                })?;
                 // `init_fn_offset` is the offset from the end of the TBF header
                 // to the entry point within the application binary.
-                let tbf_entry_offset = (binary_index + entry_offset - header_length) as u32;
+                let tbf_entry_offset = checked_u32(
+                    binary_index + entry_offset - header_length,
+                    "entry point offset",
+                )?;
                 // Set the init_fn in the header.
                 tbfheader.set_init_fn_offset(tbf_entry_offset);
                 // Save it in case we find multiple entry points.
@@ -740,15 +2115,18 @@ pub fn elf_to_tbf(
             }
         }
 
+        // Make sure the sections we are about to look at are sane.
+        check_overlapping_sections(&elf_sections, segment)?;
+
         // Iterate all sections that are in the segment we just loaded.
         //
         // We need two things:
         // 1. To find all relevant relocation data we need to add.
         // 2. To find if there are any writeable flash regions we need to set in
         //    the TBF header.
-        for (sh_name, shdr) in elf_sections.iter() {
-            // Skip zero size sections.
-            if shdr.sh_size == 0 {
+        for (section_index, (sh_name, shdr)) in elf_sections.iter().enumerate() {
+            // Skip zero size sections, and sections that are not in the TBF.
+            if shdr.sh_size == 0 || excluded_names.contains(&sh_name.as_str()) {
                 continue;
             }
 
@@ -756,10 +2134,15 @@ pub fn elf_to_tbf(
             if section_in_segment(shdr, segment) {
                 // This section is in this segment.
                 if verbose {
-                    println!(
+                    let offset = offset_after_exclusions(
+                        (shdr.sh_offset - segment.p_offset) as usize,
+                        &excluded_ranges,
+                    )
+                    .unwrap_or_default();
+                    eprintln!(
                         "    Contains section {0}. Offset: {1} ({1:#x}). Length: {2} ({2:#x}) bytes.",
                         sh_name,
-                        binary_index + (shdr.sh_offset - segment.p_offset) as usize,
+                        binary_index + offset,
                         shdr.sh_size
                     );
                 }
@@ -769,29 +2152,38 @@ pub fn elf_to_tbf(
                 // use the relocations at runtime requires being able to update
                 // the contents of the section.
                 if shdr.sh_flags as u32 & elf::abi::SHF_WRITE > 0 {
-                    // Then check if there is a ".rel.<section name>" section
-                    // that we need to include in the relocation data.
-
-                    // relocation_section_name = ".rel" + section_name
-                    let mut relocation_section_name: String = ".rel".to_owned();
-                    relocation_section_name.push_str(sh_name);
-
-                    // Get the contents of the relocation data if it exists and
-                    // add that data to a buffer of relocation data.
-                    let rel_data = elf_sections
-                        .iter()
-                        .find(|(sh_name, _)| *sh_name == relocation_section_name)
-                        .map_or(&[] as &[u8], |(_, shdr)| {
-                            elf_file.section_data(shdr).map_or(&[], |(data, _)| data)
-                        });
-                    relocation_binary.extend(rel_data);
-
-                    if verbose && !rel_data.is_empty() {
-                        println!(
-                            "      Including relocation data ({0}). Length: {1} ({1:#x}) bytes.",
-                            relocation_section_name,
-                            rel_data.len(),
-                        );
+                    // Then check if there is a REL or RELA section for this
+                    // section that we need to include in the relocation data.
+                    if let Some((relocation_section_name, rel_shdr)) =
+                        find_relocation_section(&elf_sections, section_index)
+                    {
+                        match relocation_type {
+                            Some((sh_type, first_name)) if sh_type != rel_shdr.sh_type => {
+                                return Err(elf_parse_error(format!(
+                                    "Relocation sections {} and {} have different types, cannot mix REL and RELA relocations",
+                                    first_name, relocation_section_name
+                                )));
+                            }
+                            Some(_) => {}
+                            None => {
+                                relocation_type = Some((rel_shdr.sh_type, relocation_section_name))
+                            }
+                        }
+
+                        // Get the contents of the relocation data and add that
+                        // data to a buffer of relocation data.
+                        let rel_data = elf_file
+                            .section_data(rel_shdr)
+                            .map_or(&[] as &[u8], |(data, _)| data);
+                        relocation_binary.extend(rel_data);
+
+                        if verbose && !rel_data.is_empty() {
+                            eprintln!(
+                                "      Including relocation data ({0}). Length: {1} ({1:#x}) bytes.",
+                                relocation_section_name,
+                                rel_data.len(),
+                            );
+                        }
                     }
                 }
 
@@ -799,16 +2191,23 @@ pub fn elf_to_tbf(
                 // include its details in the TBF header.
                 if sh_name.contains(".wfr") {
                     // Calculate where this .wfr section is in the segment.
-                    let wfr_offset = (shdr.sh_addr - segment.p_vaddr) as usize;
+                    // Excluded sections are skipped above, so this is not in
+                    // one of the excluded ranges.
+                    let wfr_offset = offset_after_exclusions(
+                        (shdr.sh_addr - segment.p_vaddr) as usize,
+                        &excluded_ranges,
+                    )
+                    .unwrap_or_default();
                     // Calculate the position of the writeable flash region in
                     // the TBF binary.
                     let wfr_position = binary_index + wfr_offset;
 
                     // Use these values to update the TBF header.
                     tbfheader.set_writeable_flash_region_values(
-                        wfr_position as u32,
-                        shdr.sh_size as u32,
+                        checked_u32(wfr_position, "writeable flash region offset")?,
+                        checked_u32(shdr.sh_size, "writeable flash region size")?,
                     );
+                    writeable_flash_regions.push((sh_name.clone(), wfr_position, shdr.sh_size));
                 }
             }
         }
@@ -817,77 +2216,281 @@ pub fn elf_to_tbf(
         // between segments.
         last_segment_address_end = Some(end_segment as usize);
 
+        binary_index += content.len();
         binary.extend(content);
-        binary_index += segment.p_filesz as usize;
+    }
+    for (address, content) in flattened {
+        append_flattened_segment(
+            &mut binary,
+            &mut binary_index,
+            &mut last_segment_address_end,
+            flash_start as usize,
+            address,
+            content,
+            verbose,
+        );
+    }
+
+    // Some cores fault if the entry point is not aligned, so check the address
+    // the app will actually start at. Without a fixed flash address we can only
+    // check the offset in the TBF, assuming the TBF itself is aligned.
+    let entry_align = entry_align.or(match elf_file.ehdr.e_machine {
+        elf::abi::EM_ARM => Some(2),
+        elf::abi::EM_RISCV => Some(4),
+        _ => None,
+    });
+    if let (Some(entry_align), Some(init_fn_offset)) = (entry_align, init_fn_offset) {
+        let tbf_entry_offset = init_fn_offset + header_length as u32;
+        let entry_address = match fixed_address_flash {
+            Some(flash_address) => flash_address + tbf_entry_offset - protected_region_size,
+            None => tbf_entry_offset,
+        };
+        // The lowest bit of Thumb entry points selects the Thumb instruction
+        // set and is not part of the address.
+        let entry_address = if elf_file.ehdr.e_machine == elf::abi::EM_ARM {
+            entry_address & !1
+        } else {
+            entry_address
+        };
+        if entry_address % entry_align != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Entry point at {:#x} is not aligned to {} bytes",
+                    entry_address, entry_align
+                ),
+            ));
+        }
+    }
+
+    // Force-include any requested sections after the segments, even if they
+    // are not part of a loadable segment.
+    for include_section in &include_sections {
+        let (_, shdr) = elf_sections
+            .iter()
+            .find(|(sh_name, _)| sh_name == include_section)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Section {} requested with --include-section does not exist",
+                        include_section
+                    ),
+                )
+            })?;
+        let section_data = elf_file
+            .section_data(shdr)
+            .map_or(&[] as &[u8], |(data, _)| data);
+        if verbose {
+            eprintln!(
+                "Including section {0}. Offset: {1} ({1:#x}). Length: {2} ({2:#x}) bytes.",
+                include_section,
+                binary_index,
+                section_data.len()
+            );
+        }
+        if report_section_hashes {
+            section_hashes.push((
+                include_section.clone(),
+                binary_index,
+                section_data.len(),
+                Sha256::digest(section_data).into(),
+            ));
+        }
+        binary.extend(section_data);
+        binary_index += section_data.len();
+    }
+
+    // For a header-only TBF, drop everything after the protected region. The
+    // entry point is the (empty) app binary.
+    if header_only {
+        if verbose {
+            eprintln!(
+                "Leaving out the {} byte app binary for a header-only TBF.",
+                binary_index - protected_region_size as usize
+            );
+        }
+        binary.truncate(protected_trailer_len);
+        binary_index = protected_region_size as usize;
+        relocation_binary.clear();
+        writeable_flash_regions.clear();
+        symbol_offsets.clear();
+        section_hashes.clear();
+        for (_, _, included) in &mut section_decisions {
+            *included = false;
+        }
+        tbfheader.set_init_fn_offset(protected_region_size - header_length as u32);
     }
 
     // Now that we know where the end of the section data is, we can check for
     // alignment.
-    if !relocation_binary.is_empty() && amount_alignment_needed(binary_index as u32, 4) != 0 {
-        println!(
-            "Warning! Placing relocation data at {:#x}, which is not 4-byte aligned.",
+    if !relocation_binary.is_empty()
+        && amount_alignment_needed(checked_u32(binary_index, "app binary size")?, 4) != 0
+    {
+        warnings.warn(&format!(
+            "Placing relocation data at {:#x}, which is not 4-byte aligned.",
             binary_index
-        );
+        ))?;
     }
 
     // Add 4 bytes for the relocation data length and the size of the relocation
-    // data to our total length.
-    binary_index += mem::size_of::<u32>() + relocation_binary.len();
+    // data to our total length. Without relocation data the length can be
+    // left out for kernels that do not expect it.
+    let relocation_length_word =
+        !((omit_empty_relocation || header_only) && relocation_binary.is_empty());
+    if verbose && !relocation_length_word {
+        eprintln!("Leaving out the relocation data length since there is no relocation data.");
+    }
+    if relocation_length_word {
+        binary_index += mem::size_of::<u32>();
+    }
+    binary_index += relocation_binary.len();
+
+    // Optionally append raw data (for example, a configuration table) after
+    // the relocation data. This is still part of the app binary, so it is
+    // covered by any integrity credentials.
+    let appended_binary = match append_binary {
+        Some(path) => fs::read(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read appended binary {:?}: {}", path, e),
+            )
+        })?,
+        None => Vec::new(),
+    };
+    if verbose && !appended_binary.is_empty() {
+        eprintln!(
+            "Appending binary data at offset {0} ({0:#x}). Length: {1} ({1:#x}) bytes.",
+            binary_index,
+            appended_binary.len()
+        );
+    }
+    binary_index += appended_binary.len();
+
+    // If requested, pad the end of the app binary so that the footer starts
+    // at an aligned offset. This padding is part of the app binary and is
+    // therefore covered by any integrity credentials.
+    let footer_align_pad = match footer_align {
+        Some(align) => {
+            amount_alignment_needed(checked_u32(binary_index, "app binary size")?, align) as usize
+        }
+        None => 0,
+    };
+    if verbose && footer_align_pad > 0 {
+        eprintln!(
+            "Padding app binary by {} bytes to align the footer.",
+            footer_align_pad
+        );
+    }
+    binary_index += footer_align_pad;
 
     ////////////////////////////////////////////////////////////////////////////
     // Create the TBF footer
     ////////////////////////////////////////////////////////////////////////////
 
     // Next up is the footer. Since we know where the footer starts, we can
-    // record that now. Also insert app version number.
-    tbfheader.set_binary_end_offset(binary_index as u32);
+    // record that now. Also insert app version number. Without a Program
+    // header the footer start is not recorded anywhere and kernels treat the
+    // footer space as part of the app binary.
+    let binary_end_offset = binary_index;
+    check_writeable_flash_regions(
+        &writeable_flash_regions,
+        protected_region_size as usize,
+        binary_end_offset,
+    )?;
+    if !no_program_header {
+        tbfheader.set_binary_end_offset(checked_u32(binary_end_offset, "binary end offset")?);
+    }
     tbfheader.set_app_version(app_version);
+    if let Some(main_ram_override) = main_ram_override {
+        // Only for testing kernels: let the Main header disagree with the
+        // Program header about how much RAM the app needs.
+        tbfheader.set_main_minimum_ram_size(main_ram_override);
+    }
+
+    // Credentials normally cover everything up to the footer. If they only
+    // cover the start of the TBF, each credential ends with the covered length
+    // so the kernel knows what to check.
+    let integrity_end = match integrity_end {
+        Some(end) if (end as usize) < header_length || end as usize > binary_end_offset => {
+            return Err(Elf2TabError::io_error(
+                ErrorCategory::Size,
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "integrity end {:#x} is not between the end of the TBF header ({:#x}) and the end of the app binary ({:#x})",
+                    end, header_length, binary_end_offset
+                ),
+            ));
+        }
+        Some(end) => end as usize,
+        None => binary_end_offset,
+    };
+    let covered_length_field: Vec<u8> = if integrity_end != binary_end_offset {
+        checked_u32(integrity_end, "integrity end")?
+            .to_le_bytes()
+            .to_vec()
+    } else {
+        Vec::new()
+    };
 
     // Process optional footers
     if sha256 {
         binary_index += mem::size_of::<header::TbfHeaderTlv>();
         binary_index += mem::size_of::<header::TbfFooterCredentialsType>();
         binary_index += 32; // SHA256 is 32 bytes long
+        binary_index += covered_length_field.len();
     }
 
     if sha384 {
         binary_index += mem::size_of::<header::TbfHeaderTlv>();
         binary_index += mem::size_of::<header::TbfFooterCredentialsType>();
         binary_index += 48; // SHA384 is 48 bytes long
+        binary_index += covered_length_field.len();
     }
 
     if sha512 {
         binary_index += mem::size_of::<header::TbfHeaderTlv>();
         binary_index += mem::size_of::<header::TbfFooterCredentialsType>();
         binary_index += 64; // SHA512 is 64 bytes long
+        binary_index += covered_length_field.len();
+    }
+
+    if sha3_256 {
+        binary_index += mem::size_of::<header::TbfHeaderTlv>();
+        binary_index += mem::size_of::<header::TbfFooterCredentialsType>();
+        binary_index += 32; // SHA3-256 is 32 bytes long
+        binary_index += covered_length_field.len();
+    }
+
+    if sha256_binary {
+        binary_index += mem::size_of::<header::TbfHeaderTlv>();
+        binary_index += mem::size_of::<header::TbfFooterCredentialsType>();
+        binary_index += 32; // SHA256 is 32 bytes long
     }
 
-    if rsa4096_private_key.is_some() {
+    if rsa4096_private_key.is_some() || pkcs11_uri.is_some() {
         binary_index += mem::size_of::<header::TbfHeaderTlv>();
         binary_index += mem::size_of::<header::TbfFooterCredentialsType>();
         binary_index += 1024;
+        binary_index += covered_length_field.len();
     }
 
-    let footers_initial_len = binary_index - tbfheader.binary_end_offset() as usize;
+    if let Some((_, credential_type)) = &credential_command {
+        binary_index += mem::size_of::<header::TbfHeaderTlv>();
+        binary_index += mem::size_of::<header::TbfFooterCredentialsType>();
+        binary_index += credential_type.data_length();
+        binary_index += covered_length_field.len();
+    }
+
+    let footers_initial_len = binary_index - binary_end_offset;
 
     // Flag to track if we are guaranteed to have a reserved space footer.
     let mut ensured_footer_reserved_space: bool = false;
 
     // Make sure the footer is at least the minimum requested size.
-    if (minimum_footer_size as usize) > footers_initial_len {
-        let mut needed_footer_reserved_space = (minimum_footer_size as usize) - footers_initial_len;
-
-        // We can only add reserved space to the footer with a minimum of 8
-        // bytes.
-        needed_footer_reserved_space = cmp::max(
-            needed_footer_reserved_space,
-            mem::size_of::<header::TbfHeaderTlv>()
-                + mem::size_of::<header::TbfFooterCredentialsType>(),
-        );
-        // We also must ensure that if there were to be a TLV after the
-        // reserved TLV that it would start at a 4 byte alignment.
-        needed_footer_reserved_space = align_to(needed_footer_reserved_space as u32, 4) as usize;
-
+    let needed_footer_reserved_space =
+        footer_reserved_space(footers_initial_len, minimum_footer_size as usize);
+    if needed_footer_reserved_space > 0 {
         // Add reserved space to the footer.
         binary_index += needed_footer_reserved_space;
 
@@ -896,12 +2499,50 @@ pub fn elf_to_tbf(
         ensured_footer_reserved_space = true;
     }
 
+    // Reserved space cannot always make the footer exactly the requested
+    // minimum size, so explain the layout when it ends up larger.
+    let footer_len = footers_initial_len + needed_footer_reserved_space;
+    if needed_footer_reserved_space > 0 && footer_len != minimum_footer_size as usize {
+        warnings.warn(&format!(
+            "Footer is {} bytes ({} bytes of credentials + {} bytes reserved), not the {} bytes requested by --minimum-footer-size.\n\
+             Reserved space is at least {} bytes and a multiple of 4 bytes.",
+            footer_len,
+            footers_initial_len,
+            needed_footer_reserved_space,
+            minimum_footer_size,
+            mem::size_of::<header::TbfHeaderTlv>()
+                + mem::size_of::<header::TbfFooterCredentialsType>()
+        ))?;
+    }
+    if verbose {
+        eprintln!(
+            "Footer: {} bytes of credentials + {} bytes reserved",
+            footers_initial_len, needed_footer_reserved_space
+        );
+    }
+
     // Optionally calculate the additional padding needed to ensure the app size
     // meets the padding requirements.
     //
     // This will be largely covered with a footer reservation. The
     // `post_content_pad` is any additional space that cannot be handled by
     // reserved space in the footer.
+    let unpadded_size = checked_u32(binary_index, "app binary size")?;
+    // Non-PIC apps placed one after another only start at an aligned address
+    // if the apps before them have an aligned size. Unless a padding mode was
+    // given, grow the architecture's default multiple to that alignment.
+    let trailing_padding = match trailing_padding {
+        Some(TrailingPadding::TotalSizeMultiple(multiple))
+            if fixed_address_flash.is_some()
+                && padding_mode.is_none()
+                && (NON_PIC_TBF_ALIGNMENT as usize).is_multiple_of(multiple) =>
+        {
+            Some(TrailingPadding::TotalSizeMultiple(
+                NON_PIC_TBF_ALIGNMENT as usize,
+            ))
+        }
+        trailing_padding => trailing_padding,
+    };
     let post_content_pad = trailing_padding.map_or(0, |padding_type| {
         // Calculate how many additional bytes we need to add to meet length
         // requirement.
@@ -910,8 +2551,7 @@ pub fn elf_to_tbf(
                 // Pad binary to the next power of two, but not less than 512
                 // bytes.
                 if binary_index.count_ones() > 1 {
-                    let power2len =
-                        cmp::max(1 << (32 - (binary_index as u32).leading_zeros()), 512);
+                    let power2len = cmp::max(1 << (32 - unpadded_size.leading_zeros()), 512);
                     power2len - binary_index
                 } else {
                     0
@@ -920,6 +2560,7 @@ pub fn elf_to_tbf(
             TrailingPadding::TotalSizeMultiple(multiple) => {
                 (multiple - (binary_index % multiple)) % multiple
             }
+            TrailingPadding::Disabled => 0,
         };
 
         // Increment to include the padding.
@@ -939,37 +2580,126 @@ pub fn elf_to_tbf(
     });
 
     let total_size = binary_index;
+    let sizes = TbfSizes {
+        binary_size: binary_end_offset,
+        protected_overhead: protected_region_size as usize - header_length,
+        footer_size: footer_len,
+        total_size,
+    };
+    if verbose {
+        eprintln!(
+            "Flash: {} bytes of binary + {} bytes of footer = {} bytes required, {} bytes after padding",
+            sizes.binary_size,
+            sizes.footer_size,
+            sizes.required_size(),
+            sizes.total_size
+        );
+        if sizes.protected_overhead > 0 {
+            eprintln!(
+                "Protected region overhead: {} bytes",
+                sizes.protected_overhead
+            );
+        }
+    }
+
+    // The architecture has no default padding that keeps the next non-PIC app
+    // aligned. A padding mode that was asked for is left alone.
+    if fixed_address_flash.is_some()
+        && padding_mode.is_none()
+        && !no_trailing_padding
+        && total_size % NON_PIC_TBF_ALIGNMENT as usize != 0
+    {
+        warnings.warn(&format!(
+            "TBF size {} ({:#x}) is not a multiple of {} bytes. An app placed after this one in flash will not be aligned.\n\
+             Use --padding-mode multiple:{} to pad the TBF to an aligned size.",
+            total_size, total_size, NON_PIC_TBF_ALIGNMENT, NON_PIC_TBF_ALIGNMENT
+        ))?;
+    }
 
     // Now set the total size of the app in the header.
-    tbfheader.set_total_size(total_size as u32);
+    tbfheader.set_total_size(checked_u32(total_size, "TBF total size")?);
 
-    if verbose {
-        print!("{}", tbfheader);
-    }
+    // Credentials hash the part of the TBF covered by integrity, so in that
+    // case build it in memory first. Otherwise stream it straight to `output`
+    // to avoid holding a second copy of the app.
+    let hash_credentials = sha256
+        || sha384
+        || sha512
+        || sha3_256
+        || sha256_binary
+        || rsa4096_private_key.is_some()
+        || pkcs11_uri.is_some()
+        || credential_command.is_some();
+    let mut covered: Vec<u8> = Vec::new();
+    let covered_output: &mut dyn io::Write = if hash_credentials {
+        &mut covered
+    } else {
+        &mut *output
+    };
 
     // Write the header and actual app to a binary file.
-    output.write_all(tbfheader.generate().unwrap().get_ref())?;
-    output.write_all(binary.as_ref())?;
+    if let Some(mut raw_header) = raw_header {
+        // The header from the ELF must describe the TBF we actually created.
+        let declared_total_size =
+            u32::from_le_bytes([raw_header[4], raw_header[5], raw_header[6], raw_header[7]]);
+        if declared_total_size as usize != total_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    ".tbfheader section declares total_size = {} but the TBF is {} bytes",
+                    declared_total_size, total_size
+                ),
+            ));
+        }
+
+        // Clear any existing checksum so we can compute it again.
+        raw_header[12..16].fill(0);
+        let raw_header =
+            header::TbfHeader::inject_checksum(io::Cursor::new(raw_header), header_checksum)?;
+        covered_output.write_all(raw_header.get_ref())?;
+    } else {
+        if verbose {
+            eprint!("{}", tbfheader);
+        }
 
-    let rel_data_len: [u8; 4] = (relocation_binary.len() as u32).to_le_bytes();
-    output.write_all(&rel_data_len)?;
-    output.write_all(relocation_binary.as_ref())?;
+        covered_output.write_all(tbfheader.generate().unwrap().get_ref())?;
+    }
+    covered_output.write_all(binary.as_ref())?;
+
+    if relocation_length_word {
+        // The app reads the length itself, so it has the app's byte order
+        // like the relocation entries copied from the ELF.
+        let rel_data_len = util::target_u32_bytes(
+            checked_u32(relocation_binary.len(), "relocation data size")?,
+            elf_file.ehdr.endianness,
+        );
+        covered_output.write_all(&rel_data_len)?;
+    }
+    covered_output.write_all(relocation_binary.as_ref())?;
+    covered_output.write_all(appended_binary.as_ref())?;
+    util::do_pad(covered_output, footer_align_pad)?;
+    output.write_all(&covered)?;
 
     // That is everything that we are going to include in the app binary
     // that is covered by integrity. Now add footers.
+    let integrity_covered = &covered[..cmp::min(integrity_end, covered.len())];
 
-    let footers_len = total_size - tbfheader.binary_end_offset() as usize;
+    let footers_len = total_size - binary_end_offset;
     let mut footer_space_remaining = footers_len;
+    // Keep track of what the footer is made of for verbose output.
+    let mut footer_components: Vec<(&str, usize)> = Vec::new();
+    let mut credential_names: Vec<&'static str> = Vec::new();
     if sha256 {
         // Total length
         let sha256_len = mem::size_of::<header::TbfHeaderTlv>()
             + mem::size_of::<header::TbfFooterCredentialsType>()
-            + 32; // SHA256 is 32 bytes long
-                  // Length in the TLV field
+            + 32 // SHA256 is 32 bytes long
+            + covered_length_field.len();
+        // Length in the TLV field
         let sha256_tlv_len = sha256_len - mem::size_of::<header::TbfHeaderTlv>();
 
         let mut hasher = Sha256::new();
-        hasher.update(&output[0..tbfheader.binary_end_offset() as usize]);
+        hasher.update(integrity_covered);
         let result = hasher.finalize();
         let sha_credentials = header::TbfFooterCredentials {
             base: header::TbfHeaderTlv {
@@ -977,12 +2707,14 @@ pub fn elf_to_tbf(
                 length: sha256_tlv_len as u16,
             },
             format: header::TbfFooterCredentialsType::SHA256,
-            data: result.to_vec(),
+            data: [result.as_slice(), &covered_length_field].concat(),
         };
         output.write_all(sha_credentials.generate().unwrap().get_ref())?;
         footer_space_remaining -= sha256_len;
+        footer_components.push(("SHA256 credential", sha256_len));
+        credential_names.push("sha256");
         if verbose {
-            println!("Added SHA256 credential.");
+            eprintln!("Added SHA256 credential.");
         }
     }
 
@@ -990,12 +2722,13 @@ pub fn elf_to_tbf(
         // Total length
         let sha384_len = mem::size_of::<header::TbfHeaderTlv>()
             + mem::size_of::<header::TbfFooterCredentialsType>()
-            + 48; // SHA384 is 48 bytes long
-                  // Length in the TLV field
+            + 48 // SHA384 is 48 bytes long
+            + covered_length_field.len();
+        // Length in the TLV field
         let sha384_tlv_len = sha384_len - mem::size_of::<header::TbfHeaderTlv>();
 
         let mut hasher = Sha384::new();
-        hasher.update(&output[0..tbfheader.binary_end_offset() as usize]);
+        hasher.update(integrity_covered);
         let result = hasher.finalize();
         let sha_credentials = header::TbfFooterCredentials {
             base: header::TbfHeaderTlv {
@@ -1003,12 +2736,14 @@ pub fn elf_to_tbf(
                 length: sha384_tlv_len as u16,
             },
             format: header::TbfFooterCredentialsType::SHA384,
-            data: result.to_vec(),
+            data: [result.as_slice(), &covered_length_field].concat(),
         };
         output.write_all(sha_credentials.generate().unwrap().get_ref())?;
         footer_space_remaining -= sha384_len;
+        footer_components.push(("SHA384 credential", sha384_len));
+        credential_names.push("sha384");
         if verbose {
-            println!("Added SHA384 credential.");
+            eprintln!("Added SHA384 credential.");
         }
     }
 
@@ -1016,12 +2751,13 @@ pub fn elf_to_tbf(
         // Total length
         let sha512_len = mem::size_of::<header::TbfHeaderTlv>()
             + mem::size_of::<header::TbfFooterCredentialsType>()
-            + 64; // SHA512 is 64 bytes long
-                  // Length in the TLV field
+            + 64 // SHA512 is 64 bytes long
+            + covered_length_field.len();
+        // Length in the TLV field
         let sha512_tlv_len = sha512_len - mem::size_of::<header::TbfHeaderTlv>();
 
         let mut hasher = Sha512::new();
-        hasher.update(&output[0..tbfheader.binary_end_offset() as usize]);
+        hasher.update(integrity_covered);
         let result = hasher.finalize();
         let sha_credentials = header::TbfFooterCredentials {
             base: header::TbfHeaderTlv {
@@ -1029,35 +2765,82 @@ pub fn elf_to_tbf(
                 length: sha512_tlv_len as u16,
             },
             format: header::TbfFooterCredentialsType::SHA512,
-            data: result.to_vec(),
+            data: [result.as_slice(), &covered_length_field].concat(),
         };
         output.write_all(sha_credentials.generate().unwrap().get_ref())?;
         footer_space_remaining -= sha512_len;
+        footer_components.push(("SHA512 credential", sha512_len));
+        credential_names.push("sha512");
         if verbose {
-            println!("Added SHA512 credential.");
+            eprintln!("Added SHA512 credential.");
         }
     }
 
-    if rsa4096_private_key.is_some() {
-        let rsa4096_len = mem::size_of::<header::TbfHeaderTlv>()
+    if sha3_256 {
+        // Total length
+        let sha3_256_len = mem::size_of::<header::TbfHeaderTlv>()
             + mem::size_of::<header::TbfFooterCredentialsType>()
-            + 1024; // Signature + key is 1024 bytes long
-                    // Length in the TLV field
-        let rsa4096_tlv_len = rsa4096_len - mem::size_of::<header::TbfHeaderTlv>();
+            + 32 // SHA3-256 is 32 bytes long
+            + covered_length_field.len();
+        // Length in the TLV field
+        let sha3_256_tlv_len = sha3_256_len - mem::size_of::<header::TbfHeaderTlv>();
 
-        let private_key_path_str = rsa4096_private_key.unwrap();
-        let private_key_path = Path::new(&private_key_path_str);
-        let private_key_contents = read_rsa_file(private_key_path).unwrap_or_else(|e| {
-            panic!(
-                "Failed to read private key from {:?}: {:?}",
-                private_key_path, e
-            );
-        });
+        let mut hasher = Sha3_256::new();
+        hasher.update(integrity_covered);
+        let result = hasher.finalize();
+        let sha_credentials = header::TbfFooterCredentials {
+            base: header::TbfHeaderTlv {
+                tipe: header::TbfHeaderTypes::Credentials,
+                length: sha3_256_tlv_len as u16,
+            },
+            format: header::TbfFooterCredentialsType::SHA3_256,
+            data: [result.as_slice(), &covered_length_field].concat(),
+        };
+        output.write_all(sha_credentials.generate().unwrap().get_ref())?;
+        footer_space_remaining -= sha3_256_len;
+        footer_components.push(("SHA3-256 credential", sha3_256_len));
+        credential_names.push("sha3-256");
+        if verbose {
+            eprintln!("Added SHA3-256 credential.");
+        }
+    }
+
+    if sha256_binary {
+        // Only the app binary, so TBFs with the same code but different
+        // headers get the same hash.
+        let sha256_binary_len = mem::size_of::<header::TbfHeaderTlv>()
+            + mem::size_of::<header::TbfFooterCredentialsType>()
+            + 32;
+        // Length in the TLV field
+        let sha256_binary_tlv_len = sha256_binary_len - mem::size_of::<header::TbfHeaderTlv>();
+
+        let mut hasher = Sha256::new();
+        hasher.update(&covered[protected_region_size as usize..]);
+        let result = hasher.finalize();
+        let sha_credentials = header::TbfFooterCredentials {
+            base: header::TbfHeaderTlv {
+                tipe: header::TbfHeaderTypes::Credentials,
+                length: sha256_binary_tlv_len as u16,
+            },
+            format: header::TbfFooterCredentialsType::SHA256Binary,
+            data: result.to_vec(),
+        };
+        output.write_all(sha_credentials.generate().unwrap().get_ref())?;
+        footer_space_remaining -= sha256_binary_len;
+        footer_components.push(("SHA256 binary credential", sha256_binary_len));
+        credential_names.push("sha256-binary");
+        if verbose {
+            eprintln!("Added SHA256 binary credential.");
+        }
+    }
 
-        let key_pair = ring::signature::RsaKeyPair::from_pkcs8(&private_key_contents)
-            .unwrap_or_else(|e| {
-                panic!("RSA4096 could not be parsed: {:?}", e);
-            });
+    // Get the public key modulus and signature for the RSA4096 credential,
+    // either from a private key file or by asking a PKCS#11 token to sign.
+    let rsa4096_signature = if let Some(private_key_path) = rsa4096_private_key {
+        let signing_error = |msg: String| {
+            Elf2TabError::io_error(ErrorCategory::Signing, io::ErrorKind::InvalidData, msg)
+        };
+        let key_pair = read_rsa4096_key(&private_key_path)?;
 
         let public_key: ring::signature::RsaPublicKeyComponents<Vec<u8>> =
             ring::signature::RsaPublicKeyComponents {
@@ -1073,33 +2856,68 @@ pub fn elf_to_tbf(
                     .to_vec(),
             };
 
-        if key_pair.public_modulus_len() != 512 {
-            // A 4096-bit key should have a 512-byte modulus
-            panic!(
-                "RSA4096 signature requested but key {:?} is not 4096 bits, it is {} bits",
-                private_key_path,
-                key_pair.public_modulus_len() * 8
-            );
-        }
+        // PKCS#1 v1.5 signatures are deterministic: ring only uses the RNG for
+        // blinding, so the same key and TBF always produce the same signature.
+        // Any randomized signature scheme added here must derive its nonces
+        // deterministically (e.g. RFC 6979 for ECDSA) to keep `--deterministic`
+        // builds reproducible.
         let rng = rand::SystemRandom::new();
         let mut signature = vec![0; key_pair.public_modulus_len()];
-        let _res = key_pair
+        key_pair
             .sign(
                 &signature::RSA_PKCS1_SHA512,
                 &rng,
-                &output[0..tbfheader.binary_end_offset() as usize],
+                integrity_covered,
                 &mut signature,
             )
-            .map_err(|e| {
-                panic!("Could not generate RSA4096 signature: {:?}", e);
-            });
+            .map_err(|e| signing_error(format!("Could not generate RSA4096 signature: {:?}", e)))?;
+        Some((public_key, signature))
+    } else if let Some(pkcs11_uri) = pkcs11_uri {
+        Some(
+            pkcs11::sign_rsa4096(&pkcs11_uri, integrity_covered).map_err(|e| {
+                Elf2TabError::io_error(ErrorCategory::Signing, e.kind(), e.to_string())
+            })?,
+        )
+    } else {
+        None
+    };
+
+    if let Some((public_key, signature)) = rsa4096_signature {
+        // Make sure the signature actually verifies over the covered region
+        // before we include it, so that a key or format mismatch is caught
+        // here rather than when the kernel refuses to run the app.
+        public_key
+            .verify(
+                &signature::RSA_PKCS1_2048_8192_SHA512,
+                integrity_covered,
+                &signature,
+            )
+            .map_err(|_| {
+                Elf2TabError::io_error(
+                    ErrorCategory::Signing,
+                    io::ErrorKind::InvalidData,
+                    "RSA4096 signature does not verify with the public key",
+                )
+            })?;
+        if verbose {
+            eprintln!("Verified RSA4096 signature.");
+        }
+        let public_key_modulus = public_key.n;
+
+        let rsa4096_len = mem::size_of::<header::TbfHeaderTlv>()
+            + mem::size_of::<header::TbfFooterCredentialsType>()
+            + 1024 // Signature + key is 1024 bytes long
+            + covered_length_field.len();
+        // Length in the TLV field
+        let rsa4096_tlv_len = rsa4096_len - mem::size_of::<header::TbfHeaderTlv>();
+
         let mut credentials = vec![0; 1024];
-        credentials[..key_pair.public_modulus_len()]
-            .copy_from_slice(&public_key.n[..key_pair.public_modulus_len()]);
+        credentials[..public_key_modulus.len()].copy_from_slice(&public_key_modulus);
         for (i, sig) in signature.iter().enumerate() {
-            let index = i + key_pair.public_modulus_len();
+            let index = i + public_key_modulus.len();
             credentials[index] = *sig;
         }
+        credentials.extend(&covered_length_field);
 
         let rsa4096_credentials = header::TbfFooterCredentials {
             base: header::TbfHeaderTlv {
@@ -1112,35 +2930,1364 @@ pub fn elf_to_tbf(
 
         output.write_all(rsa4096_credentials.generate().unwrap().get_ref())?;
         footer_space_remaining -= rsa4096_len;
+        footer_components.push(("RSA4096 credential", rsa4096_len));
+        credential_names.push("rsa4096");
+        if verbose {
+            eprintln!("Added PKCS#1v1.5 RSA4096 signature credential.");
+        }
+    }
+
+    // Signing schemes elf2tab does not support natively can be handled by an
+    // external command.
+    if let Some((command, credential_type)) = &credential_command {
+        let data = run_credential_command(command, integrity_covered, *credential_type)?;
+        let command_len = mem::size_of::<header::TbfHeaderTlv>()
+            + mem::size_of::<header::TbfFooterCredentialsType>()
+            + data.len()
+            + covered_length_field.len();
+        // Length in the TLV field
+        let command_tlv_len = command_len - mem::size_of::<header::TbfHeaderTlv>();
+
+        let command_credentials = header::TbfFooterCredentials {
+            base: header::TbfHeaderTlv {
+                tipe: header::TbfHeaderTypes::Credentials,
+                length: command_tlv_len as u16,
+            },
+            format: credential_type.format(),
+            data: [data.as_slice(), &covered_length_field].concat(),
+        };
+        output.write_all(command_credentials.generate().unwrap().get_ref())?;
+        footer_space_remaining -= command_len;
+        footer_components.push(("Command credential", command_len));
+        credential_names.push(credential_type.name());
         if verbose {
-            println!("Added PKCS#1v1.5 RSA4096 signature credential.");
+            eprintln!(
+                "Added {} credential from `{}`.",
+                credential_type.name(),
+                command
+            );
         }
     }
 
     let padding_len = footer_space_remaining;
 
-    // Need at least space for the base Credentials TLV.
-    if padding_len
+    // Need at least space for the base Credentials TLV. Without a Program
+    // header nothing parses the footer, so just fill it with zeros.
+    if no_program_header {
+        util::do_pad(output, padding_len)?;
+        if padding_len > 0 {
+            footer_components.push(("Padding", padding_len));
+        }
+    } else if padding_len
         >= (mem::size_of::<header::TbfHeaderTlv>()
             + mem::size_of::<header::TbfFooterCredentialsType>())
     {
         let padding_tlv_len = padding_len - mem::size_of::<header::TbfHeaderTlv>();
         let reserved_len = padding_tlv_len - mem::size_of::<header::TbfFooterCredentialsType>();
+        // Reserved space is always zeroed so the footer is reproducible.
         let reserved_vec = vec![0u8; reserved_len];
         let padding_credentials = header::TbfFooterCredentials {
             base: header::TbfHeaderTlv {
                 tipe: header::TbfHeaderTypes::Credentials,
-                length: padding_tlv_len as u16,
+                length: u16::try_from(padding_tlv_len).map_err(|_| {
+                    Elf2TabError::io_error(
+                        ErrorCategory::Size,
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "reserved footer space ({} bytes) does not fit in a credentials TLV",
+                            padding_tlv_len
+                        ),
+                    )
+                })?,
             },
             format: header::TbfFooterCredentialsType::Reserved,
             data: reserved_vec,
         };
         let creds = padding_credentials.generate().unwrap();
         output.write_all(creds.get_ref())?;
+        footer_components.push(("Reserved credential", padding_len));
     }
 
     // Pad to get a power of 2 sized flash app, if requested.
     util::do_pad(output, post_content_pad)?;
+    if post_content_pad > 0 {
+        footer_components.push(("Trailing padding", post_content_pad));
+    }
 
-    Ok(())
+    if verbose {
+        eprintln!(
+            "Footer: {0} ({0:#x}) bytes from offset {1} ({1:#x}) to {2} ({2:#x}).",
+            footers_len, binary_end_offset, total_size
+        );
+        for (component, length) in &footer_components {
+            eprintln!("  {:<20} {:>6} bytes", component, length);
+        }
+    }
+
+    let debug_sections = elf_sections
+        .iter()
+        .filter(|(sh_name, _)| sh_name.starts_with(".debug_"))
+        .map(|(sh_name, shdr)| (sh_name.clone(), shdr.sh_size))
+        .collect();
+
+    Ok(TbfInfo {
+        sizes,
+        credentials: credential_names,
+        debug_sections,
+        app_version,
+        symbol_offsets,
+        section_hashes,
+        minimum_ram_size,
+        footer_offset: if no_program_header {
+            sizes.total_size
+        } else {
+            binary_end_offset
+        },
+        section_decisions,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        check_elf, check_flash_segment_order, check_overlapping_sections,
+        check_overlapping_segments, check_writeable_flash_regions, checked_u32,
+        exclude_section_from_segment, find_relocation_section, footer_reserved_space,
+        run_credential_command, wfr_erase_size, ExcludedSection,
+    };
+    use super::{elf_to_tbf, ConvertOptions, TbfInfo, TrailingPadding};
+    use crate::error::ErrorCategory;
+    use std::io;
+
+    fn section(name: &str, offset: u64, size: u64) -> (String, elf::section::SectionHeader) {
+        (
+            name.to_string(),
+            elf::section::SectionHeader {
+                sh_name: 0,
+                sh_type: elf::abi::SHT_PROGBITS,
+                sh_flags: elf::abi::SHF_ALLOC as u64,
+                sh_addr: 0x80000000 + offset - 0x1000,
+                sh_offset: offset,
+                sh_size: size,
+                sh_link: 0,
+                sh_info: 0,
+                sh_addralign: 4,
+                sh_entsize: 0,
+            },
+        )
+    }
+
+    fn segment() -> elf::segment::ProgramHeader {
+        elf::segment::ProgramHeader {
+            p_type: elf::abi::PT_LOAD,
+            p_offset: 0x1000,
+            p_vaddr: 0x80000000,
+            p_paddr: 0x80000000,
+            p_filesz: 0x100,
+            p_memsz: 0x100,
+            p_flags: elf::abi::PF_R | elf::abi::PF_X,
+            p_align: 0x1000,
+        }
+    }
+
+    #[test]
+    pub fn accepts_adjacent_sections() {
+        let sections = vec![
+            section(".text", 0x1000, 0x80),
+            section(".rodata", 0x1080, 0x80),
+        ];
+        assert!(check_overlapping_sections(&sections, &segment()).is_ok());
+    }
+
+    #[test]
+    pub fn rejects_overlapping_sections() {
+        let sections = vec![
+            section(".rodata", 0x1040, 0x40),
+            section(".text", 0x1000, 0x80),
+        ];
+        let error = check_overlapping_sections(&sections, &segment()).unwrap_err();
+        assert!(error.to_string().contains(".text"));
+        assert!(error.to_string().contains(".rodata"));
+    }
+
+    #[test]
+    pub fn footer_reserved_space_boundaries() {
+        // No minimum, or credentials already large enough.
+        assert_eq!(footer_reserved_space(0, 0), 0);
+        assert_eq!(footer_reserved_space(40, 0), 0);
+        assert_eq!(footer_reserved_space(40, 39), 0);
+        assert_eq!(footer_reserved_space(40, 40), 0);
+        // Reserved space is never smaller than the Reserved TLV header.
+        assert_eq!(footer_reserved_space(40, 41), 8);
+        assert_eq!(footer_reserved_space(40, 47), 8);
+        assert_eq!(footer_reserved_space(40, 48), 8);
+        // Reserved space is a multiple of 4 bytes.
+        assert_eq!(footer_reserved_space(40, 49), 12);
+        assert_eq!(footer_reserved_space(40, 52), 12);
+        assert_eq!(footer_reserved_space(0, 3000), 3000);
+        assert_eq!(footer_reserved_space(0, 3001), 3004);
+    }
+
+    #[test]
+    pub fn finds_rel_and_rela_sections() {
+        let mut rel = section(".rel.data", 0x2000, 0x10);
+        rel.1.sh_type = elf::abi::SHT_REL;
+        rel.1.sh_info = 0;
+        let mut rela = section(".rela.got", 0x2010, 0x18);
+        rela.1.sh_type = elf::abi::SHT_RELA;
+        rela.1.sh_info = 1;
+        let sections = vec![
+            section(".data", 0x1000, 0x80),
+            section(".got", 0x1080, 0x80),
+            // Only the section type and sh_info matter, not the name.
+            section(".relocation_config.got", 0x1100, 0x10),
+            section(".bss", 0x1110, 0x80),
+            rel,
+            rela,
+        ];
+
+        let (name, shdr) = find_relocation_section(&sections, 0).unwrap();
+        assert_eq!(name, ".rel.data");
+        assert_eq!(shdr.sh_type, elf::abi::SHT_REL);
+
+        let (name, shdr) = find_relocation_section(&sections, 1).unwrap();
+        assert_eq!(name, ".rela.got");
+        assert_eq!(shdr.sh_type, elf::abi::SHT_RELA);
+
+        assert!(find_relocation_section(&sections, 3).is_none());
+    }
+
+    #[test]
+    pub fn excludes_sections_from_segment() {
+        // Sections at the start and end of the segment are trimmed.
+        let mut seg = segment();
+        let (_, first) = section(".first", 0x1000, 0x20);
+        assert_eq!(
+            exclude_section_from_segment(&first, &mut seg),
+            ExcludedSection::Trimmed
+        );
+        assert_eq!(seg.p_offset, 0x1020);
+        assert_eq!(seg.p_paddr, 0x80000020);
+        assert_eq!(seg.p_filesz, 0xe0);
+
+        let (_, last) = section(".last", 0x10c0, 0x40);
+        assert_eq!(
+            exclude_section_from_segment(&last, &mut seg),
+            ExcludedSection::Trimmed
+        );
+        assert_eq!(seg.p_offset, 0x1020);
+        assert_eq!(seg.p_filesz, 0xa0);
+        assert_eq!(seg.p_memsz, 0xa0);
+
+        // Sections in the middle leave the segment unchanged.
+        let (_, middle) = section(".middle", 0x1040, 0x10);
+        assert_eq!(
+            exclude_section_from_segment(&middle, &mut seg),
+            ExcludedSection::Middle(0x20)
+        );
+        assert_eq!(seg.p_filesz, 0xa0);
+
+        // Sections elsewhere are ignored.
+        let (_, other) = section(".other", 0x2000, 0x10);
+        assert_eq!(
+            exclude_section_from_segment(&other, &mut seg),
+            ExcludedSection::NotInSegment
+        );
+
+        // Memory after the file contents does not move, so only the contents
+        // shrink.
+        let mut seg = segment();
+        seg.p_memsz = 0x180;
+        let (_, last) = section(".last", 0x10c0, 0x40);
+        assert_eq!(
+            exclude_section_from_segment(&last, &mut seg),
+            ExcludedSection::Trimmed
+        );
+        assert_eq!(seg.p_filesz, 0xc0);
+        assert_eq!(seg.p_memsz, 0x180);
+    }
+
+    /// A section for `test_elf()`. NOBITS sections only use the length of
+    /// `data`, and `info` is the `sh_info` of the section header.
+    struct TestSection {
+        name: &'static str,
+        sh_type: u32,
+        data: Vec<u8>,
+        info: u32,
+    }
+
+    fn progbits(name: &'static str, data: &[u8]) -> TestSection {
+        TestSection {
+            name,
+            sh_type: elf::abi::SHT_PROGBITS,
+            data: data.to_vec(),
+            info: 0,
+        }
+    }
+
+    fn nobits(name: &'static str, size: usize) -> TestSection {
+        TestSection {
+            name,
+            sh_type: elf::abi::SHT_NOBITS,
+            data: vec![0; size],
+            info: 0,
+        }
+    }
+
+    /// A loadable segment for `test_elf()` with its sections in order. NOBITS
+    /// sections must come last.
+    struct TestSegment {
+        vaddr: u32,
+        paddr: u32,
+        flags: u32,
+        sections: Vec<TestSection>,
+    }
+
+    /// A 32-bit little-endian ARM ELF with `segments`, and `other_sections`
+    /// outside of any segment. Sections are numbered from 1 in that order, and
+    /// a SYMTAB section uses the section after it as its string table. The
+    /// entry point is the start of the first segment.
+    fn test_elf(segments: &[TestSegment], other_sections: &[TestSection]) -> Vec<u8> {
+        test_elf_with_byte_order(segments, other_sections, false)
+    }
+
+    /// Like `test_elf()`, but big-endian if `big_endian` is set. Section
+    /// contents are copied as they are.
+    fn test_elf_with_byte_order(
+        segments: &[TestSegment],
+        other_sections: &[TestSection],
+        big_endian: bool,
+    ) -> Vec<u8> {
+        let u16_bytes = |value: u16| {
+            if big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            }
+        };
+        let push_u32 = |elf: &mut Vec<u8>, values: &[u32]| {
+            for value in values {
+                elf.extend(if big_endian {
+                    value.to_be_bytes()
+                } else {
+                    value.to_le_bytes()
+                });
+            }
+        };
+
+        // Lay out the contents after the ELF and program headers.
+        let mut offset = (52 + 32 * segments.len() + 3) & !3;
+        let mut phdrs = Vec::new();
+        let mut shdrs = Vec::new();
+        let mut contents = Vec::new();
+        let mut names = vec![0];
+        let mut add_section = |section: &TestSection,
+                               flags: u32,
+                               address: u32,
+                               offset: usize,
+                               names: &mut Vec<u8>| {
+            let size = section.data.len() as u32;
+            let (link, entsize) = if section.sh_type == elf::abi::SHT_SYMTAB {
+                (shdrs.len() as u32 + 2, 16)
+            } else {
+                (0, 0)
+            };
+            shdrs.push([
+                names.len() as u32,
+                section.sh_type,
+                flags,
+                address,
+                offset as u32,
+                size,
+                link,
+                section.info,
+                4,
+                entsize,
+            ]);
+            names.extend(section.name.as_bytes());
+            names.push(0);
+        };
+        for segment in segments {
+            let start = offset;
+            let mut flags = elf::abi::SHF_ALLOC;
+            if segment.flags & elf::abi::PF_X > 0 {
+                flags |= elf::abi::SHF_EXECINSTR;
+            }
+            if segment.flags & elf::abi::PF_W > 0 {
+                flags |= elf::abi::SHF_WRITE;
+            }
+            let mut memsz = 0;
+            for section in &segment.sections {
+                let address = segment.vaddr + memsz;
+                add_section(section, flags, address, offset, &mut names);
+                memsz += section.data.len() as u32;
+                if section.sh_type != elf::abi::SHT_NOBITS {
+                    contents.extend(&section.data);
+                    offset += section.data.len();
+                }
+            }
+            let filesz = (offset - start) as u32;
+            phdrs.push([
+                elf::abi::PT_LOAD,
+                start as u32,
+                segment.vaddr,
+                segment.paddr,
+                filesz,
+                memsz,
+                segment.flags,
+                4,
+            ]);
+            let pad = (4 - offset % 4) % 4;
+            contents.extend(vec![0; pad]);
+            offset += pad;
+        }
+        for section in other_sections {
+            add_section(section, 0, 0, offset, &mut names);
+            contents.extend(&section.data);
+            offset += section.data.len();
+        }
+        let shstrtab = TestSection {
+            name: ".shstrtab",
+            sh_type: elf::abi::SHT_STRTAB,
+            data: vec![],
+            info: 0,
+        };
+        add_section(&shstrtab, 0, 0, offset, &mut names);
+        let names_len = names.len() as u32;
+        shdrs.last_mut().unwrap()[5] = names_len;
+        contents.extend(&names);
+        offset += names.len();
+        let pad = (4 - offset % 4) % 4;
+        contents.extend(vec![0; pad]);
+        offset += pad;
+
+        let data = if big_endian {
+            elf::abi::ELFDATA2MSB
+        } else {
+            elf::abi::ELFDATA2LSB
+        };
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 1, data, 1];
+        elf.resize(16, 0);
+        elf.extend(u16_bytes(2)); // e_type
+        elf.extend(u16_bytes(40)); // e_machine
+        push_u32(&mut elf, &[1, segments[0].vaddr, 52, offset as u32, 0]);
+        elf.extend(u16_bytes(52)); // e_ehsize
+        elf.extend(u16_bytes(32)); // e_phentsize
+        elf.extend(u16_bytes(phdrs.len() as u16)); // e_phnum
+        elf.extend(u16_bytes(40)); // e_shentsize
+        elf.extend(u16_bytes(shdrs.len() as u16 + 1)); // e_shnum
+        elf.extend(u16_bytes(shdrs.len() as u16)); // e_shstrndx
+        for phdr in &phdrs {
+            push_u32(&mut elf, phdr);
+        }
+        elf.resize((52 + 32 * segments.len() + 3) & !3, 0);
+        elf.extend(contents);
+        push_u32(&mut elf, &[0; 10]);
+        for shdr in &shdrs {
+            push_u32(&mut elf, shdr);
+        }
+        elf
+    }
+
+    /// Convert `elf` with `options`.
+    fn convert(elf: &[u8], options: ConvertOptions) -> io::Result<(Vec<u8>, TbfInfo)> {
+        let mut tbf = Vec::new();
+        let info = elf_to_tbf(&mut io::Cursor::new(elf), &mut tbf, options)?;
+        Ok((tbf, info))
+    }
+
+    /// The loadable segments of the ELF used to test which sections end up in
+    /// the app binary: code in flash, data copied from flash to RAM, and RAM
+    /// without contents.
+    fn segments_elf() -> Vec<u8> {
+        segments_elf_with(&[])
+    }
+
+    /// `segments_elf()` with `other_sections` outside of the segments.
+    fn segments_elf_with(other_sections: &[TestSection]) -> Vec<u8> {
+        test_elf(
+            &[
+                TestSegment {
+                    vaddr: 0x80000000,
+                    paddr: 0x80000000,
+                    flags: elf::abi::PF_R | elf::abi::PF_X,
+                    sections: vec![
+                        progbits(".text", &[1; 16]),
+                        progbits(".rodata", &[2; 8]),
+                        progbits(".const", &[3; 8]),
+                    ],
+                },
+                TestSegment {
+                    vaddr: 0x20000000,
+                    paddr: 0x80000020,
+                    flags: elf::abi::PF_R | elf::abi::PF_W,
+                    sections: vec![progbits(".data", &[4; 8]), nobits(".bss", 16)],
+                },
+                TestSegment {
+                    vaddr: 0x20000100,
+                    paddr: 0x20000100,
+                    flags: elf::abi::PF_R | elf::abi::PF_W,
+                    sections: vec![nobits(".noinit", 64)],
+                },
+            ],
+            other_sections,
+        )
+    }
+
+    #[test]
+    pub fn decides_which_sections_are_included() {
+        let elf = segments_elf();
+        // Sections to exclude, whether to only create the header, and which of
+        // .text, .rodata, .const, .data, .bss, and .noinit are included.
+        let cases: [(&[&str], bool, [bool; 6]); 6] = [
+            (&[], false, [true, true, true, true, false, false]),
+            (&[".text"], false, [false, true, true, true, false, false]),
+            (&[".rodata"], false, [true, false, true, true, false, false]),
+            (&[".const"], false, [true, true, false, true, false, false]),
+            (&[".data"], false, [true, true, true, false, false, false]),
+            (&[], true, [false; 6]),
+        ];
+        for (exclude_sections, header_only, expected) in cases {
+            let options = ConvertOptions {
+                exclude_sections: exclude_sections.iter().map(|s| s.to_string()).collect(),
+                header_only,
+                quiet: true,
+                ..Default::default()
+            };
+            let (_, info) = convert(&elf, options).unwrap();
+            let expected: Vec<_> = [
+                (0, ".text"),
+                (0, ".rodata"),
+                (0, ".const"),
+                (1, ".data"),
+                (1, ".bss"),
+                (2, ".noinit"),
+            ]
+            .iter()
+            .zip(expected)
+            .map(|((segment, name), included)| (*segment, name.to_string(), included))
+            .collect();
+            assert_eq!(info.section_decisions, expected, "{:?}", exclude_sections);
+        }
+    }
+
+    #[test]
+    pub fn excluding_a_middle_section_moves_offsets() {
+        let mut elf = test_elf(
+            &[TestSegment {
+                vaddr: 0x80000000,
+                paddr: 0x80000000,
+                flags: elf::abi::PF_R | elf::abi::PF_X,
+                sections: vec![
+                    progbits(".text", &[1; 16]),
+                    progbits(".fixture", &[9; 32]),
+                    progbits(".start", &[2; 8]),
+                    progbits(".app_state.wfr", &[0; 16]),
+                ],
+            }],
+            &[],
+        );
+        // Start in .start, after the excluded section.
+        elf[24..28].copy_from_slice(&0x80000030_u32.to_le_bytes());
+        let options = |exclude: &str| ConvertOptions {
+            exclude_sections: vec![exclude.to_string()],
+            quiet: true,
+            ..Default::default()
+        };
+        let (tbf, _) = convert(&elf, options(".fixture")).unwrap();
+        let tbf = crate::tbf::Tbf::parse(&tbf).unwrap();
+        let binary = tbf.app_binary().unwrap();
+        assert_eq!(binary[16..24], [2; 8]);
+        assert_eq!(tbf.init_fn_offset(), Some(16));
+        let wfr = tbf
+            .tlv(crate::header::TbfHeaderTypes::WriteableFlashRegions)
+            .unwrap();
+        let wfr_offset = tbf.app_binary_offset() + 24;
+        assert_eq!(wfr.data[..4], wfr_offset.to_le_bytes());
+        assert_eq!(wfr.data[4..8], 16_u32.to_le_bytes());
+
+        // An excluded region gets no WFR TLV.
+        let (tbf, _) = convert(&elf, options(".app_state.wfr")).unwrap();
+        let tbf = crate::tbf::Tbf::parse(&tbf).unwrap();
+        assert!(tbf
+            .tlv(crate::header::TbfHeaderTypes::WriteableFlashRegions)
+            .is_none());
+
+        let error = convert(&elf, options(".start")).unwrap_err();
+        assert_eq!(ErrorCategory::of(&error), ErrorCategory::ElfParse);
+        assert!(error.to_string().contains("excluded"));
+    }
+
+    #[test]
+    pub fn reports_symbol_offsets() {
+        // Symbols in .text, .rodata, .data, and .bss, in that order.
+        let symbols = [
+            ("main", 0x80000004_u32, elf::abi::STT_FUNC),
+            ("table", 0x80000010, elf::abi::STT_OBJECT),
+            ("counter", 0x20000004, elf::abi::STT_OBJECT),
+            ("buffer", 0x20000008, elf::abi::STT_OBJECT),
+        ];
+        let mut symtab = vec![0; 16];
+        let mut strtab = vec![0];
+        for (name, value, symtype) in symbols {
+            symtab.extend((strtab.len() as u32).to_le_bytes());
+            symtab.extend(value.to_le_bytes());
+            symtab.extend(4_u32.to_le_bytes());
+            symtab.extend([symtype, 0]);
+            symtab.extend(1_u16.to_le_bytes());
+            strtab.extend(name.as_bytes());
+            strtab.push(0);
+        }
+        let elf = segments_elf_with(&[
+            TestSection {
+                name: ".symtab",
+                sh_type: elf::abi::SHT_SYMTAB,
+                data: symtab,
+                info: 1,
+            },
+            TestSection {
+                name: ".strtab",
+                sh_type: elf::abi::SHT_STRTAB,
+                data: strtab,
+                info: 0,
+            },
+        ]);
+        let options = |exclude_sections: &[&str]| ConvertOptions {
+            exclude_sections: exclude_sections.iter().map(|s| s.to_string()).collect(),
+            report_symbol_offsets: true,
+            quiet: true,
+            ..Default::default()
+        };
+
+        let (tbf, info) = convert(&elf, options(&[])).unwrap();
+        let start = crate::tbf::Tbf::parse(&tbf).unwrap().app_binary_offset() as usize;
+        let expected = [
+            ("main".to_string(), 0x80000004, start + 4),
+            ("table".to_string(), 0x80000010, start + 16),
+            ("counter".to_string(), 0x20000004, start + 36),
+        ];
+        assert_eq!(info.symbol_offsets, expected);
+        // Each offset points at the symbol's contents in the TBF.
+        let contents: Vec<u8> = info.symbol_offsets.iter().map(|s| tbf[s.2]).collect();
+        assert_eq!(contents, [1, 2, 4]);
+
+        // Symbols in an excluded section are left out, and later ones move.
+        let (_, info) = convert(&elf, options(&[".rodata"])).unwrap();
+        let expected = [
+            ("main".to_string(), 0x80000004, start + 4),
+            ("counter".to_string(), 0x20000004, start + 28),
+        ];
+        assert_eq!(info.symbol_offsets, expected);
+    }
+
+    #[test]
+    pub fn checks_board_ram() {
+        let options = |board_ram, strict| ConvertOptions {
+            board_ram: Some(board_ram),
+            strict,
+            quiet: true,
+            ..Default::default()
+        };
+        let (_, info) = convert(&segments_elf(), ConvertOptions::default()).unwrap();
+        let needed = info.minimum_ram_size;
+
+        assert!(convert(&segments_elf(), options(needed, true)).is_ok());
+        // Only a warning without --strict.
+        let (_, info) = convert(&segments_elf(), options(needed - 1, false)).unwrap();
+        assert_eq!(info.minimum_ram_size, needed);
+        let error = convert(&segments_elf(), options(needed - 1, true)).unwrap_err();
+        assert_eq!(ErrorCategory::of(&error), ErrorCategory::Size);
+        assert!(error
+            .to_string()
+            .contains(&format!("App needs {} bytes of RAM", needed)));
+    }
+
+    #[test]
+    pub fn aligns_the_footer() {
+        use sha2::{Digest, Sha256};
+
+        let options = |footer_align| ConvertOptions {
+            footer_align,
+            sha256: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let (unaligned, unaligned_info) = convert(&segments_elf(), options(None)).unwrap();
+        let (tbf, info) = convert(&segments_elf(), options(Some(0x100))).unwrap();
+        assert_ne!(unaligned_info.footer_offset % 0x100, 0);
+        assert_eq!(info.footer_offset % 0x100, 0);
+
+        // The app binary is padded with zeros up to the footer, and the padding
+        // is covered by the hash.
+        let parsed = crate::tbf::Tbf::parse(&tbf).unwrap();
+        assert_eq!(parsed.binary_end_offset() as usize, info.footer_offset);
+        let padding = &tbf[unaligned_info.footer_offset..info.footer_offset];
+        assert!(padding.iter().all(|byte| *byte == 0));
+        assert_eq!(
+            tbf[unaligned_info.footer_offset - 8..unaligned_info.footer_offset],
+            unaligned[unaligned_info.footer_offset - 8..unaligned_info.footer_offset]
+        );
+        let sha256 = &parsed.footer_credentials().unwrap()[0];
+        assert_eq!(
+            sha256.data[4..],
+            Sha256::digest(&tbf[..info.footer_offset])[..]
+        );
+    }
+
+    #[test]
+    pub fn reports_footer_offset() {
+        let options = ConvertOptions {
+            sha256: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let (tbf, info) = convert(&segments_elf(), options).unwrap();
+        let parsed = crate::tbf::Tbf::parse(&tbf).unwrap();
+        assert_eq!(info.footer_offset, parsed.binary_end_offset() as usize);
+        assert_eq!(tbf[info.footer_offset..info.footer_offset + 2], [128, 0]);
+
+        // Without a Program header there is no footer.
+        let options = ConvertOptions {
+            no_program_header: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let (tbf, info) = convert(&segments_elf(), options).unwrap();
+        assert_eq!(info.footer_offset, tbf.len());
+    }
+
+    #[test]
+    pub fn adds_sha3_256_credential() {
+        use sha3::{Digest, Sha3_256};
+
+        let options = ConvertOptions {
+            sha3_256: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let (tbf, info) = convert(&segments_elf(), options).unwrap();
+        let parsed = crate::tbf::Tbf::parse(&tbf).unwrap();
+        let credentials = parsed.footer_credentials().unwrap();
+        assert_eq!(credentials[0].credentials_format(), Some(6));
+        assert_eq!(
+            credentials[0].data[4..],
+            Sha3_256::digest(&tbf[..info.footer_offset])[..]
+        );
+    }
+
+    #[test]
+    pub fn integrity_end_limits_the_hash() {
+        use sha2::{Digest, Sha256};
+
+        let options = ConvertOptions {
+            sha256: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let (tbf, _) = convert(&segments_elf(), options.clone()).unwrap();
+        let parsed = crate::tbf::Tbf::parse(&tbf).unwrap();
+        let integrity_end = parsed.app_binary_offset() as usize + 16;
+        assert!(integrity_end < parsed.binary_end_offset() as usize);
+
+        let options = ConvertOptions {
+            integrity_end: Some(integrity_end as u32),
+            ..options
+        };
+        let (tbf, _) = convert(&segments_elf(), options).unwrap();
+        let parsed = crate::tbf::Tbf::parse(&tbf).unwrap();
+        let credentials = parsed.footer_credentials().unwrap();
+        let sha256 = &credentials[0];
+        assert_eq!(sha256.credentials_format(), Some(3));
+        // Format, hash, and then the covered length.
+        assert_eq!(sha256.data.len(), 4 + 32 + 4);
+        assert_eq!(sha256.data[36..], (integrity_end as u32).to_le_bytes());
+        assert_eq!(
+            sha256.data[4..36],
+            Sha256::digest(&tbf[..integrity_end])[..]
+        );
+        assert_ne!(
+            sha256.data[4..36],
+            Sha256::digest(&tbf[..parsed.binary_end_offset() as usize])[..]
+        );
+    }
+
+    #[test]
+    pub fn hashes_sections_where_they_end_up() {
+        use sha2::{Digest, Sha256};
+
+        let options = ConvertOptions {
+            exclude_sections: vec![".rodata".to_string()],
+            report_section_hashes: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let (tbf, info) = convert(&segments_elf(), options).unwrap();
+        let names: Vec<_> = info
+            .section_hashes
+            .iter()
+            .map(|(name, _, _, _)| name.as_str())
+            .collect();
+        assert_eq!(names, [".text", ".const", ".data"]);
+        // .const moves back into the place of the excluded .rodata.
+        let text_offset = info.section_hashes[0].1;
+        assert_eq!(info.section_hashes[1].1, text_offset + 16);
+        for (name, offset, length, hash) in &info.section_hashes {
+            let data = &tbf[*offset..offset + length];
+            assert_eq!(<[u8; 32]>::from(Sha256::digest(data)), *hash, "{}", name);
+        }
+        let contents: Vec<_> = info
+            .section_hashes
+            .iter()
+            .map(|(_, offset, length, _)| tbf[*offset..offset + length].to_vec())
+            .collect();
+        assert_eq!(contents, [vec![1; 16], vec![3; 8], vec![4; 8]]);
+    }
+
+    /// An ELF with writeable data in flash and relocation sections for it.
+    /// Each relocation section applies to the section with index `info`: 2
+    /// for .data or 3 for .sdata.
+    fn relocations_elf(relocations: &[(&'static str, u32, &[u8], u32)]) -> Vec<u8> {
+        relocations_elf_with_byte_order(relocations, false)
+    }
+
+    /// Like `relocations_elf()`, but big-endian if `big_endian` is set.
+    fn relocations_elf_with_byte_order(
+        relocations: &[(&'static str, u32, &[u8], u32)],
+        big_endian: bool,
+    ) -> Vec<u8> {
+        let relocations: Vec<_> = relocations
+            .iter()
+            .map(|(name, sh_type, data, info)| TestSection {
+                name,
+                sh_type: *sh_type,
+                data: data.to_vec(),
+                info: *info,
+            })
+            .collect();
+        test_elf_with_byte_order(
+            &[
+                TestSegment {
+                    vaddr: 0x80000000,
+                    paddr: 0x80000000,
+                    flags: elf::abi::PF_R | elf::abi::PF_X,
+                    sections: vec![progbits(".text", &[1; 16])],
+                },
+                TestSegment {
+                    vaddr: 0x20000000,
+                    paddr: 0x80000010,
+                    flags: elf::abi::PF_R | elf::abi::PF_W,
+                    sections: vec![progbits(".data", &[4; 8]), progbits(".sdata", &[5; 8])],
+                },
+            ],
+            &relocations,
+            big_endian,
+        )
+    }
+
+    #[test]
+    pub fn includes_rel_and_rela_relocations() {
+        let options = || ConvertOptions {
+            quiet: true,
+            ..Default::default()
+        };
+        // One Elf32_Rel entry and one Elf32_Rela entry.
+        let rel: Vec<u8> = (0..8).collect();
+        let rela: Vec<u8> = (0..12).collect();
+        for (name, sh_type, data) in [
+            (".rel.data", elf::abi::SHT_REL, &rel),
+            (".rela.data", elf::abi::SHT_RELA, &rela),
+        ] {
+            let elf = relocations_elf(&[(name, sh_type, data, 2)]);
+            let (tbf, _) = convert(&elf, options()).unwrap();
+            let tbf = crate::tbf::Tbf::parse(&tbf).unwrap();
+            let binary = tbf.app_binary().unwrap();
+            // The relocation data follows the segments with its length first.
+            assert_eq!(
+                binary[32..36],
+                (data.len() as u32).to_le_bytes(),
+                "{}",
+                name
+            );
+            assert_eq!(&binary[36..36 + data.len()], &data[..], "{}", name);
+        }
+
+        let elf = relocations_elf(&[
+            (".rel.data", elf::abi::SHT_REL, &rel, 2),
+            (".rela.sdata", elf::abi::SHT_RELA, &rela, 3),
+        ]);
+        let error = convert(&elf, options()).unwrap_err();
+        assert_eq!(ErrorCategory::of(&error), ErrorCategory::ElfParse);
+        assert!(error.to_string().contains("cannot mix REL and RELA"));
+    }
+
+    #[test]
+    pub fn omits_empty_relocation_length() {
+        let options = |omit_empty_relocation| ConvertOptions {
+            omit_empty_relocation,
+            quiet: true,
+            ..Default::default()
+        };
+        // .text, .rodata, .const, and .data, then the relocation length.
+        let (tbf, _) = convert(&segments_elf(), options(false)).unwrap();
+        let binary = crate::tbf::Tbf::parse(&tbf)
+            .unwrap()
+            .app_binary()
+            .unwrap()
+            .to_vec();
+        assert_eq!(binary.len(), 44);
+        assert_eq!(binary[40..], [0; 4]);
+
+        let (tbf, _) = convert(&segments_elf(), options(true)).unwrap();
+        let binary = crate::tbf::Tbf::parse(&tbf)
+            .unwrap()
+            .app_binary()
+            .unwrap()
+            .to_vec();
+        assert_eq!(binary.len(), 40);
+        assert_eq!(binary[32..], [4; 8]);
+
+        // The length is still needed if there are relocations.
+        let rel: Vec<u8> = (0..8).collect();
+        let elf = relocations_elf(&[(".rel.data", elf::abi::SHT_REL, &rel, 2)]);
+        let (tbf, _) = convert(&elf, options(true)).unwrap();
+        let tbf = crate::tbf::Tbf::parse(&tbf).unwrap();
+        assert_eq!(tbf.app_binary().unwrap()[32..36], 8_u32.to_le_bytes());
+    }
+
+    #[test]
+    pub fn writes_relocation_length_in_elf_byte_order() {
+        let options = ConvertOptions {
+            quiet: true,
+            ..Default::default()
+        };
+        let rel: Vec<u8> = (0..8).collect();
+        let elf =
+            relocations_elf_with_byte_order(&[(".rel.data", elf::abi::SHT_REL, &rel, 2)], true);
+        let (tbf, _) = convert(&elf, options).unwrap();
+        // The TBF header stays little-endian, but the app reads the length.
+        let tbf = crate::tbf::Tbf::parse(&tbf).unwrap();
+        let binary = tbf.app_binary().unwrap();
+        assert_eq!(binary[32..36], 8_u32.to_be_bytes());
+        assert_eq!(&binary[36..44], &rel[..]);
+    }
+
+    #[test]
+    pub fn uses_fixed_addresses() {
+        let elf = test_elf(
+            &[TestSegment {
+                vaddr: 0x40080,
+                paddr: 0x40080,
+                flags: elf::abi::PF_R | elf::abi::PF_X,
+                sections: vec![progbits(".text", &[1; 16])],
+            }],
+            &[],
+        );
+        let options = |fixed_flash| ConvertOptions {
+            fixed_flash: Some(fixed_flash),
+            fixed_ram: Some(0x20004000),
+            quiet: true,
+            ..Default::default()
+        };
+        let (tbf, _) = convert(&elf, options(0x40080)).unwrap();
+        let tbf = crate::tbf::Tbf::parse(&tbf).unwrap();
+        let fixed_addresses = tbf
+            .tlv(crate::header::TbfHeaderTypes::FixedAddresses)
+            .unwrap();
+        assert_eq!(fixed_addresses.data[..4], 0x20004000_u32.to_le_bytes());
+        assert_eq!(tbf.fixed_address_flash(), Some(0x40080));
+
+        // A segment below the app is left out, even if it comes first.
+        let mut low_first = test_elf(
+            &[
+                TestSegment {
+                    vaddr: 0x40000,
+                    paddr: 0x40000,
+                    flags: elf::abi::PF_R,
+                    sections: vec![progbits(".rodata", &[2; 16])],
+                },
+                TestSegment {
+                    vaddr: 0x40080,
+                    paddr: 0x40080,
+                    flags: elf::abi::PF_R | elf::abi::PF_X,
+                    sections: vec![progbits(".text", &[1; 16])],
+                },
+            ],
+            &[],
+        );
+        low_first[24..28].copy_from_slice(&0x40080_u32.to_le_bytes());
+        let (tbf, _) = convert(&low_first, options(0x40080)).unwrap();
+        let tbf = crate::tbf::Tbf::parse(&tbf).unwrap();
+        assert_eq!(tbf.app_binary().unwrap()[..16], [1; 16]);
+
+        // The app binary would not start at the given address.
+        let error = convert(&elf, options(0x40040)).unwrap_err();
+        assert_eq!(ErrorCategory::of(&error), ErrorCategory::ElfParse);
+        assert!(error.to_string().contains("does not start at"));
+    }
+
+    #[test]
+    pub fn lays_out_ram_apps() {
+        let elf = test_elf(
+            &[
+                TestSegment {
+                    vaddr: 0x20000080,
+                    paddr: 0x20000080,
+                    flags: elf::abi::PF_R | elf::abi::PF_X,
+                    sections: vec![progbits(".text", &[1; 16])],
+                },
+                TestSegment {
+                    vaddr: 0x20000090,
+                    paddr: 0x20000090,
+                    flags: elf::abi::PF_R | elf::abi::PF_W,
+                    sections: vec![progbits(".data", &[4; 8]), nobits(".bss", 16)],
+                },
+            ],
+            &[],
+        );
+        let options = |ram_app| ConvertOptions {
+            ram_app,
+            quiet: true,
+            ..Default::default()
+        };
+        let (_, flash_info) = convert(&elf, options(false)).unwrap();
+        let (tbf, info) = convert(&elf, options(true)).unwrap();
+        let tbf = crate::tbf::Tbf::parse(&tbf).unwrap();
+
+        // The app runs from its lowest executable segment, with no flash
+        // address.
+        let fixed_addresses = tbf
+            .tlv(crate::header::TbfHeaderTypes::FixedAddresses)
+            .unwrap();
+        assert_eq!(fixed_addresses.data[..4], 0x20000080_u32.to_le_bytes());
+        assert_eq!(tbf.fixed_address_flash(), None);
+        // No protected region is added to align the TBF in flash.
+        assert_eq!(tbf.app_binary_offset(), tbf.header_size as u32);
+        let binary = tbf.app_binary().unwrap();
+        assert_eq!(binary[..16], [1; 16]);
+        assert_eq!(binary[16..24], [4; 8]);
+        // Every segment needs RAM.
+        assert_eq!(info.minimum_ram_size, flash_info.minimum_ram_size + 16 + 24);
+    }
+
+    #[test]
+    pub fn pads_non_pic_apps_to_alignment() {
+        let elf = |machine: u16| {
+            let mut elf = test_elf(
+                &[TestSegment {
+                    vaddr: 0x40080,
+                    paddr: 0x40080,
+                    flags: elf::abi::PF_R | elf::abi::PF_X,
+                    sections: vec![progbits(".text", &[1; 16])],
+                }],
+                &[],
+            );
+            elf[18..20].copy_from_slice(&machine.to_le_bytes());
+            elf
+        };
+        let options = |padding_mode| ConvertOptions {
+            padding_mode,
+            werror: true,
+            ..Default::default()
+        };
+
+        // RISC-V only pads to a multiple of 4 bytes by default.
+        let (tbf, _) = convert(&elf(elf::abi::EM_RISCV), options(None)).unwrap();
+        assert_eq!(tbf.len() % 256, 0);
+
+        // Without default padding the next app would not be aligned.
+        let error = convert(&elf(elf::abi::EM_AARCH64), options(None)).unwrap_err();
+        assert!(error.to_string().contains("--padding-mode multiple:256"));
+
+        // Unless that was asked for.
+        let (tbf, _) = convert(
+            &elf(elf::abi::EM_RISCV),
+            options(Some(TrailingPadding::Disabled)),
+        )
+        .unwrap();
+        assert_ne!(tbf.len() % 256, 0);
+    }
+
+    #[test]
+    pub fn flattens_elfs() {
+        let text = |address, contents: &[u8]| TestSegment {
+            vaddr: address,
+            paddr: address,
+            flags: elf::abi::PF_R | elf::abi::PF_X,
+            sections: vec![progbits(".text", contents)],
+        };
+        let app = test_elf(&[text(0x40080, &[1; 16])], &[]);
+        let path = std::env::temp_dir().join(format!("elf2tab-flatten-{}.elf", std::process::id()));
+        let convert_with = |other: Vec<u8>| {
+            std::fs::write(&path, other).unwrap();
+            let options = ConvertOptions {
+                flatten: vec![path.clone()],
+                fixed_flash: Some(0x40080),
+                quiet: true,
+                ..Default::default()
+            };
+            convert(&app, options)
+        };
+
+        // The other ELF is placed by its address, after padding.
+        let (tbf, _) = convert_with(test_elf(&[text(0x400a0, &[2; 8])], &[])).unwrap();
+        let tbf = crate::tbf::Tbf::parse(&tbf).unwrap();
+        let binary = tbf.app_binary().unwrap();
+        assert_eq!(binary[..16], [1; 16]);
+        assert_eq!(binary[16..32], [0; 16]);
+        assert_eq!(binary[32..40], [2; 8]);
+
+        let error = convert_with(test_elf(&[text(0x40088, &[2; 8])], &[])).unwrap_err();
+        assert!(error.to_string().contains("overlap"));
+
+        // RAM without contents in the other ELF.
+        let bss = TestSegment {
+            vaddr: 0x20000000,
+            paddr: 0x20000000,
+            flags: elf::abi::PF_R | elf::abi::PF_W,
+            sections: vec![nobits(".bss", 16)],
+        };
+        let error = convert_with(test_elf(&[text(0x400a0, &[2; 8]), bss], &[])).unwrap_err();
+        assert!(error.to_string().contains("16 bytes of RAM"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    pub fn checks_entry_point_alignment() {
+        let options = |entry_align| ConvertOptions {
+            entry_align,
+            quiet: true,
+            ..Default::default()
+        };
+        let mut elf = segments_elf();
+        elf[24..28].copy_from_slice(&0x80000002_u32.to_le_bytes());
+
+        // ARM entry points only need to be 2 byte aligned by default.
+        let (tbf, _) = convert(&elf, options(None)).unwrap();
+        let tbf = crate::tbf::Tbf::parse(&tbf).unwrap();
+        assert_eq!(tbf.init_fn_offset().unwrap() % 4, 2);
+
+        let error = convert(&elf, options(Some(4))).unwrap_err();
+        assert!(error.to_string().contains("is not aligned to 4 bytes"));
+
+        // The Thumb bit is not part of the address.
+        elf[24..28].copy_from_slice(&0x80000005_u32.to_le_bytes());
+        assert!(convert(&elf, options(Some(4))).is_ok());
+
+        // Fixed address apps are checked at the address they run from.
+        let mut elf = test_elf(
+            &[TestSegment {
+                vaddr: 0x40080,
+                paddr: 0x40080,
+                flags: elf::abi::PF_R | elf::abi::PF_X,
+                sections: vec![progbits(".text", &[1; 16])],
+            }],
+            &[],
+        );
+        elf[24..28].copy_from_slice(&0x40082_u32.to_le_bytes());
+        let error = convert(&elf, options(Some(4))).unwrap_err();
+        assert!(error.to_string().contains("Entry point at 0x40082"));
+    }
+
+    #[test]
+    pub fn duplicate_entry_point() {
+        // Both segments start at the entry point.
+        let elf = test_elf(
+            &[
+                TestSegment {
+                    vaddr: 0x80000000,
+                    paddr: 0x80000000,
+                    flags: elf::abi::PF_R | elf::abi::PF_X,
+                    sections: vec![progbits(".text", &[1; 16])],
+                },
+                TestSegment {
+                    vaddr: 0x80000000,
+                    paddr: 0x80000000,
+                    flags: elf::abi::PF_R | elf::abi::PF_X,
+                    sections: vec![progbits(".text.alias", &[2; 8])],
+                },
+            ],
+            &[],
+        );
+        let error = convert(&elf, ConvertOptions::default()).unwrap_err();
+        assert_eq!(ErrorCategory::of(&error), ErrorCategory::ElfParse);
+        assert!(error.to_string().contains("Duplicate entry point"));
+
+        let options = ConvertOptions {
+            allow_duplicate_entry: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let (tbf, _) = convert(&elf, options).unwrap();
+        let tbf = crate::tbf::Tbf::parse(&tbf).unwrap();
+        // The first segment is used.
+        assert_eq!(tbf.init_fn_offset(), Some(0));
+        assert_eq!(tbf.app_binary().unwrap()[..16], [1; 16]);
+
+        let options = ConvertOptions {
+            allow_duplicate_entry: true,
+            werror: true,
+            ..Default::default()
+        };
+        assert!(convert(&elf, options).is_err());
+    }
+
+    #[test]
+    pub fn reads_app_version_section() {
+        let elf = |app_version: &[u8]| {
+            test_elf(
+                &[TestSegment {
+                    vaddr: 0x80000000,
+                    paddr: 0x80000000,
+                    flags: elf::abi::PF_R | elf::abi::PF_X,
+                    sections: vec![progbits(".text", &[1; 16])],
+                }],
+                &[progbits(".app_version", app_version)],
+            )
+        };
+        let options = || ConvertOptions {
+            quiet: true,
+            ..Default::default()
+        };
+        let (tbf, _) = convert(&elf(&7_u32.to_le_bytes()), options()).unwrap();
+        let tbf = crate::tbf::Tbf::parse(&tbf).unwrap();
+        let program = tbf.tlv(crate::header::TbfHeaderTypes::Program).unwrap();
+        assert_eq!(program.data[16..20], 7_u32.to_le_bytes());
+
+        let error = convert(&elf(&[7, 0]), options()).unwrap_err();
+        assert_eq!(ErrorCategory::of(&error), ErrorCategory::ElfParse);
+        assert!(error.to_string().contains("found 2"));
+    }
+
+    /// A 32-bit ARM ELF with one executable segment at 0x1000 and no section
+    /// headers.
+    fn elf_without_sections(entry: u32) -> Vec<u8> {
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 1, 1, 1];
+        elf.resize(16, 0);
+        elf.extend(2_u16.to_le_bytes()); // e_type
+        elf.extend(40_u16.to_le_bytes()); // e_machine
+        elf.extend(1_u32.to_le_bytes()); // e_version
+        elf.extend(entry.to_le_bytes()); // e_entry
+        elf.extend(52_u32.to_le_bytes()); // e_phoff
+        elf.extend(0_u32.to_le_bytes()); // e_shoff
+        elf.extend(0_u32.to_le_bytes()); // e_flags
+        elf.extend(52_u16.to_le_bytes()); // e_ehsize
+        elf.extend(32_u16.to_le_bytes()); // e_phentsize
+        elf.extend(1_u16.to_le_bytes()); // e_phnum
+        elf.extend(40_u16.to_le_bytes()); // e_shentsize
+        elf.extend(0_u16.to_le_bytes()); // e_shnum
+        elf.extend(0_u16.to_le_bytes()); // e_shstrndx
+        for value in [elf::abi::PT_LOAD, 0, 0x1000, 0x1000, 84, 84, 5, 4] {
+            elf.extend(value.to_le_bytes());
+        }
+        elf
+    }
+
+    #[test]
+    pub fn check_elf_reports_all_problems() {
+        let problems = check_elf(&elf_without_sections(0x2000));
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].contains("no section headers"));
+        assert!(problems[1].contains("0x2000"));
+
+        let problems = check_elf(&elf_without_sections(0x1010));
+        assert_eq!(problems.len(), 1);
+
+        // The entry point in a segment that is only readable.
+        let mut elf = elf_without_sections(0x1010);
+        elf[76..80].copy_from_slice(&elf::abi::PF_R.to_le_bytes());
+        let problems = check_elf(&elf);
+        assert!(problems.iter().any(|p| p.contains("not executable")));
+
+        let problems = check_elf(b"not an elf");
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("not a valid ELF"));
+    }
+
+    #[test]
+    pub fn checked_u32_rejects_large_values() {
+        assert_eq!(checked_u32(0xffff_ffff_usize, "size").unwrap(), 0xffff_ffff);
+        let error = checked_u32(0x1_0000_0000_u64, "TBF total size").unwrap_err();
+        assert!(error.to_string().contains("TBF total size"));
+    }
+
+    #[test]
+    pub fn writeable_flash_regions_stay_in_app_binary() {
+        let region = |offset, size| vec![(".app_state.wfr".to_string(), offset, size)];
+        assert!(check_writeable_flash_regions(&region(0x100, 0x100), 0x100, 0x200).is_ok());
+        // Starting in the protected region.
+        let error = check_writeable_flash_regions(&region(0xfc, 0x10), 0x100, 0x200).unwrap_err();
+        assert!(error.to_string().contains("protected region"));
+        // Extending into the footer.
+        let error = check_writeable_flash_regions(&region(0x180, 0x100), 0x100, 0x200).unwrap_err();
+        assert!(error.to_string().contains(".app_state.wfr"));
+    }
+
+    #[test]
+    pub fn parses_wfr_erase_sizes() {
+        assert_eq!(wfr_erase_size(".wfr").unwrap(), None);
+        assert_eq!(wfr_erase_size(".app_state.wfr").unwrap(), None);
+        assert_eq!(wfr_erase_size(".wfr.4096").unwrap(), Some(4096));
+        assert_eq!(wfr_erase_size(".app_state.wfr.0x800").unwrap(), Some(0x800));
+        assert_eq!(wfr_erase_size(".wfr.config").unwrap(), None);
+        assert!(wfr_erase_size(".wfr.0").is_err());
+        assert!(wfr_erase_size(".wfr.4k").is_err());
+    }
+
+    #[test]
+    pub fn rejects_overlapping_segments() {
+        let mut segments = vec![
+            (0x40000, 0x40800, "the first ELF".to_string()),
+            (0x41000, 0x41100, "lib.elf".to_string()),
+        ];
+        assert!(check_overlapping_segments(&mut segments).is_ok());
+        segments.push((0x40700, 0x40900, "other.elf".to_string()));
+        let error = check_overlapping_segments(&mut segments).unwrap_err();
+        assert!(error.to_string().contains("other.elf"));
+        assert!(error.to_string().contains("the first ELF"));
+    }
+
+    #[test]
+    pub fn flash_segment_order() {
+        let mut text = segment();
+        text.p_paddr = 0x40000;
+        let mut data = segment();
+        data.p_paddr = 0x40100;
+        data.p_flags = elf::abi::PF_R | elf::abi::PF_W;
+        let bss = elf::segment::ProgramHeader {
+            p_paddr: 0x20000000,
+            p_filesz: 0,
+            ..data
+        };
+        assert!(check_flash_segment_order(&[text, data, bss], 0x40000).is_ok());
+
+        // Segments below the flash address are left out, and ones that start
+        // below it are truncated.
+        let mut below = data;
+        below.p_paddr = 0x3ff00;
+        assert!(check_flash_segment_order(&[below, text, data], 0x40000).is_ok());
+        below.p_paddr = 0x3ff80;
+        assert!(check_flash_segment_order(&[below, data], 0x40000).is_ok());
+
+        // A higher segment listed first.
+        let error = check_flash_segment_order(&[data, text], 0x40000).unwrap_err();
+        assert!(error.to_string().contains("does not start at"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    pub fn runs_credential_command() {
+        use crate::header::CommandCredentialType;
+
+        let covered: Vec<u8> = (0..64).collect();
+        let credential =
+            run_credential_command("head -c 32", &covered, CommandCredentialType::Sha256).unwrap();
+        assert_eq!(credential, &covered[..32]);
+
+        let error = run_credential_command("head -c 16", &covered, CommandCredentialType::Sha256)
+            .unwrap_err();
+        assert!(error.to_string().contains("returned 16 bytes"));
+        assert!(run_credential_command("false", &covered, CommandCredentialType::Sha256).is_err());
+        assert!(run_credential_command("", &covered, CommandCredentialType::Sha256).is_err());
+    }
 }
@@ -0,0 +1,106 @@
+//! Error categories, so scripts can tell failures apart by exit code.
+//!
+//! elf2tab passes errors around as `io::Error`. An error that belongs to a
+//! category carries an `Elf2TabError` as its inner error, which `main` uses to
+//! pick the exit code.
+
+use std::fmt;
+use std::io;
+
+/// What kind of problem stopped elf2tab. Each category has its own exit code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// Anything not covered below, for example failing to read a file.
+    Generic,
+    /// Invalid command line arguments. clap exits with the same code.
+    Arguments,
+    /// The input is not an ELF that elf2tab can convert.
+    ElfParse,
+    /// Reading the signing key or signing the TBF failed.
+    Signing,
+    /// A size or value does not fit where it has to go.
+    Size,
+}
+
+impl ErrorCategory {
+    /// The process exit code for this category.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorCategory::Generic => 1,
+            ErrorCategory::Arguments => 2,
+            ErrorCategory::ElfParse => 3,
+            ErrorCategory::Signing => 4,
+            ErrorCategory::Size => 5,
+        }
+    }
+
+    /// The category of `e`. Errors without an explicit category are treated
+    /// as bad arguments if their kind is `InvalidInput`, and as generic
+    /// otherwise.
+    pub fn of(e: &io::Error) -> Self {
+        match e
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<Elf2TabError>())
+        {
+            Some(error) => error.category,
+            None if e.kind() == io::ErrorKind::InvalidInput => ErrorCategory::Arguments,
+            None => ErrorCategory::Generic,
+        }
+    }
+}
+
+/// An error message tagged with its category.
+#[derive(Debug)]
+pub struct Elf2TabError {
+    pub category: ErrorCategory,
+    message: String,
+}
+
+impl Elf2TabError {
+    /// Create an `io::Error` of the given kind that carries `category`.
+    pub fn io_error<M: Into<String>>(
+        category: ErrorCategory,
+        kind: io::ErrorKind,
+        message: M,
+    ) -> io::Error {
+        io::Error::new(
+            kind,
+            Elf2TabError {
+                category,
+                message: message.into(),
+            },
+        )
+    }
+}
+
+impl fmt::Display for Elf2TabError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Elf2TabError {}
+
+#[cfg(test)]
+mod test {
+    use super::{Elf2TabError, ErrorCategory};
+    use std::io;
+
+    #[test]
+    pub fn categorizes_errors() {
+        let e = Elf2TabError::io_error(
+            ErrorCategory::Signing,
+            io::ErrorKind::InvalidData,
+            "bad key",
+        );
+        assert_eq!(ErrorCategory::of(&e), ErrorCategory::Signing);
+        assert_eq!(ErrorCategory::of(&e).exit_code(), 4);
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "bad key");
+
+        let e = io::Error::new(io::ErrorKind::InvalidInput, "no such section");
+        assert_eq!(ErrorCategory::of(&e), ErrorCategory::Arguments);
+        let e = io::Error::new(io::ErrorKind::NotFound, "no such file");
+        assert_eq!(ErrorCategory::of(&e).exit_code(), 1);
+    }
+}
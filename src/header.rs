@@ -1,7 +1,9 @@
+use crate::error::{Elf2TabError, ErrorCategory};
 use crate::util;
+use std::cmp;
 use std::fmt;
 use std::io;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{Seek, Write};
 use std::mem;
 use std::vec;
 use util::amount_alignment_needed;
@@ -10,6 +12,8 @@ use util::amount_alignment_needed;
 #[derive(Clone, Copy, Debug)]
 #[allow(dead_code)]
 pub enum TbfHeaderTypes {
+    /// Padding the kernel skips over.
+    Reserved = 0,
     Main = 1,
     WriteableFlashRegions = 2,
     PackageName = 3,
@@ -20,10 +24,48 @@ pub enum TbfHeaderTypes {
     KernelVersion = 8,
     Program = 9,
     ShortId = 10,
+    PermissionsDeny = 11,
+    MetadataHash = 12,
+    KernelVersionMinimum = 13,
+    VendorString = 14,
+    RamBreakdown = 15,
+    SecurityVersion = 16,
+    /// SHA256 of the ELF the TBF was created from.
+    ElfHash = 17,
 
     Credentials = 128,
 }
 
+impl TbfHeaderTypes {
+    /// The TLV type with the number `tipe`, if elf2tab knows it.
+    pub fn from_u16(tipe: u16) -> Option<Self> {
+        use TbfHeaderTypes::*;
+        [
+            Reserved,
+            Main,
+            WriteableFlashRegions,
+            PackageName,
+            PicOption1,
+            FixedAddresses,
+            Permissions,
+            Persistent,
+            KernelVersion,
+            Program,
+            ShortId,
+            PermissionsDeny,
+            MetadataHash,
+            KernelVersionMinimum,
+            VendorString,
+            RamBreakdown,
+            SecurityVersion,
+            ElfHash,
+            Credentials,
+        ]
+        .into_iter()
+        .find(|known| *known as u16 == tipe)
+    }
+}
+
 #[repr(u32)]
 #[derive(Clone, Copy, Debug)]
 #[allow(dead_code)]
@@ -34,6 +76,10 @@ pub enum TbfFooterCredentialsType {
     SHA256 = 3,
     SHA384 = 4,
     SHA512 = 5,
+    SHA3_256 = 6,
+    /// SHA256 of only the app binary, without the TBF header and protected
+    /// region.
+    SHA256Binary = 7,
 }
 
 #[repr(C)]
@@ -79,8 +125,15 @@ struct TbfHeaderWriteableFlashRegion {
     base: TbfHeaderTlv,
     offset: u32,
     size: u32,
+    /// Only included in the extended format (TLV length 12).
+    erase_size: u32,
 }
 
+/// TLV length of a writeable flash region without an erase size.
+const WFR_LENGTH: u16 = 8;
+/// TLV length of a writeable flash region with an erase size.
+const WFR_EXTENDED_LENGTH: u16 = 12;
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 struct TbfHeaderFixedAddresses {
@@ -131,6 +184,36 @@ struct TbfHeaderShortId {
     short_id: u32,
 }
 
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderSecurityVersion {
+    base: TbfHeaderTlv,
+    security_version: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderMetadataHash {
+    base: TbfHeaderTlv,
+    sha256: [u8; 32],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderElfHash {
+    base: TbfHeaderTlv,
+    sha256: [u8; 32],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderRamBreakdown {
+    base: TbfHeaderTlv,
+    stack_size: u32,
+    heap_size: u32,
+    segment_ram_size: u32,
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct TbfFooterCredentials {
@@ -206,7 +289,15 @@ impl fmt::Display for TbfHeaderWriteableFlashRegion {
                 offset: {0:>8} {0:>#10X}
                   size: {1:>8} {1:>#10X}",
             self.offset, self.size,
-        )
+        )?;
+        if self.base.length == WFR_EXTENDED_LENGTH {
+            writeln!(
+                f,
+                "            erase size: {0:>8} {0:>#10X}",
+                self.erase_size
+            )?;
+        }
+        Ok(())
     }
 }
 
@@ -224,13 +315,23 @@ impl fmt::Display for TbfHeaderFixedAddresses {
 
 impl fmt::Display for TbfHeaderPermissions {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(
-            f,
-            "
+        if let TbfHeaderTypes::PermissionsDeny = self.base.tipe {
+            writeln!(
+                f,
+                "
+        driver denials: {0:>8}
+               denials:   Number   Offset   Denied Bit Mask",
+                self.length,
+            )?;
+        } else {
+            writeln!(
+                f,
+                "
     driver permissions: {0:>8}
            permissions:   Number   Offset  Allowed Bit Mask",
-            self.length,
-        )?;
+                self.length,
+            )?;
+        }
 
         for perm in &self.perms {
             writeln!(
@@ -273,11 +374,15 @@ impl fmt::Display for TbfHeaderPersistentAcl {
 impl fmt::Display for TbfHeaderKernelVersion {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         // ^x.y means >= x.y, < (x+1).0
+        let requirement = match self.base.tipe {
+            TbfHeaderTypes::KernelVersionMinimum => ">=",
+            _ => "^",
+        };
         writeln!(
             f,
             "
-        kernel version: ^{}.{}",
-            self.major, self.minor
+        kernel version: {}{}.{}",
+            requirement, self.major, self.minor
         )
     }
 }
@@ -294,8 +399,289 @@ impl fmt::Display for TbfHeaderShortId {
     }
 }
 
+impl fmt::Display for TbfHeaderSecurityVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "
+      security_version: {0:>8} {0:>#10X}",
+            self.security_version
+        )
+    }
+}
+
+impl fmt::Display for TbfHeaderMetadataHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "
+  metadata.toml SHA256: "
+        )?;
+        for byte in self.sha256 {
+            write!(f, "{:02x}", byte)?;
+        }
+        writeln!(f)
+    }
+}
+
+impl fmt::Display for TbfHeaderElfHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "
+            ELF SHA256: "
+        )?;
+        for byte in self.sha256 {
+            write!(f, "{:02x}", byte)?;
+        }
+        writeln!(f)
+    }
+}
+
+impl fmt::Display for TbfHeaderRamBreakdown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "
+        RAM stack_size: {0:>8} {0:>#10X}
+         RAM heap_size: {1:>8} {1:>#10X}
+  RAM segment_ram_size: {2:>8} {2:>#10X}",
+            self.stack_size, self.heap_size, self.segment_ram_size,
+        )
+    }
+}
+
 const FLAGS_ENABLE: u32 = 0x0000_0001;
 
+/// Fold a list of (driver number, command number) pairs into the per-driver
+/// bitmasks used by the Permissions TLVs. Each entry covers 64 commands of a
+/// driver, starting at command `offset * 64`.
+fn fold_permissions(permissions: Vec<(u32, u32)>) -> Vec<TbfHeaderDriverPermission> {
+    let mut perms: Vec<TbfHeaderDriverPermission> = Vec::new();
+    for perm in permissions {
+        let offset = perm.1 / 64;
+        let allowed_command = 1 << (perm.1 % 64);
+        let mut complete = false;
+
+        for p in &mut perms {
+            if p.driver_number == perm.0 && p.offset == offset {
+                p.allowed_commands |= allowed_command;
+                complete = true;
+            }
+        }
+
+        if !complete {
+            perms.push(TbfHeaderDriverPermission {
+                driver_number: perm.0,
+                offset: perm.1 / 64,
+                allowed_commands: allowed_command,
+            })
+        }
+    }
+    perms
+}
+
+/// Size of a Permissions TLV (including the TLV header) holding `count`
+/// driver entries.
+fn permissions_tlv_size(count: usize) -> usize {
+    // base
+    mem::size_of::<TbfHeaderTlv>()
+        // length
+        + mem::size_of::<u16>()
+        // perms
+        + mem::size_of::<TbfHeaderDriverPermission>() * count
+        // padding
+        + 2
+}
+
+/// How the checksum field of the TBF header is computed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HeaderChecksum {
+    /// XOR of all header words, as the TBF format currently defines.
+    #[default]
+    Xor,
+    /// CRC-32 (IEEE 802.3) over the header bytes.
+    Crc32,
+}
+
+impl HeaderChecksum {
+    /// Compute the checksum of `header`, whose checksum field must be zero.
+    pub fn compute(self, header: &[u8]) -> u32 {
+        match self {
+            HeaderChecksum::Xor => header.chunks(4).fold(0, |checksum, chunk| {
+                // Combine the bytes back into a word, handling if we don't
+                // get a full word.
+                let mut word = 0;
+                for (i, c) in chunk.iter().enumerate() {
+                    word |= u32::from(*c) << (8 * i);
+                }
+                checksum ^ word
+            }),
+            HeaderChecksum::Crc32 => {
+                let mut crc = 0xFFFFFFFF_u32;
+                for byte in header {
+                    crc ^= u32::from(*byte);
+                    for _ in 0..8 {
+                        let mask = (crc & 1).wrapping_neg();
+                        crc = (crc >> 1) ^ (0xEDB88320 & mask);
+                    }
+                }
+                !crc
+            }
+        }
+    }
+
+    /// Compute the checksum of a big-endian `header` the way a big-endian
+    /// kernel would, reading the header words in big-endian order.
+    pub fn compute_big_endian(self, header: &[u8]) -> u32 {
+        match self {
+            // Reading every word in the other byte order swaps the bytes of
+            // the XOR of all of them.
+            HeaderChecksum::Xor => self.compute(header).swap_bytes(),
+            HeaderChecksum::Crc32 => self.compute(header),
+        }
+    }
+}
+
+/// Byte order of the multi-byte fields in the TBF header.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TbfEndian {
+    /// The byte order of the TBF format, which Tock kernels expect.
+    #[default]
+    Little,
+    /// Only for testing how kernels handle headers in the wrong byte order.
+    Big,
+}
+
+/// Reverse the byte order of every multi-byte field in a generated TBF header,
+/// whose fields are currently in `from` byte order. Strings and hashes are
+/// left as they are.
+pub fn swap_header_byte_order(header: &mut [u8], from: TbfEndian) {
+    let read_u16 = |header: &[u8], offset: usize| {
+        let bytes = [header[offset], header[offset + 1]];
+        match from {
+            TbfEndian::Little => u16::from_le_bytes(bytes),
+            TbfEndian::Big => u16::from_be_bytes(bytes),
+        }
+    };
+    let swap = |header: &mut [u8], offset: usize, width: usize| {
+        if let Some(field) = header.get_mut(offset..offset + width) {
+            field.reverse();
+        }
+    };
+
+    // Base header: version, header size, total size, flags, and checksum.
+    for (offset, width) in [(0, 2), (2, 2), (4, 4), (8, 4), (12, 4)] {
+        swap(header, offset, width);
+    }
+
+    let mut offset = mem::size_of::<TbfHeaderBase>();
+    while offset + mem::size_of::<TbfHeaderTlv>() <= header.len() {
+        let tipe = read_u16(header, offset);
+        let length = read_u16(header, offset + 2) as usize;
+        swap(header, offset, 2);
+        swap(header, offset + 2, 2);
+        let body = offset + mem::size_of::<TbfHeaderTlv>();
+        let end = cmp::min(body + length, header.len());
+
+        match TbfHeaderTypes::from_u16(tipe) {
+            Some(
+                TbfHeaderTypes::Main
+                | TbfHeaderTypes::WriteableFlashRegions
+                | TbfHeaderTypes::FixedAddresses
+                | TbfHeaderTypes::Program
+                | TbfHeaderTypes::ShortId
+                | TbfHeaderTypes::RamBreakdown
+                | TbfHeaderTypes::SecurityVersion,
+            ) => {
+                for field in (body..end).step_by(4) {
+                    swap(header, field, 4);
+                }
+            }
+            Some(TbfHeaderTypes::KernelVersion | TbfHeaderTypes::KernelVersionMinimum) => {
+                for field in (body..end).step_by(2) {
+                    swap(header, field, 2);
+                }
+            }
+            Some(TbfHeaderTypes::Permissions | TbfHeaderTypes::PermissionsDeny) => {
+                // The number of permissions, then the driver number, offset,
+                // and 64 bit command mask of each.
+                let count = read_u16(header, body) as usize;
+                swap(header, body, 2);
+                for permission in (0..count).map(|i| body + 2 + i * 16) {
+                    swap(header, permission, 4);
+                    swap(header, permission + 4, 4);
+                    swap(header, permission + 8, 8);
+                }
+            }
+            Some(TbfHeaderTypes::Persistent) => {
+                // The write ID, then the read IDs and the access IDs, each
+                // preceded by their number.
+                swap(header, body, 4);
+                let mut field = body + 4;
+                for _ in 0..2 {
+                    let count = read_u16(header, field) as usize;
+                    swap(header, field, 2);
+                    field += 2;
+                    for _ in 0..count {
+                        swap(header, field, 4);
+                        field += 4;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        offset = body + util::align_to(length as u32, 4) as usize;
+    }
+}
+
+/// Credential formats an external command can produce with
+/// `--credential-command`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandCredentialType {
+    Rsa3072,
+    Rsa4096,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl CommandCredentialType {
+    pub fn format(self) -> TbfFooterCredentialsType {
+        match self {
+            CommandCredentialType::Rsa3072 => TbfFooterCredentialsType::Rsa3072Key,
+            CommandCredentialType::Rsa4096 => TbfFooterCredentialsType::Rsa4096Key,
+            CommandCredentialType::Sha256 => TbfFooterCredentialsType::SHA256,
+            CommandCredentialType::Sha384 => TbfFooterCredentialsType::SHA384,
+            CommandCredentialType::Sha512 => TbfFooterCredentialsType::SHA512,
+        }
+    }
+
+    /// Length of the credential data. RSA credentials are the public key
+    /// modulus followed by the signature.
+    pub fn data_length(self) -> usize {
+        match self {
+            CommandCredentialType::Rsa3072 => 768,
+            CommandCredentialType::Rsa4096 => 1024,
+            CommandCredentialType::Sha256 => 32,
+            CommandCredentialType::Sha384 => 48,
+            CommandCredentialType::Sha512 => 64,
+        }
+    }
+
+    /// The name used for the credential in metadata.toml.
+    pub fn name(self) -> &'static str {
+        match self {
+            CommandCredentialType::Rsa3072 => "rsa3072",
+            CommandCredentialType::Rsa4096 => "rsa4096",
+            CommandCredentialType::Sha256 => "sha256",
+            CommandCredentialType::Sha384 => "sha384",
+            CommandCredentialType::Sha512 => "sha512",
+        }
+    }
+}
+
 pub struct TbfHeader {
     hdr_base: TbfHeaderBase,
     hdr_main: Option<TbfHeaderMain>,
@@ -304,11 +690,22 @@ pub struct TbfHeader {
     hdr_wfr: Vec<TbfHeaderWriteableFlashRegion>,
     hdr_fixed_addresses: Option<TbfHeaderFixedAddresses>,
     hdr_permissions: Option<TbfHeaderPermissions>,
+    hdr_permissions_deny: Option<TbfHeaderPermissions>,
     hdr_persistent: Option<TbfHeaderPersistentAcl>,
     hdr_kernel_version: Option<TbfHeaderKernelVersion>,
     hdr_short_id: Option<TbfHeaderShortId>,
+    hdr_metadata_hash: Option<TbfHeaderMetadataHash>,
+    hdr_elf_hash: Option<TbfHeaderElfHash>,
+    hdr_ram_breakdown: Option<TbfHeaderRamBreakdown>,
+    hdr_security_version: Option<TbfHeaderSecurityVersion>,
     package_name: String,
     package_name_pad: usize,
+    hdr_vendor_string_tlv: Option<TbfHeaderTlv>,
+    vendor_string: String,
+    vendor_string_pad: usize,
+    hdr_reserved_tlv: Option<TbfHeaderTlv>,
+    checksum_algorithm: HeaderChecksum,
+    endian: TbfEndian,
 }
 
 impl TbfHeader {
@@ -336,11 +733,22 @@ impl TbfHeader {
             hdr_wfr: Vec::new(),
             hdr_fixed_addresses: None,
             hdr_permissions: None,
+            hdr_permissions_deny: None,
             hdr_persistent: None,
             hdr_kernel_version: None,
             hdr_short_id: None,
+            hdr_metadata_hash: None,
+            hdr_elf_hash: None,
+            hdr_ram_breakdown: None,
+            hdr_security_version: None,
             package_name: String::new(),
             package_name_pad: 0,
+            hdr_vendor_string_tlv: None,
+            vendor_string: String::new(),
+            vendor_string_pad: 0,
+            hdr_reserved_tlv: None,
+            checksum_algorithm: HeaderChecksum::Xor,
+            endian: TbfEndian::Little,
         }
     }
 
@@ -350,7 +758,9 @@ impl TbfHeader {
     /// binary) can be passed in later after we know the size of the header.
     ///
     /// Returns: The length of the header in bytes. The length is guaranteed
-    ///          to be a multiple of 4.
+    ///          to be a multiple of 4. Fails if a TLV would not fit in its
+    ///          16 bit length field.
+    #[allow(clippy::too_many_arguments)]
     pub fn create(
         &mut self,
         minimum_ram_size: u32,
@@ -359,21 +769,34 @@ impl TbfHeader {
         fixed_address_ram: Option<u32>,
         fixed_address_flash: Option<u32>,
         permissions: Vec<(u32, u32)>,
+        denied_permissions: Vec<(u32, u32)>,
         storage_ids: (Option<u32>, Option<Vec<u32>>, Option<Vec<u32>>),
         kernel_version: Option<(u16, u16)>,
         short_id: Option<u32>,
         disabled: bool,
-    ) -> usize {
+        metadata_hash: Option<[u8; 32]>,
+        kernel_version_no_upper: bool,
+        vendor_string: Option<String>,
+        ram_breakdown: Option<(u32, u32, u32)>,
+        security_version: Option<u32>,
+        wfr_erase_sizes: Vec<Option<u32>>,
+    ) -> io::Result<usize> {
         // Need to calculate lengths ahead of time. Need the base and the
         // program section. For backwards compatibility we include both the main
         // and program header. The program header is preferred, and the
         // intention is for it to replace the main header. However, older Tock
         // kernels we support only recognize the main header, so we include it
         // as well. Newer kernels and other tools should use the program header
-        // and ignore the main header.
+        // and ignore the main header. The program header is only left out if
+        // `set_binary_end_offset()` was not called before this function, and
+        // the main header only if `remove_main_header()` was.
         let mut header_length = mem::size_of::<TbfHeaderBase>();
-        header_length += mem::size_of::<TbfHeaderMain>();
-        header_length += mem::size_of::<TbfHeaderProgram>();
+        if self.hdr_main.is_some() {
+            header_length += mem::size_of::<TbfHeaderMain>();
+        }
+        if self.hdr_program.is_some() {
+            header_length += mem::size_of::<TbfHeaderProgram>();
+        }
 
         // If we have a package name, add that section.
         self.package_name_pad = if !package_name.is_empty() {
@@ -388,8 +811,19 @@ impl TbfHeader {
             0
         };
 
-        // Add room for the writeable flash regions header TLV.
-        header_length += mem::size_of::<TbfHeaderWriteableFlashRegion>() * writeable_flash_regions;
+        // Add room for the writeable flash regions header TLVs. Regions with an
+        // erase size use the extended format.
+        let wfr_erase_sizes: Vec<Option<u32>> = (0..writeable_flash_regions)
+            .map(|i| wfr_erase_sizes.get(i).copied().flatten())
+            .collect();
+        for erase_size in &wfr_erase_sizes {
+            header_length += mem::size_of::<TbfHeaderTlv>()
+                + if erase_size.is_some() {
+                    WFR_EXTENDED_LENGTH
+                } else {
+                    WFR_LENGTH
+                } as usize;
+        }
 
         // Check if we are going to include the fixed address header. If so, we
         // need to make sure we include it in the length. If either address is
@@ -399,40 +833,57 @@ impl TbfHeader {
         }
 
         // Check to see how many perms we have
-        let mut perms: Vec<TbfHeaderDriverPermission> = Vec::new();
-        for perm in permissions {
-            let offset = perm.1 / 64;
-            let allowed_command = 1 << (perm.1 % 64);
-            let mut complete = false;
-
-            for p in &mut perms {
-                if p.driver_number == perm.0 && p.offset == offset {
-                    p.allowed_commands |= allowed_command;
-                    complete = true;
-                }
-            }
-
-            if !complete {
-                perms.push(TbfHeaderDriverPermission {
-                    driver_number: perm.0,
-                    offset: perm.1 / 64,
-                    allowed_commands: allowed_command,
-                })
-            }
+        let perms = fold_permissions(permissions);
+        if !perms.is_empty() {
+            header_length += permissions_tlv_size(perms.len());
         }
 
-        if !perms.is_empty() {
-            // base
-            header_length += mem::size_of::<TbfHeaderTlv>();
-            // length
-            header_length += mem::size_of::<u16>();
-            // perms
-            header_length += mem::size_of::<TbfHeaderDriverPermission>() * perms.len();
+        // Denied commands use the same layout in a separate TLV.
+        let denied_perms = fold_permissions(denied_permissions);
+        if !denied_perms.is_empty() {
+            header_length += permissions_tlv_size(denied_perms.len());
+        }
 
-            // Header length increases by that padding
-            header_length += 2;
+        // The Permissions TLV length covers the entry count and all driver
+        // entries, and must fit in a u16.
+        let max_permission_entries = (u16::MAX as usize - mem::size_of::<u16>())
+            / mem::size_of::<TbfHeaderDriverPermission>();
+        for (tlv_name, perms) in [("Permissions", &perms), ("PermissionsDeny", &denied_perms)] {
+            if perms.len() > max_permission_entries {
+                return Err(Elf2TabError::io_error(
+                    ErrorCategory::Size,
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} driver entries do not fit in the {} TLV (at most {} entries, one per driver and block of 64 commands)",
+                        perms.len(),
+                        tlv_name,
+                        max_permission_entries
+                    ),
+                ));
+            }
         }
 
+        // The Persistent ACL TLV length covers the write ID, both counts, and
+        // all read and access IDs, and must fit in a u16.
+        let read_ids_count = storage_ids.1.as_ref().map_or(0, |ids| ids.len());
+        let access_ids_count = storage_ids.2.as_ref().map_or(0, |ids| ids.len());
+        let persistent_length = (read_ids_count + access_ids_count)
+            .checked_mul(mem::size_of::<u32>())
+            .and_then(|ids_length| ids_length.checked_add(4 + 2 + 2))
+            .and_then(|length| u16::try_from(length).ok())
+            .ok_or_else(|| {
+                Elf2TabError::io_error(
+                    ErrorCategory::Size,
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} read IDs and {} access IDs do not fit in the Persistent ACL TLV (at most {} storage IDs in total)",
+                        read_ids_count,
+                        access_ids_count,
+                        (u16::MAX as usize - (4 + 2 + 2)) / mem::size_of::<u32>()
+                    ),
+                )
+            })?;
+
         if storage_ids.0.is_some() || storage_ids.1.is_some() || storage_ids.2.is_some() {
             // base
             header_length += mem::size_of::<TbfHeaderTlv>();
@@ -462,6 +913,39 @@ impl TbfHeader {
             header_length += mem::size_of::<TbfHeaderShortId>();
         }
 
+        // Check if we have to include a metadata hash header.
+        if metadata_hash.is_some() {
+            header_length += mem::size_of::<TbfHeaderMetadataHash>();
+        }
+
+        // The ELF hash is only included if `set_elf_hash()` was called before
+        // this function.
+        if self.hdr_elf_hash.is_some() {
+            header_length += mem::size_of::<TbfHeaderElfHash>();
+        }
+
+        // Check if we have to include the RAM breakdown header.
+        if ram_breakdown.is_some() {
+            header_length += mem::size_of::<TbfHeaderRamBreakdown>();
+        }
+
+        // Check if we have to include a security version header.
+        if security_version.is_some() {
+            header_length += mem::size_of::<TbfHeaderSecurityVersion>();
+        }
+
+        // If we have a vendor string, add that section. Like the package name
+        // it is padded so the header stays aligned to 4.
+        let vendor_string = vendor_string.unwrap_or_default();
+        self.vendor_string_pad = if !vendor_string.is_empty() {
+            header_length += mem::size_of::<TbfHeaderTlv>() + vendor_string.len();
+            let pad = amount_alignment_needed(header_length as u32, 4);
+            header_length += pad as usize;
+            pad as usize
+        } else {
+            0
+        };
+
         let mut flags = 0x0000_0000;
 
         if !disabled {
@@ -469,7 +953,17 @@ impl TbfHeader {
         };
 
         // Fill in the fields that we can at this point.
-        self.hdr_base.header_size = header_length as u16;
+        self.hdr_base.header_size = u16::try_from(header_length).map_err(|_| {
+            Elf2TabError::io_error(
+                ErrorCategory::Size,
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "TBF header would be {} bytes, more than the {} bytes header_size can hold",
+                    header_length,
+                    u16::MAX
+                ),
+            )
+        })?;
         self.hdr_base.flags = flags;
         self.set_minimum_ram_size(minimum_ram_size);
 
@@ -482,15 +976,29 @@ impl TbfHeader {
             });
         }
 
+        // If a vendor string exists, keep track of it and add it to the header.
+        self.vendor_string = vendor_string;
+        if !self.vendor_string.is_empty() {
+            self.hdr_vendor_string_tlv = Some(TbfHeaderTlv {
+                tipe: TbfHeaderTypes::VendorString,
+                length: self.vendor_string.len() as u16,
+            });
+        }
+
         // If there is an app state region, start setting up that header.
-        for _ in 0..writeable_flash_regions {
+        for erase_size in wfr_erase_sizes {
             self.hdr_wfr.push(TbfHeaderWriteableFlashRegion {
                 base: TbfHeaderTlv {
                     tipe: TbfHeaderTypes::WriteableFlashRegions,
-                    length: 8,
+                    length: if erase_size.is_some() {
+                        WFR_EXTENDED_LENGTH
+                    } else {
+                        WFR_LENGTH
+                    },
                 },
                 offset: 0,
                 size: 0,
+                erase_size: erase_size.unwrap_or(0),
             });
         }
 
@@ -517,11 +1025,24 @@ impl TbfHeader {
             });
         }
 
+        if !denied_perms.is_empty() {
+            self.hdr_permissions_deny = Some(TbfHeaderPermissions {
+                base: TbfHeaderTlv {
+                    tipe: TbfHeaderTypes::PermissionsDeny,
+                    length: (denied_perms.len() * mem::size_of::<TbfHeaderDriverPermission>())
+                        as u16
+                        + 2,
+                },
+                length: denied_perms.len() as u16,
+                perms: denied_perms,
+            });
+        }
+
         if storage_ids.0.is_some() || storage_ids.1.is_some() || storage_ids.2.is_some() {
             let mut hdr_persistent = TbfHeaderPersistentAcl {
                 base: TbfHeaderTlv {
                     tipe: TbfHeaderTypes::Persistent,
-                    length: 4 + 2 + 2,
+                    length: persistent_length,
                 },
                 write_id: 0,
                 read_length: 0,
@@ -535,13 +1056,11 @@ impl TbfHeader {
             }
 
             if let Some(read_ids) = storage_ids.1 {
-                hdr_persistent.base.length += (read_ids.len() as u16) * 4;
                 hdr_persistent.read_length = read_ids.len() as u16;
                 hdr_persistent.read_ids = read_ids;
             }
 
             if let Some(access_ids) = storage_ids.2 {
-                hdr_persistent.base.length += (access_ids.len() as u16) * 4;
                 hdr_persistent.access_length = access_ids.len() as u16;
                 hdr_persistent.access_ids = access_ids;
             }
@@ -551,11 +1070,16 @@ impl TbfHeader {
 
         // If the kernel version is set, we have to include the header.
         if let Some((kernel_major, kernel_minor)) = kernel_version {
+            // The KernelVersion TLV caps the major version. Apps that should
+            // run on all future kernels use a separate TLV type with the same
+            // layout that only sets a lower bound.
+            let tipe = if kernel_version_no_upper {
+                TbfHeaderTypes::KernelVersionMinimum
+            } else {
+                TbfHeaderTypes::KernelVersion
+            };
             self.hdr_kernel_version = Some(TbfHeaderKernelVersion {
-                base: TbfHeaderTlv {
-                    tipe: TbfHeaderTypes::KernelVersion,
-                    length: 4,
-                },
+                base: TbfHeaderTlv { tipe, length: 4 },
                 major: kernel_major,
                 minor: kernel_minor,
             });
@@ -572,11 +1096,47 @@ impl TbfHeader {
             });
         }
 
+        // If a metadata hash is given, we have to include the header.
+        if let Some(sha256) = metadata_hash {
+            self.hdr_metadata_hash = Some(TbfHeaderMetadataHash {
+                base: TbfHeaderTlv {
+                    tipe: TbfHeaderTypes::MetadataHash,
+                    length: 32,
+                },
+                sha256,
+            });
+        }
+
+        // If the RAM breakdown is given, we have to include the header.
+        if let Some((stack_size, heap_size, segment_ram_size)) = ram_breakdown {
+            self.hdr_ram_breakdown = Some(TbfHeaderRamBreakdown {
+                base: TbfHeaderTlv {
+                    tipe: TbfHeaderTypes::RamBreakdown,
+                    length: 12,
+                },
+                stack_size,
+                heap_size,
+                segment_ram_size,
+            });
+        }
+
+        // If a security version is given, we have to include the header.
+        if let Some(security_version) = security_version {
+            self.hdr_security_version = Some(TbfHeaderSecurityVersion {
+                base: TbfHeaderTlv {
+                    tipe: TbfHeaderTypes::SecurityVersion,
+                    length: 4,
+                },
+                security_version,
+            });
+        }
+
         // Return the length by generating the header and seeing how long it is.
-        self.generate()
+        Ok(self
+            .generate()
             .expect("No header was generated")
             .get_ref()
-            .len()
+            .len())
     }
 
     /// Update the header with the correct protected_size. protected_size should
@@ -616,24 +1176,50 @@ impl TbfHeader {
         }
     }
 
+    /// Set the minimum RAM size in only the Main header, leaving the Program
+    /// header unchanged. Must be called after `set_binary_end_offset()`, which
+    /// copies the Main header value into the Program header.
+    pub fn set_main_minimum_ram_size(&mut self, minimum_ram_size: u32) {
+        if let Some(ref mut main) = self.hdr_main {
+            main.minimum_ram_size = minimum_ram_size;
+        }
+    }
+
     /// Update the header with the correct binary end offset. If we did
     /// not have a Program Header, insert one. Note that this is the standard
     /// way to insert a Program Header.
     pub fn set_binary_end_offset(&mut self, binary_end_offset: u32) {
+        // Without a Main header, the Program header has the only copy of the
+        // values they share.
+        let Some(main) = self.hdr_main else {
+            if let Some(ref mut program) = self.hdr_program {
+                program.binary_end_offset = binary_end_offset;
+            }
+            return;
+        };
         self.hdr_program = Some(TbfHeaderProgram {
             base: TbfHeaderTlv {
                 tipe: TbfHeaderTypes::Program,
                 length: (mem::size_of::<TbfHeaderProgram>() - mem::size_of::<TbfHeaderTlv>())
                     as u16,
             },
-            init_fn_offset: self.hdr_main.map_or(0, |main| main.init_fn_offset),
-            protected_size: self.hdr_main.map_or(0, |main| main.protected_size),
-            minimum_ram_size: self.hdr_main.map_or(0, |main| main.minimum_ram_size),
+            init_fn_offset: main.init_fn_offset,
+            protected_size: main.protected_size,
+            minimum_ram_size: main.minimum_ram_size,
             binary_end_offset,
             app_version: 0,
         });
     }
 
+    /// Leave out the legacy Main header, which only kernels that predate the
+    /// Program header need. Must be called after `set_binary_end_offset()` and
+    /// before `create()`.
+    pub fn remove_main_header(&mut self) {
+        if self.hdr_program.is_some() {
+            self.hdr_main = None;
+        }
+    }
+
     pub fn binary_end_offset(&self) -> u32 {
         self.hdr_program
             .map_or(self.hdr_base.total_size, |program| {
@@ -647,6 +1233,80 @@ impl TbfHeader {
         }
     }
 
+    /// Pad the header created by `create()` to exactly `header_size` bytes
+    /// with a Reserved TLV. Fails if the header is already larger, or if the
+    /// padding cannot be expressed as a TLV.
+    ///
+    /// Returns: The new length of the header in bytes.
+    pub fn pad_to(&mut self, header_size: usize) -> io::Result<usize> {
+        let header_length = self.hdr_base.header_size as usize;
+        let padding = header_size.checked_sub(header_length).ok_or_else(|| {
+            Elf2TabError::io_error(
+                ErrorCategory::Size,
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "TBF header is {} bytes, more than the requested header size of {} bytes",
+                    header_length, header_size
+                ),
+            )
+        })?;
+        if padding == 0 {
+            return Ok(header_length);
+        }
+        if !header_size.is_multiple_of(4) || header_size > u16::MAX as usize {
+            return Err(Elf2TabError::io_error(
+                ErrorCategory::Size,
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "header size {} must be a multiple of 4 and at most {} bytes",
+                    header_size,
+                    u16::MAX - 3
+                ),
+            ));
+        }
+        self.hdr_reserved_tlv = Some(TbfHeaderTlv {
+            tipe: TbfHeaderTypes::Reserved,
+            length: (padding - mem::size_of::<TbfHeaderTlv>()) as u16,
+        });
+        self.hdr_base.header_size = header_size as u16;
+        Ok(header_size)
+    }
+
+    /// Record the SHA256 of the source ELF in an ElfHash TLV. This must be
+    /// called before `create()` so the TLV is counted in the header length.
+    pub fn set_elf_hash(&mut self, sha256: [u8; 32]) {
+        self.hdr_elf_hash = Some(TbfHeaderElfHash {
+            base: TbfHeaderTlv {
+                tipe: TbfHeaderTypes::ElfHash,
+                length: 32,
+            },
+            sha256,
+        });
+    }
+
+    /// Override the TBF version in the base header, for kernels that expect
+    /// something other than version 2.
+    pub fn set_base_version(&mut self, version: u16) {
+        self.hdr_base.version = version;
+    }
+
+    /// Override all flags in the base header, replacing the ones `create()`
+    /// set.
+    pub fn set_base_flags(&mut self, flags: u32) {
+        self.hdr_base.flags = flags;
+    }
+
+    /// Choose how the header checksum is computed. Defaults to XOR.
+    pub fn set_checksum_algorithm(&mut self, algorithm: HeaderChecksum) {
+        self.checksum_algorithm = algorithm;
+    }
+
+    /// Choose the byte order of the header. Defaults to little-endian, which
+    /// is what the TBF format specifies.
+    pub fn set_endian(&mut self, endian: TbfEndian) {
+        self.endian = endian;
+    }
+
     /// Update the header with appstate values if appropriate.
     pub fn set_writeable_flash_region_values(&mut self, offset: u32, size: u32) {
         for wfr in &mut self.hdr_wfr {
@@ -665,7 +1325,9 @@ impl TbfHeader {
 
         // Write all bytes to an in-memory file for the header.
         header_buf.write_all(unsafe { util::as_byte_slice(&self.hdr_base) })?;
-        header_buf.write_all(unsafe { util::as_byte_slice(&self.hdr_main) })?;
+        if let Some(main) = self.hdr_main {
+            header_buf.write_all(unsafe { util::as_byte_slice(&main) })?;
+        }
 
         if let Some(program) = self.hdr_program {
             header_buf.write_all(unsafe { util::as_byte_slice(&program) })?;
@@ -679,7 +1341,8 @@ impl TbfHeader {
 
         // Put all writeable flash region header elements in.
         for wfr in &self.hdr_wfr {
-            header_buf.write_all(unsafe { util::as_byte_slice(wfr) })?;
+            let length = mem::size_of::<TbfHeaderTlv>() + wfr.base.length as usize;
+            header_buf.write_all(&unsafe { util::as_byte_slice(wfr) }[..length])?;
         }
 
         // If there are fixed addresses, include that TLV.
@@ -687,8 +1350,11 @@ impl TbfHeader {
             header_buf.write_all(unsafe { util::as_byte_slice(&self.hdr_fixed_addresses) })?;
         }
 
-        // If there are permissions, include that TLV
-        if let Some(hdr_permissions) = &self.hdr_permissions {
+        // If there are permissions or denied permissions, include those TLVs
+        for hdr_permissions in [&self.hdr_permissions, &self.hdr_permissions_deny]
+            .into_iter()
+            .flatten()
+        {
             header_buf.write_all(unsafe { util::as_byte_slice(&hdr_permissions.base) })?;
             header_buf.write_all(unsafe { util::as_byte_slice(&hdr_permissions.length) })?;
             for perm in &hdr_permissions.perms {
@@ -721,47 +1387,69 @@ impl TbfHeader {
             header_buf.write_all(unsafe { util::as_byte_slice(&self.hdr_short_id) })?;
         }
 
+        // If the metadata hash is set, include that TLV
+        if let Some(hdr_metadata_hash) = &self.hdr_metadata_hash {
+            header_buf.write_all(unsafe { util::as_byte_slice(hdr_metadata_hash) })?;
+        }
+
+        // If the ELF hash is set, include that TLV
+        if let Some(hdr_elf_hash) = &self.hdr_elf_hash {
+            header_buf.write_all(unsafe { util::as_byte_slice(hdr_elf_hash) })?;
+        }
+
+        // If the vendor string is set, include that TLV
+        if let Some(hdr_ram_breakdown) = &self.hdr_ram_breakdown {
+            header_buf.write_all(unsafe { util::as_byte_slice(hdr_ram_breakdown) })?;
+        }
+
+        if let Some(hdr_security_version) = &self.hdr_security_version {
+            header_buf.write_all(unsafe { util::as_byte_slice(hdr_security_version) })?;
+        }
+
+        if let Some(hdr_vendor_string_tlv) = &self.hdr_vendor_string_tlv {
+            header_buf.write_all(unsafe { util::as_byte_slice(hdr_vendor_string_tlv) })?;
+            header_buf.write_all(self.vendor_string.as_ref())?;
+            util::do_pad(&mut header_buf, self.vendor_string_pad)?;
+        }
+
+        // Padding up to a fixed header size comes last.
+        if let Some(hdr_reserved_tlv) = &self.hdr_reserved_tlv {
+            header_buf.write_all(unsafe { util::as_byte_slice(hdr_reserved_tlv) })?;
+            util::do_pad(&mut header_buf, hdr_reserved_tlv.length as usize)?;
+        }
+
         let current_length = header_buf.get_ref().len();
         util::do_pad(
             &mut header_buf,
             amount_alignment_needed(current_length as u32, 4) as usize,
         )?;
 
-        self.inject_checksum(header_buf)
+        if self.endian == TbfEndian::Big {
+            swap_header_byte_order(header_buf.get_mut(), TbfEndian::Little);
+            let checksum = self
+                .checksum_algorithm
+                .compute_big_endian(header_buf.get_ref());
+            header_buf.get_mut()[12..16].copy_from_slice(&checksum.to_be_bytes());
+            return Ok(header_buf);
+        }
+
+        Self::inject_checksum(header_buf, self.checksum_algorithm)
     }
 
-    /// Take a TBF header and calculate the checksum. Then insert that checksum
-    /// into the actual binary.
-    fn inject_checksum(
-        &self,
+    /// Take a TBF header and calculate the checksum with `algorithm`. Then
+    /// insert that checksum into the actual binary.
+    ///
+    /// The checksum field in `header_buf` must be zero when this is called.
+    pub fn inject_checksum(
         mut header_buf: io::Cursor<vec::Vec<u8>>,
+        algorithm: HeaderChecksum,
     ) -> io::Result<io::Cursor<vec::Vec<u8>>> {
-        // Start from the beginning and iterate through the buffer as words.
-        header_buf.seek(SeekFrom::Start(0))?;
-        let mut wordbuf = [0_u8; 4];
-        let mut checksum: u32 = 0;
-        loop {
-            let count = header_buf.read(&mut wordbuf)?;
-            // Combine the bytes back into a word, handling if we don't
-            // get a full word.
-            let mut word = 0;
-            for (i, c) in wordbuf.iter().enumerate().take(count) {
-                word |= u32::from(*c) << (8 * i);
-            }
-            checksum ^= word;
-            if count != 4 {
-                break;
-            }
-        }
+        let checksum = algorithm.compute(header_buf.get_ref());
 
         // Now we need to insert the checksum into the correct position in the
         // header.
         header_buf.seek(io::SeekFrom::Start(12))?;
-        wordbuf[0] = (checksum & 0xFF) as u8;
-        wordbuf[1] = ((checksum >> 8) & 0xFF) as u8;
-        wordbuf[2] = ((checksum >> 16) & 0xFF) as u8;
-        wordbuf[3] = ((checksum >> 24) & 0xFF) as u8;
-        header_buf.write_all(&wordbuf)?;
+        header_buf.write_all(&checksum.to_le_bytes())?;
         header_buf.seek(io::SeekFrom::Start(0))?;
 
         Ok(header_buf)
@@ -784,6 +1472,9 @@ impl fmt::Display for TbfHeader {
         self.hdr_permissions
             .as_ref()
             .map_or(Ok(()), |hdr| write!(f, "{}", hdr))?;
+        self.hdr_permissions_deny
+            .as_ref()
+            .map_or(Ok(()), |hdr| write!(f, "{}", hdr))?;
         self.hdr_persistent
             .as_ref()
             .map_or(Ok(()), |hdr| write!(f, "{}", hdr))?;
@@ -791,6 +1482,565 @@ impl fmt::Display for TbfHeader {
             .map_or(Ok(()), |hdr| write!(f, "{}", hdr))?;
         self.hdr_short_id
             .map_or(Ok(()), |hdr| write!(f, "{}", hdr))?;
+        self.hdr_metadata_hash
+            .map_or(Ok(()), |hdr| write!(f, "{}", hdr))?;
+        self.hdr_elf_hash
+            .map_or(Ok(()), |hdr| write!(f, "{}", hdr))?;
+        self.hdr_ram_breakdown
+            .map_or(Ok(()), |hdr| write!(f, "{}", hdr))?;
+        self.hdr_security_version
+            .map_or(Ok(()), |hdr| write!(f, "{}", hdr))?;
+        if self.hdr_vendor_string_tlv.is_some() {
+            writeln!(
+                f,
+                "
+         vendor_string: {}",
+                self.vendor_string
+            )?;
+        }
+        if let Some(hdr_reserved_tlv) = &self.hdr_reserved_tlv {
+            writeln!(
+                f,
+                "
+              reserved: {0:>8} {0:>#10X}",
+                hdr_reserved_tlv.length
+            )?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{swap_header_byte_order, HeaderChecksum, TbfEndian, TbfHeader, TbfHeaderTypes};
+
+    #[test]
+    pub fn no_program_header() {
+        let mut tbfheader = TbfHeader::new();
+        let header_length = tbfheader
+            .create(
+                0,
+                0,
+                String::new(),
+                None,
+                None,
+                vec![],
+                vec![],
+                (None, None, None),
+                None,
+                None,
+                false,
+                None,
+                false,
+                None,
+                None,
+                None,
+                vec![],
+            )
+            .unwrap();
+        // Base header and Main header only.
+        assert_eq!(header_length, 16 + 16);
+        tbfheader.set_total_size(0x400);
+        let tbf = tbfheader.generate().unwrap().into_inner();
+        assert_eq!(u16::from_le_bytes([tbf[2], tbf[3]]), 32);
+    }
+
+    #[test]
+    pub fn vendor_string() {
+        let mut tbfheader = TbfHeader::new();
+        let header_length = tbfheader
+            .create(
+                0,
+                0,
+                String::new(),
+                None,
+                None,
+                vec![],
+                vec![],
+                (None, None, None),
+                None,
+                None,
+                false,
+                None,
+                false,
+                Some("acme 1.2".to_string()),
+                None,
+                None,
+                vec![],
+            )
+            .unwrap();
+        // Base header, Main header, and the 8 byte string with its TLV.
+        assert_eq!(header_length, 16 + 16 + 4 + 8);
+        let tbf = tbfheader.generate().unwrap().into_inner();
+        assert_eq!(&tbf[32..34], &14_u16.to_le_bytes());
+        assert_eq!(&tbf[34..36], &8_u16.to_le_bytes());
+        assert_eq!(&tbf[36..44], b"acme 1.2");
+
+        // Strings are padded to keep the header aligned.
+        let mut tbfheader = TbfHeader::new();
+        let header_length = tbfheader
+            .create(
+                0,
+                0,
+                String::new(),
+                None,
+                None,
+                vec![],
+                vec![],
+                (None, None, None),
+                None,
+                None,
+                false,
+                None,
+                false,
+                Some("acme!".to_string()),
+                None,
+                None,
+                vec![],
+            )
+            .unwrap();
+        assert_eq!(header_length, 16 + 16 + 4 + 8);
+        assert_eq!(tbfheader.generate().unwrap().get_ref().len(), header_length);
+    }
+
+    #[test]
+    pub fn ram_breakdown() {
+        let mut tbfheader = TbfHeader::new();
+        let header_length = tbfheader
+            .create(
+                0x1a00,
+                0,
+                String::new(),
+                None,
+                None,
+                vec![],
+                vec![],
+                (None, None, None),
+                None,
+                None,
+                false,
+                None,
+                false,
+                None,
+                Some((0x800, 0x1000, 0x200)),
+                None,
+                vec![],
+            )
+            .unwrap();
+        // Base header, Main header, and the three sizes with their TLV.
+        assert_eq!(header_length, 16 + 16 + 4 + 12);
+        let tbf = tbfheader.generate().unwrap().into_inner();
+        assert_eq!(&tbf[32..34], &15_u16.to_le_bytes());
+        assert_eq!(&tbf[34..36], &12_u16.to_le_bytes());
+        assert_eq!(&tbf[36..40], &0x800_u32.to_le_bytes());
+        assert_eq!(&tbf[40..44], &0x1000_u32.to_le_bytes());
+        assert_eq!(&tbf[44..48], &0x200_u32.to_le_bytes());
+        assert!(tbfheader.to_string().contains("RAM heap_size:     4096"));
+    }
+
+    #[test]
+    pub fn security_version() {
+        let mut tbfheader = TbfHeader::new();
+        let header_length = tbfheader
+            .create(
+                0,
+                0,
+                String::new(),
+                None,
+                None,
+                vec![],
+                vec![],
+                (None, None, None),
+                None,
+                None,
+                false,
+                None,
+                false,
+                None,
+                None,
+                Some(7),
+                vec![],
+            )
+            .unwrap();
+        // Base header, Main header, and the version with its TLV.
+        assert_eq!(header_length, 16 + 16 + 4 + 4);
+        let tbf = tbfheader.generate().unwrap().into_inner();
+        assert_eq!(&tbf[32..34], &16_u16.to_le_bytes());
+        assert_eq!(&tbf[34..36], &4_u16.to_le_bytes());
+        assert_eq!(&tbf[36..40], &7_u32.to_le_bytes());
+        assert!(tbfheader.to_string().contains("security_version:        7"));
+    }
+
+    #[test]
+    pub fn writeable_flash_region_erase_size() {
+        let mut tbfheader = TbfHeader::new();
+        let header_length = tbfheader
+            .create(
+                0,
+                2,
+                String::new(),
+                None,
+                None,
+                vec![],
+                vec![],
+                (None, None, None),
+                None,
+                None,
+                false,
+                None,
+                false,
+                None,
+                None,
+                None,
+                vec![None, Some(4096)],
+            )
+            .unwrap();
+        // Base header, Main header, a plain region, and an extended region.
+        assert_eq!(header_length, 16 + 16 + 12 + 16);
+        tbfheader.set_writeable_flash_region_values(0x100, 0x80);
+        tbfheader.set_writeable_flash_region_values(0x1000, 0x1000);
+        let tbf = tbfheader.generate().unwrap().into_inner();
+        assert_eq!(tbf.len(), header_length);
+        assert_eq!(&tbf[32..36], &[2, 0, 8, 0]);
+        assert_eq!(&tbf[44..48], &[2, 0, 12, 0]);
+        assert_eq!(&tbf[48..52], &0x1000_u32.to_le_bytes());
+        assert_eq!(&tbf[56..60], &4096_u32.to_le_bytes());
+        assert!(tbfheader.to_string().contains("erase size:     4096"));
+    }
+
+    #[test]
+    pub fn persistent_acl_length() {
+        let mut tbfheader = TbfHeader::new();
+        let header_length = tbfheader
+            .create(
+                0,
+                0,
+                String::new(),
+                None,
+                None,
+                vec![],
+                vec![],
+                (Some(1), Some(vec![2; 10000]), Some(vec![3; 2000])),
+                None,
+                None,
+                false,
+                None,
+                false,
+                None,
+                None,
+                None,
+                vec![],
+            )
+            .unwrap();
+        assert_eq!(header_length, 16 + 16 + 4 + 8 + 4 * 12000);
+
+        // The TLV length is a u16, so it can hold at most 16381 IDs.
+        let mut tbfheader = TbfHeader::new();
+        let error = tbfheader
+            .create(
+                0,
+                0,
+                String::new(),
+                None,
+                None,
+                vec![],
+                vec![],
+                (Some(1), Some(vec![2; 16000]), Some(vec![3; 382])),
+                None,
+                None,
+                false,
+                None,
+                false,
+                None,
+                None,
+                None,
+                vec![],
+            )
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("16000 read IDs and 382 access IDs"));
+    }
+
+    #[test]
+    pub fn persistent_acl_access_ids_only() {
+        let mut tbfheader = TbfHeader::new();
+        tbfheader
+            .create(
+                0,
+                0,
+                String::new(),
+                None,
+                None,
+                vec![],
+                vec![],
+                (None, None, Some(vec![2, 3])),
+                None,
+                None,
+                false,
+                None,
+                false,
+                None,
+                None,
+                None,
+                vec![],
+            )
+            .unwrap();
+        tbfheader.set_total_size(52);
+        let tbf = tbfheader.generate().unwrap().into_inner();
+        let parsed = crate::tbf::Tbf::parse(&tbf).unwrap();
+        let persistent = parsed.tlv(TbfHeaderTypes::Persistent).unwrap();
+        // write_id 0, no read IDs, then the two access IDs.
+        assert_eq!(
+            persistent.data,
+            &[0, 0, 0, 0, 0, 0, 2, 0, 2, 0, 0, 0, 3, 0, 0, 0][..]
+        );
+    }
+
+    #[test]
+    pub fn header_checksums() {
+        let data = b"123456789\0\0\0";
+        assert_eq!(
+            HeaderChecksum::Xor.compute(data),
+            0x34333231 ^ 0x38373635 ^ 0x39
+        );
+        assert_eq!(HeaderChecksum::Crc32.compute(&data[..9]), 0xCBF43926);
+    }
+
+    #[test]
+    pub fn pads_to_header_size() {
+        let mut tbfheader = TbfHeader::new();
+        tbfheader.set_binary_end_offset(0);
+        let header_length = tbfheader
+            .create(
+                0,
+                0,
+                String::new(),
+                None,
+                None,
+                vec![],
+                vec![],
+                (None, None, None),
+                None,
+                None,
+                false,
+                None,
+                false,
+                None,
+                None,
+                None,
+                vec![],
+            )
+            .unwrap();
+        assert_eq!(header_length, 16 + 16 + 24);
+        assert!(tbfheader.pad_to(48).is_err());
+        assert!(tbfheader.pad_to(126).is_err());
+        assert_eq!(tbfheader.pad_to(128).unwrap(), 128);
+
+        tbfheader.set_total_size(0x400);
+        let tbf = tbfheader.generate().unwrap().into_inner();
+        assert_eq!(tbf.len(), 128);
+        assert_eq!(u16::from_le_bytes([tbf[2], tbf[3]]), 128);
+        assert_eq!(&tbf[56..58], &0_u16.to_le_bytes());
+        assert_eq!(&tbf[58..60], &68_u16.to_le_bytes());
+        assert!(tbf[60..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    pub fn overrides_base_header() {
+        let mut tbfheader = TbfHeader::new();
+        tbfheader
+            .create(
+                0,
+                0,
+                String::new(),
+                None,
+                None,
+                vec![],
+                vec![],
+                (None, None, None),
+                None,
+                None,
+                false,
+                None,
+                false,
+                None,
+                None,
+                None,
+                vec![],
+            )
+            .unwrap();
+        tbfheader.set_base_version(3);
+        tbfheader.set_base_flags(0x8000_0002);
+        tbfheader.set_total_size(0x400);
+        let tbf = tbfheader.generate().unwrap().into_inner();
+        assert_eq!(&tbf[0..2], &3_u16.to_le_bytes());
+        assert_eq!(&tbf[8..12], &0x8000_0002_u32.to_le_bytes());
+        // The checksum covers the new values.
+        let mut unchecked = tbf.clone();
+        unchecked[12..16].fill(0);
+        assert_eq!(
+            &tbf[12..16],
+            &HeaderChecksum::Xor.compute(&unchecked).to_le_bytes()
+        );
+    }
+
+    #[test]
+    pub fn permissions_length() {
+        // The TLV length is a u16, so it can hold at most 4095 driver entries.
+        let mut tbfheader = TbfHeader::new();
+        let error = tbfheader
+            .create(
+                0,
+                0,
+                String::new(),
+                None,
+                None,
+                (0..4096).map(|driver| (driver, 0)).collect(),
+                vec![],
+                (None, None, None),
+                None,
+                None,
+                false,
+                None,
+                false,
+                None,
+                None,
+                None,
+                vec![],
+            )
+            .unwrap_err();
+        assert!(error.to_string().contains(
+            "4096 driver entries do not fit in the Permissions TLV (at most 4095 entries"
+        ));
+    }
+
+    #[test]
+    pub fn includes_elf_hash() {
+        let mut tbfheader = TbfHeader::new();
+        tbfheader.set_elf_hash([0xAB; 32]);
+        let header_length = tbfheader
+            .create(
+                0,
+                0,
+                String::new(),
+                None,
+                None,
+                vec![],
+                vec![],
+                (None, None, None),
+                None,
+                None,
+                false,
+                None,
+                false,
+                None,
+                None,
+                None,
+                vec![],
+            )
+            .unwrap();
+        assert_eq!(header_length, 16 + 16 + 36);
+        tbfheader.set_total_size(0x400);
+        let tbf = tbfheader.generate().unwrap().into_inner();
+        assert_eq!(tbf.len(), header_length);
+        assert_eq!(&tbf[32..34], &17_u16.to_le_bytes());
+        assert_eq!(&tbf[34..36], &32_u16.to_le_bytes());
+        assert_eq!(&tbf[36..68], &[0xAB; 32]);
+        assert!(tbfheader.to_string().contains("ELF SHA256: abab"));
+    }
+
+    #[test]
+    pub fn round_trips_big_endian_header() {
+        let header = |endian| {
+            let mut tbfheader = TbfHeader::new();
+            tbfheader
+                .create(
+                    0x1000,
+                    1,
+                    "app".to_string(),
+                    Some(0x20000000),
+                    Some(0x40000),
+                    vec![(1, 0), (2, 65)],
+                    vec![(3, 1)],
+                    (Some(7), Some(vec![7, 8]), Some(vec![9])),
+                    Some((2, 1)),
+                    Some(0x1234),
+                    false,
+                    None,
+                    false,
+                    Some("vendor".to_string()),
+                    Some((0x800, 0x400, 0x400)),
+                    Some(3),
+                    vec![None],
+                )
+                .unwrap();
+            tbfheader.set_writeable_flash_region_values(0x100, 0x200);
+            tbfheader.set_binary_end_offset(0x300);
+            tbfheader.set_total_size(0x400);
+            tbfheader.set_endian(endian);
+            tbfheader.generate().unwrap().into_inner()
+        };
+        let little = header(TbfEndian::Little);
+        let big = header(TbfEndian::Big);
+        assert_ne!(little, big);
+        assert_eq!(&big[0..2], &2_u16.to_be_bytes());
+        assert_eq!(&big[4..8], &0x400_u32.to_be_bytes());
+
+        // The checksum is what a big-endian kernel computes.
+        let mut unchecked = big.clone();
+        unchecked[12..16].fill(0);
+        assert_eq!(
+            HeaderChecksum::Xor.compute_big_endian(&unchecked),
+            u32::from_be_bytes([big[12], big[13], big[14], big[15]])
+        );
+
+        // Swapping back gives the little-endian header, apart from the
+        // checksum.
+        let mut swapped = big.clone();
+        swap_header_byte_order(&mut swapped, TbfEndian::Big);
+        assert_eq!(&swapped[..12], &little[..12]);
+        assert_eq!(&swapped[16..], &little[16..]);
+    }
+
+    #[test]
+    pub fn leaves_out_main_header() {
+        let mut tbfheader = TbfHeader::new();
+        tbfheader.set_binary_end_offset(0);
+        tbfheader.remove_main_header();
+        let header_length = tbfheader
+            .create(
+                0x1000,
+                0,
+                String::new(),
+                None,
+                None,
+                vec![],
+                vec![],
+                (None, None, None),
+                None,
+                None,
+                false,
+                None,
+                false,
+                None,
+                None,
+                None,
+                vec![],
+            )
+            .unwrap();
+        assert_eq!(header_length, 16 + 24);
+        tbfheader.set_protected_size(0);
+        tbfheader.set_init_fn_offset(0x10);
+        tbfheader.set_binary_end_offset(0x100);
+        tbfheader.set_total_size(0x100);
+        let tbf = tbfheader.generate().unwrap().into_inner();
+        assert_eq!(tbf.len(), header_length);
+        // The Program header comes right after the base header and keeps the
+        // values set before the final binary end offset.
+        assert_eq!(&tbf[16..18], &9_u16.to_le_bytes());
+        assert_eq!(&tbf[20..24], &0x10_u32.to_le_bytes());
+        assert_eq!(&tbf[28..32], &0x1000_u32.to_le_bytes());
+        assert_eq!(&tbf[32..36], &0x100_u32.to_le_bytes());
+    }
+}
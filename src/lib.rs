@@ -1,4 +1,10 @@
+pub mod capabilities;
 pub mod cmdline;
+pub mod config;
 pub mod convert;
+pub mod error;
 pub mod header;
+pub mod pkcs11;
+pub mod tbf;
+pub mod to_elf;
 pub mod util;
@@ -1,14 +1,310 @@
-use clap::Parser;
+use clap::{CommandFactory, Parser};
+use sha2::{Digest, Sha256, Sha512};
+use std::cmp;
+use std::collections::BTreeMap;
 use std::fmt::Write as fmtwrite;
 use std::fs;
 use std::io;
-use std::io::{Seek, Write};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
 
-use elf2tab::cmdline;
+use elf2tab::capabilities;
+use elf2tab::cmdline::{self, ChecksumAlgorithm, Credential};
+use elf2tab::config;
 use elf2tab::convert;
+use elf2tab::error::{Elf2TabError, ErrorCategory};
+use elf2tab::header;
+use elf2tab::tbf;
+use elf2tab::to_elf;
+use elf2tab::util;
 
 fn main() {
-    let opt = cmdline::Opt::parse();
+    let args = config::args_with_config(std::env::args_os().collect()).unwrap_or_else(|e| {
+        cmdline::Opt::command()
+            .error(clap::error::ErrorKind::Io, e)
+            .exit()
+    });
+    let mut opt = cmdline::Opt::parse_from(args);
+
+    // Commands work on existing TBFs and TABs instead of converting ELFs.
+    if let Some(command) = opt.command {
+        if let Err(e) = run_command(command) {
+            eprintln!("Error! {}", e);
+            process::exit(ErrorCategory::of(&e).exit_code());
+        }
+        return;
+    }
+
+    // `--release` is shorthand for the options that make the TBF smallest.
+    if opt.release {
+        opt.no_main_header = true;
+        opt.padding_mode = Some(convert::TrailingPadding::Disabled);
+    }
+
+    // `--sign-all-architectures-with` signs like `--rsa4096-private`, and
+    // remembers the public key to check every TBF's signature at the end.
+    let fleet_public_key = opt.sign_all_architectures_with.clone().map(|key| {
+        let key_pair = convert::read_rsa4096_key(&key).unwrap_or_else(|e| {
+            eprintln!("Error! {}", e);
+            process::exit(ErrorCategory::of(&e).exit_code());
+        });
+        opt.rsa4096_private_key = Some(key);
+        let public_key = ring::signature::KeyPair::public_key(&key_pair);
+        ring::signature::RsaPublicKeyComponents {
+            n: public_key
+                .modulus()
+                .big_endian_without_leading_zero()
+                .to_vec(),
+            e: public_key
+                .exponent()
+                .big_endian_without_leading_zero()
+                .to_vec(),
+        }
+    });
+
+    // Warnings from here on are printed, suppressed, or fatal depending on
+    // `--quiet` and `--werror`.
+    let warnings = util::Warnings::new(opt.quiet, opt.werror, opt.strict);
+    let warn = |msg: &str| {
+        if let Err(e) = warnings.warn(msg) {
+            eprintln!("Error! {}", e);
+            process::exit(1);
+        }
+    };
+
+    // With `-o -` the TAB goes to stdout, so reports that are normally printed
+    // there go to stderr instead.
+    let to_stdout = opt.output.as_os_str() == "-";
+    if to_stdout && opt.output_checksum.is_some() {
+        cmdline::Opt::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--output-checksum needs a TAB file to write the checksum next to, not -o -",
+            )
+            .exit();
+    }
+    let mut report_output: Box<dyn Write> = if to_stdout {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    };
+
+    let permissions = cmdline::expand_permissions(&opt.permissions);
+    let permissions_deny = cmdline::expand_permissions(&opt.permissions_deny);
+
+    // Catch permissions for commands the driver cannot have before they end
+    // up as a bit in the permissions mask that nothing checks.
+    if let Some(max_command) = opt.max_command {
+        let all_permissions = [&permissions[..], &permissions_deny[..]].concat();
+        if let Some((driver, command)) =
+            cmdline::permission_above_max_command(&all_permissions, max_command)
+        {
+            cmdline::Opt::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!(
+                        "permission {},{} is not below --max-command {}",
+                        driver, command, max_command
+                    ),
+                )
+                .exit();
+        }
+    }
+
+    // Catch typos in driver numbers, which would otherwise grant permissions
+    // for the wrong driver.
+    if let Some(path) = opt.valid_drivers.as_ref() {
+        let valid_drivers = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| util::parse_driver_list(&contents))
+            .unwrap_or_else(|e| {
+                cmdline::Opt::command()
+                    .error(
+                        clap::error::ErrorKind::InvalidValue,
+                        format!("could not read driver list {}: {}", path.display(), e),
+                    )
+                    .exit()
+            });
+        for (driver, command) in permissions.iter().chain(&permissions_deny) {
+            if !valid_drivers.contains(driver) {
+                cmdline::Opt::command()
+                    .error(
+                        clap::error::ErrorKind::ValueValidation,
+                        format!(
+                            "permission {},{} is for driver {} (0x{:x}) which is not in {}",
+                            driver,
+                            command,
+                            driver,
+                            driver,
+                            path.display()
+                        ),
+                    )
+                    .exit();
+            }
+        }
+    }
+
+    // Read the driver names for the capabilities report up front, so a bad
+    // file is reported before any conversion.
+    let driver_names = match opt.driver_names.as_ref() {
+        Some(path) => fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| capabilities::parse_driver_names(&contents))
+            .unwrap_or_else(|e| {
+                cmdline::Opt::command()
+                    .error(
+                        clap::error::ErrorKind::InvalidValue,
+                        format!("could not read driver names {}: {}", path.display(), e),
+                    )
+                    .exit()
+            }),
+        None => BTreeMap::new(),
+    };
+
+    // Permissions and storage IDs are only enforced by kernels that understand
+    // those TLVs. Without a minimum kernel version the app may end up on a
+    // kernel that silently ignores them.
+    let has_access_control = !opt.permissions.is_empty()
+        || !opt.permissions_deny.is_empty()
+        || opt.write_id.is_some()
+        || opt.read_ids.is_some()
+        || opt.access_ids.is_some();
+    if has_access_control && opt.kernel_major.is_none() {
+        let msg = "Permissions or storage IDs are set but no minimum kernel version is \
+                   given; use --kernel-major (and --kernel-minor) to require a kernel \
+                   that enforces them.";
+        if let Err(e) = warnings.warn_config(ErrorCategory::Arguments, msg) {
+            eprintln!("Error! {}", e);
+            process::exit(ErrorCategory::of(&e).exit_code());
+        }
+    }
+
+    // Extra files must not clash with the files elf2tab creates in the TAB or
+    // with each other.
+    for (i, (name, _)) in opt.include_files.iter().enumerate() {
+        let msg = if name == "metadata.toml" || name.ends_with(".tbf") {
+            format!(
+                "--include-file name `{}` is reserved for files created by elf2tab",
+                name
+            )
+        } else if opt.include_files[..i]
+            .iter()
+            .any(|(other, _)| other == name)
+        {
+            format!("--include-file name `{}` is used more than once", name)
+        } else {
+            continue;
+        };
+        cmdline::Opt::command()
+            .error(clap::error::ErrorKind::ValueValidation, msg)
+            .exit();
+    }
+
+    // Per-input RSA signatures need a key to sign with.
+    if opt.rsa4096_private_key.is_none()
+        && opt.pkcs11_uri.is_none()
+        && opt.input.iter().any(|elf_file| {
+            elf_file
+                .credentials
+                .as_ref()
+                .is_some_and(|credentials| credentials.contains(&Credential::Rsa4096))
+        })
+    {
+        cmdline::Opt::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "sign=rsa4096 needs --rsa4096-private or --pkcs11-uri",
+            )
+            .exit();
+    }
+
+    // SHA256 binary and SHA3-256 credentials use format numbers that are not
+    // allocated in the TBF specification yet.
+    for (credential, name) in [
+        (Credential::Sha256Binary, "sha256-binary"),
+        (Credential::Sha3_256, "sha3-256"),
+    ] {
+        if !opt.experimental_tlvs
+            && opt.input.iter().any(|elf_file| {
+                elf_file
+                    .credentials
+                    .as_ref()
+                    .is_some_and(|credentials| credentials.contains(&credential))
+            })
+        {
+            cmdline::Opt::command()
+                .error(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    format!("sign={} needs --experimental-tlvs", name),
+                )
+                .exit();
+        }
+    }
+
+    // Optionally check all ELFs before converting any of them, so every
+    // problem is reported at once.
+    if opt.check_elf {
+        let mut problems = Vec::new();
+        for elf_file in &opt.input {
+            match fs::read(&elf_file.path) {
+                Ok(elf_file_buf) => {
+                    for problem in convert::check_elf(&elf_file_buf) {
+                        problems.push(format!("{}: {}", elf_file.path.display(), problem));
+                    }
+                }
+                Err(e) => problems.push(format!("{}: {}", elf_file.path.display(), e)),
+            }
+        }
+        if !problems.is_empty() {
+            cmdline::Opt::command()
+                .error(
+                    clap::error::ErrorKind::InvalidValue,
+                    format!("ELF check failed:\n  {}", problems.join("\n  ")),
+                )
+                .exit();
+        }
+        if opt.verbose {
+            eprintln!("All ELFs passed the check.");
+        }
+    }
+
+    // With `--flatten` the other ELFs are merged into the TBF for the first
+    // one, so only that one is converted and named.
+    let flatten: Vec<PathBuf> = if opt.flatten {
+        if opt.input.len() < 2 {
+            cmdline::Opt::command()
+                .error(
+                    clap::error::ErrorKind::TooFewValues,
+                    "--flatten needs at least two ELFs to merge",
+                )
+                .exit();
+        }
+        opt.input
+            .split_off(1)
+            .into_iter()
+            .map(|elf_file| elf_file.path)
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    // Only show how the inputs would be named in the TAB.
+    if opt.list_architectures {
+        let mut seen = Vec::new();
+        for elf_file in &opt.input {
+            let architecture = resolve_architecture(elf_file, &opt.arch_map);
+            println!("{}.tbf: {}", architecture, elf_file.path.display());
+            if seen.contains(&architecture) {
+                warn(&format!(
+                    "More than one input resolves to {}.tbf.",
+                    architecture
+                ));
+            }
+            seen.push(architecture);
+        }
+        return;
+    }
 
     // Get app name from command line arguments or use empty string as default.
     let package_name = opt
@@ -27,141 +323,1007 @@ fn main() {
 
     // Create the metadata.toml file needed for the TAB file.
     let mut metadata_toml = String::new();
-    // TAB version is currently "1". This defines the general format, but
+    // TAB version defaults to "1". This defines the general format, but
     // key-value pairs can be added (or removed) and still be version 1.
-    writeln!(&mut metadata_toml, "tab-version = 1").unwrap();
+    // `--tab-version` only accepts versions elf2tab knows how to create.
+    writeln!(&mut metadata_toml, "tab-version = {}", opt.tab_version).unwrap();
     // Name is always set by elf2tab (even if it is empty).
     writeln!(&mut metadata_toml, "name = \"{}\"", package_name).unwrap();
     // Include "minimum-tock-kernel-version" key if a necessary kernel version
     // was specified.
-    minimum_tock_kernel_version.map(|(major, minor)| {
+    if let Some((major, minor)) = minimum_tock_kernel_version {
         writeln!(
             &mut metadata_toml,
             "minimum-tock-kernel-version = \"{}.{}\"",
             major, minor
         )
         .unwrap();
-    });
+    }
     // Include "only-for-boards" key if specific boards were specified.
-    opt.supported_boards.as_ref().map(|supported_boards| {
+    if let Some(supported_boards) = opt.supported_boards.as_ref() {
         writeln!(
             &mut metadata_toml,
             "only-for-boards = \"{}\"",
             supported_boards.as_str()
         )
         .unwrap();
-    });
+    }
+    // List any extra files added to the TAB.
+    if !opt.include_files.is_empty() {
+        let names: Vec<String> = opt
+            .include_files
+            .iter()
+            .map(|(name, _)| format!("\"{}\"", name))
+            .collect();
+        writeln!(
+            &mut metadata_toml,
+            "included-files = [{}]",
+            names.join(", ")
+        )
+        .unwrap();
+    }
     // Add build-date metadata unless a deterministic build is desired.
     if !opt.deterministic {
         let build_date = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
         writeln!(&mut metadata_toml, "build-date = {}", build_date).unwrap();
     }
 
-    // Start creating a tar archive which will be the .tab file.
-    let tab_name = fs::File::create(&opt.output).expect("Could not create the output file.");
-    let mut tab = tar::Builder::new(tab_name);
+    // Optionally bind each TBF to this exact metadata.toml by including its
+    // hash in the TBF header.
+    let metadata_hash: Option<[u8; 32]> = if opt.metadata_hash {
+        Some(Sha256::digest(metadata_toml.as_bytes()).into())
+    } else {
+        None
+    };
+
+    // Iterate all input elfs and convert them to Tock friendly binaries. They
+    // are added to the TAB file after the metadata, which records what the
+    // conversion created.
+    let multiple_inputs = opt.input.len() > 1;
+    let input_count = opt.input.len();
+    let mut tbfs: Vec<(String, PathBuf)> = Vec::new();
+    let mut tbf_credentials: Vec<(String, Vec<&'static str>)> = Vec::new();
+    let mut tbf_sizes: BTreeMap<String, usize> = BTreeMap::new();
+    let mut failed: Vec<(PathBuf, i32)> = Vec::new();
+    for (index, elf_file) in opt.input.into_iter().enumerate() {
+        // The TBF will be written to the same place as the ELF, with a .tbf
+        // extension.
+        let tbf_path = elf_file.path.with_extension("tbf");
+
+        let architecture = resolve_architecture(&elf_file, &opt.arch_map);
+
+        if opt.output == tbf_path {
+            eprintln!(
+                "Error! tab file {:?} and output file {:?} cannot be the same file",
+                opt.output, tbf_path
+            );
+            process::exit(ErrorCategory::Arguments.exit_code());
+        }
+
+        // Write the TBF to a temporary file first, and only replace the TBF
+        // once it is complete, so a failed conversion never leaves a partial
+        // TBF behind that a later build could mistake for a valid one.
+        let tbf_temp_path = temp_path(&tbf_path);
+
+        // Do the conversion to a tock binary. Signing many TBFs can take a
+        // while, so optionally show how far along we are.
+        if opt.verbose || opt.progress {
+            eprintln!(
+                "[{}/{}] Converting {}...",
+                index + 1,
+                input_count,
+                architecture
+            );
+        }
+        if opt.verbose {
+            eprintln!("Creating {:?}", tbf_path);
+        }
+        // Credentials chosen for this input replace the global ones.
+        let credential_enabled = |credential: Credential, global: bool| {
+            elf_file
+                .credentials
+                .as_ref()
+                .map_or(global, |credentials| credentials.contains(&credential))
+        };
+        let rsa4096_enabled = credential_enabled(Credential::Rsa4096, true);
+
+        // Settings for converting this input.
+        let options = convert::ConvertOptions {
+            package_name: opt.package_name.clone(),
+            verbose: opt.verbose,
+            stack_len: elf_file.stack_size.or(opt.stack_size),
+            app_heap_len: opt.app_heap_size,
+            kernel_heap_len: opt.kernel_heap_size,
+            protected_region_size_arg: opt.protected_region_size,
+            permissions: permissions.clone(),
+            denied_permissions: permissions_deny.clone(),
+            storage_ids: (opt.write_id, opt.read_ids.clone(), opt.access_ids.clone()),
+            kernel_version: minimum_tock_kernel_version,
+            short_id: opt.short_id,
+            disabled: opt.disabled,
+            minimum_footer_size: opt.minimum_footer_size,
+            app_version: opt.app_version,
+            sha256: credential_enabled(Credential::Sha256, opt.sha256_enable),
+            sha384: credential_enabled(Credential::Sha384, opt.sha384_enable),
+            sha512: credential_enabled(Credential::Sha512, opt.sha512_enable),
+            sha3_256: credential_enabled(Credential::Sha3_256, opt.sha3_256_enable),
+            rsa4096_private_key: opt.rsa4096_private_key.clone().filter(|_| rsa4096_enabled),
+            padding_warn_threshold: opt.padding_warn_threshold,
+            footer_align: opt.footer_align,
+            pkcs11_uri: opt.pkcs11_uri.clone().filter(|_| rsa4096_enabled),
+            raw_header: opt.raw_header,
+            quiet: opt.quiet,
+            append_binary: opt.append_binary.clone(),
+            main_ram_override: opt.main_ram_override,
+            metadata_hash,
+            kernel_version_no_upper: opt.kernel_version_no_upper,
+            include_sections: opt.include_sections.clone(),
+            exclude_sections: opt.exclude_sections.clone(),
+            allow_duplicate_entry: opt.allow_duplicate_entry,
+            entry_align: opt.entry_align,
+            no_trailing_padding: false,
+            no_program_header: opt.no_program_header,
+            vendor_string: opt.vendor_string.clone(),
+            werror: opt.werror,
+            protected_contents: opt.protected_contents.clone(),
+            padding_mode: opt.padding_mode,
+            ram_breakdown: opt.ram_breakdown,
+            flatten: flatten.clone(),
+            security_version: opt.security_version,
+            omit_empty_relocation: opt.omit_empty_relocation,
+            header_only: opt.header_only,
+            report_symbol_offsets: opt.symbol_offset_report.is_some(),
+            total_ram: opt.total_ram,
+            header_checksum: opt.header_checksum,
+            integrity_end: opt.integrity_end,
+            board_ram: opt.board_ram,
+            sha256_binary: credential_enabled(Credential::Sha256Binary, opt.sha256_binary_enable),
+            header_size: opt.header_size,
+            pic_from_elf_type: opt.pic_from_elf_type,
+            ram_app: opt.ram_app,
+            fixed_flash: opt.fixed_flash,
+            fixed_ram: opt.fixed_ram,
+            base_version: opt.base_version,
+            base_flags: opt.base_flags,
+            embed_elf_hash: opt.embed_elf_hash,
+            credential_command: opt
+                .credential_command
+                .clone()
+                .zip(opt.credential_command_type),
+            strict: opt.strict,
+            report_section_hashes: opt.section_hashes.is_some(),
+            ram_pow2: opt.ram_pow2,
+            tbf_endian: opt.tbf_endian,
+            no_main_header: opt.no_main_header,
+            record_wfr_erase_sizes: opt.wfr_erase_sizes,
+        };
+
+        // The ELF may be converted more than once with different settings.
+        let convert_elf = |fsfile: &mut fs::File,
+                           mut output: &mut dyn Write,
+                           verbose: bool,
+                           quiet: bool,
+                           no_trailing_padding: bool| {
+            convert::elf_to_tbf(
+                fsfile,
+                &mut output,
+                convert::ConvertOptions {
+                    verbose,
+                    quiet,
+                    no_trailing_padding,
+                    ..options.clone()
+                },
+            )
+        };
+        // Any failure below leaves the previous TBF alone. With `--keep-going`
+        // the other inputs are still converted.
+        let converted: Result<convert::TbfInfo, i32> = 'convert: {
+            let mut fsfile = match fs::File::open(&elf_file.path) {
+                Ok(fsfile) => fsfile,
+                Err(e) => {
+                    eprintln!("Failed to open {:?}: {}", elf_file.path, e);
+                    break 'convert Err(ErrorCategory::Generic.exit_code());
+                }
+            };
+            let mut outfile = match fs::File::create(&tbf_temp_path) {
+                Ok(outfile) => outfile,
+                Err(e) => {
+                    eprintln!("Failed to create {:?}: {}", tbf_path, e);
+                    break 'convert Err(ErrorCategory::Generic.exit_code());
+                }
+            };
+            // `elf_to_tbf()` buffers the TBF itself if a credential needs to
+            // hash it, so the TBF can be written straight to the file.
+            let mut writer = io::BufWriter::new(&mut outfile);
+            let info = match convert_elf(&mut fsfile, &mut writer, opt.verbose, opt.quiet, false)
+                .and_then(|info| writer.flush().map(|_| info))
+            {
+                Ok(info) => info,
+                Err(e) => {
+                    eprintln!("Failed to create {:?}: {}", tbf_path, e);
+                    break 'convert Err(ErrorCategory::of(&e).exit_code());
+                }
+            };
+            drop(writer);
+            drop(outfile);
+            // Production builds must not end up unsigned because a key option
+            // was forgotten.
+            if opt.require_signature && !info.has_signature() {
+                eprintln!(
+                    "Failed to create {:?}: no signature credential was added, but --require-signature was given",
+                    tbf_path
+                );
+                break 'convert Err(ErrorCategory::Signing.exit_code());
+            }
+            // The package name goes into both metadata.toml and the TBF
+            // header, so make sure they still agree. `--raw-header` takes the
+            // header from the ELF as is, and headers the parser cannot read
+            // (for example big-endian ones) are not checked.
+            if !opt.raw_header {
+                let tbf_bytes = match fs::read(&tbf_temp_path) {
+                    Ok(tbf_bytes) => tbf_bytes,
+                    Err(e) => {
+                        eprintln!("Failed to read back {:?}: {}", tbf_path, e);
+                        break 'convert Err(ErrorCategory::Generic.exit_code());
+                    }
+                };
+                if let Ok(parsed) = tbf::Tbf::parse(&tbf_bytes) {
+                    let header_name = parsed
+                        .tlv(header::TbfHeaderTypes::PackageName)
+                        .map_or(&[][..], |tlv| tlv.data);
+                    if header_name != package_name.as_bytes() {
+                        eprintln!(
+                            "Failed to create {:?}: PackageName TLV does not match the name in metadata.toml",
+                            tbf_path
+                        );
+                        break 'convert Err(ErrorCategory::Generic.exit_code());
+                    }
+                }
+            }
+            if let Err(e) = fs::rename(&tbf_temp_path, &tbf_path) {
+                eprintln!("Failed to create {:?}: {}", tbf_path, e);
+                break 'convert Err(1);
+            }
+            Ok(info)
+        };
+        let info = match converted {
+            Ok(info) => info,
+            Err(exit_code) => {
+                let _ = fs::remove_file(&tbf_temp_path);
+                if !opt.keep_going {
+                    process::exit(exit_code);
+                }
+                failed.push((elf_file.path, exit_code));
+                continue;
+            }
+        };
+        let sizes = &info.sizes;
+        // Name the TBF in the TAB, by default after the architecture.
+        let tab_tbf_name = tbf_name(
+            opt.tbf_name_pattern.as_deref().unwrap_or("{arch}.tbf"),
+            &architecture,
+            package_name,
+            info.app_version,
+        );
+        if opt.verbose {
+            eprintln!();
+        }
+
+        // The TBF is converted, now write everything else that is asked for.
+        // With `--keep-going` an input whose outputs fail is left out of the
+        // TAB, like one that fails to convert.
+        let written: Result<(), i32> = 'outputs: {
+            // Optionally convert the ELF again and make sure the result is exactly
+            // the same, to catch any nondeterminism in the conversion.
+            if opt.reproducible_check {
+                let reconverted = fs::read(&tbf_path).and_then(|first| {
+                    let mut fsfile = fs::File::open(&elf_file.path)?;
+                    let mut second = Vec::<u8>::new();
+                    convert_elf(&mut fsfile, &mut second, false, true, false)?;
+                    Ok((first, second))
+                });
+                let (first, second) = match reconverted {
+                    Ok(tbfs) => tbfs,
+                    Err(e) => {
+                        eprintln!("Failed to convert {:?} again: {}", elf_file.path, e);
+                        break 'outputs Err(ErrorCategory::of(&e).exit_code());
+                    }
+                };
+                if first != second {
+                    let offset = first
+                        .iter()
+                        .zip(&second)
+                        .position(|(a, b)| a != b)
+                        .unwrap_or(cmp::min(first.len(), second.len()));
+                    eprintln!(
+                        "Error! Converting {:?} twice produced different TBFs ({} and {} bytes, first difference at offset {:#x}).",
+                        elf_file.path,
+                        first.len(),
+                        second.len(),
+                        offset
+                    );
+                    break 'outputs Err(ErrorCategory::Generic.exit_code());
+                }
+                if opt.verbose {
+                    eprintln!(
+                        "Converting {:?} again produced an identical TBF.",
+                        elf_file.path
+                    );
+                }
+            }
+
+            // Optionally report how much flash the app needs, so it can be matched
+            // to a flash slot without trailing padding hiding the real size.
+            if opt.flash_size_report {
+                writeln!(report_output, "{}:", tab_tbf_name).unwrap();
+                writeln!(report_output, "  binary:   {:>8} bytes", sizes.binary_size).unwrap();
+                writeln!(report_output, "  footer:   {:>8} bytes", sizes.footer_size).unwrap();
+                writeln!(
+                    report_output,
+                    "  required: {:>8} bytes",
+                    sizes.required_size()
+                )
+                .unwrap();
+                writeln!(
+                    report_output,
+                    "  padding:  {:>8} bytes",
+                    sizes.padding_size()
+                )
+                .unwrap();
+                writeln!(report_output, "  total:    {:>8} bytes", sizes.total_size).unwrap();
+                if sizes.protected_overhead > 0 {
+                    writeln!(
+                        report_output,
+                        "  protected region overhead: {} bytes",
+                        sizes.protected_overhead
+                    )
+                    .unwrap();
+                }
+            }
+
+            // Optionally report the size of the debug information, which is not
+            // part of the TBF, to track its growth.
+            if opt.report_debug_size {
+                writeln!(report_output, "{} debug sections:", tab_tbf_name).unwrap();
+                for (name, size) in &info.debug_sections {
+                    writeln!(report_output, "  {:<20} {:>8} bytes", name, size).unwrap();
+                }
+                let total: u64 = info.debug_sections.iter().map(|(_, size)| size).sum();
+                writeln!(report_output, "  {:<20} {:>8} bytes", "total", total).unwrap();
+            }
+
+            // Optionally record where each symbol ended up in the TBF.
+            if let Some(symbol_offset_report) = &opt.symbol_offset_report {
+                let report_path = output_path(symbol_offset_report, &architecture, multiple_inputs);
+                let mut report = String::from("symbol_name, elf_vaddr, tbf_offset\n");
+                let mut symbol_offsets = info.symbol_offsets.clone();
+                symbol_offsets.sort_by_key(|(_, _, offset)| *offset);
+                for (name, address, offset) in &symbol_offsets {
+                    writeln!(&mut report, "{}, {:#x}, {:#x}", name, address, offset).unwrap();
+                }
+                if let Err(e) = fs::write(&report_path, report) {
+                    eprintln!("Failed to write symbol offset report: {:?}", e);
+                    break 'outputs Err(ErrorCategory::Generic.exit_code());
+                }
+                if opt.verbose {
+                    eprintln!("Wrote symbol offsets to {:?}", report_path);
+                }
+            }
+
+            // Optionally record a hash of each section for per-section updates.
+            if let Some(section_hashes) = &opt.section_hashes {
+                let report_path = output_path(section_hashes, &architecture, multiple_inputs);
+                let mut report = String::from("section_name, offset_in_tbf, size, sha256\n");
+                for (name, offset, size, hash) in &info.section_hashes {
+                    write!(&mut report, "{}, {:#x}, {:#x}, ", name, offset, size).unwrap();
+                    for byte in hash {
+                        write!(&mut report, "{:02x}", byte).unwrap();
+                    }
+                    report.push('\n');
+                }
+                if let Err(e) = fs::write(&report_path, report) {
+                    eprintln!("Failed to write section hashes: {:?}", e);
+                    break 'outputs Err(ErrorCategory::Generic.exit_code());
+                }
+                if opt.verbose {
+                    eprintln!("Wrote section hashes to {:?}", report_path);
+                }
+            }
+
+            // Optionally save the footer on its own.
+            if let Some(footer_output) = &opt.footer_output {
+                let footer_path = output_path(footer_output, &architecture, multiple_inputs);
+                if let Err(e) = fs::read(&tbf_path).and_then(|output_vector| {
+                    fs::write(&footer_path, &output_vector[info.footer_offset..])
+                }) {
+                    eprintln!("Failed to write footer: {:?}", e);
+                    break 'outputs Err(ErrorCategory::Generic.exit_code());
+                }
+                if opt.verbose {
+                    eprintln!("Wrote footer to {:?}", footer_path);
+                }
+            }
+
+            // Optionally create a second TBF without trailing padding to see the
+            // real size of the app. Only the padded TBF goes into the TAB.
+            if let Some(unpadded_output) = &opt.also_emit_unpadded {
+                let mut unpadded_vector = Vec::<u8>::new();
+                if let Err(e) = fs::File::open(&elf_file.path).and_then(|mut fsfile| {
+                    convert_elf(&mut fsfile, &mut unpadded_vector, false, true, true)
+                }) {
+                    eprintln!("Failed to create unpadded TBF: {}", e);
+                    break 'outputs Err(ErrorCategory::of(&e).exit_code());
+                }
+                let unpadded_path = output_path(unpadded_output, &architecture, multiple_inputs);
+                if let Err(e) = fs::write(&unpadded_path, &unpadded_vector) {
+                    eprintln!("Failed to write unpadded TBF: {:?}", e);
+                    break 'outputs Err(ErrorCategory::Generic.exit_code());
+                }
+                if opt.verbose {
+                    eprintln!(
+                        "Wrote unpadded TBF ({} bytes) to {:?}",
+                        unpadded_vector.len(),
+                        unpadded_path
+                    );
+                }
+            }
+            Ok(())
+        };
+        if let Err(exit_code) = written {
+            if !opt.keep_going {
+                process::exit(exit_code);
+            }
+            failed.push((elf_file.path, exit_code));
+            continue;
+        }
+
+        tbf_sizes.insert(architecture.clone(), info.sizes.total_size);
+        if !tbf_credentials
+            .iter()
+            .any(|(name, _)| *name == architecture)
+        {
+            tbf_credentials.push((architecture, info.credentials));
+        }
+        tbfs.push((tab_tbf_name, tbf_path));
+    }
+
+    // Report the inputs `--keep-going` skipped. The TAB still gets the TBFs that
+    // were created, unless there are none.
+    let report_failed = || {
+        eprintln!(
+            "Error! Failed to convert {} of {} inputs:",
+            failed.len(),
+            input_count
+        );
+        for (path, _) in &failed {
+            eprintln!("  {}", path.display());
+        }
+    };
+    if tbfs.is_empty() && !failed.is_empty() {
+        report_failed();
+        process::exit(failed[0].1);
+    }
+
+    // Every TBF must carry a signature from the fleet key. A per-input `sign=`
+    // override can leave it out.
+    if let Some(public_key) = &fleet_public_key {
+        let unsigned: Vec<&String> = tbfs
+            .iter()
+            .filter(|(_, tbf_path)| !has_rsa4096_signature(tbf_path, public_key))
+            .map(|(name, _)| name)
+            .collect();
+        if !unsigned.is_empty() {
+            eprintln!(
+                "Error! {} of {} TBFs are not signed with the --sign-all-architectures-with key:",
+                unsigned.len(),
+                tbfs.len()
+            );
+            for name in unsigned {
+                eprintln!("  {}", name);
+            }
+            process::exit(ErrorCategory::Signing.exit_code());
+        }
+        if opt.verbose {
+            eprintln!("All {} TBFs are signed with the same key.", tbfs.len());
+        }
+    }
+
+    // A custom name pattern must not give two TBFs the same name.
+    if let Some(pattern) = &opt.tbf_name_pattern {
+        for (i, (name, _)) in tbfs.iter().enumerate() {
+            if tbfs[..i].iter().any(|(other, _)| other == name) {
+                cmdline::Opt::command()
+                    .error(
+                        clap::error::ErrorKind::ValueValidation,
+                        format!(
+                            "--tbf-name-pattern `{}` gives more than one TBF the name {}",
+                            pattern, name
+                        ),
+                    )
+                    .exit();
+            }
+        }
+    }
+
+    // Record which credentials each TBF has, so an update server can check a
+    // signature policy without parsing the TBFs. With `--metadata-hash` the
+    // TBFs already contain the hash of the metadata, so it cannot change.
+    if opt.metadata_hash {
+        if tbf_credentials
+            .iter()
+            .any(|(_, credentials)| !credentials.is_empty())
+        {
+            warn(
+                "Credentials are not recorded in metadata.toml, because --metadata-hash binds the TBFs to it.",
+            );
+        }
+    } else {
+        for (architecture, credentials) in &tbf_credentials {
+            if credentials.is_empty() {
+                continue;
+            }
+            let names: Vec<String> = credentials
+                .iter()
+                .map(|name| format!("\"{}\"", name))
+                .collect();
+            writeln!(
+                &mut metadata_toml,
+                "credentials.{} = [{}]",
+                toml_key(architecture),
+                names.join(", ")
+            )
+            .unwrap();
+        }
+    }
+
+    // Optionally fail if any TBF grew too much compared to a baseline.
+    if let Some(path) = &opt.size_baseline {
+        if let Err(e) = check_size_baseline(path, &tbf_sizes, opt.size_threshold, opt.verbose) {
+            eprintln!("Error! {}", e);
+            process::exit(ErrorCategory::of(&e).exit_code());
+        }
+    }
+
+    // Optionally record the sizes as a new baseline. This happens after the
+    // check so the baseline can be checked and updated in one run.
+    if let Some(path) = &opt.write_size_baseline {
+        let baseline = serde_json::to_string_pretty(&tbf_sizes).unwrap();
+        if let Err(e) = fs::write(path, baseline + "\n") {
+            eprintln!("Failed to write size baseline {:?}: {}", path, e);
+            process::exit(1);
+        }
+        if opt.verbose {
+            eprintln!("Wrote size baseline to {:?}", path);
+        }
+    }
+
+    // Optionally summarize what the app can do, for review before it ships.
+    if let Some(path) = &opt.capabilities_report {
+        let summary = capabilities::Capabilities {
+            package_name: package_name.to_string(),
+            permissions: permissions.clone(),
+            permissions_deny: permissions_deny.clone(),
+            write_id: opt.write_id,
+            read_ids: opt.read_ids.clone().unwrap_or_default(),
+            access_ids: opt.access_ids.clone().unwrap_or_default(),
+            kernel_version: minimum_tock_kernel_version,
+            kernel_version_no_upper: opt.kernel_version_no_upper,
+            tbfs: tbf_credentials.clone(),
+        };
+        let report = if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            summary.to_json(&driver_names)
+        } else {
+            summary.to_markdown(&driver_names)
+        };
+        if let Err(e) = fs::write(path, report) {
+            eprintln!("Failed to write capabilities report {:?}: {}", path, e);
+            process::exit(1);
+        }
+        if opt.verbose {
+            eprintln!("Wrote capabilities report to {:?}", path);
+        }
+    }
+
+    // Start creating a tar archive which will be the .tab file. It is built in
+    // memory, so it can go to stdout, and a TAB file is only replaced once
+    // the TAB is complete.
+    let mut tab = tar::Builder::new(Vec::new());
     tab.mode(tar::HeaderMode::Deterministic);
 
     // Add the metadata file without creating a real file on the filesystem.
     let mut header = tar::Header::new_gnu();
-    header.set_size(metadata_toml.as_bytes().len() as u64);
+    header.set_size(metadata_toml.len() as u64);
     header.set_mode(0o644);
     header.set_cksum();
     tab.append_data(&mut header, "metadata.toml", metadata_toml.as_bytes())
         .unwrap();
 
-    // Iterate all input elfs. Convert them to Tock friendly binaries and then
-    // add them to the TAB file.
-    for elf_file in opt.input {
-        let mut fsfile = fs::File::open(&elf_file.path).expect("Could not open the .elf file.");
+    // Add the TBFs to the TAB tar file.
+    for (tab_tbf_name, tbf_path) in &tbfs {
+        if let Err(e) = fs::File::open(tbf_path)
+            .and_then(|mut tbf_file| tab.append_file(tab_tbf_name, &mut tbf_file))
+        {
+            eprintln!(
+                "Failed to add {:?} to the TAB as {}: {:?}",
+                tbf_path, tab_tbf_name, e
+            );
+            process::exit(ErrorCategory::Generic.exit_code());
+        }
+    }
 
-        // The TBF will be written to the same place as the ELF, with a .tbf
-        // extension.
-        let tbf_path = elf_file.path.with_extension("tbf");
+    // Add any extra files after the TBFs. The tar headers are deterministic, so
+    // only the file contents matter for reproducible builds.
+    for (name, path) in &opt.include_files {
+        if let Err(e) = tab.append_path_with_name(path, name) {
+            eprintln!("Failed to add {:?} to the TAB as {}: {:?}", path, name, e);
+            process::exit(ErrorCategory::Generic.exit_code());
+        }
+        if opt.verbose {
+            eprintln!("Added {:?} to the TAB as {}", path, name);
+        }
+    }
 
-        // Get the name of the architecture for the TBF. This will be used to
-        // name the TBF in the TAB, as the file name is expected to be
-        // `<architecture>.tbf`.
-        let architecture = if let Some(ref architecture) = elf_file.architecture {
-            // The caller of elf2tab explicitly told us the architecture via
-            // command line arguments.
-            architecture.clone()
-        } else {
-            // Otherwise, we must assume that the elf was named as
-            // `<architecture>.elf` and use the base name as the architecture.
-            elf_file
-                .path
-                .file_stem()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string()
+    // Like the TBFs, the TAB is written to a temporary file that only replaces
+    // the TAB once it is complete.
+    let tab_contents = tab.into_inner().unwrap();
+    if to_stdout {
+        let mut stdout = io::stdout().lock();
+        if let Err(e) = stdout.write_all(&tab_contents).and_then(|_| stdout.flush()) {
+            eprintln!("Failed to write the TAB to stdout: {}", e);
+            process::exit(1);
+        }
+    } else {
+        let tab_temp_path = temp_path(&opt.output);
+        if let Err(e) = fs::write(&tab_temp_path, &tab_contents)
+            .and_then(|_| fs::rename(&tab_temp_path, &opt.output))
+        {
+            eprintln!("Failed to create {:?}: {}", opt.output, e);
+            let _ = fs::remove_file(&tab_temp_path);
+            process::exit(ErrorCategory::Generic.exit_code());
+        }
+    }
+
+    // Optionally write a checksum file for the TAB itself next to it. This is
+    // independent of any credentials stored in the TBF footers and covers the
+    // entire bundle.
+    if let Some(algorithm) = opt.output_checksum {
+        let (digest, extension) = match algorithm {
+            ChecksumAlgorithm::Sha256 => (Sha256::digest(&tab_contents).to_vec(), "sha256"),
+            ChecksumAlgorithm::Sha512 => (Sha512::digest(&tab_contents).to_vec(), "sha512"),
         };
-        // Use the architecture to name the TBF in the TAB.
-        let tab_tbf_name = format!("{}.tbf", architecture);
-
-        if opt.output.clone() == tbf_path.clone() {
-            panic!(
-                "tab file {} and output file {} cannot be the same file",
-                opt.output.clone().to_str().unwrap(),
-                tbf_path.to_str().unwrap()
+
+        // Use the same format as `sha256sum`/`sha512sum` so the file can be
+        // checked with those tools.
+        let mut checksum_line = String::new();
+        for byte in digest {
+            write!(&mut checksum_line, "{:02x}", byte).unwrap();
+        }
+        let mut checksum_path = opt.output.clone().into_os_string();
+        checksum_path.push(".");
+        checksum_path.push(extension);
+        let written = opt
+            .output
+            .file_name()
+            .ok_or_else(|| {
+                Elf2TabError::io_error(
+                    ErrorCategory::Generic,
+                    io::ErrorKind::InvalidInput,
+                    format!("{:?} does not end in a file name", opt.output),
+                )
+            })
+            .and_then(|tab_file_name| {
+                writeln!(&mut checksum_line, "  {}", tab_file_name.to_string_lossy()).unwrap();
+                fs::write(&checksum_path, checksum_line).map_err(|e| {
+                    Elf2TabError::io_error(ErrorCategory::Generic, e.kind(), e.to_string())
+                })
+            });
+        if let Err(e) = written {
+            eprintln!(
+                "Failed to write the TAB checksum to {:?}: {}",
+                checksum_path, e
             );
+            process::exit(ErrorCategory::of(&e).exit_code());
         }
+        if opt.verbose {
+            eprintln!("Wrote TAB checksum to {:?}", checksum_path);
+        }
+    }
 
-        // Get output file as both read/write for creating the binary and
-        // adding it to the TAB tar file.
-        let mut outfile: fs::File = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(tbf_path.clone())
-            .unwrap();
+    if let Some((_, exit_code)) = failed.first() {
+        report_failed();
+        process::exit(*exit_code);
+    }
+}
 
-        // Do the conversion to a tock binary.
-        if opt.verbose {
-            println!("Creating {:?}", tbf_path);
-        }
-        // First write the TBF into a vector, to allow each read access
-        // for generating credentials; once it's written to the vector, flush
-        // it to a file.
-        let mut output_vector = Vec::<u8>::new();
-        convert::elf_to_tbf(
-            &mut fsfile,
-            &mut output_vector,
-            opt.package_name.clone(),
-            opt.verbose,
-            opt.stack_size,
-            opt.app_heap_size,
-            opt.kernel_heap_size,
-            opt.protected_region_size,
-            opt.permissions.to_vec(),
-            (opt.write_id, opt.read_ids.clone(), opt.access_ids.clone()),
-            minimum_tock_kernel_version,
-            opt.short_id,
-            opt.disabled,
-            opt.minimum_footer_size,
-            opt.app_version,
-            opt.sha256_enable,
-            opt.sha384_enable,
-            opt.sha512_enable,
-            opt.rsa4096_private_key.clone(),
-        )
-        .unwrap();
-        if opt.verbose {
-            println!("");
+/// Run one of the commands for working with existing TBFs and TABs.
+fn run_command(command: cmdline::Command) -> io::Result<()> {
+    match command {
+        cmdline::Command::ToElf {
+            tbf,
+            output,
+            machine,
+            tbf_address,
+        } => {
+            let tbf_bytes = fs::read(&tbf)?;
+            let parsed = tbf::Tbf::parse(&tbf_bytes)?;
+            let tbf_address = tbf_address
+                .or_else(|| to_elf::default_tbf_address(&parsed))
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "the TBF has no fixed flash address; use --tbf-address to give the address it is loaded at",
+                    )
+                })?;
+            let elf = to_elf::tbf_to_elf(&parsed, machine.e_machine(), tbf_address)?;
+            // Do not default to replacing the extension, which could overwrite
+            // the original ELF.
+            let output = output.unwrap_or_else(|| {
+                let mut output = tbf.into_os_string();
+                output.push(".elf");
+                output.into()
+            });
+            fs::write(&output, elf)?;
+            println!("{}", output.display());
+            Ok(())
         }
+        cmdline::Command::Extract {
+            tab,
+            architecture,
+            output,
+            list,
+        } => {
+            if list {
+                let mut archive = tar::Archive::new(fs::File::open(&tab)?);
+                for entry in archive.entries()? {
+                    println!("{}", entry?.path()?.display());
+                }
+                return Ok(());
+            }
+            let architecture = architecture.unwrap();
+            let (name, contents) = tbf::find_in_tab(fs::File::open(&tab)?, &architecture)
+                .map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", tab, e)))?;
+            let output = output.unwrap_or_else(|| PathBuf::from(&name));
+            fs::write(&output, contents)?;
+            println!("{}", output.display());
+            Ok(())
+        }
+        cmdline::Command::StripCredentials { tbf, output } => {
+            let tbf_bytes = fs::read(&tbf)?;
+            let stripped = tbf::Tbf::parse(&tbf_bytes)?.without_credentials()?;
+            let output = output.unwrap_or(tbf);
+            fs::write(&output, stripped)?;
+            println!("{}", output.display());
+            Ok(())
+        }
+        cmdline::Command::Tlvs { tbf } => {
+            let tbf_bytes = fs::read(&tbf)?;
+            let parsed = tbf::Tbf::parse(&tbf_bytes)?;
+            println!(
+                "TBF version {}, header_size {}, total_size {}",
+                parsed.version, parsed.header_size, parsed.total_size
+            );
+            for tlv in &parsed.tlvs {
+                println!("{}", tlv.describe());
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Whether the TBF at `tbf_path` has an RSA4096 credential from `public_key`
+/// whose signature verifies over the part of the TBF it covers.
+fn has_rsa4096_signature(
+    tbf_path: &Path,
+    public_key: &ring::signature::RsaPublicKeyComponents<Vec<u8>>,
+) -> bool {
+    let Ok(tbf_bytes) = fs::read(tbf_path) else {
+        return false;
+    };
+    let Ok(parsed) = tbf::Tbf::parse(&tbf_bytes) else {
+        return false;
+    };
+    let Ok(credentials) = parsed.footer_credentials() else {
+        return false;
+    };
+    let binary_end_offset = parsed.binary_end_offset() as usize;
+    credentials.iter().any(|credential| {
+        if credential.credentials_format()
+            != Some(header::TbfFooterCredentialsType::Rsa4096Key as u32)
+        {
+            return false;
+        }
+        // The format, the 512-byte modulus and signature, and with
+        // `--integrity-end` the length of the TBF the signature covers.
+        let (Some(modulus), Some(signature)) =
+            (credential.data.get(4..516), credential.data.get(516..1028))
+        else {
+            return false;
+        };
+        let covered_length = credential
+            .data
+            .get(1028..1032)
+            .map_or(binary_end_offset, |length| {
+                u32::from_le_bytes([length[0], length[1], length[2], length[3]]) as usize
+            });
+        // ring has no 4096-bit minimum, but the modulus must be the 512-byte
+        // one of the fleet key anyway.
+        modulus == public_key.n.as_slice()
+            && tbf_bytes.get(..covered_length).is_some_and(|covered| {
+                public_key
+                    .verify(
+                        &ring::signature::RSA_PKCS1_2048_8192_SHA512,
+                        covered,
+                        signature,
+                    )
+                    .is_ok()
+            })
+    })
+}
+
+/// Get the name of the architecture for the TBF. This will be used to name the
+/// TBF in the TAB, as the file name is expected to be `<architecture>.tbf`.
+fn resolve_architecture(elf_file: &cmdline::ElfFile, arch_map: &[(String, String)]) -> String {
+    let architecture = if let Some(ref architecture) = elf_file.architecture {
+        // The caller of elf2tab explicitly told us the architecture via
+        // command line arguments.
+        architecture.clone()
+    } else {
+        // Otherwise, we must assume that the elf was named as
+        // `<architecture>.elf` and use the base name as the architecture.
+        elf_file
+            .path
+            .file_stem()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    };
+    // Build systems driven by cargo name their outputs after the Rust
+    // target triple, so translate those to the architecture names Tock
+    // expects. Mappings from the command line take precedence.
+    arch_map
+        .iter()
+        .find(|(target, _)| *target == architecture)
+        .map_or_else(
+            || util::architecture_from_target_triple(&architecture).to_string(),
+            |(_, mapped)| mapped.clone(),
+        )
+}
+
+/// Get the name of a TBF in the TAB from `pattern`, replacing `{arch}`,
+/// `{name}`, and `{version}`.
+fn tbf_name(pattern: &str, architecture: &str, package_name: &str, app_version: u32) -> String {
+    pattern
+        .replace("{arch}", architecture)
+        .replace("{name}", package_name)
+        .replace("{version}", &app_version.to_string())
+}
 
-        match outfile.write_all(output_vector.as_ref()) {
-            Err(e) => {
-                println!("Failed to write TBF: {:?}", e);
-                return;
+/// Format `name` as a TOML key, quoting it if it is not a valid bare key.
+fn toml_key(name: &str) -> String {
+    let bare = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if bare {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// The temporary file an output is written to before it replaces `path`. It
+/// is in the same directory, so renaming it over `path` is atomic.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp_path = path.to_path_buf().into_os_string();
+    temp_path.push(".tmp");
+    temp_path.into()
+}
+
+/// Compare the total size of each TBF to the size in the baseline file, a JSON
+/// object mapping architectures to sizes. Growing by more than `threshold`
+/// percent is an error. Architectures missing from the baseline are skipped.
+fn check_size_baseline(
+    path: &Path,
+    tbf_sizes: &BTreeMap<String, usize>,
+    threshold: f64,
+    verbose: bool,
+) -> io::Result<()> {
+    let contents = fs::read_to_string(path)?;
+    let baseline: BTreeMap<String, usize> = serde_json::from_str(&contents).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is not a valid size baseline: {}", path, e),
+        )
+    })?;
+    let mut regressions = Vec::new();
+    for (architecture, size) in tbf_sizes {
+        let Some(&baseline_size) = baseline.get(architecture) else {
+            if verbose {
+                eprintln!("No size baseline for {}.", architecture);
             }
-            _ => {}
+            continue;
+        };
+        let growth = (*size as f64 - baseline_size as f64) * 100.0 / baseline_size as f64;
+        if verbose {
+            eprintln!(
+                "{}: {} bytes, baseline {} bytes ({:+.1}%).",
+                architecture, size, baseline_size, growth
+            );
+        }
+        if *size > baseline_size && growth > threshold {
+            regressions.push(format!(
+                "{} grew from {} to {} bytes ({:+.1}%)",
+                architecture, baseline_size, size, growth
+            ));
         }
+    }
+    if regressions.is_empty() {
+        Ok(())
+    } else {
+        Err(Elf2TabError::io_error(
+            ErrorCategory::Size,
+            io::ErrorKind::InvalidData,
+            format!(
+                "TBF size grew by more than {}% compared to {:?}: {}",
+                threshold,
+                path,
+                regressions.join(", ")
+            ),
+        ))
+    }
+}
+
+/// Get the path for an extra per-TBF output file. With more than one input the
+/// architecture is appended to the file name so each TBF gets its own file.
+fn output_path(path: &Path, architecture: &str, multiple_inputs: bool) -> PathBuf {
+    if multiple_inputs {
+        let mut path = path.to_path_buf().into_os_string();
+        path.push(".");
+        path.push(architecture);
+        path.into()
+    } else {
+        path.to_path_buf()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::check_size_baseline;
+    use elf2tab::error::ErrorCategory;
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    #[test]
+    pub fn checks_size_baseline() {
+        let path =
+            std::env::temp_dir().join(format!("elf2tab-size-baseline-{}.json", std::process::id()));
+        fs::write(&path, r#"{"cortex-m4": 1000, "rv32imac": 2000}"#).unwrap();
+        let sizes = |sizes: &[(&str, usize)]| -> BTreeMap<String, usize> {
+            sizes
+                .iter()
+                .map(|(architecture, size)| (architecture.to_string(), *size))
+                .collect()
+        };
+
+        // Growing by up to the threshold, shrinking, and architectures without
+        // a baseline are fine.
+        let within = sizes(&[("cortex-m4", 1050), ("rv32imac", 1500), ("rv32imc", 9000)]);
+        assert!(check_size_baseline(&path, &within, 5.0, false).is_ok());
+
+        let grown = sizes(&[("cortex-m4", 1051), ("rv32imac", 2000)]);
+        let error = check_size_baseline(&path, &grown, 5.0, false).unwrap_err();
+        assert_eq!(ErrorCategory::of(&error), ErrorCategory::Size);
+        assert!(error
+            .to_string()
+            .contains("cortex-m4 grew from 1000 to 1051 bytes"));
+        assert!(!error.to_string().contains("rv32imac"));
 
-        // Add the file to the TAB tar file.
-        outfile.seek(io::SeekFrom::Start(0)).unwrap();
-        tab.append_file(tab_tbf_name, &mut outfile).unwrap();
+        fs::write(&path, "[1000]").unwrap();
+        assert!(check_size_baseline(&path, &within, 5.0, false).is_err());
+        fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,306 @@
+//! Create TBF signature credentials using keys stored in a PKCS#11 token.
+//!
+//! Keys are selected with a PKCS#11 URI as described in RFC 7512, for example:
+//!
+//! ```text
+//! pkcs11:token=release;object=tock-app-key?module-path=/usr/lib/softhsm/libsofthsm2.so&pin-value=1234
+//! ```
+
+use cryptoki::context::{CInitializeArgs, CInitializeFlags, Pkcs11};
+use cryptoki::mechanism::Mechanism;
+use cryptoki::object::{Attribute, AttributeType, ObjectClass};
+use cryptoki::session::UserType;
+use cryptoki::types::AuthPin;
+use ring::signature::RsaPublicKeyComponents;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// The parts of a PKCS#11 URI that elf2tab uses to find a signing key.
+#[derive(Debug, Default, PartialEq)]
+pub struct Pkcs11Uri {
+    /// Path to the PKCS#11 module (shared library) to load.
+    pub module_path: Option<PathBuf>,
+    /// Label of the token holding the key.
+    pub token: Option<String>,
+    /// Slot the token is in, if selecting by label is not enough.
+    pub slot_id: Option<u64>,
+    /// Label of the key object.
+    pub object: Option<String>,
+    /// ID of the key object.
+    pub id: Option<Vec<u8>>,
+    /// PIN to log in to the token with.
+    pub pin_value: Option<String>,
+    /// File to read the PIN from.
+    pub pin_source: Option<PathBuf>,
+}
+
+fn pkcs11_error<E: std::fmt::Debug>(context: &str, e: E) -> io::Error {
+    io::Error::other(format!("{}: {:?}", context, e))
+}
+
+/// Decode a percent-encoded URI attribute value.
+fn percent_decode(value: &str) -> io::Result<Vec<u8>> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = value
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid percent-encoding in PKCS#11 URI value `{}`", value),
+                    )
+                })?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(decoded)
+}
+
+fn percent_decode_string(value: &str) -> io::Result<String> {
+    String::from_utf8(percent_decode(value)?).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("PKCS#11 URI value `{}` is not valid UTF-8", value),
+        )
+    })
+}
+
+impl Pkcs11Uri {
+    /// Parse a `pkcs11:` URI. Attributes elf2tab does not use are ignored.
+    pub fn parse(uri: &str) -> io::Result<Self> {
+        let rest = uri.strip_prefix("pkcs11:").ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{}` is not a PKCS#11 URI", uri),
+            )
+        })?;
+        let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+        let mut parsed = Pkcs11Uri::default();
+        let path_attributes = path.split(';').map(|attribute| (attribute, true));
+        let query_attributes = query.split('&').map(|attribute| (attribute, false));
+        for (attribute, in_path) in path_attributes.chain(query_attributes) {
+            if attribute.is_empty() {
+                continue;
+            }
+            let (name, value) = attribute.split_once('=').ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("PKCS#11 URI attribute `{}` has no value", attribute),
+                )
+            })?;
+            match (name, in_path) {
+                ("token", true) => parsed.token = Some(percent_decode_string(value)?),
+                ("object", true) => parsed.object = Some(percent_decode_string(value)?),
+                ("id", true) => parsed.id = Some(percent_decode(value)?),
+                ("slot-id", true) => {
+                    parsed.slot_id = Some(percent_decode_string(value)?.parse().map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("invalid PKCS#11 URI slot-id `{}`", value),
+                        )
+                    })?)
+                }
+                ("module-path", false) => {
+                    parsed.module_path = Some(PathBuf::from(percent_decode_string(value)?))
+                }
+                ("pin-value", false) => parsed.pin_value = Some(percent_decode_string(value)?),
+                ("pin-source", false) => {
+                    let source = percent_decode_string(value)?;
+                    let source = source.strip_prefix("file:").unwrap_or(&source);
+                    parsed.pin_source = Some(PathBuf::from(source))
+                }
+                _ => {}
+            }
+        }
+
+        if parsed.module_path.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "PKCS#11 URI must include a module-path query attribute",
+            ));
+        }
+        if parsed.object.is_none() && parsed.id.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "PKCS#11 URI must include an object or id attribute to select the key",
+            ));
+        }
+        Ok(parsed)
+    }
+}
+
+/// Sign `data` with the RSA4096 key selected by `uri` using PKCS#1 v1.5 and
+/// SHA512, the same scheme used for file-based RSA4096 keys.
+///
+/// Returns the public key and the signature. The modulus and signature are
+/// each 512 bytes long.
+pub fn sign_rsa4096(
+    uri: &Pkcs11Uri,
+    data: &[u8],
+) -> io::Result<(RsaPublicKeyComponents<Vec<u8>>, Vec<u8>)> {
+    // `parse()` guarantees the module path is set.
+    let module_path = uri.module_path.as_ref().unwrap();
+    let pkcs11 =
+        Pkcs11::new(module_path).map_err(|e| pkcs11_error("Could not load PKCS#11 module", e))?;
+    pkcs11
+        .initialize(CInitializeArgs::new(CInitializeFlags::OS_LOCKING_OK))
+        .map_err(|e| pkcs11_error("Could not initialize PKCS#11 module", e))?;
+
+    // Find the token holding the key.
+    let slots = pkcs11
+        .get_slots_with_token()
+        .map_err(|e| pkcs11_error("Could not list PKCS#11 slots", e))?;
+    let slot = slots
+        .into_iter()
+        .find(|slot| {
+            let slot_matches = uri.slot_id.is_none_or(|slot_id| slot.id() == slot_id);
+            let token_matches = uri.token.as_ref().is_none_or(|token| {
+                pkcs11
+                    .get_token_info(*slot)
+                    .is_ok_and(|info| info.label().trim_end() == token)
+            });
+            slot_matches && token_matches
+        })
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "No PKCS#11 token matches the URI")
+        })?;
+
+    let session = pkcs11
+        .open_ro_session(slot)
+        .map_err(|e| pkcs11_error("Could not open PKCS#11 session", e))?;
+
+    let pin = match (&uri.pin_value, &uri.pin_source) {
+        (Some(pin), _) => Some(pin.clone()),
+        (None, Some(pin_source)) => Some(fs::read_to_string(pin_source)?.trim_end().to_string()),
+        (None, None) => None,
+    };
+    if let Some(pin) = pin {
+        session
+            .login(UserType::User, Some(&AuthPin::new(pin.into())))
+            .map_err(|e| pkcs11_error("Could not log in to PKCS#11 token", e))?;
+    }
+
+    // Find the private key.
+    let mut template = vec![Attribute::Class(ObjectClass::PRIVATE_KEY)];
+    if let Some(object) = &uri.object {
+        template.push(Attribute::Label(object.as_bytes().to_vec()));
+    }
+    if let Some(id) = &uri.id {
+        template.push(Attribute::Id(id.clone()));
+    }
+    let keys = session
+        .find_objects(&template)
+        .map_err(|e| pkcs11_error("Could not search for PKCS#11 key", e))?;
+    let key = match keys.as_slice() {
+        [key] => *key,
+        [] => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No private key on the PKCS#11 token matches the URI",
+            ))
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "More than one private key on the PKCS#11 token matches the URI",
+            ))
+        }
+    };
+
+    // RSA private keys carry the public key, which we need to include in the
+    // credential.
+    let mut modulus = None;
+    let mut exponent = None;
+    for attribute in session
+        .get_attributes(
+            key,
+            &[AttributeType::Modulus, AttributeType::PublicExponent],
+        )
+        .map_err(|e| pkcs11_error("Could not read the PKCS#11 public key", e))?
+    {
+        match attribute {
+            Attribute::Modulus(value) => modulus = Some(value),
+            Attribute::PublicExponent(value) => exponent = Some(value),
+            _ => {}
+        }
+    }
+    let (modulus, exponent) = modulus.zip(exponent).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "PKCS#11 key is not an RSA key")
+    })?;
+    let leading_zeros = modulus.iter().take_while(|b| **b == 0).count();
+    let modulus = modulus[leading_zeros..].to_vec();
+    if modulus.len() != 512 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "RSA4096 signature requested but PKCS#11 key is {} bits",
+                modulus.len() * 8
+            ),
+        ));
+    }
+
+    let signature = session
+        .sign(&Mechanism::Sha512RsaPkcs, key, data)
+        .map_err(|e| pkcs11_error("Could not generate RSA4096 signature", e))?;
+    // The credential has room for exactly one modulus-sized signature.
+    if signature.len() != modulus.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "PKCS#11 token returned a {} byte signature, expected {} bytes",
+                signature.len(),
+                modulus.len()
+            ),
+        ));
+    }
+
+    let leading_zeros = exponent.iter().take_while(|b| **b == 0).count();
+    let public_key = RsaPublicKeyComponents {
+        n: modulus,
+        e: exponent[leading_zeros..].to_vec(),
+    };
+    Ok((public_key, signature))
+}
+
+#[cfg(test)]
+mod test {
+    use super::Pkcs11Uri;
+    use std::path::PathBuf;
+
+    #[test]
+    pub fn parses_uri() {
+        let uri = Pkcs11Uri::parse(
+            "pkcs11:token=release%20keys;object=tock;id=%01%02?module-path=/usr/lib/p11.so&pin-value=1234",
+        )
+        .unwrap();
+        assert_eq!(
+            uri,
+            Pkcs11Uri {
+                module_path: Some(PathBuf::from("/usr/lib/p11.so")),
+                token: Some("release keys".to_string()),
+                slot_id: None,
+                object: Some("tock".to_string()),
+                id: Some(vec![1, 2]),
+                pin_value: Some("1234".to_string()),
+                pin_source: None,
+            }
+        );
+    }
+
+    #[test]
+    pub fn requires_module_and_key() {
+        assert!(Pkcs11Uri::parse("pkcs11:object=tock").is_err());
+        assert!(Pkcs11Uri::parse("pkcs11:token=t?module-path=/usr/lib/p11.so").is_err());
+        assert!(Pkcs11Uri::parse("file:object=tock?module-path=/usr/lib/p11.so").is_err());
+    }
+}
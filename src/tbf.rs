@@ -0,0 +1,606 @@
+//! Parse existing TBF files.
+//!
+//! elf2tab mostly creates TBFs, but some tools (for example reconstructing an
+//! ELF for debugging) need to read them back. This parser only checks that the
+//! structure is consistent; it does not verify the checksum or credentials.
+
+use crate::header::{TbfFooterCredentials, TbfFooterCredentialsType, TbfHeaderTlv, TbfHeaderTypes};
+use crate::util::amount_alignment_needed;
+use std::cmp;
+use std::io;
+
+/// Size of the base header (version, header_size, total_size, flags, and
+/// checksum).
+const BASE_HEADER_SIZE: usize = 16;
+/// Size of the type and length fields at the start of every TLV.
+const TLV_HEADER_SIZE: usize = 4;
+/// Size of a Credentials TLV with no data: the TLV header and the format.
+const CREDENTIALS_HEADER_SIZE: usize = TLV_HEADER_SIZE + 4;
+/// The longest Credentials TLV contents that keep the length 4 byte aligned.
+const MAX_CREDENTIALS_LENGTH: usize = u16::MAX as usize & !3;
+
+/// A single TLV from a TBF header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TbfTlv<'a> {
+    /// The TLV type. This is kept as a number so unknown types can be shown.
+    pub tipe: u16,
+    /// Offset of the TLV (including its type and length) in the TBF.
+    pub offset: usize,
+    /// The TLV contents, without padding.
+    pub data: &'a [u8],
+}
+
+impl TbfTlv<'_> {
+    /// The format of a Credentials TLV.
+    pub fn credentials_format(&self) -> Option<u32> {
+        self.u32_at(0)
+    }
+
+    /// A description of the TLV: its offset, type name and number, length,
+    /// and contents in hex as little-endian words, eight per line.
+    pub fn describe(&self) -> String {
+        let name = TbfHeaderTypes::from_u16(self.tipe)
+            .map_or_else(|| "Unknown".to_string(), |tipe| format!("{:?}", tipe));
+        let mut description = format!(
+            "{:#06x}  {} ({}), {} bytes",
+            self.offset,
+            name,
+            self.tipe,
+            self.data.len()
+        );
+        for line in self.data.chunks(32) {
+            description.push_str("\n       ");
+            for word in line.chunks(4) {
+                description.push(' ');
+                for byte in word {
+                    description.push_str(&format!("{:02x}", byte));
+                }
+            }
+        }
+        description
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        self.data
+            .get(offset..offset + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+/// A parsed TBF. The TLVs and the app binary borrow from the original bytes.
+#[derive(Debug)]
+pub struct Tbf<'a> {
+    pub version: u16,
+    pub header_size: u16,
+    pub total_size: u32,
+    pub flags: u32,
+    pub checksum: u32,
+    pub tlvs: Vec<TbfTlv<'a>>,
+    tbf: &'a [u8],
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl<'a> Tbf<'a> {
+    /// Parse the TBF at the start of `tbf`. Any bytes after `total_size` are
+    /// ignored.
+    pub fn parse(tbf: &'a [u8]) -> io::Result<Self> {
+        if tbf.len() < BASE_HEADER_SIZE {
+            return Err(invalid(format!(
+                "TBF is {} bytes, too short for the {} byte base header",
+                tbf.len(),
+                BASE_HEADER_SIZE
+            )));
+        }
+        let u16_at = |offset: usize| u16::from_le_bytes([tbf[offset], tbf[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                tbf[offset],
+                tbf[offset + 1],
+                tbf[offset + 2],
+                tbf[offset + 3],
+            ])
+        };
+
+        let version = u16_at(0);
+        if version != 2 {
+            return Err(invalid(format!("unsupported TBF version {}", version)));
+        }
+        let header_size = u16_at(2);
+        let total_size = u32_at(4);
+        if (header_size as usize) < BASE_HEADER_SIZE || header_size as u32 > total_size {
+            return Err(invalid(format!(
+                "TBF header_size {} does not fit in total_size {}",
+                header_size, total_size
+            )));
+        }
+        if total_size as usize > tbf.len() {
+            return Err(invalid(format!(
+                "TBF total_size is {} but only {} bytes are available",
+                total_size,
+                tbf.len()
+            )));
+        }
+
+        // Walk the TLVs. Each TLV is padded so the next one is 4 byte aligned.
+        let mut tlvs = Vec::new();
+        let mut offset = BASE_HEADER_SIZE;
+        while offset + TLV_HEADER_SIZE <= header_size as usize {
+            let tipe = u16_at(offset);
+            let length = u16_at(offset + 2) as usize;
+            let start = offset + TLV_HEADER_SIZE;
+            if start + length > header_size as usize {
+                return Err(invalid(format!(
+                    "TLV type {} at offset {:#x} extends past the end of the header",
+                    tipe, offset
+                )));
+            }
+            tlvs.push(TbfTlv {
+                tipe,
+                offset,
+                data: &tbf[start..start + length],
+            });
+            offset = start + length + amount_alignment_needed(length as u32, 4) as usize;
+        }
+
+        let parsed = Tbf {
+            version,
+            header_size,
+            total_size,
+            flags: u32_at(8),
+            checksum: u32_at(12),
+            tlvs,
+            tbf: &tbf[..total_size as usize],
+        };
+        // The app binary and footer are found from the Program header, so its
+        // offsets must be inside the TBF.
+        if parsed.tlv(TbfHeaderTypes::Program).is_some() {
+            let binary_start = header_size as u64 + parsed.protected_size().unwrap_or(0) as u64;
+            let binary_end = parsed.binary_end_offset();
+            if binary_start > binary_end as u64 || binary_end > total_size {
+                return Err(invalid(format!(
+                    "app binary from {:#x} to {:#x} is not inside the TBF of {:#x} bytes",
+                    binary_start, binary_end, total_size
+                )));
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// The first TLV of the given type, if any.
+    pub fn tlv(&self, tipe: TbfHeaderTypes) -> Option<&TbfTlv<'a>> {
+        self.tlvs.iter().find(|tlv| tlv.tipe == tipe as u16)
+    }
+
+    /// The Program header if there is one, otherwise the Main header. Both
+    /// start with init_fn_offset, protected_size, and minimum_ram_size.
+    fn program_or_main(&self) -> Option<&TbfTlv<'a>> {
+        self.tlv(TbfHeaderTypes::Program)
+            .or_else(|| self.tlv(TbfHeaderTypes::Main))
+    }
+
+    /// Offset of the entry point from the end of the TBF header.
+    pub fn init_fn_offset(&self) -> Option<u32> {
+        self.program_or_main().and_then(|tlv| tlv.u32_at(0))
+    }
+
+    /// Size of the protected region after the TBF header.
+    pub fn protected_size(&self) -> Option<u32> {
+        self.program_or_main().and_then(|tlv| tlv.u32_at(4))
+    }
+
+    pub fn minimum_ram_size(&self) -> Option<u32> {
+        self.program_or_main().and_then(|tlv| tlv.u32_at(8))
+    }
+
+    /// Offset of the end of the app binary. Without a Program header there is
+    /// no footer, so this is the total size.
+    pub fn binary_end_offset(&self) -> u32 {
+        self.tlv(TbfHeaderTypes::Program)
+            .and_then(|tlv| tlv.u32_at(12))
+            .unwrap_or(self.total_size)
+    }
+
+    /// The flash address the app binary must be placed at, for non-PIC apps.
+    pub fn fixed_address_flash(&self) -> Option<u32> {
+        self.tlv(TbfHeaderTypes::FixedAddresses)
+            .and_then(|tlv| tlv.u32_at(4))
+            .filter(|address| *address != 0xFFFF_FFFF)
+    }
+
+    /// Offset of the app binary, after the header and protected region.
+    pub fn app_binary_offset(&self) -> u32 {
+        self.header_size as u32 + self.protected_size().unwrap_or(0)
+    }
+
+    /// The Credentials TLVs in the footer, after the app binary. Without a
+    /// Program header there is no footer.
+    pub fn footer_credentials(&self) -> io::Result<Vec<TbfTlv<'a>>> {
+        let mut credentials = Vec::new();
+        if self.tlv(TbfHeaderTypes::Program).is_none() {
+            return Ok(credentials);
+        }
+        let mut offset = self.binary_end_offset() as usize;
+        while offset < self.tbf.len() {
+            let tlv = self
+                .tbf
+                .get(offset..offset + TLV_HEADER_SIZE)
+                .ok_or_else(|| {
+                    invalid(format!("footer TLV at offset {:#x} is truncated", offset))
+                })?;
+            let tipe = u16::from_le_bytes([tlv[0], tlv[1]]);
+            let length = u16::from_le_bytes([tlv[2], tlv[3]]) as usize;
+            let start = offset + TLV_HEADER_SIZE;
+            if tipe != TbfHeaderTypes::Credentials as u16 || start + length > self.tbf.len() {
+                return Err(invalid(format!(
+                    "footer at offset {:#x} is not a Credentials TLV within the TBF",
+                    offset
+                )));
+            }
+            credentials.push(TbfTlv {
+                tipe,
+                offset,
+                data: &self.tbf[start..start + length],
+            });
+            offset = start + length;
+        }
+        Ok(credentials)
+    }
+
+    /// A copy of the TBF with all footer credentials replaced by Reserved
+    /// space, so the TBF keeps its total size. The header and app binary are
+    /// unchanged.
+    pub fn without_credentials(&self) -> io::Result<Vec<u8>> {
+        if self.tlv(TbfHeaderTypes::Program).is_none() {
+            return Err(invalid(
+                "TBF has no Program header, so it has no footer credentials".to_string(),
+            ));
+        }
+        // Check the footer really is made of credentials before replacing it.
+        self.footer_credentials()?;
+
+        let binary_end_offset = self.binary_end_offset() as usize;
+        let mut tbf = self.tbf[..binary_end_offset].to_vec();
+        let mut remaining = self.tbf.len() - binary_end_offset;
+        while remaining > 0 {
+            if remaining < CREDENTIALS_HEADER_SIZE {
+                return Err(invalid(format!(
+                    "{} bytes of footer are too few for a Reserved credential",
+                    remaining
+                )));
+            }
+            // A large footer needs more than one TLV, and whatever this one
+            // leaves must fit another.
+            let mut length = cmp::min(remaining - TLV_HEADER_SIZE, MAX_CREDENTIALS_LENGTH);
+            let rest = remaining - TLV_HEADER_SIZE - length;
+            if rest > 0 && rest < CREDENTIALS_HEADER_SIZE {
+                length -= CREDENTIALS_HEADER_SIZE;
+            }
+            let reserved = TbfFooterCredentials {
+                base: TbfHeaderTlv {
+                    tipe: TbfHeaderTypes::Credentials,
+                    length: length as u16,
+                },
+                format: TbfFooterCredentialsType::Reserved,
+                data: vec![0; length - 4],
+            };
+            tbf.extend(reserved.generate()?.into_inner());
+            remaining -= TLV_HEADER_SIZE + length;
+        }
+        Ok(tbf)
+    }
+
+    /// The app binary, between the protected region and the footer.
+    pub fn app_binary(&self) -> io::Result<&'a [u8]> {
+        let start = self.app_binary_offset() as usize;
+        let end = self.binary_end_offset() as usize;
+        self.tbf.get(start..end).ok_or_else(|| {
+            invalid(format!(
+                "app binary from {:#x} to {:#x} is not inside the TBF",
+                start, end
+            ))
+        })
+    }
+}
+
+/// Find the TBF for `architecture` in a TAB and return its name and contents.
+///
+/// TBFs are named `{arch}.tbf` by default, but `--tbf-name-pattern` can put
+/// the architecture anywhere in the name. Any TBF whose name contains the
+/// architecture, not followed or preceded by a letter or digit, matches, so
+/// `rv32i` does not match `rv32imac.tbf`.
+pub fn find_in_tab<R: io::Read>(tab: R, architecture: &str) -> io::Result<(String, Vec<u8>)> {
+    let names_architecture = |name: &str| {
+        let Some(stem) = name.strip_suffix(".tbf") else {
+            return false;
+        };
+        stem.match_indices(architecture).any(|(start, _)| {
+            let before = stem[..start].chars().next_back();
+            let after = stem[start + architecture.len()..].chars().next();
+            !before.is_some_and(|c| c.is_ascii_alphanumeric())
+                && !after.is_some_and(|c| c.is_ascii_alphanumeric())
+        })
+    };
+
+    let mut archive = tar::Archive::new(tab);
+    let mut names = Vec::new();
+    let mut matches = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        if names_architecture(&name) {
+            let mut contents = Vec::new();
+            io::copy(&mut entry, &mut contents)?;
+            matches.push((name.clone(), contents));
+        }
+        names.push(name);
+    }
+
+    // The default name wins if a custom name also matches.
+    let default_name = format!("{}.tbf", architecture);
+    if let Some(index) = matches.iter().position(|(name, _)| *name == default_name) {
+        return Ok(matches.swap_remove(index));
+    }
+    match matches.len() {
+        0 => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "no TBF for {}; the TAB has: {}",
+                architecture,
+                names.join(", ")
+            ),
+        )),
+        1 => Ok(matches.remove(0)),
+        _ => {
+            let matching: Vec<&str> = matches.iter().map(|(name, _)| name.as_str()).collect();
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "more than one TBF for {}: {}",
+                    architecture,
+                    matching.join(", ")
+                ),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find_in_tab, Tbf, TbfTlv};
+    use crate::header::{TbfHeader, TbfHeaderTypes};
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    pub fn parses_generated_header() {
+        let mut tbfheader = TbfHeader::new();
+        tbfheader.set_binary_end_offset(0);
+        let header_length = tbfheader
+            .create(
+                0x1000,
+                0,
+                "blink".to_string(),
+                None,
+                Some(0x40000),
+                vec![],
+                vec![],
+                (None, None, None),
+                None,
+                None,
+                false,
+                None,
+                false,
+                None,
+                None,
+                None,
+                vec![],
+            )
+            .unwrap();
+        tbfheader.set_protected_size(0x20);
+        tbfheader.set_init_fn_offset(0x24);
+        tbfheader.set_binary_end_offset(0x200);
+        tbfheader.set_total_size(0x400);
+        let mut tbf = tbfheader.generate().unwrap().into_inner();
+        tbf.resize(0x400, 0xAA);
+
+        let parsed = Tbf::parse(&tbf).unwrap();
+        assert_eq!(parsed.header_size as usize, header_length);
+        assert_eq!(parsed.total_size, 0x400);
+        assert_eq!(parsed.init_fn_offset(), Some(0x24));
+        assert_eq!(parsed.protected_size(), Some(0x20));
+        assert_eq!(parsed.minimum_ram_size(), Some(0x1000));
+        assert_eq!(parsed.binary_end_offset(), 0x200);
+        assert_eq!(parsed.fixed_address_flash(), Some(0x40000));
+        assert_eq!(
+            parsed.tlv(TbfHeaderTypes::PackageName).unwrap().data,
+            b"blink"
+        );
+        assert_eq!(
+            parsed.app_binary().unwrap().len(),
+            0x200 - header_length - 0x20
+        );
+    }
+
+    #[test]
+    pub fn rejects_truncated_tbf() {
+        let mut tbfheader = TbfHeader::new();
+        tbfheader
+            .create(
+                0,
+                0,
+                String::new(),
+                None,
+                None,
+                vec![],
+                vec![],
+                (None, None, None),
+                None,
+                None,
+                false,
+                None,
+                false,
+                None,
+                None,
+                None,
+                vec![],
+            )
+            .unwrap();
+        tbfheader.set_total_size(0x400);
+        let tbf = tbfheader.generate().unwrap().into_inner();
+        assert!(Tbf::parse(&tbf).is_err());
+        assert!(Tbf::parse(&tbf[..8]).is_err());
+    }
+
+    #[test]
+    pub fn strips_credentials() {
+        let mut tbfheader = TbfHeader::new();
+        tbfheader.set_binary_end_offset(0);
+        tbfheader
+            .create(
+                0x1000,
+                0,
+                "blink".to_string(),
+                None,
+                None,
+                vec![],
+                vec![],
+                (None, None, None),
+                None,
+                None,
+                false,
+                None,
+                false,
+                None,
+                None,
+                None,
+                vec![],
+            )
+            .unwrap();
+        tbfheader.set_binary_end_offset(0x100);
+        tbfheader.set_total_size(0x200);
+        let mut tbf = tbfheader.generate().unwrap().into_inner();
+        tbf.resize(0x100, 0xAA);
+        // A SHA256 credential followed by Reserved space.
+        let hash = Sha256::digest(&tbf);
+        tbf.extend([128, 0, 36, 0, 3, 0, 0, 0]);
+        tbf.extend(hash);
+        tbf.extend([128, 0, 0xD4, 0, 0, 0, 0, 0]);
+        tbf.resize(0x200, 0);
+
+        let parsed = Tbf::parse(&tbf).unwrap();
+        let credentials = parsed.footer_credentials().unwrap();
+        assert_eq!(credentials.len(), 2);
+        assert_eq!(credentials[0].credentials_format(), Some(3));
+        assert_eq!(credentials[0].data[4..], hash[..]);
+
+        let stripped = parsed.without_credentials().unwrap();
+        assert_eq!(stripped.len(), 0x200);
+        assert_eq!(stripped[..0x100], tbf[..0x100]);
+        let parsed = Tbf::parse(&stripped).unwrap();
+        let credentials = parsed.footer_credentials().unwrap();
+        assert_eq!(credentials.len(), 1);
+        assert_eq!(credentials[0].credentials_format(), Some(0));
+        assert_eq!(credentials[0].data.len(), 0xFC);
+    }
+
+    #[test]
+    pub fn rejects_binary_end_outside_tbf() {
+        let tbf_with = |binary_end_offset| {
+            let mut tbfheader = TbfHeader::new();
+            tbfheader.set_binary_end_offset(0);
+            let header_length = tbfheader
+                .create(
+                    0,
+                    0,
+                    String::new(),
+                    None,
+                    None,
+                    vec![],
+                    vec![],
+                    (None, None, None),
+                    None,
+                    None,
+                    false,
+                    None,
+                    false,
+                    None,
+                    None,
+                    None,
+                    vec![],
+                )
+                .unwrap();
+            tbfheader.set_protected_size(0);
+            tbfheader.set_binary_end_offset(binary_end_offset);
+            tbfheader.set_total_size(0x80);
+            let mut tbf = tbfheader.generate().unwrap().into_inner();
+            tbf.resize(0x80, 0);
+            (tbf, header_length as u32)
+        };
+        let (tbf, header_length) = tbf_with(0x80);
+        assert!(Tbf::parse(&tbf).unwrap().without_credentials().is_ok());
+        // Past the end of the TBF.
+        let (tbf, _) = tbf_with(0x1000);
+        let error = Tbf::parse(&tbf).unwrap_err();
+        assert!(error.to_string().contains("not inside the TBF"));
+        // Before the end of the header.
+        let (tbf, _) = tbf_with(header_length - 4);
+        assert!(Tbf::parse(&tbf).is_err());
+    }
+
+    #[test]
+    pub fn describes_tlvs() {
+        let data: Vec<u8> = (0..36).collect();
+        let tlv = TbfTlv {
+            tipe: 3,
+            offset: 0x28,
+            data: &data[..5],
+        };
+        assert_eq!(
+            tlv.describe(),
+            "0x0028  PackageName (3), 5 bytes\n        00010203 04"
+        );
+        let tlv = TbfTlv {
+            tipe: 42,
+            offset: 0x30,
+            data: &data,
+        };
+        assert_eq!(
+            tlv.describe(),
+            "0x0030  Unknown (42), 36 bytes\n        \
+             00010203 04050607 08090a0b 0c0d0e0f 10111213 14151617 18191a1b 1c1d1e1f\n        \
+             20212223"
+        );
+    }
+
+    #[test]
+    pub fn finds_tbfs_with_custom_names() {
+        // Named like `--tbf-name-pattern "{name}.{arch}-v{version}.tbf"`.
+        let mut tab = tar::Builder::new(Vec::new());
+        tab.mode(tar::HeaderMode::Deterministic);
+        for (name, contents) in [
+            ("metadata.toml", &b"tab-version = 1\n"[..]),
+            ("blink.rv32imac-v3.tbf", &[1; 8]),
+            ("blink.cortex-m4-v3.tbf", &[2; 8]),
+            ("blink.rv32i-v3.tbf", &[3; 8]),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tab.append_data(&mut header, name, contents).unwrap();
+        }
+        let tab = tab.into_inner().unwrap();
+
+        let (name, contents) = find_in_tab(&tab[..], "cortex-m4").unwrap();
+        assert_eq!(name, "blink.cortex-m4-v3.tbf");
+        assert_eq!(contents, [2; 8]);
+        let (name, contents) = find_in_tab(&tab[..], "rv32i").unwrap();
+        assert_eq!(name, "blink.rv32i-v3.tbf");
+        assert_eq!(contents, [3; 8]);
+        assert!(find_in_tab(&tab[..], "cortex-m0").is_err());
+        // Both names contain `blink`.
+        assert!(find_in_tab(&tab[..], "blink").is_err());
+    }
+}
@@ -0,0 +1,175 @@
+//! Reconstruct a minimal ELF from a TBF for debugging.
+//!
+//! The ELF has a single loadable segment and `.text` section containing the
+//! app binary, placed at the flash address the app binary runs from, and uses
+//! the TBF's entry point. Everything else from the original ELF (symbols,
+//! RAM segments, relocations) is lost, but this is enough for a debugger to map
+//! addresses to the app.
+
+use crate::tbf::Tbf;
+use std::io;
+
+const ELF_HEADER_SIZE: u16 = 52;
+const PROGRAM_HEADER_SIZE: u16 = 32;
+const SECTION_HEADER_SIZE: u16 = 40;
+/// Offset of the app binary in the ELF file.
+const TEXT_OFFSET: u32 = 0x60;
+/// Names of the sections, in order. The first section is the null section.
+const SHSTRTAB: &[u8] = b"\0.text\0.shstrtab\0";
+
+fn push_u16(elf: &mut Vec<u8>, value: u16) {
+    elf.extend(value.to_le_bytes());
+}
+
+fn push_u32(elf: &mut Vec<u8>, value: u32) {
+    elf.extend(value.to_le_bytes());
+}
+
+/// Address in flash of the start of the TBF, derived from the fixed flash
+/// address of the app binary if the TBF has one.
+pub fn default_tbf_address(tbf: &Tbf) -> Option<u32> {
+    tbf.fixed_address_flash()
+        .and_then(|address| address.checked_sub(tbf.app_binary_offset()))
+}
+
+/// Create a 32-bit little-endian ELF for `e_machine` containing the app binary
+/// of `tbf`, assuming the TBF starts at `tbf_address` in flash.
+pub fn tbf_to_elf(tbf: &Tbf, e_machine: u16, tbf_address: u32) -> io::Result<Vec<u8>> {
+    let binary = tbf.app_binary()?;
+    let binary_len = u32::try_from(binary.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "app binary is too large"))?;
+    let address = tbf_address.wrapping_add(tbf.app_binary_offset());
+    // init_fn_offset is relative to the end of the TBF header.
+    let entry = tbf_address
+        .wrapping_add(tbf.header_size as u32)
+        .wrapping_add(tbf.init_fn_offset().unwrap_or(0));
+
+    let shstrtab_offset = TEXT_OFFSET + binary_len;
+    let shoff = crate::util::align_to(shstrtab_offset + SHSTRTAB.len() as u32, 4);
+
+    let mut elf = Vec::new();
+    // ELF header
+    elf.extend([0x7f, b'E', b'L', b'F', 1, 1, 1]);
+    elf.resize(16, 0);
+    push_u16(&mut elf, elf::abi::ET_EXEC);
+    push_u16(&mut elf, e_machine);
+    push_u32(&mut elf, 1); // e_version
+    push_u32(&mut elf, entry);
+    push_u32(&mut elf, ELF_HEADER_SIZE as u32); // e_phoff
+    push_u32(&mut elf, shoff);
+    push_u32(&mut elf, 0); // e_flags
+    push_u16(&mut elf, ELF_HEADER_SIZE);
+    push_u16(&mut elf, PROGRAM_HEADER_SIZE);
+    push_u16(&mut elf, 1); // e_phnum
+    push_u16(&mut elf, SECTION_HEADER_SIZE);
+    push_u16(&mut elf, 3); // e_shnum
+    push_u16(&mut elf, 2); // e_shstrndx
+
+    // The single loadable segment.
+    push_u32(&mut elf, elf::abi::PT_LOAD);
+    push_u32(&mut elf, TEXT_OFFSET);
+    push_u32(&mut elf, address); // p_vaddr
+    push_u32(&mut elf, address); // p_paddr
+    push_u32(&mut elf, binary_len); // p_filesz
+    push_u32(&mut elf, binary_len); // p_memsz
+    push_u32(&mut elf, elf::abi::PF_R | elf::abi::PF_X);
+    push_u32(&mut elf, 4); // p_align
+
+    elf.resize(TEXT_OFFSET as usize, 0);
+    elf.extend(binary);
+    elf.extend(SHSTRTAB);
+    elf.resize(shoff as usize, 0);
+
+    // Section headers: null, .text, and .shstrtab.
+    elf.extend([0; SECTION_HEADER_SIZE as usize]);
+    for value in [
+        1, // sh_name
+        elf::abi::SHT_PROGBITS,
+        elf::abi::SHF_ALLOC | elf::abi::SHF_EXECINSTR,
+        address,
+        TEXT_OFFSET,
+        binary_len,
+        0, // sh_link
+        0, // sh_info
+        4, // sh_addralign
+        0, // sh_entsize
+    ] {
+        push_u32(&mut elf, value);
+    }
+    for value in [
+        7, // sh_name
+        elf::abi::SHT_STRTAB,
+        0,
+        0,
+        shstrtab_offset,
+        SHSTRTAB.len() as u32,
+        0,
+        0,
+        1,
+        0,
+    ] {
+        push_u32(&mut elf, value);
+    }
+
+    Ok(elf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{default_tbf_address, tbf_to_elf};
+    use crate::header::TbfHeader;
+    use crate::tbf::Tbf;
+
+    #[test]
+    pub fn reconstructs_elf() {
+        let mut tbfheader = TbfHeader::new();
+        tbfheader.set_binary_end_offset(0);
+        let header_length = tbfheader
+            .create(
+                0x1000,
+                0,
+                String::new(),
+                None,
+                Some(0x40080),
+                vec![],
+                vec![],
+                (None, None, None),
+                None,
+                None,
+                false,
+                None,
+                false,
+                None,
+                None,
+                None,
+                vec![],
+            )
+            .unwrap() as u32;
+        let protected_size = 0x80 - header_length;
+        tbfheader.set_protected_size(protected_size);
+        tbfheader.set_init_fn_offset(protected_size + 0x11);
+        tbfheader.set_binary_end_offset(0x100);
+        tbfheader.set_total_size(0x200);
+        let mut tbf = tbfheader.generate().unwrap().into_inner();
+        tbf.resize(0x80, 0);
+        tbf.extend((0..0x80).map(|i| i as u8));
+        tbf.resize(0x200, 0);
+
+        let tbf = Tbf::parse(&tbf).unwrap();
+        let tbf_address = default_tbf_address(&tbf).unwrap();
+        assert_eq!(tbf_address, 0x40000);
+        let elf_bytes = tbf_to_elf(&tbf, elf::abi::EM_ARM, tbf_address).unwrap();
+
+        let elf_file =
+            elf::ElfBytes::<elf::endian::AnyEndian>::minimal_parse(elf_bytes.as_slice()).unwrap();
+        assert_eq!(elf_file.ehdr.e_machine, elf::abi::EM_ARM);
+        assert_eq!(elf_file.ehdr.e_entry, 0x40091);
+        let segments: Vec<_> = elf_file.segments().unwrap().iter().collect();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].p_paddr, 0x40080);
+        assert_eq!(segments[0].p_filesz, 0x80);
+        let text = elf_file.section_header_by_name(".text").unwrap().unwrap();
+        let (data, _) = elf_file.section_data(&text).unwrap();
+        assert_eq!(data, tbf.app_binary().unwrap());
+    }
+}
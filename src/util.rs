@@ -1,3 +1,4 @@
+use crate::error::{Elf2TabError, ErrorCategory};
 use std::cmp;
 use std::io;
 use std::mem;
@@ -18,7 +19,7 @@ pub fn amount_alignment_needed(value: u32, box_size: u32) -> u32 {
     align_to(value, box_size) - value
 }
 
-pub fn do_pad<W: io::Write>(output: &mut W, length: usize) -> io::Result<()> {
+pub fn do_pad<W: io::Write + ?Sized>(output: &mut W, length: usize) -> io::Result<()> {
     let mut pad = length;
     let zero_buf = [0_u8; 512];
     while pad > 0 {
@@ -28,6 +29,16 @@ pub fn do_pad<W: io::Write>(output: &mut W, length: usize) -> io::Result<()> {
     Ok(())
 }
 
+/// `value` as it is stored in memory on a target with the given byte order.
+/// The TBF header is always little-endian, but words in the app binary are read
+/// by the app itself and so must match the ELF.
+pub fn target_u32_bytes(value: u32, endianness: elf::endian::AnyEndian) -> [u8; 4] {
+    match endianness {
+        elf::endian::AnyEndian::Little => value.to_le_bytes(),
+        elf::endian::AnyEndian::Big => value.to_be_bytes(),
+    }
+}
+
 /// Get a raw buffer for the memory of type `T`.
 ///
 /// # Safety
@@ -37,9 +48,113 @@ pub unsafe fn as_byte_slice<T: Copy>(input: &T) -> &[u8] {
     slice::from_raw_parts(input as *const T as *const u8, mem::size_of::<T>())
 }
 
+/// Rust/LLVM target triples and the Tock architecture names used for TBFs
+/// built for those targets.
+const TARGET_TRIPLE_ARCHITECTURES: &[(&str, &str)] = &[
+    ("thumbv6m-none-eabi", "cortex-m0"),
+    ("thumbv7m-none-eabi", "cortex-m3"),
+    ("thumbv7em-none-eabi", "cortex-m4"),
+    ("thumbv7em-none-eabihf", "cortex-m4"),
+    ("thumbv8m.base-none-eabi", "cortex-m23"),
+    ("thumbv8m.main-none-eabi", "cortex-m33"),
+    ("thumbv8m.main-none-eabihf", "cortex-m33"),
+    ("riscv32i-unknown-none-elf", "rv32i"),
+    ("riscv32imc-unknown-none-elf", "rv32imc"),
+    ("riscv32imac-unknown-none-elf", "rv32imac"),
+];
+
+/// Convert a target triple to the short architecture name Tock expects. Names
+/// that are not a known target triple are returned unchanged.
+pub fn architecture_from_target_triple(name: &str) -> &str {
+    TARGET_TRIPLE_ARCHITECTURES
+        .iter()
+        .find(|(triple, _)| *triple == name)
+        .map_or(name, |(_, architecture)| architecture)
+}
+
+/// Parse a list of driver numbers given as a JSON array of non-negative
+/// integers, e.g. `[0, 1, 262145]`. JSON has no hexadecimal numbers.
+pub fn parse_driver_list(contents: &str) -> Result<Vec<u32>, String> {
+    serde_json::from_str(contents)
+        .map_err(|e| format!("expected a JSON array of driver numbers: {}", e))
+}
+
+/// How warnings are reported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum WarningMode {
+    /// Print warnings to stderr.
+    #[default]
+    Print,
+    /// Do not print warnings.
+    Quiet,
+    /// Treat warnings as errors.
+    Error,
+}
+
+/// How to report warnings, from the `--quiet`, `--werror`, and `--strict`
+/// flags.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Warnings {
+    /// How all warnings are reported.
+    pub mode: WarningMode,
+    /// Treat warnings about the app configuration as errors.
+    pub strict: bool,
+}
+
+impl Warnings {
+    /// Pick how to report warnings from the `--quiet`, `--werror`, and
+    /// `--strict` flags. Warnings are still errors with `--quiet` and
+    /// `--werror` both set.
+    pub fn new(quiet: bool, werror: bool, strict: bool) -> Self {
+        let mode = if werror {
+            WarningMode::Error
+        } else if quiet {
+            WarningMode::Quiet
+        } else {
+            WarningMode::Print
+        };
+        Warnings { mode, strict }
+    }
+
+    /// Report a warning. Any further lines of `msg` are indented to line up
+    /// with the first. If warnings are errors, this returns the warning as an
+    /// error instead of printing it.
+    pub fn warn(self, msg: &str) -> io::Result<()> {
+        match self.mode {
+            WarningMode::Print => {
+                eprintln!("Warning! {}", msg.replace('\n', "\n         "));
+                Ok(())
+            }
+            WarningMode::Quiet => Ok(()),
+            WarningMode::Error => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("warning treated as error: {}", msg.replace('\n', " ")),
+            )),
+        }
+    }
+
+    /// Report a warning about the app configuration, such as a setting the
+    /// board or kernel may not honor. With `--strict` this returns an error
+    /// of `category` instead; otherwise it is reported like any warning.
+    pub fn warn_config(self, category: ErrorCategory, msg: &str) -> io::Result<()> {
+        if self.strict {
+            return Err(Elf2TabError::io_error(
+                category,
+                io::ErrorKind::InvalidData,
+                msg.replace('\n', " "),
+            ));
+        }
+        self.warn(msg)
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::{align_to, amount_alignment_needed};
+    use super::{
+        align_to, amount_alignment_needed, architecture_from_target_triple, parse_driver_list,
+        WarningMode, Warnings,
+    };
+    use crate::error::ErrorCategory;
 
     #[test]
     pub fn keeps_aligned_values() {
@@ -68,4 +183,74 @@ mod test {
 
         assert_eq!(result, 1);
     }
+
+    #[test]
+    pub fn maps_known_target_triples() {
+        assert_eq!(
+            architecture_from_target_triple("thumbv7em-none-eabihf"),
+            "cortex-m4"
+        );
+        assert_eq!(
+            architecture_from_target_triple("riscv32imc-unknown-none-elf"),
+            "rv32imc"
+        );
+    }
+
+    #[test]
+    pub fn keeps_unknown_architectures() {
+        assert_eq!(architecture_from_target_triple("cortex-m0"), "cortex-m0");
+        assert_eq!(architecture_from_target_triple("my-board"), "my-board");
+    }
+
+    #[test]
+    pub fn warnings_follow_flags() {
+        let mode = |quiet, werror| Warnings::new(quiet, werror, false).mode;
+        assert_eq!(mode(false, false), WarningMode::Print);
+        assert_eq!(mode(true, false), WarningMode::Quiet);
+        assert_eq!(mode(true, true), WarningMode::Error);
+        assert!(Warnings::new(true, false, false).warn("ignored").is_ok());
+        let error = Warnings::new(false, true, false)
+            .warn("large padding")
+            .unwrap_err();
+        assert!(error.to_string().contains("large padding"));
+
+        // `--strict` only makes configuration warnings errors.
+        let strict = Warnings::new(true, false, true);
+        assert!(strict.warn("ignored").is_ok());
+        let error = strict
+            .warn_config(ErrorCategory::Size, "too much RAM")
+            .unwrap_err();
+        assert_eq!(ErrorCategory::of(&error), ErrorCategory::Size);
+        assert!(Warnings::new(true, false, false)
+            .warn_config(ErrorCategory::Size, "too much RAM")
+            .is_ok());
+    }
+
+    #[test]
+    pub fn parses_driver_lists() {
+        assert_eq!(
+            parse_driver_list("[0, 1,\n 262145]\n"),
+            Ok(vec![0, 1, 0x40001])
+        );
+        assert_eq!(parse_driver_list(" [ ] "), Ok(vec![]));
+        assert!(parse_driver_list("0, 1").is_err());
+        assert!(parse_driver_list("[0, 1,]").is_err());
+        assert!(parse_driver_list("[-1]").is_err());
+        assert!(parse_driver_list("[1.5]").is_err());
+        assert!(parse_driver_list("[01]").is_err());
+        assert!(parse_driver_list("[\"1\"]").is_err());
+    }
+
+    #[test]
+    pub fn writes_words_in_target_byte_order() {
+        use elf::endian::AnyEndian;
+        assert_eq!(
+            super::target_u32_bytes(0x12345678, AnyEndian::Little),
+            [0x78, 0x56, 0x34, 0x12]
+        );
+        assert_eq!(
+            super::target_u32_bytes(0x12345678, AnyEndian::Big),
+            [0x12, 0x34, 0x56, 0x78]
+        );
+    }
 }